@@ -2,6 +2,10 @@
 //!
 //! 各ステップで共有する機能を提供します。
 
+// 各ステップのコードはバイナリとしても使われるため、ライブラリ内でも
+// `rust_tcp_handson_with_claude_code::stepNN`のパスで参照できるようにする
+extern crate self as rust_tcp_handson_with_claude_code;
+
 pub mod step01 {
     //! Step 01: Raw socket基本機能
 
@@ -15,3 +19,17 @@ pub mod step02 {
     // Step02のコードを公開
     include!("step02/main.rs");
 }
+
+pub mod step04 {
+    //! Step 04: TCP状態マシン
+
+    // Step04のコードを公開
+    include!("step04/main.rs");
+}
+
+pub mod step05 {
+    //! Step 05: データ送受信と再送制御
+
+    // Step05のコードを公開
+    include!("step05/main.rs");
+}
@@ -0,0 +1,303 @@
+// Packet device abstraction
+//
+// TCPの処理はIPデータグラムを「送る」「タイムアウト付きで受け取る」ことさえできれば
+// 下のリンクが何であっても変わらない。raw socket・TUN・メモリ上のループバックを
+// 同じトレイトで扱えるようにして、handshakeのテストをroot権限なしで実行できるようにする。
+
+use super::{get_errno, AddressFamily, Ipv6Header, IPV6_HEADER_SIZE};
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// IPデータグラムを送受信するデバイス
+///
+/// 送受信するのはIPヘッダーから始まる、ネットワークバイトオーダーの
+/// （そのまま回線に出せる）IPデータグラム
+pub trait PacketDevice {
+    /// IPデータグラムを1つ送信する
+    fn send(&self, datagram: &[u8]) -> Result<(), Box<dyn Error>>;
+
+    /// IPデータグラムを1つ受信する。タイムアウトまでに届かなければ`Ok(None)`
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+
+    /// 受信を待つためのファイルディスクリプタ（epollなどに登録する）。持たなければNone
+    fn raw_fd(&self) -> Option<i32> {
+        None
+    }
+}
+
+/// 1つのデバイスを複数の接続で共有する（リスナーと、そこから受け付けた接続など）
+impl<D: PacketDevice + ?Sized> PacketDevice for Rc<D> {
+    fn send(&self, datagram: &[u8]) -> Result<(), Box<dyn Error>> {
+        (**self).send(datagram)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        (**self).recv_timeout(timeout)
+    }
+
+    fn raw_fd(&self) -> Option<i32> {
+        (**self).raw_fd()
+    }
+}
+
+/// poll(2)用にタイムアウトをミリ秒へ切り上げる（切り捨てると早く戻りすぎる）
+pub(crate) fn poll_timeout_ms(timeout: Duration) -> i32 {
+    let millis = timeout.as_nanos().div_ceil(1_000_000);
+    millis.min(i32::MAX as u128) as i32
+}
+
+/// fdが読み込み可能になるまで待つ。タイムアウトしたら`Ok(false)`
+pub(crate) fn wait_readable(fd: i32, timeout: Duration) -> Result<bool, Box<dyn Error>> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut pollfd, 1, poll_timeout_ms(timeout)) };
+    if ready < 0 {
+        let errno = get_errno();
+        if errno == libc::EINTR {
+            return Ok(false);
+        }
+        return Err(format!("poll failed: errno {}", errno).into());
+    }
+    Ok(ready > 0)
+}
+
+/// raw socketによるデバイス（要root権限）
+///
+/// - IPv4: IP_HDRINCLで自前のIPヘッダーごと送る
+/// - IPv6: IPヘッダーはカーネルが付与・除去するので、送信時は取り除き、
+///   受信時は送信元アドレスから組み立て直して他のデバイスと形をそろえる
+#[derive(Debug)]
+pub struct RawSocketDevice {
+    fd: i32,
+    local_ip: IpAddr,
+}
+
+impl RawSocketDevice {
+    /// `local_ip`のアドレスファミリーでraw socketを作成
+    pub fn open(local_ip: IpAddr) -> Result<Self, Box<dyn Error>> {
+        let fd = AddressFamily::of(&local_ip).create_raw_socket()?;
+        Ok(Self { fd, local_ip })
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    fn send_ipv4(&self, datagram: &[u8]) -> Result<isize, Box<dyn Error>> {
+        if datagram.len() < 20 {
+            return Err("Datagram too short for IPv4 header".into());
+        }
+        let dest = Ipv4Addr::new(datagram[16], datagram[17], datagram[18], datagram[19]);
+
+        // IpHeader::to_bytes()と同じ形式に戻す:
+        // length/flags_fragmentはホストバイトオーダー、id/checksumはカーネルに任せる
+        let mut packet = datagram.to_vec();
+        let length = u16::from_be_bytes([packet[2], packet[3]]);
+        let flags_fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        packet[2..4].copy_from_slice(&length.to_ne_bytes());
+        packet[4..6].copy_from_slice(&[0, 0]);
+        packet[6..8].copy_from_slice(&flags_fragment.to_ne_bytes());
+        packet[10..12].copy_from_slice(&[0, 0]);
+
+        let dest_sockaddr = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: 0,
+            sin_addr: libc::in_addr {
+                s_addr: u32::from(dest).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+        Ok(unsafe {
+            libc::sendto(
+                self.fd,
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &dest_sockaddr as *const _ as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in>() as u32,
+            )
+        })
+    }
+
+    fn send_ipv6(&self, datagram: &[u8]) -> Result<isize, Box<dyn Error>> {
+        let header = Ipv6Header::from_bytes(datagram)?;
+
+        let mut dest_sockaddr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        dest_sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        dest_sockaddr.sin6_addr.s6_addr = header.dest_ip().octets();
+
+        // IPv6ヘッダーはカーネルが付与するので、ペイロードだけを送る
+        let payload = &datagram[IPV6_HEADER_SIZE..];
+        Ok(unsafe {
+            libc::sendto(
+                self.fd,
+                payload.as_ptr() as *const libc::c_void,
+                payload.len(),
+                0,
+                &dest_sockaddr as *const _ as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in6>() as u32,
+            )
+        })
+    }
+}
+
+impl PacketDevice for RawSocketDevice {
+    fn send(&self, datagram: &[u8]) -> Result<(), Box<dyn Error>> {
+        let result = match self.local_ip {
+            IpAddr::V4(_) => self.send_ipv4(datagram)?,
+            IpAddr::V6(_) => self.send_ipv6(datagram)?,
+        };
+        if result < 0 {
+            return Err(format!("Failed to send packet: errno {}", get_errno()).into());
+        }
+        Ok(())
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if !wait_readable(self.fd, timeout)? {
+            return Ok(None);
+        }
+
+        // 最大IPパケットサイズ（65535バイト）
+        const MAX_PACKET_SIZE: usize = 65535;
+        let mut buffer = vec![0u8; MAX_PACKET_SIZE];
+        let mut source: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut source_len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        let bytes_received = unsafe {
+            libc::recvfrom(
+                self.fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                libc::MSG_DONTWAIT,
+                &mut source as *mut _ as *mut libc::sockaddr,
+                &mut source_len,
+            )
+        };
+        if bytes_received < 0 {
+            let errno = get_errno();
+            if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK {
+                return Ok(None);
+            }
+            return Err(format!("Failed to receive packet: errno {}", errno).into());
+        }
+        buffer.truncate(bytes_received as usize);
+
+        if let IpAddr::V6(local_ip) = self.local_ip {
+            let source = unsafe { *(&source as *const _ as *const libc::sockaddr_in6) };
+            let source_ip = Ipv6Addr::from(source.sin6_addr.s6_addr);
+            let mut packet = Ipv6Header::new(source_ip, local_ip, buffer.len() as u16).to_bytes();
+            packet.extend_from_slice(&buffer);
+            buffer = packet;
+        }
+        Ok(Some(buffer))
+    }
+
+    fn raw_fd(&self) -> Option<i32> {
+        Some(self.fd)
+    }
+}
+
+impl Drop for RawSocketDevice {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl PacketDevice for super::TunDevice {
+    fn send(&self, datagram: &[u8]) -> Result<(), Box<dyn Error>> {
+        super::TunDevice::send(self, datagram)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        super::TunDevice::recv_timeout(self, timeout)
+    }
+
+    fn raw_fd(&self) -> Option<i32> {
+        Some(self.fd())
+    }
+}
+
+/// メモリ上のループバックデバイス
+///
+/// `pair()`で作った2つのデバイスは、片方で送ったデータグラムがもう片方で受信できる。
+/// root権限もネットワークも不要なので、テストで相手ホストの役を演じるのに使う
+#[derive(Debug)]
+pub struct LoopbackDevice {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl LoopbackDevice {
+    /// 互いにつながった2つのデバイスを作成
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        (Self { tx: a_tx, rx: a_rx }, Self { tx: b_tx, rx: b_rx })
+    }
+}
+
+impl PacketDevice for LoopbackDevice {
+    fn send(&self, datagram: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.tx
+            .send(datagram.to_vec())
+            .map_err(|_| "Loopback peer has been dropped".into())
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(datagram) => Ok(Some(datagram)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err("Loopback peer has been dropped".into()),
+        }
+    }
+}
+
+/// socketpair(2)によるループバックデバイス
+///
+/// `LoopbackDevice`と同じく2つのデバイスを互いにつなぐが、ファイルディスクリプタを
+/// 持つので、epollで他のfdやタイマーと一緒に待てる
+#[derive(Debug)]
+pub struct SocketPairDevice {
+    socket: UnixDatagram,
+}
+
+impl SocketPairDevice {
+    /// 互いにつながった2つのデバイスを作成
+    pub fn pair() -> Result<(Self, Self), Box<dyn Error>> {
+        let (a, b) = UnixDatagram::pair()?;
+        Ok((Self { socket: a }, Self { socket: b }))
+    }
+}
+
+impl PacketDevice for SocketPairDevice {
+    fn send(&self, datagram: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.socket.send(datagram)?;
+        Ok(())
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if !wait_readable(self.socket.as_raw_fd(), timeout)? {
+            return Ok(None);
+        }
+        let mut buffer = vec![0u8; 65535];
+        let len = self.socket.recv(&mut buffer)?;
+        buffer.truncate(len);
+        Ok(Some(buffer))
+    }
+
+    fn raw_fd(&self) -> Option<i32> {
+        Some(self.socket.as_raw_fd())
+    }
+}
@@ -0,0 +1,294 @@
+// IPv6 Header (RFC 8200 Section 3, 4)
+//
+// IPv6ヘッダーは40バイト固定で、オプションの代わりに「拡張ヘッダー」を
+// Next Headerフィールドで数珠つなぎにする。上位プロトコル（TCP）に到達するには
+// 拡張ヘッダーを順に辿る必要がある。
+
+use super::{IpParseError, IP_PROTOCOL_TCP};
+use libc::{AF_INET6, IPPROTO_TCP, SOCK_RAW};
+use std::error::Error;
+use std::net::Ipv6Addr;
+
+pub const IPV6_HEADER_SIZE: usize = 40;
+
+/// Next Header の値（IANA "Assigned Internet Protocol Numbers"）
+pub mod ipv6_next_header {
+    pub const HOP_BY_HOP: u8 = 0; // RFC 8200
+    pub const TCP: u8 = 6;
+    pub const ROUTING: u8 = 43; // RFC 8200
+    pub const FRAGMENT: u8 = 44; // RFC 8200
+    pub const ESP: u8 = 50; // RFC 4303
+    pub const AUTHENTICATION: u8 = 51; // RFC 4302
+    pub const NO_NEXT_HEADER: u8 = 59; // RFC 8200
+    pub const DESTINATION_OPTIONS: u8 = 60; // RFC 8200
+}
+
+/// IPv6 Header Format (RFC 8200 Section 3)
+///
+/// ```text
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |Version| Traffic Class |           Flow Label                  |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |         Payload Length        |  Next Header  |   Hop Limit   |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |                         Source Address (128bit)               |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |                      Destination Address (128bit)             |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// ```
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv6Header {
+    // Version(4bit) + Traffic Class(8bit) + Flow Label(20bit)
+    version_class_flow: u32,
+    // 拡張ヘッダーを含む、IPv6ヘッダー以降の長さ
+    payload_length: u16,
+    // 直後のヘッダーの種類（拡張ヘッダー or 上位プロトコル）
+    next_header: u8,
+    // IPv4のTTLに相当
+    hop_limit: u8,
+    source: u128,
+    destination: u128,
+}
+
+impl Ipv6Header {
+    /// TCPを直接運ぶ（拡張ヘッダーなしの）IPv6ヘッダーを作成
+    pub fn new(source: Ipv6Addr, dest: Ipv6Addr, payload_len: u16) -> Self {
+        Self {
+            version_class_flow: 6 << 28,
+            payload_length: payload_len,
+            next_header: IP_PROTOCOL_TCP,
+            hop_limit: 64,
+            source: u128::from(source),
+            destination: u128::from(dest),
+        }
+    }
+
+    /// IPv6ヘッダーをバイト配列に変換
+    ///
+    /// IPv4と違いカーネル向けのバイトオーダーの癖はなく、すべてネットワークバイトオーダー
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(IPV6_HEADER_SIZE);
+        bytes.extend_from_slice(&self.version_class_flow.to_be_bytes());
+        bytes.extend_from_slice(&self.payload_length.to_be_bytes());
+        bytes.push(self.next_header);
+        bytes.push(self.hop_limit);
+        bytes.extend_from_slice(&self.source.to_be_bytes());
+        bytes.extend_from_slice(&self.destination.to_be_bytes());
+        bytes
+    }
+
+    /// ネットワークバイトオーダーのバイト配列から固定ヘッダー部分を読み込む
+    pub fn from_bytes(data: &[u8]) -> Result<Self, IpParseError> {
+        if data.len() < IPV6_HEADER_SIZE {
+            return Err(IpParseError::Truncated {
+                needed: IPV6_HEADER_SIZE,
+                available: data.len(),
+            });
+        }
+
+        let version = data[0] >> 4;
+        if version != 6 {
+            return Err(IpParseError::UnsupportedVersion(version));
+        }
+
+        let mut source = [0u8; 16];
+        let mut destination = [0u8; 16];
+        source.copy_from_slice(&data[8..24]);
+        destination.copy_from_slice(&data[24..40]);
+
+        Ok(Self {
+            version_class_flow: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            payload_length: u16::from_be_bytes([data[4], data[5]]),
+            next_header: data[6],
+            hop_limit: data[7],
+            source: u128::from_be_bytes(source),
+            destination: u128::from_be_bytes(destination),
+        })
+    }
+
+    pub fn version(&self) -> u8 {
+        (self.version_class_flow >> 28) as u8
+    }
+
+    pub fn traffic_class(&self) -> u8 {
+        (self.version_class_flow >> 20) as u8
+    }
+
+    pub fn flow_label(&self) -> u32 {
+        self.version_class_flow & 0x000F_FFFF
+    }
+
+    pub fn payload_length(&self) -> u16 {
+        self.payload_length
+    }
+
+    pub fn next_header(&self) -> u8 {
+        self.next_header
+    }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.hop_limit
+    }
+
+    pub fn source_ip(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.source)
+    }
+
+    pub fn dest_ip(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.destination)
+    }
+}
+
+/// 拡張ヘッダー1つ分（RFC 8200 Section 4）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv6ExtensionHeader {
+    /// この拡張ヘッダーの種類（直前のヘッダーのNext Header値）
+    pub header_type: u8,
+    /// 次のヘッダーの種類
+    pub next_header: u8,
+    /// Next Header/Length以降の内容
+    pub data: Vec<u8>,
+}
+
+impl Ipv6ExtensionHeader {
+    /// Fragmentヘッダーの場合、(フラグメントオフセット（バイト）, More Fragments)
+    pub fn fragment_info(&self) -> Option<(usize, bool)> {
+        if self.header_type != ipv6_next_header::FRAGMENT || self.data.len() < 6 {
+            return None;
+        }
+        let offset_flags = u16::from_be_bytes([self.data[1], self.data[2]]);
+        Some(((offset_flags & 0xFFF8) as usize, offset_flags & 0x0001 != 0))
+    }
+}
+
+/// 拡張ヘッダーを辿り終えたIPv6パケット
+#[derive(Debug, Clone)]
+pub struct Ipv6Packet {
+    pub header: Ipv6Header,
+    pub extension_headers: Vec<Ipv6ExtensionHeader>,
+    /// 上位プロトコル（最後のNext Header値）
+    pub protocol: u8,
+    /// パケット先頭から上位プロトコルのヘッダーまでのバイト数
+    pub payload_offset: usize,
+}
+
+impl Ipv6Packet {
+    /// フラグメント化されたパケットか（IPv6の再構築は未対応）
+    pub fn is_fragment(&self) -> bool {
+        self.extension_headers
+            .iter()
+            .filter_map(|ext| ext.fragment_info())
+            .any(|(offset, more)| offset != 0 || more)
+    }
+}
+
+/// 拡張ヘッダーを上位プロトコルに到達するまで辿る
+///
+/// `data`はIPv6固定ヘッダーの直後から始まるバイト列。
+/// ESP以降は暗号化されていて辿れないため、ESPを上位プロトコルとして扱う。
+pub fn walk_extension_headers(
+    first_header: u8,
+    data: &[u8],
+) -> Result<(Vec<Ipv6ExtensionHeader>, u8, usize), IpParseError> {
+    use ipv6_next_header::*;
+
+    let mut headers = Vec::new();
+    let mut header_type = first_header;
+    let mut offset = 0;
+
+    loop {
+        let length = match header_type {
+            HOP_BY_HOP | ROUTING | DESTINATION_OPTIONS | FRAGMENT | AUTHENTICATION => {
+                if data.len() < offset + 2 {
+                    return Err(IpParseError::MalformedExtensionHeader(header_type));
+                }
+                match header_type {
+                    // Fragmentヘッダーは8バイト固定
+                    FRAGMENT => 8,
+                    // AHの長さは4オクテット単位（先頭8バイトを除く）
+                    AUTHENTICATION => (data[offset + 1] as usize + 2) * 4,
+                    // その他は8オクテット単位（先頭8バイトを除く）
+                    _ => (data[offset + 1] as usize + 1) * 8,
+                }
+            }
+            _ => return Ok((headers, header_type, offset)),
+        };
+
+        if data.len() < offset + length {
+            return Err(IpParseError::MalformedExtensionHeader(header_type));
+        }
+        let next_header = data[offset];
+        headers.push(Ipv6ExtensionHeader {
+            header_type,
+            next_header,
+            data: data[offset + 2..offset + length].to_vec(),
+        });
+        offset += length;
+        header_type = next_header;
+    }
+}
+
+/// 受信したIPv6パケットを解析・検証する（`parse_ip_header`のIPv6版）
+///
+/// - 固定ヘッダーとPayload Lengthに対してバッファ長が足りているか
+/// - 拡張ヘッダーを辿った先の上位プロトコルがTCPか
+///
+/// IPv6ヘッダーにはチェックサムがない（TCPの疑似ヘッダーで保護される）
+pub fn parse_ipv6_header(data: &[u8]) -> Result<Ipv6Packet, IpParseError> {
+    let header = Ipv6Header::from_bytes(data)?;
+
+    let total_length = IPV6_HEADER_SIZE + header.payload_length() as usize;
+    if data.len() < total_length {
+        return Err(IpParseError::Truncated {
+            needed: total_length,
+            available: data.len(),
+        });
+    }
+
+    let (extension_headers, protocol, offset) =
+        walk_extension_headers(header.next_header(), &data[IPV6_HEADER_SIZE..total_length])?;
+
+    if protocol != IP_PROTOCOL_TCP {
+        return Err(IpParseError::UnexpectedProtocol {
+            expected: IP_PROTOCOL_TCP,
+            actual: protocol,
+        });
+    }
+
+    Ok(Ipv6Packet {
+        header,
+        extension_headers,
+        protocol,
+        payload_offset: IPV6_HEADER_SIZE + offset,
+    })
+}
+
+/// IPv6用のraw socketを作成
+///
+/// IPv6のraw socketではIPヘッダーをカーネルが付与し、受信時も取り除かれる
+/// （IPv4のIP_HDRINCLに相当する使い方はしない）
+pub fn create_raw_socket_v6() -> Result<i32, Box<dyn Error>> {
+    let socket_fd = unsafe { libc::socket(AF_INET6, SOCK_RAW, IPPROTO_TCP) };
+    if socket_fd < 0 {
+        return Err("Failed to create IPv6 raw socket".into());
+    }
+    Ok(socket_fd)
+}
+
+/// 宛先に到達するために使われるローカルIPv6アドレスを取得
+pub fn get_local_ipv6(remote: Ipv6Addr) -> Option<Ipv6Addr> {
+    use std::net::UdpSocket;
+
+    // UDPソケットをconnectすると、カーネルが経路に応じた送信元アドレスを選ぶ
+    if let Ok(socket) = UdpSocket::bind("[::]:0") {
+        if socket.connect((remote, 9)).is_ok() {
+            if let Ok(local_addr) = socket.local_addr() {
+                if let std::net::IpAddr::V6(ipv6) = local_addr.ip() {
+                    return Some(ipv6);
+                }
+            }
+        }
+    }
+    None
+}
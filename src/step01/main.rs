@@ -1,10 +1,196 @@
 use libc::{AF_INET, IPPROTO_TCP, SOCK_RAW};
 use log::info;
-use std::{error::Error, net::Ipv4Addr};
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+};
 
 // 必要な定数
 pub const IP_HEADER_SIZE: usize = 20;
 pub const IP_PROTOCOL_TCP: u8 = 6;
+/// IPv4オプションの最大長（IHL最大値15 × 4 - 固定部20バイト）
+pub const IP_MAX_OPTIONS_SIZE: usize = 40;
+
+mod reassembly;
+pub use reassembly::{
+    FragmentKey, Reassembler, DEFAULT_REASSEMBLY_MEMORY, DEFAULT_REASSEMBLY_TIMEOUT,
+};
+
+mod ipv6;
+pub use ipv6::{
+    create_raw_socket_v6, get_local_ipv6, ipv6_next_header, parse_ipv6_header,
+    walk_extension_headers, Ipv6ExtensionHeader, Ipv6Header, Ipv6Packet, IPV6_HEADER_SIZE,
+};
+
+#[cfg(target_os = "linux")]
+mod tun;
+#[cfg(target_os = "linux")]
+pub use tun::TunDevice;
+
+mod device;
+pub use device::{LoopbackDevice, PacketDevice, RawSocketDevice, SocketPairDevice};
+
+/// IPヘッダー解析時のエラー
+///
+/// 呼び出し側が「途中で切れている」「壊れている」「TCP以外」を区別できるようにする
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpParseError {
+    /// バッファが必要な長さに足りない（固定部・IHL・Total Lengthのいずれか）
+    Truncated { needed: usize, available: usize },
+    /// バージョンが4ではない
+    UnsupportedVersion(u8),
+    /// IHLが5未満（20バイト未満のヘッダーは存在しない）
+    InvalidHeaderLength(u8),
+    /// Total LengthがIPヘッダー長より小さい
+    InvalidTotalLength {
+        total_length: u16,
+        header_length: usize,
+    },
+    /// ヘッダーチェックサムが一致しない
+    ChecksumMismatch { checksum: u16 },
+    /// IPオプションの形式が不正
+    MalformedOption,
+    /// フラグメントの長さやオフセットが不正
+    InvalidFragment,
+    /// IPv6拡張ヘッダーが途中で切れている（値は拡張ヘッダーの種類）
+    MalformedExtensionHeader(u8),
+    /// 上位プロトコルが期待と異なる
+    UnexpectedProtocol { expected: u8, actual: u8 },
+}
+
+impl fmt::Display for IpParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpParseError::Truncated { needed, available } => write!(
+                f,
+                "Truncated IP packet: need {} bytes, got {}",
+                needed, available
+            ),
+            IpParseError::UnsupportedVersion(version) => {
+                write!(f, "Expected IPv4 (version 4), got version {}", version)
+            }
+            IpParseError::InvalidHeaderLength(ihl) => {
+                write!(f, "Invalid IP header length: IHL={}", ihl)
+            }
+            IpParseError::InvalidTotalLength {
+                total_length,
+                header_length,
+            } => write!(
+                f,
+                "Invalid total length {} (header length {})",
+                total_length, header_length
+            ),
+            IpParseError::ChecksumMismatch { checksum } => {
+                write!(f, "IP header checksum mismatch: 0x{:04X}", checksum)
+            }
+            IpParseError::MalformedOption => write!(f, "Malformed IP option"),
+            IpParseError::InvalidFragment => write!(f, "Invalid IP fragment"),
+            IpParseError::MalformedExtensionHeader(header_type) => {
+                write!(f, "Malformed IPv6 extension header: type {}", header_type)
+            }
+            IpParseError::UnexpectedProtocol { expected, actual } => {
+                write!(f, "Expected protocol {}, got protocol {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl Error for IpParseError {}
+
+/// IPv4オプション（RFC 791 Section 3.1）
+///
+/// Type(1バイト) = copied flag(1bit) + class(2bit) + number(5bit)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ipv4Option {
+    /// Type=0: オプションリストの終端
+    EndOfOptionList,
+    /// Type=1: 境界合わせ用
+    NoOperation,
+    /// Type=7: Record Route
+    RecordRoute(Vec<u8>),
+    /// Type=68: Internet Timestamp
+    Timestamp(Vec<u8>),
+    /// Type=131: Loose Source and Record Route
+    LooseSourceRoute(Vec<u8>),
+    /// Type=137: Strict Source and Record Route
+    StrictSourceRoute(Vec<u8>),
+    /// Type=148: Router Alert（RFC 2113）
+    RouterAlert(u16),
+    /// 未知のオプション
+    Unknown { option_type: u8, data: Vec<u8> },
+}
+
+impl Ipv4Option {
+    /// オプションのType値
+    pub fn option_type(&self) -> u8 {
+        match self {
+            Ipv4Option::EndOfOptionList => 0,
+            Ipv4Option::NoOperation => 1,
+            Ipv4Option::RecordRoute(_) => 7,
+            Ipv4Option::Timestamp(_) => 68,
+            Ipv4Option::LooseSourceRoute(_) => 131,
+            Ipv4Option::StrictSourceRoute(_) => 137,
+            Ipv4Option::RouterAlert(_) => 148,
+            Ipv4Option::Unknown { option_type, .. } => *option_type,
+        }
+    }
+
+    /// フラグメント化の際に全フラグメントへコピーすべきオプションか（copied flag）
+    pub fn is_copied(&self) -> bool {
+        self.option_type() & 0x80 != 0
+    }
+}
+
+/// IPv4オプション領域をパース
+pub fn parse_ipv4_options(data: &[u8]) -> Result<Vec<Ipv4Option>, IpParseError> {
+    let mut options = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let option_type = data[i];
+        match option_type {
+            0 => {
+                options.push(Ipv4Option::EndOfOptionList);
+                break;
+            }
+            1 => {
+                options.push(Ipv4Option::NoOperation);
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        if i + 1 >= data.len() {
+            return Err(IpParseError::MalformedOption);
+        }
+        let length = data[i + 1] as usize;
+        if length < 2 || i + length > data.len() {
+            return Err(IpParseError::MalformedOption);
+        }
+        let body = data[i + 2..i + length].to_vec();
+
+        let option = match option_type {
+            7 => Ipv4Option::RecordRoute(body),
+            68 => Ipv4Option::Timestamp(body),
+            131 => Ipv4Option::LooseSourceRoute(body),
+            137 => Ipv4Option::StrictSourceRoute(body),
+            148 => {
+                if body.len() != 2 {
+                    return Err(IpParseError::MalformedOption);
+                }
+                Ipv4Option::RouterAlert(u16::from_be_bytes([body[0], body[1]]))
+            }
+            _ => Ipv4Option::Unknown {
+                option_type,
+                data: body,
+            },
+        };
+        options.push(option);
+        i += length;
+    }
+    Ok(options)
+}
 
 // クロスプラットフォーム対応: errnoを取得
 #[cfg(target_os = "linux")]
@@ -35,6 +221,7 @@ pub fn get_local_ip() -> Option<Ipv4Addr> {
 }
 
 #[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
 pub struct IpHeader {
     // 1バイト目：Version(4bit) + IHL(4bit)
     version_ihl: u8,
@@ -56,6 +243,10 @@ pub struct IpHeader {
     source: u32,
     // 17-20バイト目：宛先IPアドレス（ホストバイトオーダー）
     destination: u32,
+    // 21バイト目以降：IPオプション（IHL > 5 の場合のみ）
+    options: [u8; IP_MAX_OPTIONS_SIZE],
+    // optionsのうち有効なバイト数
+    options_len: u8,
 }
 
 pub fn create_raw_socket() -> Result<i32, Box<dyn Error>> {
@@ -87,6 +278,48 @@ pub fn create_raw_socket() -> Result<i32, Box<dyn Error>> {
     Ok(socket_fd)
 }
 
+/// アドレスファミリー（IPv4 / IPv6）
+///
+/// raw socketの作り方と、受信データにIPヘッダーが含まれるかどうかが異なる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn of(addr: &IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => AddressFamily::Ipv4,
+            IpAddr::V6(_) => AddressFamily::Ipv6,
+        }
+    }
+
+    /// このアドレスファミリー用のraw socketを作成
+    pub fn create_raw_socket(self) -> Result<i32, Box<dyn Error>> {
+        match self {
+            AddressFamily::Ipv4 => create_raw_socket(),
+            AddressFamily::Ipv6 => create_raw_socket_v6(),
+        }
+    }
+
+    /// IPヘッダーの長さ（オプション・拡張ヘッダーなし）
+    pub fn header_size(self) -> usize {
+        match self {
+            AddressFamily::Ipv4 => IP_HEADER_SIZE,
+            AddressFamily::Ipv6 => IPV6_HEADER_SIZE,
+        }
+    }
+}
+
+/// 宛先と同じアドレスファミリーのローカルIPアドレスを取得
+pub fn get_local_ip_for(remote: IpAddr) -> Option<IpAddr> {
+    match remote {
+        IpAddr::V4(_) => get_local_ip().map(IpAddr::V4),
+        IpAddr::V6(remote) => get_local_ipv6(remote).map(IpAddr::V6),
+    }
+}
+
 impl IpHeader {
     /// macOS raw socket用のIPヘッダー作成
     ///
@@ -105,6 +338,8 @@ impl IpHeader {
             checksum: 0,            // チェックサム（カーネルが計算）
             source: u32::from(source), // 送信元IP
             destination: u32::from(dest), // 宛先IP
+            options: [0; IP_MAX_OPTIONS_SIZE],
+            options_len: 0,
         }
     }
 
@@ -130,21 +365,55 @@ impl IpHeader {
         // IPアドレス: ネットワークバイトオーダー
         bytes.extend_from_slice(&self.source.to_be_bytes());
         bytes.extend_from_slice(&self.destination.to_be_bytes());
+        bytes.extend_from_slice(self.options_bytes());
 
         bytes
     }
 
+    /// カーネルを介さずにそのまま回線へ出せるバイト配列に変換（TUNデバイス用）
+    ///
+    /// `new()`で作ったヘッダー専用。`to_bytes()`と違い、全フィールドをネットワークバイトオーダーで出力し、
+    /// Identificationとチェックサムも自分で設定する
+    pub fn to_wire_bytes(&self, identification: u16) -> Vec<u8> {
+        let mut wire = *self;
+        // new()はflags_fragmentをホストバイトオーダーで持っているので論理値に戻す
+        wire.flags_fragment = u16::from_be_bytes(self.flags_fragment.to_ne_bytes());
+        wire.id = identification;
+        wire.calculate_checksum();
+        wire.to_network_bytes()
+    }
+
     /// ネットワークバイトオーダーのバイト配列からIpHeaderを作成
     ///
-    /// Step1では実際には使用しない（受信処理はmacOSで制限される）
-    /// 学習目的でのパケット解析用の実装
-    fn from_bytes(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+    /// IHLに従ってオプション領域まで読み込む。
+    /// Total Lengthやチェックサムの検証は`parse_ip_header`で行う。
+    pub fn from_bytes(data: &[u8]) -> Result<Self, IpParseError> {
         if data.len() < IP_HEADER_SIZE {
-            return Err("Packet too short for IP header".into());
+            return Err(IpParseError::Truncated {
+                needed: IP_HEADER_SIZE,
+                available: data.len(),
+            });
         }
 
         // ネットワークバイトオーダー→ネイティブバイトオーダー変換
         let version_ihl = data[0];
+        let version = version_ihl >> 4;
+        if version != 4 {
+            return Err(IpParseError::UnsupportedVersion(version));
+        }
+
+        let ihl = version_ihl & 0x0F;
+        if ihl < 5 {
+            return Err(IpParseError::InvalidHeaderLength(ihl));
+        }
+        let header_length = ihl as usize * 4;
+        if data.len() < header_length {
+            return Err(IpParseError::Truncated {
+                needed: header_length,
+                available: data.len(),
+            });
+        }
+
         let tos = data[1];
         let length = u16::from_be_bytes([data[2], data[3]]);
         let id = u16::from_be_bytes([data[4], data[5]]);
@@ -155,6 +424,10 @@ impl IpHeader {
         let source = u32::from_be_bytes([data[12], data[13], data[14], data[15]]);
         let destination = u32::from_be_bytes([data[16], data[17], data[18], data[19]]);
 
+        let options_len = header_length - IP_HEADER_SIZE;
+        let mut options = [0; IP_MAX_OPTIONS_SIZE];
+        options[..options_len].copy_from_slice(&data[IP_HEADER_SIZE..header_length]);
+
         Ok(IpHeader {
             version_ihl,
             tos,
@@ -166,44 +439,110 @@ impl IpHeader {
             checksum,
             source,
             destination,
+            options,
+            options_len: options_len as u8,
         })
     }
 
     // フィールドアクセス用メソッド
-    fn version(&self) -> u8 {
+    pub fn version(&self) -> u8 {
         (self.version_ihl >> 4) & 0x0F
     }
 
-    fn header_length(&self) -> u8 {
+    pub fn header_length(&self) -> u8 {
         (self.version_ihl & 0x0F) * 4
     }
 
-    fn source_ip(&self) -> Ipv4Addr {
+    pub fn total_length(&self) -> u16 {
+        self.length
+    }
+
+    pub fn identification(&self) -> u16 {
+        self.id
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    pub fn checksum(&self) -> u16 {
+        self.checksum
+    }
+
+    // フラグメント関連（from_bytesで読み込んだ受信ヘッダー用）
+    // new()はmacOSカーネル向けにflags_fragmentをホストバイトオーダーで持つため対象外
+
+    /// Don't Fragmentフラグ
+    pub fn dont_fragment(&self) -> bool {
+        self.flags_fragment & 0x4000 != 0
+    }
+
+    /// More Fragmentsフラグ
+    pub fn more_fragments(&self) -> bool {
+        self.flags_fragment & 0x2000 != 0
+    }
+
+    /// フラグメントオフセット（バイト単位 = フィールド値 × 8）
+    pub fn fragment_offset(&self) -> usize {
+        (self.flags_fragment & 0x1FFF) as usize * 8
+    }
+
+    /// データグラムの一部（フラグメント）かどうか
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+
+    pub fn source_ip(&self) -> Ipv4Addr {
         Ipv4Addr::from(self.source)
     }
 
-    fn dest_ip(&self) -> Ipv4Addr {
+    pub fn dest_ip(&self) -> Ipv4Addr {
         Ipv4Addr::from(self.destination)
     }
 
-    /*
-    /// NOTE: チェックサムはカーネルが計算するため不要になった
+    /// IPオプションの生バイト列（IHL > 5 の場合のみ非空）
+    pub fn options_bytes(&self) -> &[u8] {
+        &self.options[..self.options_len as usize]
+    }
+
+    /// IPオプションを型付きのリストとして取得
+    pub fn options(&self) -> Result<Vec<Ipv4Option>, IpParseError> {
+        parse_ipv4_options(self.options_bytes())
+    }
+
+    /// 全フィールドをネットワークバイトオーダーで並べたヘッダー（チェックサム計算用）
+    ///
+    /// `to_bytes()`はmacOSカーネル向けにlength等をホストバイトオーダーで出力し、
+    /// id/checksumを0にするため、チェックサム計算には使えない
+    fn to_network_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.header_length() as usize);
+        bytes.push(self.version_ihl);
+        bytes.push(self.tos);
+        bytes.extend_from_slice(&self.length.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(&self.flags_fragment.to_be_bytes());
+        bytes.push(self.ttl);
+        bytes.push(self.protocol);
+        bytes.extend_from_slice(&self.checksum.to_be_bytes());
+        bytes.extend_from_slice(&self.source.to_be_bytes());
+        bytes.extend_from_slice(&self.destination.to_be_bytes());
+        bytes.extend_from_slice(self.options_bytes());
+        bytes
+    }
+
     /// RFC 1071のチェックサム計算（共通ロジック）
     fn compute_checksum_sum(&self) -> u32 {
+        let header_bytes = self.to_network_bytes();
         let mut sum: u32 = 0;
 
-        // IPヘッダーをバイト配列として取得
-        let header_bytes = unsafe {
-            std::slice::from_raw_parts(
-                self as *const _ as *const u8,
-                std::mem::size_of::<IpHeader>(),
-            )
-        };
-
         // 16bit（2バイト）単位で加算
-        for i in (0..IP_HEADER_SIZE).step_by(2) {
+        for chunk in header_bytes.chunks(2) {
             // 2バイトを16bit値として結合（ビッグエンディアン）
-            let word = ((header_bytes[i] as u16) << 8) + header_bytes[i + 1] as u16;
+            let word = ((chunk[0] as u16) << 8) + chunk[1] as u16;
             sum += word as u32;
         }
 
@@ -216,24 +555,22 @@ impl IpHeader {
     }
 
     /// RFC 1071のチェックサムアルゴリズム実装
-    fn calculate_checksum(&mut self) {
+    ///
+    /// 送信時はカーネルが計算するため、主に受信データの検証やテストで使う
+    pub fn calculate_checksum(&mut self) {
         self.checksum = 0;
         let sum = self.compute_checksum_sum();
 
         // 全ビット反転（1の補数）
-        let checksum = !(sum as u16);
-
-        // ネットワークバイトオーダーで設定
-        self.checksum = checksum.to_be();
+        self.checksum = !(sum as u16);
     }
 
     /// チェックサム検証
-    fn verify_checksum(&self) -> bool {
+    pub fn verify_checksum(&self) -> bool {
         let sum = self.compute_checksum_sum();
         // RFC 1071: 検証時は結果が0xFFFFになるべき
         sum as u16 == 0xFFFF
     }
-    */
 }
 
 pub fn send_packet(
@@ -288,43 +625,64 @@ pub fn send_packet(
     Ok(())
 }
 
-fn parse_ip_header(data: &[u8]) -> Result<IpHeader, Box<dyn Error>> {
+/// 受信したIPパケットを解析・検証する
+///
+/// - 固定部・IHL・Total Lengthに対してバッファ長が足りているか
+/// - ヘッダーチェックサムが正しいか
+/// - 上位プロトコルがTCPか
+pub fn parse_ip_header(data: &[u8]) -> Result<IpHeader, IpParseError> {
     let header = IpHeader::from_bytes(data)?;
 
     // バリデーション
-    if header.version() != 4 {
-        return Err(format!(
-            "Expected IPv4 (version 4), got version {}",
-            header.version()
-        )
-        .into());
+    let header_length = header.header_length() as usize;
+    let total_length = header.total_length();
+    if (total_length as usize) < header_length {
+        return Err(IpParseError::InvalidTotalLength {
+            total_length,
+            header_length,
+        });
+    }
+    if data.len() < total_length as usize {
+        return Err(IpParseError::Truncated {
+            needed: total_length as usize,
+            available: data.len(),
+        });
+    }
+
+    if !header.verify_checksum() {
+        return Err(IpParseError::ChecksumMismatch {
+            checksum: header.checksum(),
+        });
     }
 
     if header.protocol != IP_PROTOCOL_TCP {
-        return Err(format!(
-            "Expected TCP (protocol {}), got protocol {}",
-            IP_PROTOCOL_TCP, header.protocol
-        )
-        .into());
+        return Err(IpParseError::UnexpectedProtocol {
+            expected: IP_PROTOCOL_TCP,
+            actual: header.protocol,
+        });
     }
 
     // 各フィールドをログ出力
     info!("=== IP Header Analysis ===");
     info!(
-        "Version: {}, Header Length: {} bytes",
+        "Version: {}, Header Length: {} bytes, Total Length: {} bytes",
         header.version(),
-        header.header_length()
+        header.header_length(),
+        total_length
     );
     info!("Protocol: {} (TCP)", header.protocol);
     info!("Source: {}", header.source_ip());
     info!("Destination: {}", header.dest_ip());
     let checksum = header.checksum;
     info!("Checksum: 0x{:04X}", checksum);
+    if !header.options_bytes().is_empty() {
+        info!("Options: {:?}", header.options());
+    }
 
     Ok(header)
 }
 
-fn receive_packet(socket_fd: i32) -> Result<Vec<u8>, Box<dyn Error>> {
+pub fn receive_packet(socket_fd: i32) -> Result<Vec<u8>, Box<dyn Error>> {
     // 最大IPパケットサイズ（65535バイト）
     const MAX_PACKET_SIZE: usize = 65535;
     let mut buffer = vec![0u8; MAX_PACKET_SIZE];
@@ -349,17 +707,34 @@ fn receive_packet(socket_fd: i32) -> Result<Vec<u8>, Box<dyn Error>> {
 
     info!("Received {} bytes", bytes_received);
 
-    // IPヘッダー部分（最初の20バイト）
-    let ip_header_bytes = &buffer[0..IP_HEADER_SIZE];
-
-    // IPヘッダーの解析
-    let _ip_header = parse_ip_header(ip_header_bytes)?;
-
     // 受信したパケット全体を返す
     buffer.truncate(bytes_received as usize);
+
+    // IPヘッダーの解析（オプション・Total Length・チェックサムも検証）
+    let _ip_header = parse_ip_header(&buffer)?;
+
     Ok(buffer)
 }
 
+/// フラグメントを再構築しながら、完全なIPデータグラムを1つ受信する
+///
+/// フラグメントを受信した場合は揃うまで受信を繰り返す
+pub fn receive_datagram(
+    socket_fd: i32,
+    reassembler: &mut Reassembler,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    loop {
+        let packet = receive_packet(socket_fd)?;
+        if let Some(datagram) = reassembler.process(&packet, std::time::Instant::now())? {
+            return Ok(datagram);
+        }
+        info!(
+            "Fragment buffered ({} datagrams pending)",
+            reassembler.pending()
+        );
+    }
+}
+
 fn sender_mode() -> Result<(), Box<dyn Error>> {
     info!("=== SENDER MODE ===");
 
@@ -439,8 +814,9 @@ fn receiver_mode() -> Result<(), Box<dyn Error>> {
         );
     }
 
-    // 1回だけ受信を試行
-    match receive_packet(socket_fd) {
+    // 1回だけ受信を試行（フラグメントの場合は揃うまで待つ）
+    let mut reassembler = Reassembler::default();
+    match receive_datagram(socket_fd, &mut reassembler) {
         Ok(_packet) => {
             info!("Packet processed successfully");
         }
@@ -494,3 +870,6 @@ fn main() -> Result<(), Box<dyn Error>> {
         sender_mode()
     }
 }
+
+#[cfg(test)]
+mod tests;
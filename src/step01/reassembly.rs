@@ -0,0 +1,294 @@
+// IPv4 Fragment Reassembly (RFC 791 Section 3.2, RFC 815)
+//
+// MTUより大きいIPデータグラムは経路上でフラグメント化される。
+// 受信側は (送信元, 宛先, Identification, Protocol) の組でフラグメントを集め、
+// RFC 815 の「穴（hole）リスト」アルゴリズムで元のデータグラムを復元する。
+
+use super::{IpHeader, IpParseError};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// 再構築のタイムアウト（RFC 791の推奨値15秒）
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// 再構築中のフラグメントが使ってよいメモリの上限（バイト）
+pub const DEFAULT_REASSEMBLY_MEMORY: usize = 256 * 1024;
+
+/// IPデータグラムの最大長
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// 同じデータグラムに属するフラグメントを識別するキー（RFC 791）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub identification: u16,
+    pub protocol: u8,
+}
+
+impl FragmentKey {
+    fn from_header(header: &IpHeader) -> Self {
+        Self {
+            source: header.source_ip(),
+            destination: header.dest_ip(),
+            identification: header.identification(),
+            protocol: header.protocol(),
+        }
+    }
+}
+
+/// RFC 815 のhole descriptor（まだ受信していないペイロードの範囲、両端を含む）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Hole {
+    first: usize,
+    last: usize,
+}
+
+/// 1つのデータグラムの再構築状態
+#[derive(Debug)]
+struct ReassemblyBuffer {
+    // オフセット0のフラグメントのヘッダー（受信するまではNone）
+    first_header: Option<IpHeader>,
+    // ペイロード（フラグメントを受信するたびに伸びる）
+    payload: Vec<u8>,
+    // まだ埋まっていない範囲。初期値は [0, ∞]
+    holes: Vec<Hole>,
+    // 最終フラグメント（MF=0）で決まったペイロードの長さ（受信するまではNone）
+    end: Option<usize>,
+    // 最初のフラグメントを受信した時刻（タイムアウト判定用）
+    created_at: Instant,
+}
+
+impl ReassemblyBuffer {
+    fn new(now: Instant) -> Self {
+        Self {
+            first_header: None,
+            payload: Vec::new(),
+            holes: vec![Hole {
+                first: 0,
+                last: usize::MAX,
+            }],
+            end: None,
+            created_at: now,
+        }
+    }
+
+    /// RFC 815 Section 3 のアルゴリズムでフラグメントを取り込む
+    ///
+    /// 最終フラグメントで決まった長さを超えるもの、長さの食い違う2つ目の最終フラグメントは
+    /// 取り込まずにエラーにする
+    fn insert(
+        &mut self,
+        first: usize,
+        data: &[u8],
+        more_fragments: bool,
+    ) -> Result<(), IpParseError> {
+        let last = first + data.len() - 1;
+        match self.end {
+            Some(end) if last >= end || (!more_fragments && last + 1 != end) => {
+                return Err(IpParseError::InvalidFragment);
+            }
+            // 受信済みのデータより手前で終わる最終フラグメント
+            None if !more_fragments && self.payload.len() > last + 1 => {
+                return Err(IpParseError::InvalidFragment);
+            }
+            _ => {}
+        }
+        if !more_fragments {
+            self.end = Some(last + 1);
+        }
+
+        let mut new_holes = Vec::with_capacity(self.holes.len() + 1);
+        for hole in &self.holes {
+            // Step 2, 3: 重ならない穴はそのまま
+            if first > hole.last || last < hole.first {
+                new_holes.push(*hole);
+                continue;
+            }
+            // Step 4: 穴を削除し、Step 5, 6: 残った部分を新しい穴として登録
+            if first > hole.first {
+                new_holes.push(Hole {
+                    first: hole.first,
+                    last: first - 1,
+                });
+            }
+            if last < hole.last && more_fragments {
+                new_holes.push(Hole {
+                    first: last + 1,
+                    last: hole.last,
+                });
+            }
+        }
+        self.holes = new_holes;
+
+        // 最終フラグメント（MF=0）より後ろの穴は存在しない
+        if !more_fragments {
+            self.holes.retain(|hole| hole.first <= last);
+        }
+
+        // 重複部分は後から届いたデータで上書きする（RFC 815）
+        if self.payload.len() < last + 1 {
+            self.payload.resize(last + 1, 0);
+        }
+        self.payload[first..=last].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.holes.is_empty() && self.first_header.is_some()
+    }
+}
+
+/// IPv4フラグメントの再構築器
+///
+/// `process()`に受信したIPパケットを渡すと、フラグメント化されていなければそのまま、
+/// フラグメントならすべて揃った時点で再構築済みのデータグラムを返す。
+#[derive(Debug)]
+pub struct Reassembler {
+    buffers: HashMap<FragmentKey, ReassemblyBuffer>,
+    timeout: Duration,
+    max_memory: usize,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(DEFAULT_REASSEMBLY_TIMEOUT, DEFAULT_REASSEMBLY_MEMORY)
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, max_memory: usize) -> Self {
+        Self {
+            buffers: HashMap::new(),
+            timeout,
+            max_memory,
+        }
+    }
+
+    /// 受信したIPパケットを処理する
+    ///
+    /// - ヘッダーチェックサムが合わなければエラー（壊れたフラグメントは保持しない）
+    /// - フラグメントでなければそのまま返す
+    /// - フラグメントならバッファに保持し、揃った時点で1つのデータグラムにして返す
+    /// - 揃っていなければ`Ok(None)`
+    pub fn process(
+        &mut self,
+        packet: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, IpParseError> {
+        self.expire(now);
+
+        let header = IpHeader::from_bytes(packet)?;
+        if !header.verify_checksum() {
+            return Err(IpParseError::ChecksumMismatch {
+                checksum: header.checksum(),
+            });
+        }
+        if !header.is_fragment() {
+            return Ok(Some(packet.to_vec()));
+        }
+
+        let header_length = header.header_length() as usize;
+        let total_length = header.total_length() as usize;
+        if total_length < header_length || packet.len() < total_length {
+            return Err(IpParseError::Truncated {
+                needed: total_length.max(header_length),
+                available: packet.len(),
+            });
+        }
+        let data = &packet[header_length..total_length];
+        let offset = header.fragment_offset();
+        let more_fragments = header.more_fragments();
+
+        // 空のフラグメント、8の倍数でない途中のフラグメント、65535超えは不正
+        if data.is_empty()
+            || (more_fragments && !data.len().is_multiple_of(8))
+            || offset + data.len() > MAX_DATAGRAM_SIZE - header_length
+        {
+            return Err(IpParseError::InvalidFragment);
+        }
+
+        let key = FragmentKey::from_header(&header);
+        let buffer = self
+            .buffers
+            .entry(key)
+            .or_insert_with(|| ReassemblyBuffer::new(now));
+        buffer.insert(offset, data, more_fragments)?;
+        if offset == 0 {
+            buffer.first_header = Some(header);
+        }
+
+        if buffer.is_complete() {
+            let buffer = self.buffers.remove(&key).expect("buffer exists");
+            // 長さの上限は、最初のフラグメントのヘッダー長で確かめ直す
+            let header_length = buffer
+                .first_header
+                .map_or(0, |header| header.header_length() as usize);
+            if header_length + buffer.payload.len() > MAX_DATAGRAM_SIZE {
+                return Err(IpParseError::InvalidFragment);
+            }
+            return Ok(Some(Self::build_datagram(buffer)));
+        }
+
+        self.enforce_memory_limit(key);
+        Ok(None)
+    }
+
+    /// タイムアウトした再構築バッファを破棄し、破棄した数を返す
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let timeout = self.timeout;
+        let before = self.buffers.len();
+        self.buffers
+            .retain(|_, buffer| now.duration_since(buffer.created_at) < timeout);
+        before - self.buffers.len()
+    }
+
+    /// 再構築中のデータグラム数
+    pub fn pending(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// 再構築中のフラグメントが使っているメモリ量（バイト）
+    pub fn memory_used(&self) -> usize {
+        self.buffers.values().map(|b| b.payload.len()).sum()
+    }
+
+    /// メモリ上限を超えた場合、古いものから破棄する（直前に更新したものは最後まで残す）
+    fn enforce_memory_limit(&mut self, current: FragmentKey) {
+        while self.memory_used() > self.max_memory {
+            let oldest = self
+                .buffers
+                .iter()
+                .filter(|(key, _)| **key != current)
+                .min_by_key(|(_, buffer)| buffer.created_at)
+                .map(|(key, _)| *key);
+            match oldest {
+                Some(key) => {
+                    self.buffers.remove(&key);
+                }
+                None => {
+                    // 単独で上限を超えるデータグラムは再構築しない
+                    self.buffers.remove(&current);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// 最初のフラグメントのヘッダーとペイロードから、フラグメント化されていない
+    /// データグラム（ネットワークバイトオーダー）を作る
+    fn build_datagram(buffer: ReassemblyBuffer) -> Vec<u8> {
+        let mut header = buffer.first_header.expect("complete buffer has header");
+        let total_length = header.header_length() as usize + buffer.payload.len();
+
+        header.length = total_length as u16;
+        // MFとフラグメントオフセットをクリア（DFは維持）
+        header.flags_fragment &= 0x4000;
+        header.calculate_checksum();
+
+        let mut datagram = header.to_network_bytes();
+        datagram.extend_from_slice(&buffer.payload);
+        datagram
+    }
+}
@@ -0,0 +1,641 @@
+use super::*;
+
+/// テスト用: ネットワークバイトオーダーの正しいIPv4パケットを組み立てる
+fn build_packet(options: &[u8], payload: &[u8]) -> Vec<u8> {
+    let header_len = IP_HEADER_SIZE + options.len();
+    let total_len = (header_len + payload.len()) as u16;
+
+    let mut packet = vec![
+        0x40 | (header_len / 4) as u8, // Version=4, IHL
+        0x00,                          // TOS
+    ];
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&0x1234u16.to_be_bytes()); // Identification
+    packet.extend_from_slice(&0x4000u16.to_be_bytes()); // DF
+    packet.push(64); // TTL
+    packet.push(IP_PROTOCOL_TCP);
+    packet.extend_from_slice(&[0, 0]); // Checksum（後で計算）
+    packet.extend_from_slice(&[192, 168, 1, 10]);
+    packet.extend_from_slice(&[192, 168, 1, 20]);
+    packet.extend_from_slice(options);
+
+    let mut header = IpHeader::from_bytes(&packet).unwrap();
+    header.calculate_checksum();
+    packet[10..12].copy_from_slice(&header.checksum().to_be_bytes());
+
+    packet.extend_from_slice(payload);
+    packet
+}
+
+// =============================================================================
+// IPヘッダー解析 - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod ip_header_parse_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_basic_header() {
+        let packet = build_packet(&[], b"payload");
+        let header = parse_ip_header(&packet).unwrap();
+
+        assert_eq!(header.version(), 4);
+        assert_eq!(header.header_length(), 20);
+        assert_eq!(header.total_length(), 27);
+        assert_eq!(header.identification(), 0x1234);
+        assert_eq!(header.ttl(), 64);
+        assert_eq!(header.protocol(), IP_PROTOCOL_TCP);
+        assert_eq!(header.source_ip(), Ipv4Addr::new(192, 168, 1, 10));
+        assert_eq!(header.dest_ip(), Ipv4Addr::new(192, 168, 1, 20));
+        assert!(header.options_bytes().is_empty());
+        assert!(header.verify_checksum());
+    }
+
+    #[test]
+    fn test_parse_header_with_options() {
+        // Router Alert(4バイト) + NOP×3 + EOL = 8バイト → IHL=7
+        let options = [148, 4, 0, 0, 1, 1, 1, 0];
+        let packet = build_packet(&options, b"");
+        let header = parse_ip_header(&packet).unwrap();
+
+        assert_eq!(header.header_length(), 28);
+        assert_eq!(header.options_bytes(), &options);
+        assert_eq!(
+            header.options().unwrap(),
+            vec![
+                Ipv4Option::RouterAlert(0),
+                Ipv4Option::NoOperation,
+                Ipv4Option::NoOperation,
+                Ipv4Option::NoOperation,
+                Ipv4Option::EndOfOptionList,
+            ]
+        );
+        assert!(Ipv4Option::RouterAlert(0).is_copied());
+        assert!(!Ipv4Option::RecordRoute(vec![]).is_copied());
+    }
+
+    #[test]
+    fn test_truncated_fixed_header() {
+        let packet = build_packet(&[], b"");
+        assert_eq!(
+            parse_ip_header(&packet[..10]).unwrap_err(),
+            IpParseError::Truncated {
+                needed: 20,
+                available: 10
+            }
+        );
+    }
+
+    #[test]
+    fn test_truncated_options() {
+        // IHL=6（24バイト）なのに20バイトしかない
+        let packet = build_packet(&[1, 1, 1, 0], b"");
+        assert_eq!(
+            IpHeader::from_bytes(&packet[..20]).unwrap_err(),
+            IpParseError::Truncated {
+                needed: 24,
+                available: 20
+            }
+        );
+    }
+
+    #[test]
+    fn test_total_length_exceeds_buffer() {
+        let packet = build_packet(&[], b"0123456789");
+        // ペイロードの途中で切れたパケット
+        let result = parse_ip_header(&packet[..25]);
+        assert_eq!(
+            result.unwrap_err(),
+            IpParseError::Truncated {
+                needed: 30,
+                available: 25
+            }
+        );
+    }
+
+    #[test]
+    fn test_total_length_smaller_than_header() {
+        let mut packet = build_packet(&[], b"");
+        packet[2..4].copy_from_slice(&10u16.to_be_bytes());
+        assert_eq!(
+            parse_ip_header(&packet).unwrap_err(),
+            IpParseError::InvalidTotalLength {
+                total_length: 10,
+                header_length: 20
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_version_and_ihl() {
+        let mut packet = build_packet(&[], b"");
+        packet[0] = 0x65; // version 6
+        assert_eq!(
+            IpHeader::from_bytes(&packet).unwrap_err(),
+            IpParseError::UnsupportedVersion(6)
+        );
+
+        packet[0] = 0x44; // IHL=4
+        assert_eq!(
+            IpHeader::from_bytes(&packet).unwrap_err(),
+            IpParseError::InvalidHeaderLength(4)
+        );
+    }
+
+    #[test]
+    fn test_corrupted_checksum() {
+        let mut packet = build_packet(&[], b"data");
+        packet[8] = 1; // TTLを書き換え（チェックサムは古いまま）
+
+        match parse_ip_header(&packet) {
+            Err(IpParseError::ChecksumMismatch { .. }) => {}
+            other => panic!("Expected ChecksumMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_wrong_protocol() {
+        let mut packet = build_packet(&[], b"");
+        // UDPに書き換え、チェックサムを再計算して「正しいUDPパケット」にする
+        packet[9] = 17;
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let mut header = IpHeader::from_bytes(&packet).unwrap();
+        header.calculate_checksum();
+        packet[10..12].copy_from_slice(&header.checksum().to_be_bytes());
+
+        assert_eq!(
+            parse_ip_header(&packet).unwrap_err(),
+            IpParseError::UnexpectedProtocol {
+                expected: IP_PROTOCOL_TCP,
+                actual: 17
+            }
+        );
+    }
+
+    #[test]
+    fn test_malformed_option() {
+        // Length=10 だがオプション領域は4バイトしかない
+        let packet = build_packet(&[7, 10, 0, 0], b"");
+        let header = IpHeader::from_bytes(&packet).unwrap();
+        assert_eq!(header.options(), Err(IpParseError::MalformedOption));
+    }
+
+    #[test]
+    fn test_wire_bytes_are_valid_on_the_wire() {
+        // TUNにはカーネルを経由せずに書き込むので、そのまま受信側で検証を通る必要がある
+        let source = Ipv4Addr::new(10, 0, 0, 2);
+        let dest = Ipv4Addr::new(10, 0, 0, 1);
+        let mut packet = IpHeader::new(source, dest, 4).to_wire_bytes(0x1234);
+        packet.extend_from_slice(b"data");
+
+        let header = parse_ip_header(&packet).unwrap();
+        assert_eq!(header.total_length(), 24);
+        assert_eq!(header.identification(), 0x1234);
+        assert!(header.dont_fragment());
+        assert!(!header.is_fragment());
+        assert_eq!(header.source_ip(), source);
+        assert_eq!(header.dest_ip(), dest);
+    }
+}
+
+/// テスト用: フラグメントを組み立てる（offsetはバイト単位）
+fn build_fragment(id: u16, offset: usize, more_fragments: bool, payload: &[u8]) -> Vec<u8> {
+    let mut packet = build_packet(&[], payload);
+    let mut flags_fragment = (offset / 8) as u16;
+    if more_fragments {
+        flags_fragment |= 0x2000;
+    }
+    packet[4..6].copy_from_slice(&id.to_be_bytes());
+    packet[6..8].copy_from_slice(&flags_fragment.to_be_bytes());
+
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let mut header = IpHeader::from_bytes(&packet).unwrap();
+    header.calculate_checksum();
+    packet[10..12].copy_from_slice(&header.checksum().to_be_bytes());
+    packet
+}
+
+// =============================================================================
+// フラグメント再構築 - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod reassembly_tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_unfragmented_packet_passes_through() {
+        let mut reassembler = Reassembler::default();
+        let packet = build_packet(&[], b"not fragmented");
+
+        let result = reassembler.process(&packet, Instant::now()).unwrap();
+        assert_eq!(result, Some(packet));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_fragment_flags_parsing() {
+        let packet = build_fragment(7, 16, true, &[0; 8]);
+        let header = IpHeader::from_bytes(&packet).unwrap();
+        assert!(header.more_fragments());
+        assert!(!header.dont_fragment());
+        assert_eq!(header.fragment_offset(), 16);
+        assert!(header.is_fragment());
+    }
+
+    #[test]
+    fn test_reassemble_in_order() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        let first = build_fragment(1, 0, true, b"01234567");
+        let second = build_fragment(1, 8, true, b"89abcdef");
+        let last = build_fragment(1, 16, false, b"XYZ");
+
+        assert_eq!(reassembler.process(&first, now).unwrap(), None);
+        assert_eq!(reassembler.process(&second, now).unwrap(), None);
+        let datagram = reassembler.process(&last, now).unwrap().unwrap();
+
+        // 再構築したデータグラムは正しいIPパケットとして解析できる
+        let header = parse_ip_header(&datagram).unwrap();
+        assert!(!header.is_fragment());
+        assert_eq!(header.total_length() as usize, 20 + 19);
+        assert_eq!(&datagram[20..], b"0123456789abcdefXYZ");
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        // 最終フラグメント → 先頭 → 中間 の順で到着
+        let fragments = [
+            build_fragment(2, 16, false, b"!!"),
+            build_fragment(2, 0, true, b"AAAAAAAA"),
+            build_fragment(2, 8, true, b"BBBBBBBB"),
+        ];
+
+        assert_eq!(reassembler.process(&fragments[0], now).unwrap(), None);
+        assert_eq!(reassembler.process(&fragments[1], now).unwrap(), None);
+        let datagram = reassembler.process(&fragments[2], now).unwrap().unwrap();
+        assert_eq!(&datagram[20..], b"AAAAAAAABBBBBBBB!!");
+    }
+
+    #[test]
+    fn test_overlapping_fragments() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        // [0,16) と [8,24) が重なる。後から届いたデータで上書きされる
+        let first = build_fragment(3, 0, true, b"aaaaaaaaaaaaaaaa");
+        let overlap = build_fragment(3, 8, false, b"bbbbbbbbbbbbbbbb");
+
+        assert_eq!(reassembler.process(&first, now).unwrap(), None);
+        let datagram = reassembler.process(&overlap, now).unwrap().unwrap();
+        assert_eq!(&datagram[20..], b"aaaaaaaabbbbbbbbbbbbbbbb");
+    }
+
+    #[test]
+    fn test_duplicate_fragment_is_harmless() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        let first = build_fragment(4, 0, true, b"01234567");
+        let last = build_fragment(4, 8, false, b"89");
+
+        assert_eq!(reassembler.process(&first, now).unwrap(), None);
+        assert_eq!(reassembler.process(&first, now).unwrap(), None);
+        let datagram = reassembler.process(&last, now).unwrap().unwrap();
+        assert_eq!(&datagram[20..], b"0123456789");
+    }
+
+    #[test]
+    fn test_fragments_keyed_by_identification() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        // 異なるIDのフラグメントは混ざらない
+        reassembler
+            .process(&build_fragment(10, 0, true, b"AAAAAAAA"), now)
+            .unwrap();
+        reassembler
+            .process(&build_fragment(11, 0, true, b"BBBBBBBB"), now)
+            .unwrap();
+        assert_eq!(reassembler.pending(), 2);
+
+        let datagram = reassembler
+            .process(&build_fragment(11, 8, false, b"b"), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&datagram[20..], b"BBBBBBBBb");
+        assert_eq!(reassembler.pending(), 1);
+    }
+
+    #[test]
+    fn test_reassembly_timeout() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(15), DEFAULT_REASSEMBLY_MEMORY);
+        let start = Instant::now();
+
+        reassembler
+            .process(&build_fragment(5, 0, true, b"01234567"), start)
+            .unwrap();
+        assert_eq!(reassembler.expire(start + Duration::from_secs(14)), 0);
+        assert_eq!(reassembler.expire(start + Duration::from_secs(15)), 1);
+
+        // タイムアウト後に残りが届いても完成しない
+        let late = build_fragment(5, 8, false, b"89");
+        let result = reassembler
+            .process(&late, start + Duration::from_secs(16))
+            .unwrap();
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn test_memory_cap_evicts_oldest() {
+        let mut reassembler = Reassembler::new(DEFAULT_REASSEMBLY_TIMEOUT, 20);
+        let start = Instant::now();
+
+        reassembler
+            .process(&build_fragment(20, 0, true, &[0; 16]), start)
+            .unwrap();
+        reassembler
+            .process(
+                &build_fragment(21, 0, true, &[0; 16]),
+                start + Duration::from_millis(1),
+            )
+            .unwrap();
+
+        // 上限20バイトなので古いID=20が破棄される
+        assert_eq!(reassembler.pending(), 1);
+        assert!(reassembler.memory_used() <= 20);
+        let result = reassembler
+            .process(
+                &build_fragment(20, 16, false, b"x"),
+                start + Duration::from_millis(2),
+            )
+            .unwrap();
+        assert_eq!(result, None);
+    }
+
+    #[test]
+    fn test_invalid_fragments_rejected() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        // 途中のフラグメントのペイロード長が8の倍数でない
+        let odd = build_fragment(6, 0, true, b"12345");
+        assert_eq!(
+            reassembler.process(&odd, now),
+            Err(IpParseError::InvalidFragment)
+        );
+
+        // オフセット + 長さが65535を超える
+        let too_far = build_fragment(6, 65528, false, &[0; 16]);
+        assert_eq!(
+            reassembler.process(&too_far, now),
+            Err(IpParseError::InvalidFragment)
+        );
+    }
+
+    #[test]
+    fn test_corrupted_packets_rejected() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        // ヘッダーが壊れたフラグメントはバッファに入れない
+        let mut fragment = build_fragment(7, 0, true, b"01234567");
+        fragment[8] ^= 0x01; // TTL
+        assert!(matches!(
+            reassembler.process(&fragment, now),
+            Err(IpParseError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(reassembler.pending(), 0);
+
+        // フラグメントでないパケットもそのまま通さない
+        let mut packet = build_packet(&[], b"not fragmented");
+        packet[8] ^= 0x01;
+        assert!(matches!(
+            reassembler.process(&packet, now),
+            Err(IpParseError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_fragments_beyond_final_end_rejected() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        // 最終フラグメント（MF=0）でペイロードは19バイトに決まる
+        let last = build_fragment(1, 16, false, b"XYZ");
+        assert_eq!(reassembler.process(&last, now).unwrap(), None);
+
+        // それより後ろのフラグメントや、別の位置で終わる最終フラグメントは取り込まない
+        let beyond = build_fragment(1, 24, true, b"garbage!");
+        assert_eq!(
+            reassembler.process(&beyond, now),
+            Err(IpParseError::InvalidFragment)
+        );
+        let other_end = build_fragment(1, 8, false, b"89abcdefXYZW");
+        assert_eq!(
+            reassembler.process(&other_end, now),
+            Err(IpParseError::InvalidFragment)
+        );
+        // 同じ最終フラグメントの重複は受け入れる
+        assert_eq!(reassembler.process(&last, now).unwrap(), None);
+
+        let first = build_fragment(1, 0, true, b"01234567");
+        let second = build_fragment(1, 8, true, b"89abcdef");
+        assert_eq!(reassembler.process(&first, now).unwrap(), None);
+        let datagram = reassembler.process(&second, now).unwrap().unwrap();
+        assert_eq!(
+            parse_ip_header(&datagram).unwrap().total_length() as usize,
+            20 + 19
+        );
+        assert_eq!(&datagram[20..], b"0123456789abcdefXYZ");
+    }
+
+    #[test]
+    fn test_reassembled_length_uses_first_header() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        // 先頭のフラグメントだけ60バイトのヘッダー（40バイトのNOPオプション）を持つ
+        let mut first = build_packet(&[1; 40], b"01234567");
+        first[4..6].copy_from_slice(&1u16.to_be_bytes());
+        first[6..8].copy_from_slice(&0x2000u16.to_be_bytes());
+        first[10..12].copy_from_slice(&[0, 0]);
+        let mut header = IpHeader::from_bytes(&first).unwrap();
+        header.calculate_checksum();
+        first[10..12].copy_from_slice(&header.checksum().to_be_bytes());
+        assert_eq!(reassembler.process(&first, now).unwrap(), None);
+
+        // 20バイトのヘッダーなら収まるが、先頭のヘッダーと合わせると65535を超える
+        let last = build_fragment(1, 8, false, &vec![0; 65515 - 8]);
+        assert_eq!(
+            reassembler.process(&last, now),
+            Err(IpParseError::InvalidFragment)
+        );
+        assert_eq!(reassembler.pending(), 0);
+    }
+}
+
+// =============================================================================
+// IPv6ヘッダー・拡張ヘッダー - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod ipv6_tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    const SRC: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const DST: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+
+    /// 拡張ヘッダー列とペイロードを持つIPv6パケットを組み立てる
+    fn build_ipv6_packet(first_header: u8, extensions: &[u8], payload: &[u8]) -> Vec<u8> {
+        let header = Ipv6Header::new(SRC, DST, (extensions.len() + payload.len()) as u16);
+        let mut packet = header.to_bytes();
+        packet[6] = first_header; // Next Header
+        packet.extend_from_slice(extensions);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_ipv6_header_roundtrip() {
+        let header = Ipv6Header::new(SRC, DST, 20);
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), IPV6_HEADER_SIZE);
+        assert_eq!(bytes[0] >> 4, 6);
+
+        let parsed = Ipv6Header::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.version(), 6);
+        assert_eq!(parsed.payload_length(), 20);
+        assert_eq!(parsed.next_header(), IP_PROTOCOL_TCP);
+        assert_eq!(parsed.hop_limit(), 64);
+        assert_eq!(parsed.source_ip(), SRC);
+        assert_eq!(parsed.dest_ip(), DST);
+    }
+
+    #[test]
+    fn test_ipv6_rejects_ipv4_packet() {
+        let packet = build_packet(&[], &[0; 20]);
+        let mut padded = packet.clone();
+        padded.resize(IPV6_HEADER_SIZE, 0);
+        assert_eq!(
+            Ipv6Header::from_bytes(&padded).unwrap_err(),
+            IpParseError::UnsupportedVersion(4)
+        );
+        // 逆にIPv4のパーサーはIPv6を拒否する
+        let v6 = Ipv6Header::new(SRC, DST, 0).to_bytes();
+        assert_eq!(
+            IpHeader::from_bytes(&v6).unwrap_err(),
+            IpParseError::UnsupportedVersion(6)
+        );
+    }
+
+    #[test]
+    fn test_parse_without_extension_headers() {
+        let packet = build_ipv6_packet(IP_PROTOCOL_TCP, &[], &[0xAA; 20]);
+        let parsed = parse_ipv6_header(&packet).unwrap();
+        assert!(parsed.extension_headers.is_empty());
+        assert_eq!(parsed.protocol, IP_PROTOCOL_TCP);
+        assert_eq!(parsed.payload_offset, IPV6_HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_walk_hop_by_hop_and_destination_options() {
+        // Hop-by-Hop (8バイト, PadN) → Destination Options (16バイト) → TCP
+        let mut extensions = vec![ipv6_next_header::DESTINATION_OPTIONS, 0, 1, 4, 0, 0, 0, 0];
+        extensions.extend_from_slice(&[IP_PROTOCOL_TCP, 1, 1, 12]);
+        extensions.extend_from_slice(&[0; 12]);
+
+        let packet = build_ipv6_packet(ipv6_next_header::HOP_BY_HOP, &extensions, &[0; 20]);
+        let parsed = parse_ipv6_header(&packet).unwrap();
+
+        let types: Vec<u8> = parsed
+            .extension_headers
+            .iter()
+            .map(|ext| ext.header_type)
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                ipv6_next_header::HOP_BY_HOP,
+                ipv6_next_header::DESTINATION_OPTIONS
+            ]
+        );
+        assert_eq!(parsed.payload_offset, IPV6_HEADER_SIZE + 24);
+        assert!(!parsed.is_fragment());
+    }
+
+    #[test]
+    fn test_atomic_fragment_header_is_not_fragment() {
+        // オフセット0・M=0のFragmentヘッダー（RFC 6946 atomic fragment）
+        let extensions = [IP_PROTOCOL_TCP, 0, 0, 0, 0, 0, 0x12, 0x34];
+        let packet = build_ipv6_packet(ipv6_next_header::FRAGMENT, &extensions, &[0; 20]);
+        let parsed = parse_ipv6_header(&packet).unwrap();
+        assert_eq!(
+            parsed.extension_headers[0].fragment_info(),
+            Some((0, false))
+        );
+        assert!(!parsed.is_fragment());
+
+        // M=1なら本物のフラグメント
+        let extensions = [IP_PROTOCOL_TCP, 0, 0, 1, 0, 0, 0x12, 0x34];
+        let packet = build_ipv6_packet(ipv6_next_header::FRAGMENT, &extensions, &[0; 16]);
+        assert!(parse_ipv6_header(&packet).unwrap().is_fragment());
+    }
+
+    #[test]
+    fn test_truncated_extension_header() {
+        // Lengthは16バイトを示しているが8バイトしかない
+        let extensions = [IP_PROTOCOL_TCP, 1, 0, 0, 0, 0, 0, 0];
+        let packet = build_ipv6_packet(ipv6_next_header::ROUTING, &extensions, &[]);
+        assert_eq!(
+            parse_ipv6_header(&packet).unwrap_err(),
+            IpParseError::MalformedExtensionHeader(ipv6_next_header::ROUTING)
+        );
+    }
+
+    #[test]
+    fn test_payload_length_exceeds_buffer() {
+        let mut packet = build_ipv6_packet(IP_PROTOCOL_TCP, &[], &[0; 20]);
+        packet.truncate(IPV6_HEADER_SIZE + 10);
+        assert_eq!(
+            parse_ipv6_header(&packet).unwrap_err(),
+            IpParseError::Truncated {
+                needed: IPV6_HEADER_SIZE + 20,
+                available: IPV6_HEADER_SIZE + 10,
+            }
+        );
+    }
+
+    #[test]
+    fn test_non_tcp_upper_layer_rejected() {
+        let packet = build_ipv6_packet(ipv6_next_header::NO_NEXT_HEADER, &[], &[]);
+        assert_eq!(
+            parse_ipv6_header(&packet).unwrap_err(),
+            IpParseError::UnexpectedProtocol {
+                expected: IP_PROTOCOL_TCP,
+                actual: ipv6_next_header::NO_NEXT_HEADER,
+            }
+        );
+    }
+
+    #[test]
+    fn test_address_family_of() {
+        assert_eq!(
+            AddressFamily::of(&IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            AddressFamily::Ipv4
+        );
+        assert_eq!(
+            AddressFamily::of(&IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            AddressFamily::Ipv6
+        );
+        assert_eq!(AddressFamily::Ipv6.header_size(), IPV6_HEADER_SIZE);
+    }
+}
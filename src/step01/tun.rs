@@ -0,0 +1,203 @@
+// TUN device backend (Linux /dev/net/tun)
+//
+// raw socket（IP_HDRINCL）で送ったSYNに対するSYN-ACKはホストのカーネルにも届き、
+// カーネルは「知らない接続」としてRSTを返してしまう。
+// TUNインターフェースの先のアドレスを自前のTCPスタックが持つことにすれば、
+// カーネルにとってそのアドレスは「別のホスト」になり、RSTは返らない。
+//
+//   カーネル（10.0.0.1, tun0）  <--- read/write --->  自前スタック（10.0.0.2）
+
+use std::error::Error;
+use std::ffi::CString;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use super::device::wait_readable;
+use super::get_errno;
+
+/// TUNデバイスから1回で読み込む最大サイズ
+const TUN_MTU_BUFFER: usize = 65535;
+
+/// TUNデバイス（IPパケットをそのまま読み書きできる仮想インターフェース）
+///
+/// `IFF_NO_PI`で開くため、読み書きするデータはIPヘッダーから始まる
+/// ネットワークバイトオーダーのIPパケットそのもの
+#[derive(Debug)]
+pub struct TunDevice {
+    fd: i32,
+    name: String,
+}
+
+impl TunDevice {
+    /// TUNインターフェースを作成（または既存のものに接続）する
+    ///
+    /// `name`が空文字列の場合はカーネルが`tun0`などの名前を割り当てる。
+    /// CAP_NET_ADMINが必要（ネットワーク名前空間内のrootで十分）
+    pub fn open(name: &str) -> Result<Self, Box<dyn Error>> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(format!("Interface name too long: {}", name).into());
+        }
+
+        let path = CString::new("/dev/net/tun")?;
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(format!("Failed to open /dev/net/tun: errno {}", get_errno()).into());
+        }
+
+        let mut ifr = new_ifreq(name);
+        ifr.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+
+        let result = unsafe { libc::ioctl(fd, libc::TUNSETIFF as _, &mut ifr) };
+        if result < 0 {
+            let errno = get_errno();
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(format!("TUNSETIFF failed: errno {}", errno).into());
+        }
+
+        Ok(Self {
+            fd,
+            name: ifreq_name(&ifr),
+        })
+    }
+
+    /// カーネルが割り当てたインターフェース名
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// インターフェース側（カーネル側）にIPv4アドレスを設定してUPにする
+    ///
+    /// `ip addr add <address>/<prefix_len> dev <name> && ip link set <name> up` と同じ
+    pub fn configure_ipv4(&self, address: Ipv4Addr, prefix_len: u8) -> Result<(), Box<dyn Error>> {
+        if prefix_len > 32 {
+            return Err(format!("Invalid prefix length: {}", prefix_len).into());
+        }
+        let netmask = if prefix_len == 0 {
+            0
+        } else {
+            u32::MAX << (32 - prefix_len)
+        };
+
+        // インターフェースの設定には任意のソケットが必要
+        let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        if sock < 0 {
+            return Err(format!("Failed to create control socket: errno {}", get_errno()).into());
+        }
+
+        let result = (|| -> Result<(), Box<dyn Error>> {
+            let mut ifr = new_ifreq(&self.name);
+            ifr.ifr_ifru.ifru_addr = sockaddr_from_ipv4(address);
+            ioctl_checked(sock, libc::SIOCSIFADDR, &mut ifr, "SIOCSIFADDR")?;
+
+            let mut ifr = new_ifreq(&self.name);
+            ifr.ifr_ifru.ifru_netmask = sockaddr_from_ipv4(Ipv4Addr::from(netmask));
+            ioctl_checked(sock, libc::SIOCSIFNETMASK, &mut ifr, "SIOCSIFNETMASK")?;
+
+            let mut ifr = new_ifreq(&self.name);
+            ioctl_checked(sock, libc::SIOCGIFFLAGS, &mut ifr, "SIOCGIFFLAGS")?;
+            unsafe {
+                ifr.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            }
+            ioctl_checked(sock, libc::SIOCSIFFLAGS, &mut ifr, "SIOCSIFFLAGS")
+        })();
+
+        unsafe {
+            libc::close(sock);
+        }
+        result
+    }
+
+    /// IPパケットを1つ書き込む（カーネルからはインターフェースで受信したように見える）
+    pub fn send(&self, packet: &[u8]) -> Result<(), Box<dyn Error>> {
+        let written = unsafe {
+            libc::write(
+                self.fd,
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+            )
+        };
+        if written < 0 {
+            return Err(format!("Failed to write to {}: errno {}", self.name, get_errno()).into());
+        }
+        if written as usize != packet.len() {
+            return Err(format!("Short write to {}: {} bytes", self.name, written).into());
+        }
+        Ok(())
+    }
+
+    /// IPパケットを1つ読み込む。タイムアウトまでに届かなければ`Ok(None)`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if !wait_readable(self.fd, timeout)? {
+            return Ok(None);
+        }
+
+        let mut buffer = vec![0u8; TUN_MTU_BUFFER];
+        let bytes_read = unsafe {
+            libc::read(
+                self.fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if bytes_read < 0 {
+            return Err(format!("Failed to read from {}: errno {}", self.name, get_errno()).into());
+        }
+        buffer.truncate(bytes_read as usize);
+        Ok(Some(buffer))
+    }
+}
+
+impl Drop for TunDevice {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn new_ifreq(name: &str) -> libc::ifreq {
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    ifr
+}
+
+fn ifreq_name(ifr: &libc::ifreq) -> String {
+    ifr.ifr_name
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8 as char)
+        .collect()
+}
+
+fn sockaddr_from_ipv4(address: Ipv4Addr) -> libc::sockaddr {
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(address).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    unsafe { std::mem::transmute::<libc::sockaddr_in, libc::sockaddr>(sin) }
+}
+
+fn ioctl_checked(
+    sock: i32,
+    request: libc::Ioctl,
+    ifr: &mut libc::ifreq,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    let result = unsafe { libc::ioctl(sock, request, ifr as *mut libc::ifreq) };
+    if result < 0 {
+        return Err(format!("{} failed: errno {}", name, get_errno()).into());
+    }
+    Ok(())
+}
@@ -1,5 +1,10 @@
+use std::net::{IpAddr, Ipv6Addr};
+
 pub const TCP_HEADER_SIZE: usize = 20;
 
+mod options;
+pub use options::{parse_options, serialize_options, tcp_option_kind, TcpOption, MAX_OPTIONS_SIZE};
+
 /// TCP Header Structure (RFC 9293 Section 3.1 - 2022 updated standard)
 ///
 /// 0                   1                   2                   3
@@ -37,6 +42,10 @@ pub struct TcpHeader {
     window_size: u16,
     checksum: u16,
     urgent_pointer: u16,
+    // オプション領域（32bit境界にパディング済みのバイト列）
+    options: [u8; MAX_OPTIONS_SIZE],
+    // optionsのうち有効なバイト数（常に4の倍数）
+    options_len: u8,
 }
 
 /// TCP Flags (RFC 9293 Section 3.1)
@@ -72,12 +81,45 @@ impl TcpHeader {
             window_size: window,
             urgent_pointer: 0,
             checksum: 0,
+            options: [0; MAX_OPTIONS_SIZE],
+            options_len: 0,
         }
     }
 
+    /// Set TCP options (RFC 9293 Section 3.1)
+    ///
+    /// Options are padded to a 32-bit boundary and the data offset is
+    /// updated to cover them. Fails if the encoded options exceed 40 bytes.
+    pub fn set_options(&mut self, options: &[TcpOption]) -> Result<(), &'static str> {
+        let bytes = serialize_options(options)?;
+        self.options = [0; MAX_OPTIONS_SIZE];
+        self.options[..bytes.len()].copy_from_slice(&bytes);
+        self.options_len = bytes.len() as u8;
+
+        let data_offset = ((TCP_HEADER_SIZE + bytes.len()) / 4) as u16;
+        let flags = self.data_offset_and_flags & 0x0FFF;
+        self.data_offset_and_flags = (data_offset << 12) | flags;
+        Ok(())
+    }
+
+    /// Parse the options area into typed options
+    pub fn options(&self) -> Result<Vec<TcpOption>, &'static str> {
+        parse_options(self.options_bytes())
+    }
+
+    /// Raw options bytes (including padding)
+    pub fn options_bytes(&self) -> &[u8] {
+        &self.options[..self.options_len as usize]
+    }
+
+    /// Header length in bytes (data offset × 4)
+    pub fn header_len(&self) -> usize {
+        self.get_data_offset() as usize * 4
+    }
+
     /// Convert TCP header to byte array
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(20);
+        let mut bytes = Vec::with_capacity(self.header_len());
         bytes.extend_from_slice(&self.source_port.to_be_bytes());
         bytes.extend_from_slice(&self.destination_port.to_be_bytes());
         bytes.extend_from_slice(&self.sequence_number.to_be_bytes());
@@ -86,15 +128,31 @@ impl TcpHeader {
         bytes.extend_from_slice(&self.window_size.to_be_bytes());
         bytes.extend_from_slice(&self.checksum.to_be_bytes());
         bytes.extend_from_slice(&self.urgent_pointer.to_be_bytes());
+        bytes.extend_from_slice(self.options_bytes());
         bytes
     }
 
     /// Parse TCP header from byte array
+    ///
+    /// Honors the data offset field: bytes between the fixed header and
+    /// `data offset × 4` are kept as the options area.
     pub fn from_bytes(data: &[u8]) -> Result<Self, &'static str> {
         if data.len() < TCP_HEADER_SIZE {
             return Err("Data too short for TCP header");
         }
 
+        let header_len = ((data[12] >> 4) as usize) * 4;
+        if header_len < TCP_HEADER_SIZE {
+            return Err("Invalid data offset");
+        }
+        if data.len() < header_len {
+            return Err("Data too short for TCP options");
+        }
+
+        let options_len = header_len - TCP_HEADER_SIZE;
+        let mut options = [0; MAX_OPTIONS_SIZE];
+        options[..options_len].copy_from_slice(&data[TCP_HEADER_SIZE..header_len]);
+
         Ok(Self {
             source_port: u16::from_be_bytes([data[0], data[1]]),
             destination_port: u16::from_be_bytes([data[2], data[3]]),
@@ -104,22 +162,33 @@ impl TcpHeader {
             window_size: u16::from_be_bytes([data[14], data[15]]),
             checksum: u16::from_be_bytes([data[16], data[17]]),
             urgent_pointer: u16::from_be_bytes([data[18], data[19]]),
+            options,
+            options_len: options_len as u8,
         })
     }
 
     /// 内部ヘルパー: チェックサム計算用の全データを準備
     fn prepare_checksum_data(&self, src_ip: u32, dst_ip: u32, tcp_data: &[u8]) -> Vec<u8> {
-        let pseudo_header =
-            create_pseudo_header(src_ip, dst_ip, (TCP_HEADER_SIZE + tcp_data.len()) as u16);
+        let pseudo_header = create_pseudo_header(src_ip, dst_ip, self.tcp_length(tcp_data) as u16);
+        self.prepare_checksum_data_with(&pseudo_header, tcp_data)
+    }
+
+    /// 内部ヘルパー: 疑似ヘッダーの後ろにTCPヘッダーとデータを連結
+    fn prepare_checksum_data_with(&self, pseudo_header: &[u8], tcp_data: &[u8]) -> Vec<u8> {
         let tcp_header_bytes = self.to_bytes();
 
         let mut all_data = Vec::new();
-        all_data.extend_from_slice(&pseudo_header);
+        all_data.extend_from_slice(pseudo_header);
         all_data.extend_from_slice(&tcp_header_bytes);
         all_data.extend_from_slice(tcp_data);
         all_data
     }
 
+    /// TCP長はオプションを含むヘッダー長 + データ長
+    fn tcp_length(&self, tcp_data: &[u8]) -> usize {
+        self.header_len() + tcp_data.len()
+    }
+
     /// Calculate TCP checksum with pseudo header
     pub fn calculate_checksum(&mut self, src_ip: u32, dst_ip: u32, tcp_data: &[u8]) {
         self.checksum = 0; // 先にクリア
@@ -128,13 +197,41 @@ impl TcpHeader {
     }
 
     /// Verify TCP checksum
-    fn verify_checksum(&self, src_ip: u32, dst_ip: u32, tcp_data: &[u8]) -> bool {
+    pub fn verify_checksum(&self, src_ip: u32, dst_ip: u32, tcp_data: &[u8]) -> bool {
         // checksumはそのまま（クリアしない）
         let all_data = self.prepare_checksum_data(src_ip, dst_ip, tcp_data);
         let result = calculate_1s_complement_sum(&all_data);
         result == 0xFFFF // 正しければ0xFFFF
     }
 
+    /// Calculate TCP checksum for either address family
+    ///
+    /// Uses the IPv4 (RFC 9293) or IPv6 (RFC 8200) pseudo header depending
+    /// on the addresses. Both addresses must be of the same family.
+    pub fn calculate_checksum_ip(
+        &mut self,
+        src_ip: IpAddr,
+        dst_ip: IpAddr,
+        tcp_data: &[u8],
+    ) -> Result<(), &'static str> {
+        self.checksum = 0;
+        let pseudo_header = create_pseudo_header_ip(src_ip, dst_ip, self.tcp_length(tcp_data))?;
+        let all_data = self.prepare_checksum_data_with(&pseudo_header, tcp_data);
+        self.checksum = calculate_checksum_rfc1071(&all_data);
+        Ok(())
+    }
+
+    /// Verify TCP checksum for either address family
+    pub fn verify_checksum_ip(&self, src_ip: IpAddr, dst_ip: IpAddr, tcp_data: &[u8]) -> bool {
+        match create_pseudo_header_ip(src_ip, dst_ip, self.tcp_length(tcp_data)) {
+            Ok(pseudo_header) => {
+                let all_data = self.prepare_checksum_data_with(&pseudo_header, tcp_data);
+                calculate_1s_complement_sum(&all_data) == 0xFFFF
+            }
+            Err(_) => false,
+        }
+    }
+
     /// Extract flags from data_offset_and_flags field
     pub fn get_flags(&self) -> u8 {
         // Extract lower 8 bits as flags
@@ -142,7 +239,7 @@ impl TcpHeader {
     }
 
     /// Extract data offset from data_offset_and_flags field
-    fn get_data_offset(&self) -> u8 {
+    pub fn get_data_offset(&self) -> u8 {
         ((self.data_offset_and_flags >> 12) & 0x0F) as u8
     }
 
@@ -161,6 +258,10 @@ impl TcpHeader {
     pub fn get_sequence_number(&self) -> u32 {
         self.sequence_number
     }
+
+    pub fn get_window_size(&self) -> u16 {
+        self.window_size
+    }
 }
 
 /// 1の補数和を計算（キャリー処理まで、補数演算なし）
@@ -213,6 +314,60 @@ fn create_pseudo_header(src_ip: u32, dst_ip: u32, tcp_length: u16) -> Vec<u8> {
     bytes
 }
 
+/// Create IPv6 pseudo header for checksum calculation (RFC 8200 Section 8.1)
+///
+/// ```text
+/// +--------+--------+--------+--------+
+/// |                                   |
+/// +          Source Address           +
+/// |             (128bit)              |
+/// +--------+--------+--------+--------+
+/// |                                   |
+/// +        Destination Address        +
+/// |             (128bit)              |
+/// +--------+--------+--------+--------+
+/// |        Upper-Layer Packet Length  |
+/// +--------+--------+--------+--------+
+/// |          zero            |  Next  |
+/// +--------+--------+--------+--------+
+/// ```
+///
+/// Next = 6 (TCP protocol number)
+fn create_pseudo_header_v6(src_ip: Ipv6Addr, dst_ip: Ipv6Addr, tcp_length: u32) -> Vec<u8> {
+    // Pseudo header format (40 bytes):
+    let mut bytes = Vec::with_capacity(40);
+    bytes.extend_from_slice(&src_ip.octets());
+    bytes.extend_from_slice(&dst_ip.octets());
+    bytes.extend_from_slice(&tcp_length.to_be_bytes());
+    bytes.extend_from_slice(&[0, 0, 0]);
+    bytes.push(6); // Next Header
+    bytes
+}
+
+/// アドレスファミリーに応じた疑似ヘッダーを作成
+pub fn create_pseudo_header_ip(
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    tcp_length: usize,
+) -> Result<Vec<u8>, &'static str> {
+    match (src_ip, dst_ip) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            if tcp_length > u16::MAX as usize {
+                return Err("TCP segment too long for IPv4");
+            }
+            Ok(create_pseudo_header(
+                u32::from(src),
+                u32::from(dst),
+                tcp_length as u16,
+            ))
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            Ok(create_pseudo_header_v6(src, dst, tcp_length as u32))
+        }
+        _ => Err("Source and destination address families differ"),
+    }
+}
+
 fn main() {
     println!("Step 2: TCP Header Analysis");
     println!("==========================");
@@ -0,0 +1,204 @@
+// TCP Options (RFC 9293 Section 3.1, 3.2)
+//
+// TCPヘッダーの固定部（20バイト）の後ろには、最大40バイトのオプションを置ける。
+// オプションは Kind(1バイト) + Length(1バイト) + Data の TLV 形式で、
+// Kind=0 (End of Option List) と Kind=1 (No-Operation) だけは1バイトで完結する。
+
+/// オプション領域の最大長（Data Offset最大値15 × 4 - 固定部20バイト）
+pub const MAX_OPTIONS_SIZE: usize = 40;
+
+/// TCP Option Kinds (IANA "TCP Option Kind Numbers")
+pub mod tcp_option_kind {
+    pub const END_OF_OPTION_LIST: u8 = 0; // RFC 9293
+    pub const NO_OPERATION: u8 = 1; // RFC 9293
+    pub const MAXIMUM_SEGMENT_SIZE: u8 = 2; // RFC 9293
+    pub const WINDOW_SCALE: u8 = 3; // RFC 7323
+    pub const SACK_PERMITTED: u8 = 4; // RFC 2018
+    pub const SACK: u8 = 5; // RFC 2018
+    pub const TIMESTAMPS: u8 = 8; // RFC 7323
+}
+
+/// 型付きのTCPオプション
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    /// Kind=0: オプションリストの終端（以降はパディング）
+    EndOfOptionList,
+    /// Kind=1: 境界合わせ用の1バイトオプション
+    NoOperation,
+    /// Kind=2: 受信可能な最大セグメントサイズ（SYNでのみ送信）
+    MaximumSegmentSize(u16),
+    /// Kind=3: ウィンドウスケールのシフト数（SYNでのみ送信）
+    WindowScale(u8),
+    /// Kind=4: SACKを使用可能（SYNでのみ送信）
+    SackPermitted,
+    /// Kind=5: SACKブロック（左端, 右端）のリスト、最大4ブロック
+    Sack(Vec<(u32, u32)>),
+    /// Kind=8: タイムスタンプ（TSval, TSecr）
+    Timestamps { tsval: u32, tsecr: u32 },
+    /// 未知のオプション（Kindとデータをそのまま保持）
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+impl TcpOption {
+    /// ワイヤー上でのバイト長（Kind/Lengthを含む）
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            TcpOption::EndOfOptionList | TcpOption::NoOperation => 1,
+            TcpOption::MaximumSegmentSize(_) => 4,
+            TcpOption::WindowScale(_) => 3,
+            TcpOption::SackPermitted => 2,
+            TcpOption::Sack(blocks) => 2 + blocks.len() * 8,
+            TcpOption::Timestamps { .. } => 10,
+            TcpOption::Unknown { data, .. } => 2 + data.len(),
+        }
+    }
+
+    /// オプション1つをバイト列に追加
+    pub fn write_to(&self, bytes: &mut Vec<u8>) {
+        use tcp_option_kind::*;
+        match self {
+            TcpOption::EndOfOptionList => bytes.push(END_OF_OPTION_LIST),
+            TcpOption::NoOperation => bytes.push(NO_OPERATION),
+            TcpOption::MaximumSegmentSize(mss) => {
+                bytes.extend_from_slice(&[MAXIMUM_SEGMENT_SIZE, 4]);
+                bytes.extend_from_slice(&mss.to_be_bytes());
+            }
+            TcpOption::WindowScale(shift) => {
+                bytes.extend_from_slice(&[WINDOW_SCALE, 3, *shift]);
+            }
+            TcpOption::SackPermitted => bytes.extend_from_slice(&[SACK_PERMITTED, 2]),
+            TcpOption::Sack(blocks) => {
+                bytes.extend_from_slice(&[SACK, (2 + blocks.len() * 8) as u8]);
+                for (left, right) in blocks {
+                    bytes.extend_from_slice(&left.to_be_bytes());
+                    bytes.extend_from_slice(&right.to_be_bytes());
+                }
+            }
+            TcpOption::Timestamps { tsval, tsecr } => {
+                bytes.extend_from_slice(&[TIMESTAMPS, 10]);
+                bytes.extend_from_slice(&tsval.to_be_bytes());
+                bytes.extend_from_slice(&tsecr.to_be_bytes());
+            }
+            TcpOption::Unknown { kind, data } => {
+                bytes.push(*kind);
+                bytes.push((2 + data.len()) as u8);
+                bytes.extend_from_slice(data);
+            }
+        }
+    }
+}
+
+/// オプションのリストをバイト列に変換し、32bit境界までパディングする
+///
+/// パディングにはEnd of Option List（0）を使う。結果が40バイトを超える場合はエラー。
+pub fn serialize_options(options: &[TcpOption]) -> Result<Vec<u8>, &'static str> {
+    let mut bytes = Vec::with_capacity(MAX_OPTIONS_SIZE);
+    for option in options {
+        if let TcpOption::Sack(blocks) = option {
+            if blocks.is_empty() || blocks.len() > 4 {
+                return Err("SACK option must carry 1 to 4 blocks");
+            }
+        }
+        if let TcpOption::Unknown { data, .. } = option {
+            if data.len() > MAX_OPTIONS_SIZE - 2 {
+                return Err("TCP option too long");
+            }
+        }
+        option.write_to(&mut bytes);
+    }
+
+    // 4バイト境界へのパディング
+    while bytes.len() % 4 != 0 {
+        bytes.push(tcp_option_kind::END_OF_OPTION_LIST);
+    }
+
+    if bytes.len() > MAX_OPTIONS_SIZE {
+        return Err("TCP options exceed 40 bytes");
+    }
+    Ok(bytes)
+}
+
+/// オプション領域のバイト列を型付きのリストに変換
+///
+/// End of Option List に到達した時点で解析を終了する（以降はパディング）。
+/// NOPは境界合わせ用なので結果には含めない。
+pub fn parse_options(data: &[u8]) -> Result<Vec<TcpOption>, &'static str> {
+    use tcp_option_kind::*;
+
+    let mut options = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let kind = data[i];
+        match kind {
+            END_OF_OPTION_LIST => break,
+            NO_OPERATION => {
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        // Kind + Length 形式のオプション
+        if i + 1 >= data.len() {
+            return Err("Truncated TCP option");
+        }
+        let length = data[i + 1] as usize;
+        if length < 2 || i + length > data.len() {
+            return Err("Invalid TCP option length");
+        }
+        let body = &data[i + 2..i + length];
+
+        let option = match kind {
+            MAXIMUM_SEGMENT_SIZE => {
+                if body.len() != 2 {
+                    return Err("Invalid MSS option length");
+                }
+                TcpOption::MaximumSegmentSize(u16::from_be_bytes([body[0], body[1]]))
+            }
+            WINDOW_SCALE => {
+                if body.len() != 1 {
+                    return Err("Invalid Window Scale option length");
+                }
+                TcpOption::WindowScale(body[0])
+            }
+            SACK_PERMITTED => {
+                if !body.is_empty() {
+                    return Err("Invalid SACK-Permitted option length");
+                }
+                TcpOption::SackPermitted
+            }
+            SACK => {
+                if body.is_empty() || !body.len().is_multiple_of(8) {
+                    return Err("Invalid SACK option length");
+                }
+                let blocks = body
+                    .chunks(8)
+                    .map(|b| {
+                        (
+                            u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+                            u32::from_be_bytes([b[4], b[5], b[6], b[7]]),
+                        )
+                    })
+                    .collect();
+                TcpOption::Sack(blocks)
+            }
+            TIMESTAMPS => {
+                if body.len() != 8 {
+                    return Err("Invalid Timestamps option length");
+                }
+                TcpOption::Timestamps {
+                    tsval: u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                    tsecr: u32::from_be_bytes([body[4], body[5], body[6], body[7]]),
+                }
+            }
+            _ => TcpOption::Unknown {
+                kind,
+                data: body.to_vec(),
+            },
+        };
+        options.push(option);
+        i += length;
+    }
+
+    Ok(options)
+}
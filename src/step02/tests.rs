@@ -416,6 +416,221 @@ mod integration_tests {
     }
 }
 
+// =============================================================================
+// TCP Options - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod options_tests {
+    use super::*;
+
+    #[test]
+    fn test_set_options_updates_data_offset() {
+        let mut header = TcpHeader::new(1234, 80, 1000, 0, tcp_flags::SYN, 8192);
+        header
+            .set_options(&[TcpOption::MaximumSegmentSize(1460)])
+            .unwrap();
+
+        // MSS(4バイト)でちょうど32bit境界
+        assert_eq!(header.get_data_offset(), 6);
+        assert_eq!(header.header_len(), 24);
+        assert_eq!(header.get_flags(), tcp_flags::SYN); // フラグは維持
+
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), 24);
+        assert_eq!(&bytes[20..24], &[2, 4, 0x05, 0xB4]); // Kind=2, Len=4, 1460
+    }
+
+    #[test]
+    fn test_options_padded_to_32bit_boundary() {
+        let mut header = TcpHeader::new(1234, 80, 1000, 0, tcp_flags::SYN, 8192);
+        // MSS(4) + WindowScale(3) + SackPermitted(2) = 9バイト → 12バイトにパディング
+        header
+            .set_options(&[
+                TcpOption::MaximumSegmentSize(1460),
+                TcpOption::WindowScale(7),
+                TcpOption::SackPermitted,
+            ])
+            .unwrap();
+
+        assert_eq!(header.options_bytes().len(), 12);
+        assert_eq!(header.get_data_offset(), 8);
+        assert_eq!(&header.options_bytes()[9..], &[0, 0, 0]); // EOLでパディング
+    }
+
+    #[test]
+    fn test_options_round_trip() {
+        let options = vec![
+            TcpOption::MaximumSegmentSize(1460),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamps {
+                tsval: 0x01020304,
+                tsecr: 0,
+            },
+            TcpOption::WindowScale(7),
+        ];
+        let mut original = TcpHeader::new(443, 8080, 1, 2, tcp_flags::SYN, 65535);
+        original.set_options(&options).unwrap();
+
+        let parsed = TcpHeader::from_bytes(&original.to_bytes()).unwrap();
+        assert_eq!(parsed.get_data_offset(), original.get_data_offset());
+        assert_eq!(parsed.options().unwrap(), options);
+    }
+
+    #[test]
+    fn test_parse_options_skips_nop() {
+        // 実際のスタックが送る典型的な並び: NOP, NOP, Timestamps
+        let data = [1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2];
+        let options = parse_options(&data).unwrap();
+        assert_eq!(options, vec![TcpOption::Timestamps { tsval: 1, tsecr: 2 }]);
+    }
+
+    #[test]
+    fn test_parse_sack_blocks_and_unknown_kind() {
+        let mut data = vec![5, 18];
+        for value in [100u32, 200, 300, 400] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&[30, 4, 0xAA, 0xBB]); // 未知のKind=30
+
+        let options = parse_options(&data).unwrap();
+        assert_eq!(
+            options,
+            vec![
+                TcpOption::Sack(vec![(100, 200), (300, 400)]),
+                TcpOption::Unknown {
+                    kind: 30,
+                    data: vec![0xAA, 0xBB]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_options_rejects_malformed() {
+        assert!(parse_options(&[2]).is_err()); // Lengthがない
+        assert!(parse_options(&[2, 1]).is_err()); // Length < 2
+        assert!(parse_options(&[2, 6, 0, 0]).is_err()); // データ不足
+        assert!(parse_options(&[3, 4, 0, 0]).is_err()); // WindowScaleは3バイト固定
+    }
+
+    #[test]
+    fn test_options_too_long() {
+        let mut header = TcpHeader::new(1, 2, 0, 0, tcp_flags::ACK, 0);
+        // SACK 4ブロック(34) + Timestamps(10) = 44バイト > 40
+        let result = header.set_options(&[
+            TcpOption::Sack(vec![(1, 2), (3, 4), (5, 6), (7, 8)]),
+            TcpOption::Timestamps { tsval: 1, tsecr: 2 },
+        ]);
+        assert!(result.is_err());
+        assert_eq!(header.get_data_offset(), 5); // 失敗時は変更しない
+    }
+
+    #[test]
+    fn test_from_bytes_honors_data_offset() {
+        // data offset = 6 だが21バイト目以降が欠けている
+        let mut bytes = [0u8; 20];
+        bytes[12] = 0x60;
+        assert_eq!(
+            TcpHeader::from_bytes(&bytes).unwrap_err(),
+            "Data too short for TCP options"
+        );
+
+        // data offset < 5 は不正
+        bytes[12] = 0x40;
+        assert_eq!(
+            TcpHeader::from_bytes(&bytes).unwrap_err(),
+            "Invalid data offset"
+        );
+    }
+
+    #[test]
+    fn test_checksum_covers_options() {
+        let src_ip = u32::from_be_bytes([10, 0, 0, 1]);
+        let dst_ip = u32::from_be_bytes([10, 0, 0, 2]);
+
+        let mut header = TcpHeader::new(40000, 80, 1000, 0, tcp_flags::SYN, 8192);
+        header
+            .set_options(&[TcpOption::MaximumSegmentSize(1460)])
+            .unwrap();
+        header.calculate_checksum(src_ip, dst_ip, &[]);
+        assert!(header.verify_checksum(src_ip, dst_ip, &[]));
+
+        // オプションを書き換えるとチェックサムが合わなくなる
+        let mut bytes = header.to_bytes();
+        bytes[23] ^= 0x01;
+        let tampered = TcpHeader::from_bytes(&bytes).unwrap();
+        assert!(!tampered.verify_checksum(src_ip, dst_ip, &[]));
+    }
+}
+
+// =============================================================================
+// IPv6疑似ヘッダー - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod ipv6_checksum_tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    const SRC: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const DST: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+
+    #[test]
+    fn test_ipv6_pseudo_header_layout() {
+        let pseudo = create_pseudo_header_ip(SRC.into(), DST.into(), 0x1234).unwrap();
+        assert_eq!(pseudo.len(), 40);
+        assert_eq!(&pseudo[0..16], &SRC.octets());
+        assert_eq!(&pseudo[16..32], &DST.octets());
+        assert_eq!(&pseudo[32..36], &[0, 0, 0x12, 0x34]); // 32bitの上位層長
+        assert_eq!(&pseudo[36..40], &[0, 0, 0, 6]); // zero + Next Header
+    }
+
+    #[test]
+    fn test_ipv4_pseudo_header_matches_legacy() {
+        let src = Ipv4Addr::new(10, 0, 0, 1);
+        let dst = Ipv4Addr::new(10, 0, 0, 2);
+        let pseudo = create_pseudo_header_ip(src.into(), dst.into(), 20).unwrap();
+        assert_eq!(
+            pseudo,
+            create_pseudo_header(u32::from(src), u32::from(dst), 20)
+        );
+
+        // IpAddr版とu32版のチェックサムは一致する
+        let mut a = TcpHeader::new(40000, 80, 1, 0, tcp_flags::SYN, 8192);
+        let mut b = a;
+        a.calculate_checksum(u32::from(src), u32::from(dst), b"data");
+        b.calculate_checksum_ip(src.into(), dst.into(), b"data")
+            .unwrap();
+        assert_eq!({ a.checksum }, { b.checksum });
+    }
+
+    #[test]
+    fn test_ipv6_checksum_roundtrip() {
+        let mut header = TcpHeader::new(40000, 80, 1000, 0, tcp_flags::SYN, 8192);
+        header
+            .set_options(&[TcpOption::MaximumSegmentSize(1440)])
+            .unwrap();
+        header
+            .calculate_checksum_ip(SRC.into(), DST.into(), b"hello")
+            .unwrap();
+        assert!(header.verify_checksum_ip(SRC.into(), DST.into(), b"hello"));
+
+        // 宛先アドレスが違えば検証に失敗する（疑似ヘッダーで保護されている）
+        let other = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!(!header.verify_checksum_ip(SRC.into(), other, b"hello"));
+    }
+
+    #[test]
+    fn test_mixed_address_families_rejected() {
+        let mut header = TcpHeader::new(40000, 80, 1000, 0, tcp_flags::SYN, 8192);
+        let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!(header.calculate_checksum_ip(v4, v6, &[]).is_err());
+        assert!(!header.verify_checksum_ip(v6, v4, &[]));
+    }
+}
+
 // =============================================================================
 // TDD実行ガイド
 // =============================================================================
@@ -0,0 +1,600 @@
+// Passive open (RFC 9293 Section 3.5, Figure 6)
+//
+// サーバー側の3-way handshake:
+//
+//   LISTEN        <-- SYN (seq=x)
+//   SYN-RECEIVED  --> SYN-ACK (seq=y, ack=x+1)
+//   ESTABLISHED   <-- ACK (seq=x+1, ack=y+1)
+//
+// SYNを受けた時点の「半開き」の接続は4タプルごとに保持し、
+// 正しい最後のACKが届いたものだけをaccept()で返す。
+//
+// 待ち受けていないポートへのセグメントや、半開き接続への不正なACKにはRSTを返す
+// （RFC 9293 Section 3.5.2）。
+//
+// リスナーと受け付けた接続は1つのデバイスを共有する。どちらが受信しても、
+// データグラムは相手の(IP, ポート)ごとのキューに振り分け、持ち主が取り出す:
+//
+//   受け付けた接続の相手から  → その接続のキュー
+//   それ以外（SYNなど）       → リスナーのキュー
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use rust_tcp_handson_with_claude_code::step01::TunDevice;
+use rust_tcp_handson_with_claude_code::step01::{
+    AddressFamily, PacketDevice, RawSocketDevice, Reassembler,
+};
+use rust_tcp_handson_with_claude_code::step02::{tcp_flags, TcpHeader, TcpOption};
+use rust_tcp_handson_with_claude_code::step04::TcpEvent;
+use rust_tcp_handson_with_claude_code::step05::rto::RtoEstimator;
+use rust_tcp_handson_with_claude_code::step05::window_scale_for;
+
+use super::{
+    build_datagram, datagram_addresses, generate_isn_for, ip_header_len, mss_option, reset_packet,
+    segment_event, time_wait_table, timestamp_offset_for, timestamp_value, timestamps_option,
+    window_scale_option, SequenceNumber, TcpConnection, DEFAULT_REMOTE_MSS, LOCAL_MSS,
+    LOCAL_RECV_BUFFER_SIZE,
+};
+
+/// 最後のACKを待たずに保持できる半開き接続の数（listen(2)のbacklog相当）
+const DEFAULT_BACKLOG: usize = 128;
+
+/// SYN-ACKの再送回数の上限（Linuxのtcp_synack_retriesと同じ）
+const SYN_ACK_RETRIES: u32 = 5;
+
+/// 相手の(IPアドレス, ポート)
+type Peer = (IpAddr, u16);
+
+/// SYN-RECEIVED状態の接続
+#[derive(Debug, Clone, Copy)]
+struct HalfOpen {
+    local_isn: u32,
+    remote_isn: u32,
+    remote_mss: u16,
+    remote_window: u16,
+    // SYNのWindow Scaleオプション。なければウィンドウスケールを使わない
+    remote_wscale: Option<u8>,
+    // SYNのTSval。なければTimestampsを使わない
+    remote_tsval: Option<u32>,
+    ts_offset: u32,
+    // 最初にSYN-ACKを送った時刻（再送しなければ最後のACKまでがRTT）
+    sent_at: Instant,
+    rto: RtoEstimator,
+    retransmits: u32,
+    next_retransmit: Instant,
+}
+
+/// リスナーと受け付けた接続で共有するデバイスと、相手ごとの受信キュー
+#[derive(Debug)]
+struct Demux<D: PacketDevice> {
+    device: D,
+    family: AddressFamily,
+    local_ip: IpAddr,
+    local_port: u16,
+    // IPv4のフラグメントはポートが分からないので、振り分ける前に再構築する
+    reassembler: RefCell<Reassembler>,
+    // 受け付けた接続ごとのキュー（接続がなくなると取り除く）
+    accepted: RefCell<HashMap<Peer, VecDeque<Vec<u8>>>>,
+    // どの接続にも当てはまらないデータグラム
+    unmatched: RefCell<VecDeque<Vec<u8>>>,
+    // リスナーがなくなったら、リスナー宛てのものは溜めずに捨てる
+    listening: Cell<bool>,
+}
+
+impl<D: PacketDevice> Demux<D> {
+    /// `owner`（Noneはリスナー）宛てのデータグラムを1つ受信する。
+    /// 他の持ち主宛てのものは、そのキューに入れて受信を続ける
+    fn recv_for(
+        &self,
+        owner: Option<Peer>,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(datagram) = self.pop(owner) {
+                return Ok(Some(datagram));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(packet) = self.device.recv_timeout(remaining)? else {
+                return Ok(None);
+            };
+            let datagram = match self.family {
+                AddressFamily::Ipv4 => {
+                    match self
+                        .reassembler
+                        .borrow_mut()
+                        .process(&packet, Instant::now())
+                    {
+                        Ok(datagram) => datagram,
+                        Err(e) => {
+                            println!("Dropped malformed IP packet: {}", e);
+                            None
+                        }
+                    }
+                }
+                AddressFamily::Ipv6 => Some(packet),
+            };
+            if let Some(datagram) = datagram {
+                let target = self.route(&datagram);
+                if target == owner {
+                    return Ok(Some(datagram));
+                }
+                self.push(target, datagram);
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// データグラムの持ち主。受け付けた接続の相手からでなければリスナー（None）
+    fn route(&self, datagram: &[u8]) -> Option<Peer> {
+        let (source, dest) = datagram_addresses(self.family, datagram).ok()?;
+        let offset = ip_header_len(self.family, datagram).ok()?;
+        let ports = datagram.get(offset..offset + 4)?;
+        let source_port = u16::from_be_bytes([ports[0], ports[1]]);
+        let dest_port = u16::from_be_bytes([ports[2], ports[3]]);
+        let peer = (source, source_port);
+        let accepted = dest == self.local_ip
+            && dest_port == self.local_port
+            && self.accepted.borrow().contains_key(&peer);
+        accepted.then_some(peer)
+    }
+
+    fn pop(&self, owner: Option<Peer>) -> Option<Vec<u8>> {
+        match owner {
+            Some(peer) => self.accepted.borrow_mut().get_mut(&peer)?.pop_front(),
+            None => self.unmatched.borrow_mut().pop_front(),
+        }
+    }
+
+    fn push(&self, owner: Option<Peer>, datagram: Vec<u8>) {
+        match owner {
+            Some(peer) => {
+                if let Some(queue) = self.accepted.borrow_mut().get_mut(&peer) {
+                    queue.push_back(datagram);
+                }
+            }
+            None if self.listening.get() => self.unmatched.borrow_mut().push_back(datagram),
+            None => {}
+        }
+    }
+}
+
+/// 受け付けた接続のデバイス。リスナーとデバイスを共有し、自分の相手からのものだけを受信する
+#[derive(Debug)]
+pub struct AcceptedDevice<D: PacketDevice> {
+    demux: Rc<Demux<D>>,
+    peer: Peer,
+}
+
+impl<D: PacketDevice> PacketDevice for AcceptedDevice<D> {
+    fn send(&self, datagram: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.demux.device.send(datagram)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.demux.recv_for(Some(self.peer), timeout)
+    }
+
+    // キューに溜まったものはfdからは分からないので、raw_fd()は持たない
+}
+
+impl<D: PacketDevice> Drop for AcceptedDevice<D> {
+    fn drop(&mut self) {
+        self.demux.accepted.borrow_mut().remove(&self.peer);
+    }
+}
+
+/// ローカルポートで接続を待ち受けるリスナー
+///
+/// デバイスは受け付けた接続と共有し、受信したものは相手ごとに振り分ける（`AcceptedDevice`）。
+/// raw socketで待ち受けるとカーネルもSYNにRSTを返してしまうので、実機ではTUNを使う
+#[derive(Debug)]
+pub struct TcpListener<D: PacketDevice = RawSocketDevice> {
+    demux: Rc<Demux<D>>,
+    family: AddressFamily,
+    local_ip: IpAddr,
+    local_port: u16,
+    backlog: usize,
+    syn_received: HashMap<Peer, HalfOpen>,
+    // 他のポート宛てのセグメントにRSTを返す（デバイスのアドレスをこのスタックが持つとき）
+    reset_closed_ports: bool,
+}
+
+impl TcpListener<RawSocketDevice> {
+    /// raw socketで`local_ip:local_port`を待ち受ける（要root権限）
+    pub fn bind(local_ip: impl Into<IpAddr>, local_port: u16) -> Result<Self, Box<dyn Error>> {
+        let local_ip = local_ip.into();
+        let device = RawSocketDevice::open(local_ip)?;
+        let mut listener = Self::with_device(device, local_ip, local_port)?;
+        // 他のポートはカーネルが受け持っているので、こちらからはRSTを返さない
+        listener.set_reset_closed_ports(false);
+        Ok(listener)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl TcpListener<TunDevice> {
+    /// TUNインターフェースの先の`local_ip:local_port`を待ち受ける
+    pub fn bind_tun(
+        tun: TunDevice,
+        local_ip: impl Into<IpAddr>,
+        local_port: u16,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_device(tun, local_ip, local_port)
+    }
+}
+
+impl<D: PacketDevice> TcpListener<D> {
+    /// 任意のデバイスで待ち受ける
+    pub fn with_device(
+        device: D,
+        local_ip: impl Into<IpAddr>,
+        local_port: u16,
+    ) -> Result<Self, Box<dyn Error>> {
+        let local_ip = local_ip.into();
+        let family = AddressFamily::of(&local_ip);
+        let demux = Demux {
+            device,
+            family,
+            local_ip,
+            local_port,
+            reassembler: RefCell::new(Reassembler::default()),
+            accepted: RefCell::new(HashMap::new()),
+            unmatched: RefCell::new(VecDeque::new()),
+            listening: Cell::new(true),
+        };
+        Ok(Self {
+            demux: Rc::new(demux),
+            family,
+            local_ip,
+            local_port,
+            backlog: DEFAULT_BACKLOG,
+            syn_received: HashMap::new(),
+            reset_closed_ports: true,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// 半開き接続の上限を変更する。上限に達している間に届いたSYNは無視する
+    pub fn set_backlog(&mut self, backlog: usize) {
+        self.backlog = backlog;
+    }
+
+    /// 待ち受けていないポート宛てのセグメントにRSTを返すかどうか（デフォルトは返す）
+    pub fn set_reset_closed_ports(&mut self, enabled: bool) {
+        self.reset_closed_ports = enabled;
+    }
+
+    /// SYN-RECEIVED状態（最後のACK待ち）の接続数
+    pub fn pending(&self) -> usize {
+        self.syn_received.len()
+    }
+
+    /// 3-way handshakeを完了した接続を1つ受け付ける
+    ///
+    /// `timeout`までに確立した接続がなければエラー。
+    /// 待っている間もSYNへの応答とSYN-ACKの再送は続ける
+    pub fn accept(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<TcpConnection<AcceptedDevice<D>>, Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            self.retransmit_syn_acks(now)?;
+            if now >= deadline {
+                return Err(format!(
+                    "Timeout: no connection completed within {:?} ({} pending)",
+                    timeout,
+                    self.pending()
+                )
+                .into());
+            }
+
+            // 次のSYN-ACKの再送までか、期限まで眠る
+            let wake = self
+                .syn_received
+                .values()
+                .map(|half_open| half_open.next_retransmit)
+                .fold(deadline, Instant::min);
+            let Some(datagram) = self
+                .demux
+                .recv_for(None, wake.saturating_duration_since(now))?
+            else {
+                continue;
+            };
+
+            match self.handle_datagram(&datagram, now) {
+                Ok(Some(conn)) => return Ok(conn),
+                Ok(None) => {}
+                Err(e) => println!("Dropped segment: {}", e),
+            }
+        }
+    }
+
+    /// 受信したデータグラムを処理し、handshakeが完了したら接続を返す
+    fn handle_datagram(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Option<TcpConnection<AcceptedDevice<D>>>, Box<dyn Error>> {
+        let (source, dest) = datagram_addresses(self.family, datagram)?;
+        if dest != self.local_ip {
+            return Ok(None);
+        }
+        let offset = ip_header_len(self.family, datagram)?;
+        let header = TcpHeader::from_bytes(&datagram[offset..])?;
+        if header.get_destination_port() != self.local_port && !self.reset_closed_ports {
+            return Ok(None);
+        }
+        let payload = &datagram[offset + header.header_len()..];
+        if !header.verify_checksum_ip(source, dest, payload) {
+            return Err("TCP checksum mismatch".into());
+        }
+        if header.get_destination_port() != self.local_port {
+            // CLOSED: RST以外のセグメントにはRSTを返す
+            self.send_reset(source, &header, payload)?;
+            return Ok(None);
+        }
+
+        let peer = (source, header.get_source_port());
+        match segment_event(&header) {
+            Some(TcpEvent::ReceiveRst) => {
+                // SYN-RECEIVED + RST → LISTENに戻る（半開き接続を捨てる）
+                if self.syn_received.remove(&peer).is_some() {
+                    println!(
+                        "RST from {}:{}, half-open connection dropped",
+                        peer.0, peer.1
+                    );
+                }
+                Ok(None)
+            }
+            Some(TcpEvent::ReceiveSyn) => {
+                self.handle_syn(peer, &header, now)?;
+                Ok(None)
+            }
+            Some(TcpEvent::ReceiveAck) => self.handle_ack(peer, &header, payload, now),
+            // LISTEN: ACKの付いたセグメント（SYN-ACKなど）には<SEQ=SEG.ACK><CTL=RST>を返す
+            // （RFC 9293 Section 3.10.7.2）。ACKのないFINなどは黙って捨てる
+            _ if header.get_flags() & tcp_flags::ACK != 0
+                && !self.syn_received.contains_key(&peer) =>
+            {
+                self.send_reset(source, &header, payload)?;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// LISTEN + SYN → SYN-ACKを返してSYN-RECEIVEDへ
+    fn handle_syn(
+        &mut self,
+        peer: Peer,
+        header: &TcpHeader,
+        now: Instant,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(half_open) = self.syn_received.get(&peer) {
+            // SYN-ACKが失われて相手がSYNを再送してきた: 同じISNで応答し直す
+            if half_open.remote_isn == header.get_sequence_number() {
+                self.send_syn_ack(peer, half_open)?;
+            }
+            return Ok(());
+        }
+        // 同じ4タプルの前の接続がTIME-WAIT中なら、前の接続より新しいSYNだけ受け入れる（RFC 6191）
+        let quadruple = (
+            SocketAddr::new(self.local_ip, self.local_port),
+            SocketAddr::new(peer.0, peer.1),
+        );
+        let seq = SequenceNumber::new(header.get_sequence_number());
+        let tsval = timestamps_option(header).map(|(tsval, _)| tsval);
+        if !time_wait_table().accept_syn(&quadruple, seq, tsval, now) {
+            println!(
+                "SYN from {}:{} ignored: connection is in TIME-WAIT",
+                peer.0, peer.1
+            );
+            return Ok(());
+        }
+        if self.syn_received.len() >= self.backlog {
+            println!("Backlog full, SYN from {}:{} ignored", peer.0, peer.1);
+            return Ok(());
+        }
+
+        let rto = RtoEstimator::new();
+        let half_open = HalfOpen {
+            local_isn: generate_isn_for(self.local_ip, self.local_port, peer.0, peer.1),
+            ts_offset: timestamp_offset_for(self.local_ip, self.local_port, peer.0, peer.1),
+            remote_isn: header.get_sequence_number(),
+            remote_mss: mss_option(header).unwrap_or(DEFAULT_REMOTE_MSS),
+            remote_window: header.get_window_size(),
+            remote_wscale: window_scale_option(header),
+            remote_tsval: timestamps_option(header).map(|(tsval, _)| tsval),
+            sent_at: now,
+            rto,
+            retransmits: 0,
+            next_retransmit: now + rto.rto(),
+        };
+        self.send_syn_ack(peer, &half_open)?;
+        self.syn_received.insert(peer, half_open);
+        Ok(())
+    }
+
+    /// SYN-RECEIVED + ACK → ESTABLISHED
+    fn handle_ack(
+        &mut self,
+        peer: Peer,
+        header: &TcpHeader,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<Option<TcpConnection<AcceptedDevice<D>>>, Box<dyn Error>> {
+        let Some(half_open) = self.syn_received.get(&peer) else {
+            // LISTEN + ACK: 半開き接続のない相手からのACKにはRSTを返す
+            self.send_reset(peer.0, header, payload)?;
+            return Ok(None);
+        };
+
+        // 自分のSYNを確認応答し、相手のSYNの次から始まるACKだけを受け付ける
+        let expected_ack = half_open.local_isn.wrapping_add(1);
+        let expected_seq = half_open.remote_isn.wrapping_add(1);
+        if header.get_ack_number() != expected_ack || header.get_sequence_number() != expected_seq {
+            // SYN-RECEIVEDで自分のSYNを確認応答していないACKにはRSTを返す。
+            // 半開き接続は残し、正しいACKやSYN-ACKの再送を待つ
+            if header.get_ack_number() != expected_ack {
+                self.send_reset(peer.0, header, payload)?;
+            }
+            return Err(format!(
+                "Unacceptable ACK from {}:{}: seq={} (expected {}), ack={} (expected {})",
+                peer.0,
+                peer.1,
+                header.get_sequence_number(),
+                expected_seq,
+                header.get_ack_number(),
+                expected_ack
+            )
+            .into());
+        }
+
+        let half_open = self.syn_received.remove(&peer).expect("half-open exists");
+        // ここから先、この相手からのデータグラムは接続のキューに入る
+        self.demux
+            .accepted
+            .borrow_mut()
+            .insert(peer, VecDeque::new());
+        let device = AcceptedDevice {
+            demux: Rc::clone(&self.demux),
+            peer,
+        };
+        let mut conn = TcpConnection::with_device(device, self.local_ip, peer.0, peer.1)?;
+        conn.local_port = self.local_port;
+        // 受け付けた接続の状態マシンもLISTEN → SYN-RECEIVED → ESTABLISHEDとたどる
+        conn.machine.passive_open()?;
+        conn.apply(TcpEvent::ReceiveSyn)?;
+        conn.machine.accept_connection()?;
+        conn.local_seq = half_open.local_isn.wrapping_add(1);
+        conn.remote_seq = half_open.remote_isn;
+        conn.remote_mss = half_open.remote_mss;
+        conn.send_window = half_open.remote_window as u32;
+        conn.negotiate_window_scale(half_open.remote_wscale);
+        conn.ts_offset = half_open.ts_offset;
+        // 最後のACKのTSvalがあればそれを、なければSYNのTSvalを覚えておく
+        conn.ts_recent = half_open
+            .remote_tsval
+            .map(|syn_tsval| timestamps_option(header).map_or(syn_tsval, |(tsval, _)| tsval));
+        conn.start_receiving(half_open.remote_isn);
+        // Karn: SYN-ACKを再送していなければ、最後のACKまでをRTTの最初の測定値にする
+        conn.rto = half_open.rto;
+        if half_open.retransmits == 0 {
+            conn.rto
+                .on_rtt_sample(now.saturating_duration_since(half_open.sent_at));
+        }
+        println!(
+            "Connection accepted from {}:{} (mss={})",
+            peer.0, peer.1, conn.remote_mss
+        );
+        Ok(Some(conn))
+    }
+
+    fn send_syn_ack(&self, peer: Peer, half_open: &HalfOpen) -> Result<(), Box<dyn Error>> {
+        let mut header = TcpHeader::new(
+            self.local_port,
+            peer.1,
+            half_open.local_isn,
+            half_open.remote_isn.wrapping_add(1),
+            tcp_flags::SYN | tcp_flags::ACK,
+            // 受け付ける接続の受信バッファはまだ空なので、全体をウィンドウとして広告する
+            // （SYN-ACKのウィンドウはスケールしない）
+            LOCAL_RECV_BUFFER_SIZE.min(u16::MAX as usize) as u16,
+        );
+        let mut options = vec![TcpOption::MaximumSegmentSize(LOCAL_MSS)];
+        // Window Scaleオプションは、SYNに含まれていたときだけ返す（RFC 7323 Section 2.2）
+        if half_open.remote_wscale.is_some() {
+            options.push(TcpOption::NoOperation);
+            options.push(TcpOption::WindowScale(window_scale_for(
+                LOCAL_RECV_BUFFER_SIZE,
+            )));
+        }
+        if let Some(tsecr) = half_open.remote_tsval {
+            options.extend([
+                TcpOption::NoOperation,
+                TcpOption::NoOperation,
+                TcpOption::Timestamps {
+                    tsval: timestamp_value(half_open.ts_offset),
+                    tsecr,
+                },
+            ]);
+        }
+        header.set_options(&options)?;
+        header.calculate_checksum_ip(self.local_ip, peer.0, &[])?;
+
+        let packet = build_datagram(self.local_ip, peer.0, &header.to_bytes(), &[])?;
+        self.demux.device.send(&packet)?;
+        println!(
+            "SYN-ACK sent to {}:{}: seq={}, ack={}",
+            peer.0,
+            peer.1,
+            half_open.local_isn,
+            half_open.remote_isn.wrapping_add(1)
+        );
+        Ok(())
+    }
+
+    fn send_reset(
+        &self,
+        remote_ip: IpAddr,
+        header: &TcpHeader,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(packet) = reset_packet(self.local_ip, remote_ip, header, payload)? {
+            self.demux.device.send(&packet)?;
+            println!(
+                "RST sent to {}:{} (port {})",
+                remote_ip,
+                header.get_source_port(),
+                header.get_destination_port()
+            );
+        }
+        Ok(())
+    }
+
+    /// 最後のACKが来ない半開き接続にSYN-ACKを再送する（RTOは毎回2倍）。
+    /// 上限まで再送しても応答がなければ捨てる
+    fn retransmit_syn_acks(&mut self, now: Instant) -> Result<(), Box<dyn Error>> {
+        let mut resend = Vec::new();
+        self.syn_received.retain(|peer, half_open| {
+            if now < half_open.next_retransmit {
+                return true;
+            }
+            if half_open.retransmits >= SYN_ACK_RETRIES {
+                println!("Handshake with {}:{} timed out", peer.0, peer.1);
+                return false;
+            }
+            half_open.retransmits += 1;
+            half_open.rto.backoff();
+            half_open.next_retransmit = now + half_open.rto.rto();
+            resend.push((*peer, *half_open));
+            true
+        });
+
+        for (peer, half_open) in resend {
+            self.send_syn_ack(peer, &half_open)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: PacketDevice> Drop for TcpListener<D> {
+    fn drop(&mut self) {
+        self.demux.listening.set(false);
+        self.demux.unmatched.borrow_mut().clear();
+    }
+}
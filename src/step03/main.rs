@@ -1,81 +1,276 @@
-use std::fmt::format;
-use std::net::Ipv4Addr;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use log::info;
 // Step01とStep02の実装を共通ライブラリから使用
+#[cfg(target_os = "linux")]
+use rust_tcp_handson_with_claude_code::step01::TunDevice;
 use rust_tcp_handson_with_claude_code::step01::{
-    create_raw_socket, get_local_ip, IpHeader, IP_HEADER_SIZE, IP_PROTOCOL_TCP,
+    get_local_ip_for, parse_ipv6_header, AddressFamily, IpHeader, Ipv6Header, PacketDevice,
+    RawSocketDevice, Reassembler, IPV6_HEADER_SIZE, IP_HEADER_SIZE, IP_PROTOCOL_TCP,
+};
+use rust_tcp_handson_with_claude_code::step02::{tcp_flags, TcpHeader, TcpOption, TCP_HEADER_SIZE};
+use rust_tcp_handson_with_claude_code::step04::{TcpEvent, TcpState, TcpStateMachine};
+use rust_tcp_handson_with_claude_code::step05::keepalive::KeepaliveConfig;
+use rust_tcp_handson_with_claude_code::step05::rto::RtoEstimator;
+use rust_tcp_handson_with_claude_code::step05::time_wait::{
+    Quadruple, TimeWaitEntry, TimeWaitTable, DEFAULT_MSL,
 };
-use rust_tcp_handson_with_claude_code::step02::{
-    calculate_checksum_rfc1071, tcp_flags, TcpHeader, TCP_HEADER_SIZE,
+use rust_tcp_handson_with_claude_code::step05::{
+    window_scale_for, ReceiveBuffer, SequenceNumber, TcpSegment, MAX_WINDOW_SCALE,
 };
 
-// クロスプラットフォーム対応: errnoを取得
-#[cfg(target_os = "linux")]
-fn get_errno() -> i32 {
-    unsafe { *libc::__errno_location() }
-}
+mod listener;
+pub use listener::TcpListener;
 
-#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
-fn get_errno() -> i32 {
-    unsafe { *libc::__error() }
-}
+/// SYNで広告するMSS（Ethernet MTU 1500 - IPヘッダー20 - TCPヘッダー20）
+const LOCAL_MSS: u16 = 1460;
 
-// Raw socketの基本機能（Step1から再利用）
-// 実装時にStep1のコードを参考にしてください
+/// 相手がMSSオプションを送ってこなかった場合のデフォルト値（RFC 9293 Section 3.7.1）
+const DEFAULT_REMOTE_MSS: u16 = 536;
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum TcpState {
-    Closed,
-    SynSent,
-    Established,
-}
+/// 受信バッファの大きさ。64KiBを超えるので、広告にはウィンドウスケール（RFC 7323）が必要
+const LOCAL_RECV_BUFFER_SIZE: usize = 256 * 1024;
 
+/// TIME-WAIT中でないローカルポートを探す回数
+const PORT_SELECTION_ATTEMPTS: usize = 16;
+
+/// 3-way handshakeを行うコネクション
+///
+/// IPデータグラムの送受信は`PacketDevice`に任せる。
+/// 実機ではraw socketやTUN、テストではメモリ上のループバックを使う。
+/// 状態はStep04の`TcpStateMachine`が持ち、許されない遷移になる操作はエラーにする
 #[derive(Debug)]
-pub struct TcpConnection {
-    socket_fd: i32,
-    state: TcpState,
+pub struct TcpConnection<D: PacketDevice = RawSocketDevice> {
+    device: D,
+    machine: TcpStateMachine,
     local_seq: u32,  // 自分のシーケンス番号
     remote_seq: u32, // 相手のシーケンス番号
-    local_ip: Ipv4Addr,
+    family: AddressFamily,
+    local_ip: IpAddr,
     local_port: u16,
-    remote_ip: Ipv4Addr,
+    remote_ip: IpAddr,
     remote_port: u16,
+    remote_mss: u16,            // 相手がSYN-ACKで広告したMSS
+    reassembler: Reassembler,   // 受信したIPフラグメントの再構築
+    rto: RtoEstimator,          // SYNの再送間隔（RFC 6298）
+    recv_buffer: ReceiveBuffer, // 受信データ。広告するウィンドウはこの空き容量
+    send_window: u32,           // 相手が広告した受信ウィンドウ（SND.WND、バイト）
+    snd_wscale: u8,             // 相手のウィンドウを左シフトする数（合意しなければ0）
+    rcv_wscale: u8,             // 自分のウィンドウを右シフトする数（合意しなければ0）
+    ts_offset: u32,             // TSvalに足すオフセット（接続ごとに推測しにくい値）
+    ts_recent: Option<u32>,     // 相手の最新のTSval（Timestampsを合意したときだけSome）
+    msl: Duration,              // TIME-WAITには2·MSLの間留まる
 }
 
-impl TcpConnection {
-    fn new(remote_ip: Ipv4Addr, remote_port: u16) -> Result<Self, Box<dyn std::error::Error>> {
-        // - Raw socket作成
-        let socket_fd = create_raw_socket()?;
-
-        let local_ip = get_local_ip().unwrap();
+impl TcpConnection<RawSocketDevice> {
+    /// raw socket経由で接続するTcpConnectionを作成（要root権限）
+    fn new(
+        remote_ip: impl Into<IpAddr>,
+        remote_port: u16,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let remote_ip = remote_ip.into();
+        let local_ip = get_local_ip_for(remote_ip).ok_or("Failed to determine local IP")?;
         println!("local_ip: {}", local_ip);
-        let local_port = Self::choose_local_port();
+
+        // - Raw socket作成（IPv4はIP_HDRINCL付き、IPv6はカーネルがヘッダーを付与）
+        let device = RawSocketDevice::open(local_ip)?;
+        Self::with_device(device, local_ip, remote_ip, remote_port)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl TcpConnection<TunDevice> {
+    /// TUNデバイス経由で接続するTcpConnectionを作成
+    ///
+    /// `local_ip`はTUNインターフェースの先（カーネルから見て別ホスト）のアドレス。
+    /// 例: tun0に10.0.0.1/24を設定し、自前スタックは10.0.0.2を名乗る
+    fn new_tun(
+        tun: TunDevice,
+        local_ip: impl Into<IpAddr>,
+        remote_ip: impl Into<IpAddr>,
+        remote_port: u16,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let local_ip = local_ip.into();
+        println!("local_ip: {} (via {})", local_ip, tun.name());
+        Self::with_device(tun, local_ip, remote_ip, remote_port)
+    }
+}
+
+impl<D: PacketDevice> TcpConnection<D> {
+    /// 任意のデバイスでTcpConnectionを作成
+    fn with_device(
+        device: D,
+        local_ip: impl Into<IpAddr>,
+        remote_ip: impl Into<IpAddr>,
+        remote_port: u16,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let local_ip = local_ip.into();
+        let remote_ip = remote_ip.into();
+        let family = AddressFamily::of(&remote_ip);
+        if AddressFamily::of(&local_ip) != family {
+            return Err("Local and remote address families differ".into());
+        }
+
+        let mut recv_buffer = ReceiveBuffer::new(SequenceNumber::new(0));
+        recv_buffer.set_capacity(LOCAL_RECV_BUFFER_SIZE);
+
+        // TIME-WAIT中の4タプルにならないローカルポートを選ぶ
+        let now = Instant::now();
+        let remote = SocketAddr::new(remote_ip, remote_port);
+        let local_port = (0..PORT_SELECTION_ATTEMPTS)
+            .map(|_| Self::choose_local_port())
+            .find(|&port| {
+                !time_wait_table().contains(&(SocketAddr::new(local_ip, port), remote), now)
+            })
+            .ok_or("No local port available: all candidates are in TIME-WAIT")?;
 
         Ok(Self {
-            socket_fd,
-            state: TcpState::Closed,
+            device,
+            family,
+            machine: TcpStateMachine::new(),
             local_seq: 0,
             remote_seq: 0,
             local_ip,
             local_port,
             remote_ip,
             remote_port,
+            remote_mss: DEFAULT_REMOTE_MSS,
+            reassembler: Reassembler::default(),
+            rto: RtoEstimator::new(),
+            recv_buffer,
+            send_window: 0,
+            snd_wscale: 0,
+            rcv_wscale: 0,
+            ts_offset: 0,
+            ts_recent: None,
+            msl: DEFAULT_MSL,
         })
     }
 
+    /// この接続の(ローカル, リモート)のアドレスとポート
+    fn quadruple(&self) -> Quadruple {
+        (
+            SocketAddr::new(self.local_ip, self.local_port),
+            SocketAddr::new(self.remote_ip, self.remote_port),
+        )
+    }
+
+    /// 広告する受信ウィンドウ（受信バッファの空き容量をrcv_wscaleだけ右シフトし、16ビットに収まる分）
+    ///
+    /// SYNを送る時点ではまだ合意していないので、スケールしない
+    fn receive_window(&self) -> u16 {
+        (self.recv_buffer.window() >> self.rcv_wscale).min(u16::MAX as usize) as u16
+    }
+
+    /// SYNのWindow Scaleオプションで送るシフト数（受信バッファ全体を広告できる値）
+    fn local_window_scale(&self) -> u8 {
+        window_scale_for(self.recv_buffer.capacity())
+    }
+
+    /// SYN-ACKのWindow Scaleオプションを見て、ウィンドウスケールを使うか決める
+    ///
+    /// 両方がオプションを送ったときだけ有効。14を超える値は14として扱う（RFC 7323 Section 2.3）
+    fn negotiate_window_scale(&mut self, remote_shift: Option<u8>) {
+        if let Some(shift) = remote_shift {
+            self.snd_wscale = shift.min(MAX_WINDOW_SCALE);
+            self.rcv_wscale = self.local_window_scale();
+        }
+    }
+
+    /// 相手のISNが分かったので、受信バッファを相手のISN + 1から始める
+    fn start_receiving(&mut self, remote_isn: u32) {
+        let capacity = self.recv_buffer.capacity();
+        self.recv_buffer = ReceiveBuffer::new(SequenceNumber::new(remote_isn.wrapping_add(1)));
+        self.recv_buffer.set_capacity(capacity);
+    }
+
+    /// 現在の状態
+    pub fn state(&self) -> TcpState {
+        self.machine.current_state()
+    }
+
+    /// これまでの状態遷移（遷移前, 遷移後, イベント）
+    pub fn state_history(&self) -> &[(TcpState, TcpState, TcpEvent)] {
+        self.machine.get_state_history()
+    }
+
+    /// 状態マシンにイベントを渡す。許されない遷移ならエラー
+    fn apply(&mut self, event: TcpEvent) -> Result<TcpState, Box<dyn std::error::Error>> {
+        Ok(self.machine.transition(event)?)
+    }
+
     fn connect(&mut self, timeout_secs: u64) -> Result<(), Box<dyn std::error::Error>> {
+        // 同じ4タプルの前の接続がTIME-WAIT中なら、期限まで使えない
+        if time_wait_table().contains(&self.quadruple(), Instant::now()) {
+            return Err(format!("Address in use: {:?} is in TIME-WAIT", self.quadruple()).into());
+        }
+
         // Task F1: 完全な3-way handshakeの実装
-        // 1. SYN送信
+        // 1. SYN送信（CLOSED以外からは状態マシンが拒否する）
         self.send_syn()?;
-        // 2. SYN-ACK受信・検証
-        let received_data = self.receive_packet_timeout(timeout_secs)?;
-        let tcp_header = self.parse_received_packet(&received_data)?;
+        self.establish(timeout_secs)
+    }
+
+    /// SYNを送った後のhandshake: SYN-ACKを待ち（RTOごとにSYNを再送）、ACKを返して確立する
+    fn establish(&mut self, timeout_secs: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let sent_at = Instant::now();
+        let deadline = sent_at + Duration::from_secs(timeout_secs);
+        let mut retransmitted = false;
+
+        // 2. SYN-ACK受信・検証（RTOまでに届かなければSYNを再送する）
+        let received_data = loop {
+            let wait = self
+                .rto
+                .rto()
+                .min(deadline.saturating_duration_since(Instant::now()));
+            match self.receive_packet_timeout(wait) {
+                Ok(data) => break data,
+                Err(e) if Instant::now() >= deadline => {
+                    // SYN-SENT + Timeout → CLOSED
+                    self.apply(TcpEvent::Timeout)?;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.retransmit_syn()?;
+                    retransmitted = true;
+                }
+            }
+        };
+        let tcp_header = match self.parse_received_packet(&received_data) {
+            Ok(header) => header,
+            Err(e) => {
+                self.apply(TcpEvent::Close)?;
+                return Err(e);
+            }
+        };
 
         if !self.is_correct_syn_ack(&tcp_header) {
-            // 受信したフラグの詳細
+            // 自分のSYNを確認応答するRSTは接続拒否（SYN-SENT + RST → CLOSED）。
+            // それ以外の応答でもこの接続試行は中止する（SYN-SENT + Close → CLOSED）
+            let refused = segment_event(&tcp_header) == Some(TcpEvent::ReceiveRst)
+                && tcp_header.get_ack_number() == self.local_seq.wrapping_add(1);
+            self.apply(if refused {
+                TcpEvent::ReceiveRst
+            } else {
+                TcpEvent::Close
+            })?;
+
+            // 自分のSYNを確認応答していないACKにはRSTを返す（RFC 9293 Section 3.10.7.3）
             let flags = tcp_header.get_flags();
+            if flags & tcp_flags::ACK != 0
+                && flags & tcp_flags::RST == 0
+                && tcp_header.get_ack_number() != self.local_seq.wrapping_add(1)
+            {
+                if let Err(e) = self.send_reset(&tcp_header, &received_data) {
+                    println!("Failed to send RST: {}", e);
+                }
+            }
+
+            // 受信したフラグの詳細
             let flag_str = format!(
                 "SYN:{} ACK:{} RST:{} FIN:{}",
                 (flags & tcp_flags::SYN) != 0,
@@ -85,7 +280,7 @@ impl TcpConnection {
             );
 
             // 期待値vs実際値
-            let expected_ack = self.local_seq + 1;
+            let expected_ack = self.local_seq.wrapping_add(1);
             let actual_ack = tcp_header.get_ack_number();
 
             // デバッグメッセージ
@@ -99,13 +294,28 @@ impl TcpConnection {
             ).into());
         }
 
+        // Karn: 再送したSYNへの応答からはRTTを測らない
+        if !retransmitted {
+            self.rto.on_rtt_sample(sent_at.elapsed());
+        }
+
+        // 相手のMSSを記録（オプションがなければデフォルトの536のまま）
+        if let Some(mss) = mss_option(&tcp_header) {
+            self.remote_mss = mss;
+        }
+        // 相手の受信ウィンドウを記録する（SYNのウィンドウはスケールしない）
+        self.send_window = tcp_header.get_window_size() as u32;
+        self.negotiate_window_scale(window_scale_option(&tcp_header));
+        // SYN-ACKにTimestampsがあれば、以降のセグメントでそのTSvalを返す
+        self.ts_recent = timestamps_option(&tcp_header).map(|(tsval, _)| tsval);
+        self.start_receiving(tcp_header.get_sequence_number());
+
         // 3. ACK送信
-        let ack_number = tcp_header.get_sequence_number() + 1;
+        let ack_number = tcp_header.get_sequence_number().wrapping_add(1);
         self.send_ack(ack_number)?;
 
         // 4. 接続完了
-        self.complete_handshake();
-        Ok(())
+        self.complete_handshake()
     }
 
     fn send_tcp_packet(
@@ -113,50 +323,55 @@ impl TcpConnection {
         tcp_header_bytes: &[u8],
         data: &[u8],
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let source = self.local_ip;
-        let dest = Ipv4Addr::from(self.remote_ip);
-        let data_len = tcp_header_bytes.len() + data.len();
-
-        let ip_header = IpHeader::new(source, dest, data_len as u16);
-
-        let mut packet = Vec::new();
-        packet.extend_from_slice(&ip_header.to_bytes());
-        packet.extend_from_slice(tcp_header_bytes);
-        packet.extend_from_slice(data);
-
-        let dest_sockaddr = libc::sockaddr_in {
-            sin_family: libc::AF_INET as libc::sa_family_t,
-            sin_port: 0,
-            sin_addr: libc::in_addr {
-                s_addr: u32::from(self.remote_ip), // Ipv4Addr -> u32（ネットワークバイトオーダー）
-            },
-            sin_zero: [0; 8],
-        };
+        let packet = build_datagram(self.local_ip, self.remote_ip, tcp_header_bytes, data)?;
+        self.device.send(&packet)
+    }
 
-        let result = unsafe {
-            libc::sendto(
-                self.socket_fd,                                      // ソケットFD
-                packet.as_ptr() as *const libc::c_void,              // 送信データ
-                packet.len(),                                        // データサイズ
-                0,                                                   // フラグ
-                &dest_sockaddr as *const _ as *const libc::sockaddr, // 宛先アドレス
-                std::mem::size_of::<libc::sockaddr_in>() as u32,     // アドレス構造体サイズ
-            )
-        };
-        if result < 0 {
-            let errno = get_errno();
-            return Err(format!("Failed to send packet: errno {}", errno).into());
+    /// Task C2: SYN送信機能
+    fn send_syn(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let isn = generate_isn_for(
+            self.local_ip,
+            self.local_port,
+            self.remote_ip,
+            self.remote_port,
+        );
+        self.send_syn_with_isn(isn)
+    }
+
+    /// `isn`をISNにしてSYNを送る
+    fn send_syn_with_isn(&mut self, isn: u32) -> Result<(), Box<dyn std::error::Error>> {
+        // CLOSED + Connect → SYN-SENT
+        self.apply(TcpEvent::Connect)?;
+        self.local_seq = isn;
+        // TSvalのオフセットは接続ごとに1度だけ、ISNとは別のハッシュで決める（RFC 7323 Section 5.4）
+        self.ts_offset = timestamp_offset_for(
+            self.local_ip,
+            self.local_port,
+            self.remote_ip,
+            self.remote_port,
+        );
+        let sent = self
+            .create_syn_packet()
+            .and_then(|syn_packet| self.send_tcp_packet(&syn_packet, &[]));
+        if let Err(e) = sent {
+            // 送れなかった接続試行は中止する
+            self.apply(TcpEvent::Close)?;
+            return Err(e);
         }
+        println!("SYN sent: seq={}", self.local_seq);
         Ok(())
     }
 
-    /// Task C2: SYN送信機能
-    fn send_syn(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.local_seq = generate_isn();
+    /// RTOが切れたのでSYNを同じISNで再送し、RTOを2倍にする（RFC 6298 Section 5.5）
+    fn retransmit_syn(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.rto.backoff();
         let syn_packet = self.create_syn_packet()?;
         self.send_tcp_packet(&syn_packet, &[])?;
-        self.state = TcpState::SynSent;
-        println!("SYN sent: seq={}", self.local_seq);
+        println!(
+            "SYN retransmitted: seq={}, next RTO={:?}",
+            self.local_seq,
+            self.rto.rto()
+        );
         Ok(())
     }
 
@@ -167,53 +382,64 @@ impl TcpConnection {
             self.local_seq, // ISN
             0,              // ACK番号は0
             tcp_flags::SYN, // SYNフラグ（bit 1）
-            8192,           // ウィンドウサイズ
+            self.receive_window(),
         );
 
-        // チェックサム計算
-        header.calculate_checksum(
-            u32::from(self.local_ip),
-            u32::from(self.remote_ip),
-            &[], // データなし
-        );
+        // MSSオプション（広告しないと相手は536バイトにフォールバックする）と
+        // Window Scale・Timestampsオプション（SYNで申し出たときだけ使える）
+        header.set_options(&[
+            TcpOption::MaximumSegmentSize(LOCAL_MSS),
+            TcpOption::NoOperation,
+            TcpOption::WindowScale(self.local_window_scale()),
+            TcpOption::NoOperation,
+            TcpOption::NoOperation,
+            TcpOption::Timestamps {
+                tsval: timestamp_value(self.ts_offset),
+                tsecr: 0,
+            },
+        ])?;
+
+        // チェックサム計算（オプション込み、疑似ヘッダーはアドレスファミリーに応じて切り替え）
+        header.calculate_checksum_ip(self.local_ip, self.remote_ip, &[])?;
 
         Ok(header.to_bytes())
     }
 
     fn receive_packet_timeout(
-        &self,
-        timeout_secs: u64,
+        &mut self,
+        timeout: Duration,
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let start = Instant::now();
-        let timeout = Duration::from_secs(timeout_secs);
-        let mut attempt_count = 0;
-
+        // デバイスのrecv_timeout()は届くまでpoll(2)で眠るので、残り時間をまとめて待つ
+        // （短い間隔で起きて確かめ直すと、その分CPUを使うだけになる）
+        let deadline = Instant::now() + timeout;
         loop {
-            attempt_count += 1;
-            // ノンブロッキング受信を試行
-            match self.try_receive_packet() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.try_receive_packet(remaining) {
                 Ok(data) => {
-                    println!(
-                        "Successfully received packet after {} attempts",
-                        attempt_count
-                    );
-                    return Ok(data);
+                    // フラグメントなら再構築器に預け、揃うまで受信を続ける
+                    // （IPv6のフラグメント再構築は未対応なのでそのまま渡す）
+                    let processed = match self.family {
+                        AddressFamily::Ipv4 => self.reassembler.process(&data, Instant::now()),
+                        AddressFamily::Ipv6 => Ok(Some(data)),
+                    };
+                    match processed {
+                        Ok(Some(datagram)) => return Ok(datagram),
+                        Ok(None) => {
+                            println!(
+                                "IP fragment buffered ({} datagrams pending)",
+                                self.reassembler.pending()
+                            );
+                        }
+                        Err(e) => println!("Dropped malformed IP packet: {}", e),
+                    }
+                    if Instant::now() >= deadline {
+                        return Err(format!("Timeout after {:?}", timeout).into());
+                    }
                 }
                 Err(e) => {
-                    // 10秒ごとに進捗を表示
-                    if attempt_count % 1000 == 0 {
-                        println!(
-                            "Attempt {}: {}, elapsed: {:?}",
-                            attempt_count,
-                            e,
-                            start.elapsed()
-                        );
-                    }
-
-                    if start.elapsed() > timeout {
-                        return Err(format!("Timeout after {} attempts", attempt_count).into());
+                    if Instant::now() >= deadline {
+                        return Err(format!("Timeout after {:?}: {}", timeout, e).into());
                     }
-                    std::thread::sleep(Duration::from_millis(10));
                 }
             }
         }
@@ -221,21 +447,9 @@ impl TcpConnection {
 
     fn parse_received_packet(&self, data: &[u8]) -> Result<TcpHeader, Box<dyn std::error::Error>> {
         // Task D2: 受信パケット解析
-        // - IPヘッダー長計算
-        if data.len() < IP_HEADER_SIZE {
-            return Err("Packet too short".into());
-        }
-
-        // IPプロトコルチェック
-        let protocol = data[9];
-        if protocol != IP_PROTOCOL_TCP {
-            return Err("Not a TCP packet".into());
-        }
-
-        // IP ヘッダーの 1 バイト目は version (4bit) + IHL: Internet Header Length (4bit)
-        // IHL の情報から IP ヘッダーのバイト数を算出するため下位 4bit の IHL だけを抽出
-        let ip_header_len = ((data[0] & 0x0F) * 4) as usize;
-        if data.len() < ip_header_len + 20 {
+        // - IPヘッダー長計算（IPv6は拡張ヘッダーを辿った先がTCPヘッダー）
+        let ip_header_len = ip_header_len(self.family, data)?;
+        if data.len() < ip_header_len + TCP_HEADER_SIZE {
             return Err("TCP header incomplete".into());
         }
 
@@ -252,12 +466,11 @@ impl TcpConnection {
 
     fn is_correct_syn_ack(&self, tcp_header: &TcpHeader) -> bool {
         // Task D3: SYN-ACK検証
-        // - SYN + ACKフラグチェック
-        let flags = tcp_header.get_flags();
-        let has_syn_ack = (flags & tcp_flags::SYN) != 0 && (flags & tcp_flags::ACK) != 0;
+        // - SYN + ACKフラグチェック（RSTが付いていればSYN-ACKとは見なさない）
+        let has_syn_ack = segment_event(tcp_header) == Some(TcpEvent::ReceiveSynAck);
 
         // - ACK番号の正確性チェック (local_seq + 1)
-        let has_correct_ack = tcp_header.get_ack_number() == self.local_seq + 1;
+        let has_correct_ack = tcp_header.get_ack_number() == self.local_seq.wrapping_add(1);
 
         // - ポート番号チェック
         let has_correct_ports = tcp_header.get_source_port() == self.remote_port
@@ -271,47 +484,276 @@ impl TcpConnection {
         let ack_packet = self.create_ack_packet(ack_number)?;
         self.send_tcp_packet(&ack_packet, &[])?;
 
-        self.remote_seq = ack_number - 1;
+        self.remote_seq = ack_number.wrapping_sub(1);
 
-        println!("ACK sent: seq={}, ack={}", self.local_seq + 1, ack_number);
+        println!(
+            "ACK sent: seq={}, ack={}",
+            self.local_seq.wrapping_add(1),
+            ack_number
+        );
         Ok(())
     }
 
     fn create_ack_packet(&self, ack_number: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         // Task E1: ACKパケット構築
         // - ACKフラグ付きTCPヘッダー作成
-        // - 正しいseq/ack番号設定
+        // - 正しいseq/ack番号設定（SYN 送信後なので+1）
+        self.create_segment(tcp_flags::ACK, self.local_seq.wrapping_add(1), ack_number)
+    }
 
+    /// データを含まないセグメント（ACK, FIN）を作る。Timestampsを合意していればTSvalとTSecrを付ける
+    fn create_segment(
+        &self,
+        flags: u8,
+        seq: u32,
+        ack_number: u32,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         let mut header = TcpHeader::new(
             self.local_port,
             self.remote_port,
-            self.local_seq + 1, // SYN 送信後なので+1
+            seq,
             ack_number,
-            tcp_flags::ACK, // ACKフラグ
-            8192,           // ウィンドウサイズ
+            flags,
+            self.receive_window(),
         );
+        if let Some(tsecr) = self.ts_recent {
+            header.set_options(&[
+                TcpOption::NoOperation,
+                TcpOption::NoOperation,
+                TcpOption::Timestamps {
+                    tsval: timestamp_value(self.ts_offset),
+                    tsecr,
+                },
+            ])?;
+        }
 
         // チェックサム計算
-        header.calculate_checksum(
-            u32::from(self.local_ip),
-            u32::from(self.remote_ip),
-            &[], // データなし
-        );
+        header.calculate_checksum_ip(self.local_ip, self.remote_ip, &[])?;
 
         Ok(header.to_bytes())
     }
 
-    fn complete_handshake(&mut self) {
+    fn complete_handshake(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         // Task E3: 接続確立完了
-        // - 状態をESTABLISHEDに変更
-        self.state = TcpState::Established;
+        // - 状態をESTABLISHEDに変更（SYN-SENT + SYN-ACK）
+        self.apply(TcpEvent::ReceiveSynAck)?;
         // - シーケンス番号更新
-        self.local_seq += 1;
+        self.local_seq = self.local_seq.wrapping_add(1);
+        Ok(())
     }
 
     fn is_connected(&self) -> bool {
         // Task E4: 接続確認
-        self.state == TcpState::Established
+        self.machine.is_established()
+    }
+
+    /// 接続を閉じる: FINを送り、TIME-WAITかCLOSEDになるまで待つ
+    ///
+    /// このステップの接続はデータを送らないので、すぐにFINを送れる。
+    /// ESTABLISHEDからはFIN-WAIT-1 → FIN-WAIT-2 → TIME-WAIT（相手も同時に閉じれば
+    /// FIN-WAIT-1 → CLOSING → TIME-WAIT）、相手が先に閉じていればLAST-ACK → CLOSEDと進む。
+    /// ACKが届かなければRTOごとにFINを再送する。
+    /// TIME-WAITに入ったら、2·MSLの間は同じ4タプルを使えないよう表に残す
+    fn close(&mut self, timeout_secs: u64) -> Result<(), Box<dyn std::error::Error>> {
+        // ESTABLISHED + Close → FIN-WAIT-1, CLOSE-WAIT + Close → LAST-ACK
+        self.apply(TcpEvent::Close)?;
+        let fin_seq = self.local_seq;
+        self.send_fin(fin_seq)?;
+        // FINもシーケンス番号を1つ消費する
+        self.local_seq = fin_seq.wrapping_add(1);
+
+        let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+        let mut retransmit_at = Instant::now() + self.rto.rto();
+        while !matches!(self.state(), TcpState::TimeWait | TcpState::Closed) {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(format!("Close timed out in state {}", self.state()).into());
+            }
+            if now >= retransmit_at && self.fin_outstanding() {
+                self.rto.backoff();
+                self.send_fin(fin_seq)?;
+                println!(
+                    "FIN retransmitted: seq={}, next RTO={:?}",
+                    fin_seq,
+                    self.rto.rto()
+                );
+            }
+            if now >= retransmit_at {
+                retransmit_at = now + self.rto.rto();
+            }
+            let wait = retransmit_at.min(deadline).saturating_duration_since(now);
+            if let Ok(data) = self.receive_packet_timeout(wait) {
+                self.on_closing_segment(&data)?;
+            }
+        }
+        if self.state() == TcpState::TimeWait {
+            let entry = TimeWaitEntry {
+                snd_nxt: SequenceNumber::new(self.local_seq),
+                rcv_nxt: self.recv_buffer.next_expected(),
+                ts_recent: self.ts_recent,
+                expires_at: Instant::now() + 2 * self.msl,
+            };
+            time_wait_table().insert(self.quadruple(), entry);
+        }
+        println!("Connection closed: state={}", self.state());
+        Ok(())
+    }
+
+    /// アイドルの接続で相手がまだ生きているか確かめる（RFC 1122 Section 4.2.3.6）
+    ///
+    /// `config.idle`の間に相手から何も届かなければ、`config.interval`ごとに
+    /// キープアライブプローブ（SEG.SEQ = SND.NXT - 1、データなし）を送る。
+    /// `config.probes`回送っても応答がなければ接続を中止してCLOSEDにする
+    fn keepalive(&mut self, config: &KeepaliveConfig) -> Result<(), Box<dyn std::error::Error>> {
+        if !matches!(self.state(), TcpState::Established | TcpState::CloseWait) {
+            return Err(format!("Keepalive not valid in state {}", self.state()).into());
+        }
+        let mut deadline = Instant::now() + config.idle;
+        let mut probes = 0;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                if probes >= config.probes {
+                    // 応答のない相手との接続を中止する（ESTABLISHED + Timeout → CLOSED）
+                    self.apply(TcpEvent::Timeout)?;
+                    return Err(
+                        format!("Keepalive timeout: no response to {} probes", probes).into(),
+                    );
+                }
+                let seq = self.local_seq.wrapping_sub(1);
+                let ack_number = self.recv_buffer.next_expected().value();
+                let probe = self.create_segment(tcp_flags::ACK, seq, ack_number)?;
+                self.send_tcp_packet(&probe, &[])?;
+                probes += 1;
+                println!("Keepalive probe {} sent: seq={}", probes, seq);
+                deadline = now + config.interval;
+                continue;
+            }
+            let Ok(data) = self.receive_packet_timeout(deadline - now) else {
+                continue;
+            };
+            let Ok(header) = self.parse_received_packet(&data) else {
+                continue;
+            };
+            if header.get_source_port() != self.remote_port {
+                continue;
+            }
+            let flags = header.get_flags();
+            if flags & tcp_flags::RST != 0 {
+                // 再起動した相手は、プローブにRSTを返してくる
+                if header.get_sequence_number() == self.recv_buffer.next_expected().value() {
+                    self.apply(TcpEvent::ReceiveRst)?;
+                    return Err("Connection reset by peer".into());
+                }
+                continue;
+            }
+            println!("Keepalive: peer is alive");
+            return Ok(());
+        }
+    }
+
+    /// 自分のFINを送ったが、まだACKされていない
+    fn fin_outstanding(&self) -> bool {
+        matches!(
+            self.state(),
+            TcpState::FinWait1 | TcpState::Closing | TcpState::LastAck
+        )
+    }
+
+    fn send_fin(&mut self, fin_seq: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let ack_number = self.recv_buffer.next_expected().value();
+        let fin_packet =
+            self.create_segment(tcp_flags::FIN | tcp_flags::ACK, fin_seq, ack_number)?;
+        self.send_tcp_packet(&fin_packet, &[])?;
+        println!("FIN sent: seq={}, ack={}", fin_seq, ack_number);
+        Ok(())
+    }
+
+    /// 接続を閉じている間に届いたセグメントを処理する
+    ///
+    /// 自分のFINへのACKと相手のFINで状態を進め、相手のFINにはACKを返す
+    /// （再送されたFINにも、こちらのACKが失われたかもしれないので返し直す）
+    fn on_closing_segment(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let Ok(header) = self.parse_received_packet(data) else {
+            return Ok(());
+        };
+        if header.get_source_port() != self.remote_port {
+            return Ok(());
+        }
+        let flags = header.get_flags();
+        let seq = SequenceNumber::new(header.get_sequence_number());
+        let rcv_nxt = self.recv_buffer.next_expected();
+        if flags & tcp_flags::RST != 0 {
+            // RCV.NXTにちょうど一致するRSTだけを受け入れ、ウィンドウ内の推測には
+            // チャレンジACKを返す（RFC 5961 Section 3）
+            if seq == rcv_nxt {
+                self.apply(TcpEvent::ReceiveRst)?;
+                return Err("Connection reset by peer while closing".into());
+            }
+            let window = (self.recv_buffer.window() as u32).max(1);
+            if seq > rcv_nxt && seq < rcv_nxt.wrapping_add(window) {
+                self.send_current_ack()?;
+            }
+            return Ok(());
+        }
+        if flags & tcp_flags::SYN != 0 {
+            // 確立した接続へのSYNにはチャレンジACKを返して捨てる（RFC 5961 Section 4）
+            self.send_current_ack()?;
+            return Ok(());
+        }
+        if let (Some(_), Some((tsval, _))) = (self.ts_recent, timestamps_option(&header)) {
+            self.ts_recent = Some(tsval);
+        }
+
+        // FIN-WAIT-1 → FIN-WAIT-2, CLOSING → TIME-WAIT, LAST-ACK → CLOSED
+        if flags & tcp_flags::ACK != 0
+            && self.fin_outstanding()
+            && header.get_ack_number() == self.local_seq
+        {
+            self.apply(TcpEvent::ReceiveAck)?;
+        }
+
+        if flags & tcp_flags::FIN != 0 {
+            // FINはデータの直後のシーケンス番号を1つ消費する
+            let payload_start = ip_header_len(self.family, data)? + header.header_len();
+            let payload = data.get(payload_start..).unwrap_or_default();
+            if !payload.is_empty() {
+                self.recv_buffer.receive(seq, payload)?;
+            }
+            let was_received = self.recv_buffer.fin_received();
+            self.recv_buffer
+                .receive_fin(seq.wrapping_add(payload.len() as u32));
+            if !was_received && self.recv_buffer.fin_received() {
+                // FIN-WAIT-1 → CLOSING（同時クローズ）, FIN-WAIT-2 → TIME-WAIT
+                self.apply(TcpEvent::ReceiveFin)?;
+            }
+            self.send_current_ack()?;
+        }
+        Ok(())
+    }
+
+    /// 今のSND.NXTとRCV.NXTでACKを送る（FINへの応答、チャレンジACK）
+    fn send_current_ack(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let ack_number = self.recv_buffer.next_expected().value();
+        let ack_packet = self.create_segment(tcp_flags::ACK, self.local_seq, ack_number)?;
+        self.send_tcp_packet(&ack_packet, &[])?;
+        println!("ACK sent: seq={}, ack={}", self.local_seq, ack_number);
+        Ok(())
+    }
+
+    /// 受け取ったデータグラムのセグメントにRSTで応える
+    fn send_reset(
+        &self,
+        header: &TcpHeader,
+        datagram: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let payload_start = ip_header_len(self.family, datagram)? + header.header_len();
+        let payload = datagram.get(payload_start..).unwrap_or_default();
+        if let Some(packet) = reset_packet(self.local_ip, self.remote_ip, header, payload)? {
+            self.device.send(&packet)?;
+            println!("RST sent: seq={}", header.get_ack_number());
+        }
+        Ok(())
     }
 
     /// 動的にローカルポートを選択
@@ -334,114 +776,412 @@ impl TcpConnection {
                 % (65535 - 49152))
     }
 
-    fn try_receive_packet(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        // 最大IPパケットサイズ（65535バイト）
-        const MAX_PACKET_SIZE: usize = 65535;
-        let mut buffer = vec![0u8; MAX_PACKET_SIZE];
-
-        let bytes_received = unsafe {
-            libc::recv(
-                self.socket_fd,
-                buffer.as_mut_ptr() as *mut libc::c_void,
-                buffer.len(),
-                libc::MSG_DONTWAIT, // ノンブロッキングフラグ
+    fn try_receive_packet(&self, timeout: Duration) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let Some(packet) = self.device.recv_timeout(timeout)? else {
+            return Err(format!(
+                "No data available (waiting for TCP to {}:{}",
+                self.remote_ip, self.remote_port,
             )
+            .into());
         };
 
-        if bytes_received < 0 {
-            let errno = get_errno();
-
-            // ノンブロッキングで利用可能なデータがない場合
-            if errno == libc::EAGAIN || errno == libc::EWOULDBLOCK {
-                info!("Received {} bytes", bytes_received);
-                return Err(format!(
-                    "No data available (waiting for TCP to {}:{}",
-                    self.remote_ip, self.remote_port,
-                )
-                .into());
-            }
-            return Err(format!("Failed to receive packet: errno {}", errno).into());
-        }
-
-        if bytes_received < IP_HEADER_SIZE as isize {
+        let ip_header_size = self.family.header_size();
+        if packet.len() < ip_header_size {
             return Err("Received packet too short for IP header".into());
         }
         // 受信したパケットの詳細をログ出力
-        if bytes_received >= IP_HEADER_SIZE as isize {
-            let protocol = buffer[9];
+        let (protocol, ip_header_len) = match self.family {
+            AddressFamily::Ipv4 => (packet[9], ((packet[0] & 0x0F) * 4) as usize),
+            AddressFamily::Ipv6 => (packet[6], IPV6_HEADER_SIZE),
+        };
+        println!(
+            "Received packet: {} bytes, protocol={}",
+            packet.len(),
+            protocol
+        );
+
+        if protocol == IP_PROTOCOL_TCP && packet.len() >= ip_header_len + TCP_HEADER_SIZE {
+            // TCPヘッダーの基本情報を表示
+            let tcp_data = &packet[ip_header_len..];
+            let src_port = u16::from_be_bytes([tcp_data[0], tcp_data[1]]);
+            let dst_port = u16::from_be_bytes([tcp_data[2], tcp_data[3]]);
             println!(
-                "Received packet: {} bytes, protocol={}",
-                bytes_received, protocol
+                "TCP packet: {}:{} -> {}:{}",
+                self.remote_ip, src_port, self.local_ip, dst_port
             );
+        }
+        info!("Received {} bytes", packet.len());
 
-            if protocol == IP_PROTOCOL_TCP && bytes_received >= 40 {
-                // TCPヘッダーの基本情報を表示
-                let ip_header_len = ((buffer[0] & 0x0F) * 4) as usize;
-                if bytes_received as usize >= ip_header_len + 20 {
-                    let tcp_data = &buffer[ip_header_len..];
-                    let src_port = u16::from_be_bytes([tcp_data[0], tcp_data[1]]);
-                    let dst_port = u16::from_be_bytes([tcp_data[2], tcp_data[3]]);
-                    println!(
-                        "TCP packet: {}:{} -> {}:{}",
-                        self.remote_ip, src_port, self.local_ip, dst_port
-                    );
-                }
+        // 受信したパケット全体を返す
+        Ok(packet)
+    }
+}
+
+/// 受信したセグメントのフラグに対応する状態マシンのイベント
+///
+/// 複数のフラグが立っている場合はRST > SYN-ACK > SYN > FIN > ACKの順に優先する
+fn segment_event(header: &TcpHeader) -> Option<TcpEvent> {
+    let flags = header.get_flags();
+    let has = |flag: u8| flags & flag != 0;
+    if has(tcp_flags::RST) {
+        Some(TcpEvent::ReceiveRst)
+    } else if has(tcp_flags::SYN) && has(tcp_flags::ACK) {
+        Some(TcpEvent::ReceiveSynAck)
+    } else if has(tcp_flags::SYN) {
+        Some(TcpEvent::ReceiveSyn)
+    } else if has(tcp_flags::FIN) {
+        Some(TcpEvent::ReceiveFin)
+    } else if has(tcp_flags::ACK) {
+        Some(TcpEvent::ReceiveAck)
+    } else {
+        None
+    }
+}
+
+/// SYN/SYN-ACKのMSSオプションの値
+fn mss_option(header: &TcpHeader) -> Option<u16> {
+    header
+        .options()
+        .ok()?
+        .into_iter()
+        .find_map(|option| match option {
+            TcpOption::MaximumSegmentSize(mss) => Some(mss),
+            _ => None,
+        })
+}
+
+/// SYN/SYN-ACKのWindow Scaleオプションの値
+fn window_scale_option(header: &TcpHeader) -> Option<u8> {
+    header
+        .options()
+        .ok()?
+        .into_iter()
+        .find_map(|option| match option {
+            TcpOption::WindowScale(shift) => Some(shift),
+            _ => None,
+        })
+}
+
+/// SYN/SYN-ACKのTimestampsオプションの(TSval, TSecr)
+fn timestamps_option(header: &TcpHeader) -> Option<(u32, u32)> {
+    header
+        .options()
+        .ok()?
+        .into_iter()
+        .find_map(|option| match option {
+            TcpOption::Timestamps { tsval, tsecr } => Some((tsval, tsecr)),
+            _ => None,
+        })
+}
+
+/// 最近TIME-WAITに入った4タプル（プロセス内の接続とリスナーで共有する）
+fn time_wait_table() -> MutexGuard<'static, TimeWaitTable> {
+    static TABLE: OnceLock<Mutex<TimeWaitTable>> = OnceLock::new();
+    TABLE
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// 送るセグメントのTSval: プロセス内で共通の1ミリ秒刻みの時計に、接続ごとのオフセットを足す
+fn timestamp_value(offset: u32) -> u32 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    let millis = EPOCH.get_or_init(Instant::now).elapsed().as_millis() as u32;
+    offset.wrapping_add(millis)
+}
+
+/// 受け取ったセグメントに応答するRSTのデータグラム（RFC 9293 Section 3.10.7.1）。RSTにはNone
+fn reset_packet(
+    local_ip: IpAddr,
+    remote_ip: IpAddr,
+    header: &TcpHeader,
+    payload: &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let segment = TcpSegment::new(
+        SequenceNumber::new(header.get_sequence_number()),
+        SequenceNumber::new(header.get_ack_number()),
+        header.get_flags(),
+        header.get_window_size(),
+    )
+    .with_payload(payload.to_vec());
+    let Some(reply) = segment.reset_reply() else {
+        return Ok(None);
+    };
+    let mut rst = TcpHeader::new(
+        header.get_destination_port(),
+        header.get_source_port(),
+        reply.seq.value(),
+        reply.ack.value(),
+        reply.flags,
+        reply.window,
+    );
+    rst.calculate_checksum_ip(local_ip, remote_ip, &[])?;
+    Ok(Some(build_datagram(
+        local_ip,
+        remote_ip,
+        &rst.to_bytes(),
+        &[],
+    )?))
+}
+
+/// IPヘッダーとTCPセグメントを1つのIPデータグラムにまとめる
+fn build_datagram(
+    local_ip: IpAddr,
+    remote_ip: IpAddr,
+    tcp_header_bytes: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let payload_len = (tcp_header_bytes.len() + data.len()) as u16;
+    let mut packet = match (local_ip, remote_ip) {
+        // DF付きのデータグラムなのでIdentificationは0でよい（RFC 6864）
+        (IpAddr::V4(source), IpAddr::V4(dest)) => {
+            IpHeader::new(source, dest, payload_len).to_wire_bytes(0)
+        }
+        (IpAddr::V6(source), IpAddr::V6(dest)) => {
+            Ipv6Header::new(source, dest, payload_len).to_bytes()
+        }
+        _ => return Err("Local and remote address families differ".into()),
+    };
+    packet.extend_from_slice(tcp_header_bytes);
+    packet.extend_from_slice(data);
+    Ok(packet)
+}
+
+/// 受信したIPデータグラムの先頭からTCPヘッダーまでのバイト数
+fn ip_header_len(family: AddressFamily, data: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+    match family {
+        AddressFamily::Ipv4 => ipv4_header_len(data),
+        AddressFamily::Ipv6 => {
+            let packet = parse_ipv6_header(data)?;
+            if packet.is_fragment() {
+                return Err("Fragmented IPv6 packets are not supported".into());
             }
+            Ok(packet.payload_offset)
         }
-        info!("Received {} bytes", bytes_received);
+    }
+}
 
-        // 受信したパケット全体を返す
-        buffer.truncate(bytes_received as usize);
-        Ok(buffer)
+fn ipv4_header_len(data: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+    if data.len() < IP_HEADER_SIZE {
+        return Err("Packet too short".into());
     }
+
+    // IPプロトコルチェック
+    let protocol = data[9];
+    if protocol != IP_PROTOCOL_TCP {
+        return Err("Not a TCP packet".into());
+    }
+
+    // IP ヘッダーの 1 バイト目は version (4bit) + IHL: Internet Header Length (4bit)
+    // IHL の情報から IP ヘッダーのバイト数を算出するため下位 4bit の IHL だけを抽出
+    Ok(((data[0] & 0x0F) * 4) as usize)
 }
 
-/// ISN: The Initial Sequence Number
-fn generate_isn() -> u32 {
-    // RFC 6528推奨の安全な初期シーケンス番号生成
-    // 簡易実装: 現在時刻 + ランダム値
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32;
+/// 受信したIPデータグラムの（送信元, 宛先）アドレス
+fn datagram_addresses(
+    family: AddressFamily,
+    data: &[u8],
+) -> Result<(IpAddr, IpAddr), Box<dyn std::error::Error>> {
+    match family {
+        AddressFamily::Ipv4 => {
+            let header = IpHeader::from_bytes(data)?;
+            Ok((header.source_ip().into(), header.dest_ip().into()))
+        }
+        AddressFamily::Ipv6 => {
+            let header = Ipv6Header::from_bytes(data)?;
+            Ok((header.source_ip().into(), header.dest_ip().into()))
+        }
+    }
+}
 
-    // 注意: 本来はrand crateを使用しますが、簡易実装として現在時刻を使用
-    // 実際の実装では crypto-secure randomを使用してください
-    now.wrapping_add(12345) // 簡易的なランダム要素
+/// 4タプルと秘密鍵の鍵付きハッシュ（SipHash）
+///
+/// `domain`を入力に混ぜて用途ごとに別の値にする。同じ4タプルのISNとTSvalのオフセットが
+/// 同じ値になると、TSvalからISNが分かってしまう
+fn keyed_hash(
+    domain: &str,
+    local_ip: IpAddr,
+    local_port: u16,
+    remote_ip: IpAddr,
+    remote_port: u16,
+) -> u32 {
+    // 秘密鍵はプロセス起動時にランダムに決まる（RandomStateの鍵を流用）
+    static SECRET: OnceLock<RandomState> = OnceLock::new();
+    SECRET.get_or_init(RandomState::new).hash_one((
+        domain,
+        local_ip,
+        local_port,
+        remote_ip,
+        remote_port,
+    )) as u32
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Step 3: 3-way Handshake Implementation");
-    println!("========================================");
+/// 4タプルごとのISN（RFC 6528）
+///
+/// ISN = M + F(localip, localport, remoteip, remoteport, secretkey)
+/// - M: `generate_isn()`のタイマー
+/// - F: 4タプルと秘密鍵のハッシュ（SipHash）。外部から次のISNを推測できない
+fn generate_isn_for(local_ip: IpAddr, local_port: u16, remote_ip: IpAddr, remote_port: u16) -> u32 {
+    generate_isn().wrapping_add(keyed_hash(
+        "isn",
+        local_ip,
+        local_port,
+        remote_ip,
+        remote_port,
+    ))
+}
 
-    // ログ初期化（必要に応じて）
-    // env_logger::init();
+/// 4タプルごとのTSvalのオフセット（RFC 7323 Section 5.4）
+///
+/// ISNとは別の鍵付きハッシュから作るので、TSvalを見てもISNは推測できない
+fn timestamp_offset_for(
+    local_ip: IpAddr,
+    local_port: u16,
+    remote_ip: IpAddr,
+    remote_port: u16,
+) -> u32 {
+    keyed_hash("tsval", local_ip, local_port, remote_ip, remote_port)
+}
 
-    // デモ実行例
-    println!("Demo: Attempting 3-way handshake with localhost:80");
+/// ISN: The Initial Sequence Number
+///
+/// RFC 6528のM: 4マイクロ秒ごとに1増えるタイマー（約4.55時間で一周）。
+/// これだけでは推測できてしまうので、実際に使うのは`generate_isn_for()`
+fn generate_isn() -> u32 {
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros();
+    (micros / 4) as u32
+}
 
-    let remote_ip = Ipv4Addr::new(127, 0, 0, 1); // localhost
+/// raw socketでのデモ用コネクション
+fn raw_socket_connection(args: &[String]) -> Result<TcpConnection, Box<dyn std::error::Error>> {
+    // 宛先は引数で指定可能（IPv4/IPv6どちらも可、例: ::1）。省略時はlocalhost
+    let remote_ip: IpAddr = match args.get(1) {
+        Some(arg) => arg.parse()?,
+        None => Ipv4Addr::new(127, 0, 0, 1).into(),
+    };
+    println!("Demo: Attempting 3-way handshake with {}:80", remote_ip);
 
     // 注意: 実際にはlocalhostの80番ポートにHTTPサーバーが動いている必要があります
     // テスト用にnetcatを使用: nc -l 80 （別ターミナルで実行）
+    TcpConnection::new(remote_ip, 80)
+}
 
-    let mut conn = TcpConnection::new(remote_ip, 80)?;
+/// TUNでのデモ用コネクション: tun <カーネル側IP> <自分のIP> <ポート>
+#[cfg(target_os = "linux")]
+fn tun_connection(args: &[String]) -> Result<TcpConnection<TunDevice>, Box<dyn std::error::Error>> {
+    if args.len() < 5 {
+        return Err("Usage: step03 tun <kernel-side-ip> <local-ip> <port>".into());
+    }
+    let kernel_ip: Ipv4Addr = args[2].parse()?;
+    let local_ip: Ipv4Addr = args[3].parse()?;
+    let port: u16 = args[4].parse()?;
+
+    let tun = TunDevice::open("step03tun")?;
+    tun.configure_ipv4(kernel_ip, 24)?;
+    println!(
+        "Demo: Attempting 3-way handshake with {}:{} via {}",
+        kernel_ip,
+        port,
+        tun.name()
+    );
+    TcpConnection::new_tun(tun, local_ip, kernel_ip, port)
+}
+
+/// TUNでの待ち受けデモ: listen <カーネル側IP> <自分のIP> <ポート>
+#[cfg(target_os = "linux")]
+fn tun_listen_demo(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if args.len() < 5 {
+        return Err("Usage: step03 listen <kernel-side-ip> <local-ip> <port>".into());
+    }
+    let kernel_ip: Ipv4Addr = args[2].parse()?;
+    let local_ip: Ipv4Addr = args[3].parse()?;
+    let port: u16 = args[4].parse()?;
+
+    let tun = TunDevice::open("step03tun")?;
+    tun.configure_ipv4(kernel_ip, 24)?;
+    let mut listener = TcpListener::bind_tun(tun, local_ip, port)?;
+    println!(
+        "Demo: Listening on {}:{} (try: nc {} {})",
+        local_ip, port, local_ip, port
+    );
+
+    let conn = listener.accept(Duration::from_secs(60))?;
+    println!(
+        "✅ Accepted connection from {}:{}",
+        conn.remote_ip, conn.remote_port
+    );
+    println!("Final state: {:?}", conn.state());
+    Ok(())
+}
 
-    println!("Initial state: {:?}", conn.state);
+/// デバイスに関係なく同じ手順でhandshakeを実行
+fn run_demo<D: PacketDevice + std::fmt::Debug>(mut conn: TcpConnection<D>) {
+    println!("Initial state: {:?}", conn.state());
 
     match conn.connect(5) {
         Ok(_) => {
             println!("✅ Successfully established TCP connection!");
-            println!("Final state: {:?}", conn.state);
+            println!("Final state: {:?}", conn.state());
             println!("Connection details:");
             println!("  Local seq: {}", conn.local_seq);
             println!("  Remote seq: {}", conn.remote_seq);
+            println!("  Send window: {}", conn.send_window);
+            println!(
+                "  Window scale: send={}, receive={}",
+                conn.snd_wscale, conn.rcv_wscale
+            );
+            // 1秒アイドルにしてから、キープアライブで相手が生きているか確かめる
+            let keepalive = KeepaliveConfig {
+                idle: Duration::from_secs(1),
+                interval: Duration::from_secs(1),
+                probes: 3,
+            };
+            if let Err(e) = conn.keepalive(&keepalive) {
+                println!("❌ Keepalive failed: {}", e);
+                println!("State transitions:");
+                conn.machine.print_state_diagram();
+                return;
+            }
+            if let Err(e) = conn.close(5) {
+                println!("❌ Close failed: {}", e);
+            }
         }
         Err(e) => {
             println!("❌ Connection failed: {}", e);
-            println!("Current state: {:?}", conn.state);
+            println!("Current state: {:?}", conn.state());
         }
     }
+    println!("State transitions:");
+    conn.machine.print_state_diagram();
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Step 3: 3-way Handshake Implementation");
+    println!("========================================");
+
+    // ログ初期化（必要に応じて）
+    // env_logger::init();
+
+    // デモ実行例
+    let args: Vec<String> = std::env::args().collect();
+
+    // TUNモード: cargo run --bin step03 -- tun <カーネル側IP> <自分のIP> <ポート>
+    // 例: tun 10.0.0.1 10.0.0.2 8080（別ターミナルで nc -l 8080）
+    // 待ち受けモード: cargo run --bin step03 -- listen <カーネル側IP> <自分のIP> <ポート>
+    // 例: listen 10.0.0.1 10.0.0.2 8080（別ターミナルで nc 10.0.0.2 8080）
+    #[cfg(target_os = "linux")]
+    if args.len() > 1 && args[1] == "tun" {
+        run_demo(tun_connection(&args)?);
+    } else if args.len() > 1 && args[1] == "listen" {
+        tun_listen_demo(&args)?;
+    } else {
+        run_demo(raw_socket_connection(&args)?);
+    }
+    #[cfg(not(target_os = "linux"))]
+    run_demo(raw_socket_connection(&args)?);
 
     println!("\n📝 Next steps:");
     println!("1. 各Task（A1-F4）を順番に実装してください");
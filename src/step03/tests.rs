@@ -1,6 +1,35 @@
 use super::*;
+use rust_tcp_handson_with_claude_code::step01::{parse_ip_header, LoopbackDevice};
 use std::time::{Duration, Instant};
 
+/// テスト用: 自分側のアドレス（ループバックデバイスなので経路は関係ない）
+const TEST_LOCAL_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// テスト用: メモリ上のループバックで接続を作り、相手ホスト側のデバイスも返す（root不要）
+fn loopback_connection(
+    remote_ip: Ipv4Addr,
+    remote_port: u16,
+) -> (TcpConnection<LoopbackDevice>, LoopbackDevice) {
+    let (local, peer) = LoopbackDevice::pair();
+    let conn = TcpConnection::with_device(local, TEST_LOCAL_IP, remote_ip, remote_port).unwrap();
+    (conn, peer)
+}
+
+/// テスト用: 相手ホストが受け取ったデータグラムからTCPヘッダーを取り出す
+fn parse_tcp(datagram: &[u8]) -> TcpHeader {
+    let ip_header = IpHeader::from_bytes(datagram).unwrap();
+    TcpHeader::from_bytes(&datagram[ip_header.header_length() as usize..]).unwrap()
+}
+
+/// テスト用: 相手ホストとしてTCPヘッダーをIPデータグラムに包む
+fn wrap_tcp(source: Ipv4Addr, dest: Ipv4Addr, mut header: TcpHeader) -> Vec<u8> {
+    header.calculate_checksum(u32::from(source), u32::from(dest), &[]);
+    let tcp_bytes = header.to_bytes();
+    let mut datagram = IpHeader::new(source, dest, tcp_bytes.len() as u16).to_wire_bytes(0);
+    datagram.extend_from_slice(&tcp_bytes);
+    datagram
+}
+
 // =============================================================================
 // Phase A: 3-way handshakeの理解と設計 - TDD Tests
 // =============================================================================
@@ -18,9 +47,9 @@ mod phase_a_tests {
         assert_ne!(state, TcpState::SynSent);
         assert_ne!(state, TcpState::Established);
 
-        // Clone, PartialEqが実装されているかテスト
-        let cloned_state = state.clone();
-        assert_eq!(state, cloned_state);
+        // Copy, PartialEqが実装されているかテスト（Step04の状態を使う）
+        let copied_state = state;
+        assert_eq!(state, copied_state);
     }
 
     // Task A4: TcpConnection基本構造のテスト
@@ -28,10 +57,10 @@ mod phase_a_tests {
     fn test_tcp_connection_creation() {
         // TcpConnection::new実装後にテストを有効化
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let conn = TcpConnection::new(remote_ip, 80).unwrap();
+        let (conn, _peer) = loopback_connection(remote_ip, 80);
 
         // 初期状態の確認
-        assert_eq!(conn.state, TcpState::Closed);
+        assert_eq!(conn.state(), TcpState::Closed);
         assert!(!conn.is_connected());
         assert_eq!(conn.remote_ip, remote_ip);
         assert_eq!(conn.remote_port, 80);
@@ -52,13 +81,12 @@ mod phase_b_tests {
         let remote_ip = Ipv4Addr::new(192, 168, 1, 100);
         let remote_port = 8080;
 
-        let conn = TcpConnection::new(remote_ip, remote_port).unwrap();
+        let (conn, _peer) = loopback_connection(remote_ip, remote_port);
 
         // 基本フィールドの確認
-        assert_eq!(conn.state, TcpState::Closed);
+        assert_eq!(conn.state(), TcpState::Closed);
         assert_eq!(conn.remote_ip, remote_ip);
         assert_eq!(conn.remote_port, remote_port);
-        assert!(conn.socket_fd > 0); // 有効なソケットファイルディスクリプタ
         assert!(conn.local_port > 0); // 動的に割り当てられたポート
     }
 
@@ -104,13 +132,14 @@ mod phase_b_tests {
 
     // Task B4: パケット送信インフラの基本テスト
     #[test]
+    #[ignore] // raw socketの作成にはroot権限が必要: sudo cargo test --bin step03 -- --ignored
     fn test_socket_creation() {
         // TcpConnection::newでraw socketが作成されることをテスト
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
         let conn = TcpConnection::new(remote_ip, 80).unwrap();
 
         // ソケットファイルディスクリプタが有効
-        assert!(conn.socket_fd > 0);
+        assert!(conn.device.fd() > 0);
     }
 }
 
@@ -127,12 +156,31 @@ mod phase_c_tests {
     fn test_syn_packet_creation() {
         // TcpConnection実装後に有効化
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let conn = TcpConnection::new(remote_ip, 80).unwrap();
+        let (conn, _peer) = loopback_connection(remote_ip, 80);
 
         let syn_packet = conn.create_syn_packet().unwrap();
 
         // TCPヘッダーのサイズチェック
-        assert_eq!(syn_packet.len(), 20);
+        // （固定部20バイト + MSS 4 + NOP 1 + Window Scale 3 + NOP 2 + Timestamps 10）
+        assert_eq!(syn_packet.len(), 40);
+        assert_eq!(syn_packet[12] >> 4, 10); // data offset = 40 / 4
+
+        // MSSオプション: Kind=2, Length=4, MSS=1460
+        // Window Scaleオプション: Kind=3, Length=3, 256KiBの受信バッファを広告できるシフト数3
+        // Timestampsオプション: Kind=8, Length=10
+        assert_eq!(syn_packet[20..28], [2, 4, 0x05, 0xB4, 1, 3, 3, 3]);
+        assert_eq!(syn_packet[28..32], [1, 1, 8, 10]);
+        let parsed = TcpHeader::from_bytes(&syn_packet).unwrap();
+        let options = parsed.options().unwrap();
+        assert_eq!(
+            options[..2],
+            [
+                TcpOption::MaximumSegmentSize(LOCAL_MSS),
+                TcpOption::WindowScale(3),
+            ]
+        );
+        // 相手のTSvalはまだ知らないのでTSecrは0
+        assert!(matches!(options[2], TcpOption::Timestamps { tsecr: 0, .. }));
 
         // TCPヘッダーのフィールド確認
 
@@ -167,19 +215,29 @@ mod phase_c_tests {
     #[test]
     fn test_send_syn() {
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let mut conn = TcpConnection::new(remote_ip, 80).unwrap();
+        let (mut conn, peer) = loopback_connection(remote_ip, 80);
 
         // 初期状態確認
-        assert_eq!(conn.state, TcpState::Closed);
+        assert_eq!(conn.state(), TcpState::Closed);
         let initial_seq = conn.local_seq;
 
         // SYN送信
         conn.send_syn().unwrap();
 
         // 状態変化確認
-        assert_eq!(conn.state, TcpState::SynSent);
+        assert_eq!(conn.state(), TcpState::SynSent);
         assert_ne!(conn.local_seq, initial_seq); // ISNが設定された
         assert_ne!(conn.local_seq, 0);
+
+        // 相手ホストにSYNが届いている
+        let datagram = peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        let ip_header = parse_ip_header(&datagram).unwrap();
+        assert_eq!(ip_header.source_ip(), TEST_LOCAL_IP);
+        assert_eq!(ip_header.dest_ip(), remote_ip);
+        let syn = parse_tcp(&datagram);
+        assert_eq!(syn.get_flags(), tcp_flags::SYN);
+        assert_eq!(syn.get_sequence_number(), conn.local_seq);
+        assert!(syn.verify_checksum(u32::from(TEST_LOCAL_IP), u32::from(remote_ip), &[]));
     }
 }
 
@@ -194,11 +252,11 @@ mod phase_d_tests {
     // Task D1: タイムアウト付き受信テスト
     #[test]
     fn test_receive_timeout() {
-        let remote_ip = Ipv4Addr::new(192, 168, 255, 254); // 到達不可能
-        let conn = TcpConnection::new(remote_ip, 12345).unwrap();
+        let remote_ip = Ipv4Addr::new(192, 168, 255, 254); // 応答しない相手
+        let (mut conn, _peer) = loopback_connection(remote_ip, 12345);
 
         let start = Instant::now();
-        let result = conn.receive_packet_timeout(1); // 1秒でタイムアウト
+        let result = conn.receive_packet_timeout(Duration::from_secs(1)); // 1秒でタイムアウト
         let elapsed = start.elapsed();
 
         // タイムアウトエラー
@@ -211,7 +269,7 @@ mod phase_d_tests {
     #[test]
     fn test_packet_parsing() {
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let conn = TcpConnection::new(remote_ip, 80).unwrap();
+        let (conn, _peer) = loopback_connection(remote_ip, 80);
 
         // Step2のTcpHeaderで正しいTCPヘッダーを作成
         let tcp_header = TcpHeader::new(
@@ -224,7 +282,10 @@ mod phase_d_tests {
         );
 
         // 完全なパケットを構築（IP + TCP）
-        let ip_header = IpHeader::new(remote_ip, conn.local_ip, TCP_HEADER_SIZE as u16);
+        let IpAddr::V4(local_ip) = conn.local_ip else {
+            panic!("IPv4 connection expected");
+        };
+        let ip_header = IpHeader::new(remote_ip, local_ip, TCP_HEADER_SIZE as u16);
         let mut packet = ip_header.to_bytes();
         packet.extend_from_slice(&tcp_header.to_bytes());
 
@@ -246,7 +307,7 @@ mod phase_d_tests {
     #[test]
     fn test_syn_ack_validation() {
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let mut conn = TcpConnection::new(remote_ip, 80).unwrap();
+        let (mut conn, _peer) = loopback_connection(remote_ip, 80);
         conn.local_seq = 1000; // テスト用に設定
 
         // 正しいSYN-ACKパケット用のTcpHeader
@@ -314,7 +375,7 @@ mod phase_e_tests {
     #[test]
     fn test_ack_packet_creation() {
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let mut conn = TcpConnection::new(remote_ip, 80).unwrap();
+        let (mut conn, _peer) = loopback_connection(remote_ip, 80);
         conn.local_seq = 1000;
 
         let ack_packet = conn.create_ack_packet(2000).unwrap();
@@ -354,11 +415,14 @@ mod phase_e_tests {
     fn test_send_ack() {
         // send_ack実装後に有効化
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let mut conn = TcpConnection::new(remote_ip, 80).unwrap();
+        let (mut conn, peer) = loopback_connection(remote_ip, 80);
         conn.local_seq = 1000;
 
         // ACK送信
         conn.send_ack(2001).unwrap();
+        let ack = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+        assert_eq!(ack.get_flags(), tcp_flags::ACK);
+        assert_eq!(ack.get_ack_number(), 2001);
 
         // リモートシーケンス番号が更新されている
         assert_eq!(conn.remote_seq, 2000); // ack_number - 1
@@ -369,7 +433,7 @@ mod phase_e_tests {
         );
 
         assert_eq!(
-            conn.state,
+            conn.state(),
             TcpState::Closed,
             "state should not change in send_ack"
         );
@@ -379,17 +443,17 @@ mod phase_e_tests {
     #[test]
     fn test_handshake_completion() {
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let mut conn = TcpConnection::new(remote_ip, 80).expect("Failed to create connection");
+        let (mut conn, _peer) = loopback_connection(remote_ip, 80);
 
         // SYN-SENT状態から開始（テスト用）
-        conn.state = TcpState::SynSent;
+        conn.machine.active_open().unwrap();
         conn.local_seq = 1000;
 
         // 接続完了
-        conn.complete_handshake();
+        conn.complete_handshake().unwrap();
 
         // 状態変化の確認
-        assert_eq!(conn.state, TcpState::Established);
+        assert_eq!(conn.state(), TcpState::Established);
         assert_eq!(conn.local_seq, 1001); // SYN消費で+1
         assert!(conn.is_connected());
     }
@@ -398,15 +462,15 @@ mod phase_e_tests {
     #[test]
     fn test_connection_status() {
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let mut conn = TcpConnection::new(remote_ip, 80).expect("Failed to create connection");
+        let (mut conn, _peer) = loopback_connection(remote_ip, 80);
 
         // 各状態での接続確認
         assert!(!conn.is_connected()); // CLOSED
 
-        conn.state = TcpState::SynSent;
+        conn.machine.active_open().unwrap();
         assert!(!conn.is_connected()); // SYN-SENT
 
-        conn.state = TcpState::Established;
+        conn.machine.complete_active_open().unwrap();
         assert!(conn.is_connected()); // ESTABLISHED
     }
 }
@@ -424,15 +488,47 @@ mod phase_f_tests {
     fn test_complete_handshake() {
         // connect実装後に有効化
         let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
-        let mut conn = TcpConnection::new(remote_ip, 40000).unwrap();
+        let (mut conn, peer) = loopback_connection(remote_ip, 40000);
+
+        // 相手ホスト役: SYNを受けたらSYN-ACKを返し、最後のACKを受け取る
+        let server = std::thread::spawn(move || {
+            let syn = parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap());
+            let mut syn_ack = TcpHeader::new(
+                40000,
+                syn.get_source_port(),
+                5000,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::SYN | tcp_flags::ACK,
+                29200,
+            );
+            // SYNのウィンドウは空の受信バッファ全体
+            assert_eq!(syn.get_window_size(), 65535);
+            syn_ack
+                .set_options(&[TcpOption::MaximumSegmentSize(1400)])
+                .unwrap();
+            peer.send(&wrap_tcp(remote_ip, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+            parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap())
+        });
 
         // 3-way handshake実行
         let result = conn.connect(5);
         assert!(result.is_ok(), "Connection should succeed: {:?}", result);
-        assert_eq!(conn.state, TcpState::Established);
+        assert_eq!(conn.state(), TcpState::Established);
         assert!(conn.is_connected());
         assert_ne!(conn.local_seq, 0);
         assert_ne!(conn.remote_seq, 0);
+        assert_eq!(conn.remote_mss, 1400);
+        assert_eq!(conn.send_window, 29200);
+        // 相手がWindow Scaleオプションを返さなかったのでスケールしない
+        assert_eq!((conn.snd_wscale, conn.rcv_wscale), (0, 0));
+
+        // 最後のACKは相手のISN + 1を確認応答している
+        let ack = server.join().unwrap();
+        assert_eq!(ack.get_flags(), tcp_flags::ACK);
+        assert_eq!(ack.get_ack_number(), 5001);
+        assert_eq!(ack.get_sequence_number(), conn.local_seq);
+        assert_eq!(ack.get_window_size(), conn.receive_window());
     }
 
     // Task F2: 実サーバーテスト
@@ -448,7 +544,7 @@ mod phase_f_tests {
         let elapsed = start.elapsed();
 
         assert!(result.is_ok(), "Connection should succeed: {:?}", result);
-        assert_eq!(conn.state, TcpState::Established);
+        assert_eq!(conn.state(), TcpState::Established);
     }
 
     // Task F3: Wiresharkキャプチャ用テスト
@@ -466,7 +562,7 @@ mod phase_f_tests {
 
         /*
         let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
-        let mut conn = TcpConnection::new(remote_ip, 8080).unwrap();
+        let (mut conn, _peer) = loopback_connection(remote_ip, 8080);
 
         println!("Starting 3-way handshake...");
         match conn.connect(10) {
@@ -481,9 +577,9 @@ mod phase_f_tests {
     // Task F4: エラーケーステスト
     #[test]
     fn test_connection_timeout() {
-        // 到達不可能なアドレスでタイムアウトテスト
+        // 応答しない相手でタイムアウトテスト
         let remote_ip = Ipv4Addr::new(192, 168, 255, 254);
-        let mut conn = TcpConnection::new(remote_ip, 12345).unwrap();
+        let (mut conn, peer) = loopback_connection(remote_ip, 12345);
 
         let start = Instant::now();
         let result = conn.connect(2); // 2秒でタイムアウト
@@ -495,22 +591,1369 @@ mod phase_f_tests {
 
         assert!(elapsed >= Duration::from_secs(2));
         assert!(elapsed < Duration::from_secs(4));
-        assert_ne!(conn.state, TcpState::Established);
+        assert_eq!(conn.state(), TcpState::Closed); // SYN-SENT + Timeout
+
+        // 0秒と1秒（初期RTO）にSYNを送り、次のRTO（2秒）の前に諦める
+        let mut syns = 0;
+        while let Some(datagram) = peer.recv_timeout(Duration::from_millis(10)).unwrap() {
+            assert_eq!(parse_tcp(&datagram).get_flags(), tcp_flags::SYN);
+            syns += 1;
+        }
+        assert_eq!(syns, 2);
     }
 
     #[test]
     fn test_connection_refused() {
         // 接続拒否テスト
         let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
-        let mut conn = TcpConnection::new(remote_ip, 65432).unwrap(); // 未使用ポート
+        let (mut conn, peer) = loopback_connection(remote_ip, 65432); // 未使用ポート
+
+        // 相手ホスト役: LISTENしていないポートへのSYNにはRST+ACKを返す
+        let server = std::thread::spawn(move || {
+            let syn = parse_tcp(&peer.recv_timeout(Duration::from_secs(2)).unwrap().unwrap());
+            let rst = TcpHeader::new(
+                65432,
+                syn.get_source_port(),
+                0,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::RST | tcp_flags::ACK,
+                0,
+            );
+            peer.send(&wrap_tcp(remote_ip, TEST_LOCAL_IP, rst)).unwrap();
+        });
 
         let result = conn.connect(2);
+        server.join().unwrap();
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("RST:true"), "unexpected error: {}", error);
+        assert_eq!(conn.state(), TcpState::Closed); // SYN-SENT + RST
+    }
+}
+
+// =============================================================================
+// TUNデバイス経由のhandshake - Tests
+// =============================================================================
+
+#[cfg(all(test, target_os = "linux"))]
+mod tun_tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener};
+
+    // TUNの先のアドレスはカーネルにとって別ホストなので、SYN-ACKにRSTが返らない
+    #[test]
+    #[ignore] // CAP_NET_ADMINが必要: sudo cargo test --bin step03 tun_tests -- --ignored
+    fn test_handshake_over_tun() {
+        let kernel_ip = Ipv4Addr::new(10, 78, 0, 1);
+        let local_ip = Ipv4Addr::new(10, 78, 0, 2);
+
+        let tun = TunDevice::open("step03test").unwrap();
+        tun.configure_ipv4(kernel_ip, 24).unwrap();
+        let listener = TcpListener::bind((kernel_ip, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut conn = TcpConnection::new_tun(tun, local_ip, kernel_ip, port).unwrap();
+        let result = conn.connect(5);
+        assert!(result.is_ok(), "Connection should succeed: {:?}", result);
+        assert_eq!(conn.state(), TcpState::Established);
+        assert_eq!(conn.remote_mss, 1460); // カーネルは自分のMTUに合わせたMSSを返す
+
+        // カーネル側でもコネクションが確立している
+        let (_stream, peer) = listener.accept().unwrap();
+        assert_eq!(peer, SocketAddr::from((local_ip, conn.local_port)));
+    }
+}
+
+// =============================================================================
+// Step04の状態マシンとの連携 - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod state_machine_tests {
+    use super::*;
+
+    fn header_with(flags: u8) -> TcpHeader {
+        TcpHeader::new(80, 40000, 1, 1, flags, 8192)
+    }
+
+    #[test]
+    fn test_segment_event_from_flags() {
+        use tcp_flags::*;
+        let cases = [
+            (SYN, Some(TcpEvent::ReceiveSyn)),
+            (SYN | ACK, Some(TcpEvent::ReceiveSynAck)),
+            (ACK, Some(TcpEvent::ReceiveAck)),
+            (FIN | ACK, Some(TcpEvent::ReceiveFin)),
+            (RST | ACK, Some(TcpEvent::ReceiveRst)),
+            (RST | SYN | ACK, Some(TcpEvent::ReceiveRst)), // RSTが最優先
+            (PSH, None),
+        ];
+        for (flags, expected) in cases {
+            assert_eq!(
+                segment_event(&header_with(flags)),
+                expected,
+                "flags={:#x}",
+                flags
+            );
+        }
+    }
+
+    #[test]
+    fn test_active_open_history() {
+        let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, 80);
+
+        let server = std::thread::spawn(move || {
+            let syn = parse_tcp(&peer.recv_timeout(Duration::from_secs(2)).unwrap().unwrap());
+            let syn_ack = TcpHeader::new(
+                80,
+                syn.get_source_port(),
+                9000,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::SYN | tcp_flags::ACK,
+                8192,
+            );
+            peer.send(&wrap_tcp(remote_ip, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+            peer // 最後のACKを受け取れるように相手側を残す
+        });
+        conn.connect(2).unwrap();
+        let _peer = server.join().unwrap();
+
+        assert_eq!(
+            conn.state_history(),
+            [
+                (TcpState::Closed, TcpState::SynSent, TcpEvent::Connect),
+                (
+                    TcpState::SynSent,
+                    TcpState::Established,
+                    TcpEvent::ReceiveSynAck
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_handshake_with_isns_at_sequence_wrap() {
+        let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, 80);
+
+        // 両方のISNがu32::MAX: SYNの次のシーケンス番号は0に戻る
+        let server = std::thread::spawn(move || {
+            let syn = parse_tcp(&peer.recv_timeout(Duration::from_secs(2)).unwrap().unwrap());
+            assert_eq!(syn.get_sequence_number(), u32::MAX);
+            let syn_ack = TcpHeader::new(
+                80,
+                syn.get_source_port(),
+                u32::MAX,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::SYN | tcp_flags::ACK,
+                8192,
+            );
+            peer.send(&wrap_tcp(remote_ip, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+            parse_tcp(&peer.recv_timeout(Duration::from_secs(2)).unwrap().unwrap())
+        });
+        conn.send_syn_with_isn(u32::MAX).unwrap();
+        conn.establish(2).unwrap();
+        let ack = server.join().unwrap();
+
+        assert_eq!(conn.state(), TcpState::Established);
+        assert_eq!(conn.local_seq, 0);
+        assert_eq!(conn.remote_seq, u32::MAX);
+        assert_eq!(conn.recv_buffer.next_expected().value(), 0);
+        assert_eq!(ack.get_flags(), tcp_flags::ACK);
+        assert_eq!(ack.get_sequence_number(), 0);
+        assert_eq!(ack.get_ack_number(), 0);
+    }
+
+    #[test]
+    fn test_second_connect_is_rejected() {
+        let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, 80);
+        conn.send_syn().unwrap();
+        let isn = conn.local_seq;
+
+        // SYN-SENT + Connectは状態マシンにない遷移: SYNは送らずISNも変えない
+        let error = conn.connect(1).unwrap_err();
+        assert!(
+            error.to_string().contains("Invalid transition"),
+            "{}",
+            error
+        );
+        assert_eq!(conn.state(), TcpState::SynSent);
+        assert_eq!(conn.local_seq, isn);
+
+        assert!(peer
+            .recv_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_some());
+        assert!(peer
+            .recv_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_complete_handshake_requires_syn_sent() {
+        let remote_ip = Ipv4Addr::new(127, 0, 0, 1);
+        let (mut conn, _peer) = loopback_connection(remote_ip, 80);
+        conn.local_seq = 1000;
+
+        // SYNを送っていないのにSYN-ACKで確立はできない
+        assert!(conn.complete_handshake().is_err());
+        assert_eq!(conn.state(), TcpState::Closed);
+        assert_eq!(conn.local_seq, 1000);
+    }
+
+    #[test]
+    fn test_unexpected_reply_aborts_connect() {
+        let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, 80);
+
+        // SYN-ACKのACK番号が間違っている: 接続試行を中止してCLOSEDに戻る
+        let server = std::thread::spawn(move || {
+            let syn = parse_tcp(&peer.recv_timeout(Duration::from_secs(2)).unwrap().unwrap());
+            let syn_ack = TcpHeader::new(
+                80,
+                syn.get_source_port(),
+                9000,
+                syn.get_sequence_number().wrapping_add(100),
+                tcp_flags::SYN | tcp_flags::ACK,
+                8192,
+            );
+            peer.send(&wrap_tcp(remote_ip, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+        });
+        assert!(conn.connect(2).is_err());
+        server.join().unwrap();
+
+        assert_eq!(conn.state(), TcpState::Closed);
+        assert_eq!(conn.state_history().last().unwrap().2, TcpEvent::Close);
+    }
+}
+
+// =============================================================================
+// SYNの再送（RFC 6298） - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod syn_retransmission_tests {
+    use super::*;
+
+    const SERVER_ISN: u32 = 9000;
+
+    /// 相手ホスト役: 最初の`ignore`個のSYNを無視してからSYN-ACKを返す。
+    /// 受け取ったSYN（シーケンス番号, 受信時刻）と、最後のACKを受け取った相手側を返す
+    fn lazy_server(
+        peer: LoopbackDevice,
+        remote_ip: Ipv4Addr,
+        ignore: usize,
+    ) -> std::thread::JoinHandle<(Vec<(u32, Instant)>, LoopbackDevice)> {
+        std::thread::spawn(move || {
+            let mut syns = Vec::new();
+            let syn = loop {
+                let datagram = peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+                let syn = parse_tcp(&datagram);
+                syns.push((syn.get_sequence_number(), Instant::now()));
+                if syns.len() > ignore {
+                    break syn;
+                }
+            };
+            let syn_ack = TcpHeader::new(
+                syn.get_destination_port(),
+                syn.get_source_port(),
+                SERVER_ISN,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::SYN | tcp_flags::ACK,
+                8192,
+            );
+            peer.send(&wrap_tcp(remote_ip, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+            let ack = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+            assert_eq!(ack.get_flags(), tcp_flags::ACK);
+            (syns, peer)
+        })
+    }
+
+    #[test]
+    fn test_rtt_is_measured_from_syn_ack() {
+        let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, 80);
+        let server = lazy_server(peer, remote_ip, 0);
+
+        conn.connect(5).unwrap();
+        let (syns, _peer) = server.join().unwrap();
+
+        assert_eq!(syns.len(), 1);
+        let srtt = conn.rto.srtt().expect("RTT should be sampled");
+        assert!(srtt < Duration::from_secs(1));
+        assert_eq!(conn.rto.backoffs(), 0);
+    }
+
+    #[test]
+    fn test_lost_syn_is_retransmitted() {
+        let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, 80);
+        let server = lazy_server(peer, remote_ip, 1);
+
+        conn.connect(5).unwrap();
+        let (syns, _peer) = server.join().unwrap();
+
+        assert_eq!(conn.state(), TcpState::Established);
+        // 同じISNのSYNを初期RTO（1秒）後に再送している
+        assert_eq!(syns.len(), 2);
+        assert_eq!(syns[0].0, syns[1].0);
+        assert_eq!(syns[0].0.wrapping_add(1), conn.local_seq);
+        assert!(syns[1].1 - syns[0].1 >= Duration::from_millis(900));
+
+        // Karn: 再送したSYNへの応答ではRTTを測らず、バックオフしたRTOを保つ
+        assert_eq!(conn.rto.srtt(), None);
+        assert_eq!(conn.rto.rto(), Duration::from_secs(2));
+        // 再送は状態を変えない
+        assert_eq!(conn.state_history().len(), 2);
+    }
+}
+
+// =============================================================================
+// Passive open (TcpListener) - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod listener_tests {
+    use super::*;
+
+    const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 9);
+    const CLIENT_PORT: u16 = 40000;
+    const CLIENT_ISN: u32 = 7000;
+
+    fn loopback_listener(port: u16) -> (TcpListener<LoopbackDevice>, LoopbackDevice) {
+        let (local, peer) = LoopbackDevice::pair();
+        let listener = TcpListener::with_device(local, TEST_LOCAL_IP, port).unwrap();
+        (listener, peer)
+    }
+
+    fn client_syn(dest_port: u16) -> Vec<u8> {
+        let mut syn = TcpHeader::new(CLIENT_PORT, dest_port, CLIENT_ISN, 0, tcp_flags::SYN, 8192);
+        syn.set_options(&[TcpOption::MaximumSegmentSize(1200)])
+            .unwrap();
+        wrap_tcp(CLIENT_IP, TEST_LOCAL_IP, syn)
+    }
+
+    fn client_ack(dest_port: u16, seq: u32, ack: u32) -> Vec<u8> {
+        let header = TcpHeader::new(CLIENT_PORT, dest_port, seq, ack, tcp_flags::ACK, 8192);
+        wrap_tcp(CLIENT_IP, TEST_LOCAL_IP, header)
+    }
+
+    /// SYNを送ってSYN-ACKを受け取る（accept()はまだ確立しないのでタイムアウトする）
+    fn exchange_syn(
+        listener: &mut TcpListener<LoopbackDevice>,
+        peer: &LoopbackDevice,
+    ) -> (TcpHeader, Vec<u8>) {
+        peer.send(&client_syn(listener.local_port())).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        let datagram = peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        (parse_tcp(&datagram), datagram)
+    }
+
+    #[test]
+    fn test_passive_handshake() {
+        let (mut listener, peer) = loopback_listener(8080);
+        let (syn_ack, datagram) = exchange_syn(&mut listener, &peer);
+
+        assert_eq!(syn_ack.get_flags(), tcp_flags::SYN | tcp_flags::ACK);
+        assert_eq!(syn_ack.get_source_port(), 8080);
+        assert_eq!(syn_ack.get_destination_port(), CLIENT_PORT);
+        assert_eq!(syn_ack.get_ack_number(), CLIENT_ISN + 1);
+        assert_eq!(mss_option(&syn_ack), Some(LOCAL_MSS));
+        assert_eq!(syn_ack.get_window_size(), 65535);
+        let offset = parse_ip_header(&datagram).unwrap().header_length() as usize;
+        assert!(syn_ack.verify_checksum(
+            u32::from(TEST_LOCAL_IP),
+            u32::from(CLIENT_IP),
+            &datagram[offset + syn_ack.header_len()..]
+        ));
+        assert_eq!(listener.pending(), 1);
+
+        let server_isn = syn_ack.get_sequence_number();
+        peer.send(&client_ack(
+            8080,
+            CLIENT_ISN + 1,
+            server_isn.wrapping_add(1),
+        ))
+        .unwrap();
+        let conn = listener.accept(Duration::from_secs(1)).unwrap();
+
+        assert_eq!(conn.state(), TcpState::Established);
+        assert_eq!(conn.local_port, 8080);
+        assert_eq!(conn.remote_port, CLIENT_PORT);
+        assert_eq!(conn.remote_ip, IpAddr::V4(CLIENT_IP));
+        assert_eq!(conn.remote_mss, 1200);
+        assert_eq!(conn.send_window, 8192);
+        assert_eq!(
+            conn.recv_buffer.next_expected().value(),
+            CLIENT_ISN.wrapping_add(1)
+        );
+        assert_eq!(conn.local_seq, server_isn.wrapping_add(1));
+        assert_eq!(listener.pending(), 0);
+        assert!(conn.rto.srtt().is_some()); // SYN-ACKから最後のACKまでのRTT
+
+        let events: Vec<TcpEvent> = conn.state_history().iter().map(|t| t.2).collect();
+        assert_eq!(
+            events,
+            [TcpEvent::Listen, TcpEvent::ReceiveSyn, TcpEvent::ReceiveAck]
+        );
+    }
+
+    #[test]
+    fn test_unacceptable_ack_is_ignored() {
+        let (mut listener, peer) = loopback_listener(8080);
+        let (syn_ack, _) = exchange_syn(&mut listener, &peer);
+        let server_isn = syn_ack.get_sequence_number();
+
+        // 自分のSYNを確認応答していないACKでは確立しない
+        peer.send(&client_ack(
+            8080,
+            CLIENT_ISN + 1,
+            server_isn.wrapping_add(2),
+        ))
+        .unwrap();
+        let error = listener.accept(Duration::from_millis(50)).unwrap_err();
+        assert!(error.to_string().starts_with("Timeout"), "{}", error);
+        assert_eq!(listener.pending(), 1);
+
+        peer.send(&client_ack(
+            8080,
+            CLIENT_ISN + 1,
+            server_isn.wrapping_add(1),
+        ))
+        .unwrap();
+        assert!(listener.accept(Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_syn_to_other_port_is_reset() {
+        let (mut listener, peer) = loopback_listener(8080);
+        peer.send(&client_syn(9090)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        assert_eq!(listener.pending(), 0);
+
+        // 待ち受けていないポート: <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+        let rst = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+        assert_eq!(rst.get_flags(), tcp_flags::RST | tcp_flags::ACK);
+        assert_eq!(rst.get_source_port(), 9090);
+        assert_eq!(rst.get_destination_port(), CLIENT_PORT);
+        assert_eq!(rst.get_sequence_number(), 0);
+        assert_eq!(rst.get_ack_number(), CLIENT_ISN + 1);
+    }
+
+    #[test]
+    fn test_stray_ack_is_reset() {
+        let (mut listener, peer) = loopback_listener(8080);
+        // 半開き接続のない相手からのACK（LISTEN + ACK）: <SEQ=SEG.ACK><CTL=RST>
+        peer.send(&client_ack(8080, CLIENT_ISN + 1, 12345)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+
+        let rst = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+        assert_eq!(rst.get_flags(), tcp_flags::RST);
+        assert_eq!(rst.get_source_port(), 8080);
+        assert_eq!(rst.get_destination_port(), CLIENT_PORT);
+        assert_eq!(rst.get_sequence_number(), 12345);
+        assert_eq!(listener.pending(), 0);
+
+        // ACKのないFINは黙って捨てる
+        let fin = TcpHeader::new(CLIENT_PORT, 8080, CLIENT_ISN, 0, tcp_flags::FIN, 8192);
+        peer.send(&wrap_tcp(CLIENT_IP, TEST_LOCAL_IP, fin)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        assert!(peer
+            .recv_timeout(Duration::from_millis(50))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_stray_syn_ack_is_reset() {
+        let (mut listener, peer) = loopback_listener(8080);
+        let syn_ack = TcpHeader::new(
+            CLIENT_PORT,
+            8080,
+            CLIENT_ISN,
+            777,
+            tcp_flags::SYN | tcp_flags::ACK,
+            8192,
+        );
+        peer.send(&wrap_tcp(CLIENT_IP, TEST_LOCAL_IP, syn_ack))
+            .unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+
+        // LISTENへのSYN-ACKでは半開き接続を作らず、RSTを返す
+        let rst = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+        assert_eq!(rst.get_flags(), tcp_flags::RST);
+        assert_eq!(rst.get_sequence_number(), 777);
+        assert_eq!(listener.pending(), 0);
+    }
+
+    #[test]
+    fn test_duplicate_syn_resends_same_syn_ack() {
+        let (mut listener, peer) = loopback_listener(8080);
+        let (first, _) = exchange_syn(&mut listener, &peer);
+        let (second, _) = exchange_syn(&mut listener, &peer);
+
+        assert_eq!(second.get_sequence_number(), first.get_sequence_number());
+        assert_eq!(listener.pending(), 1);
+    }
+
+    #[test]
+    fn test_segments_are_demultiplexed_by_peer() {
+        let (mut listener, peer) = loopback_listener(8080);
+        let segment = |port: u16, seq: u32, ack: u32, flags: u8| {
+            let header = TcpHeader::new(port, 8080, seq, ack, flags, 8192);
+            wrap_tcp(CLIENT_IP, TEST_LOCAL_IP, header)
+        };
+        let source_port = |datagram: &[u8]| parse_tcp(datagram).get_source_port();
+        let dest_port = |datagram: &[u8]| parse_tcp(datagram).get_destination_port();
+
+        // 2つのクライアントが同時にhandshakeを始める
+        peer.send(&segment(40000, 1000, 0, tcp_flags::SYN)).unwrap();
+        peer.send(&segment(40001, 2000, 0, tcp_flags::SYN)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        let mut server_isn = std::collections::HashMap::new();
+        for _ in 0..2 {
+            let syn_ack = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+            server_isn.insert(
+                syn_ack.get_destination_port(),
+                syn_ack.get_sequence_number(),
+            );
+        }
+        let ack = |port: u16, seq: u32| {
+            segment(port, seq, server_isn[&port].wrapping_add(1), tcp_flags::ACK)
+        };
+
+        peer.send(&ack(40000, 1001)).unwrap();
+        let first = listener.accept(Duration::from_secs(1)).unwrap();
+        assert_eq!(first.remote_port, 40000);
+
+        // 受け付けた接続へのセグメントが先に届いても、リスナーは捨てずに次の接続を受け付ける
+        peer.send(&ack(40000, 1001)).unwrap();
+        peer.send(&ack(40001, 2001)).unwrap();
+        let second = listener.accept(Duration::from_secs(1)).unwrap();
+        assert_eq!(second.remote_port, 40001);
+        let queued = first.device.recv_timeout(Duration::ZERO).unwrap().unwrap();
+        assert_eq!(source_port(&queued), 40000);
+
+        // 接続が受信しても、他の接続やリスナー宛てのものはそれぞれのキューに残る
+        peer.send(&segment(40002, 3000, 0, tcp_flags::SYN)).unwrap();
+        peer.send(&ack(40001, 2001)).unwrap();
+        assert!(first
+            .device
+            .recv_timeout(Duration::from_millis(50))
+            .unwrap()
+            .is_none());
+        let queued = second.device.recv_timeout(Duration::ZERO).unwrap().unwrap();
+        assert_eq!(source_port(&queued), 40001);
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        let syn_ack = peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(dest_port(&syn_ack), 40002);
+        assert_eq!(listener.pending(), 1);
+    }
+
+    #[test]
+    fn test_syn_ack_retransmission() {
+        let (mut listener, peer) = loopback_listener(8080);
+        let (first, _) = exchange_syn(&mut listener, &peer);
+
+        // 最後のACKが来ないので、初期RTO(1秒)後にSYN-ACKを再送する
+        let start = Instant::now();
+        assert!(listener.accept(Duration::from_millis(1300)).is_err());
+        let datagram = peer
+            .recv_timeout(Duration::from_millis(10))
+            .unwrap()
+            .unwrap();
+        let resent = parse_tcp(&datagram);
+
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        assert_eq!(resent.get_flags(), tcp_flags::SYN | tcp_flags::ACK);
+        assert_eq!(resent.get_sequence_number(), first.get_sequence_number());
+    }
+
+    #[test]
+    fn test_rst_drops_half_open_connection() {
+        let (mut listener, peer) = loopback_listener(8080);
+        let (syn_ack, _) = exchange_syn(&mut listener, &peer);
+
+        let rst = TcpHeader::new(
+            CLIENT_PORT,
+            8080,
+            CLIENT_ISN + 1,
+            syn_ack.get_sequence_number().wrapping_add(1),
+            tcp_flags::RST,
+            0,
+        );
+        peer.send(&wrap_tcp(CLIENT_IP, TEST_LOCAL_IP, rst)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        assert_eq!(listener.pending(), 0);
+    }
+
+    #[test]
+    fn test_backlog_limit() {
+        let (mut listener, peer) = loopback_listener(8080);
+        listener.set_backlog(1);
+        exchange_syn(&mut listener, &peer);
+
+        let mut syn = TcpHeader::new(CLIENT_PORT + 1, 8080, CLIENT_ISN, 0, tcp_flags::SYN, 8192);
+        syn.set_options(&[TcpOption::MaximumSegmentSize(1200)])
+            .unwrap();
+        peer.send(&wrap_tcp(CLIENT_IP, TEST_LOCAL_IP, syn)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+
+        assert_eq!(listener.pending(), 1);
+        assert!(peer
+            .recv_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+    }
+}
+
+// =============================================================================
+// ウィンドウスケール（RFC 7323）のテスト
+// =============================================================================
+
+#[cfg(test)]
+mod window_scale_tests {
+    use super::*;
+
+    const SERVER_PORT: u16 = 40000;
+    const SERVER_ISN: u32 = 5000;
+
+    /// 相手ホスト役: SYN-ACKに`options`を付けて返し、最後のACKを受け取る
+    fn active_open(options: Vec<TcpOption>) -> (TcpConnection<LoopbackDevice>, TcpHeader) {
+        let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, SERVER_PORT);
+        let server = std::thread::spawn(move || {
+            let syn = parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap());
+            let mut syn_ack = TcpHeader::new(
+                SERVER_PORT,
+                syn.get_source_port(),
+                SERVER_ISN,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::SYN | tcp_flags::ACK,
+                65535,
+            );
+            syn_ack.set_options(&options).unwrap();
+            peer.send(&wrap_tcp(remote_ip, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+            parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap())
+        });
+        conn.connect(5).unwrap();
+        (conn, server.join().unwrap())
+    }
+
+    #[test]
+    fn test_active_open_negotiates_scale() {
+        let (conn, ack) = active_open(vec![
+            TcpOption::MaximumSegmentSize(1460),
+            TcpOption::NoOperation,
+            TcpOption::WindowScale(7),
+        ]);
+
+        assert_eq!((conn.snd_wscale, conn.rcv_wscale), (7, 3));
+        // SYN-ACKのウィンドウはスケールしない
+        assert_eq!(conn.send_window, 65535);
+        // 最後のACKからはスケールしたウィンドウを広告する: 256KiB >> 3
+        assert_eq!(ack.get_window_size(), 32768);
+    }
+
+    #[test]
+    fn test_oversized_shift_is_clamped() {
+        let (conn, _) = active_open(vec![TcpOption::WindowScale(15)]);
+        assert_eq!(conn.snd_wscale, MAX_WINDOW_SCALE);
+    }
+
+    #[test]
+    fn test_unscaled_ack_is_capped() {
+        let (conn, ack) = active_open(vec![TcpOption::MaximumSegmentSize(1460)]);
+        assert_eq!((conn.snd_wscale, conn.rcv_wscale), (0, 0));
+        // 64KiBを超える受信バッファも65535までしか広告できない
+        assert_eq!(ack.get_window_size(), 65535);
+    }
+
+    #[test]
+    fn test_passive_open_echoes_scale() {
+        let (local, peer) = LoopbackDevice::pair();
+        let mut listener = TcpListener::with_device(local, TEST_LOCAL_IP, 8080).unwrap();
+        let client_ip = Ipv4Addr::new(10, 0, 0, 9);
+
+        let mut syn = TcpHeader::new(40000, 8080, 7000, 0, tcp_flags::SYN, 65535);
+        syn.set_options(&[
+            TcpOption::MaximumSegmentSize(1460),
+            TcpOption::NoOperation,
+            TcpOption::WindowScale(2),
+        ])
+        .unwrap();
+        peer.send(&wrap_tcp(client_ip, TEST_LOCAL_IP, syn)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        let syn_ack = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+        assert_eq!(window_scale_option(&syn_ack), Some(3));
+        assert_eq!(syn_ack.get_window_size(), 65535);
+
+        let ack = TcpHeader::new(
+            40000,
+            8080,
+            7001,
+            syn_ack.get_sequence_number().wrapping_add(1),
+            tcp_flags::ACK,
+            1000,
+        );
+        peer.send(&wrap_tcp(client_ip, TEST_LOCAL_IP, ack)).unwrap();
+        let conn = listener.accept(Duration::from_secs(1)).unwrap();
+        assert_eq!((conn.snd_wscale, conn.rcv_wscale), (2, 3));
+        assert_eq!(conn.receive_window(), 32768);
+    }
+
+    #[test]
+    fn test_passive_open_without_scale() {
+        let (local, peer) = LoopbackDevice::pair();
+        let mut listener = TcpListener::with_device(local, TEST_LOCAL_IP, 8080).unwrap();
+        let syn = TcpHeader::new(40000, 8080, 7000, 0, tcp_flags::SYN, 65535);
+        peer.send(&wrap_tcp(Ipv4Addr::new(10, 0, 0, 9), TEST_LOCAL_IP, syn))
+            .unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+
+        // SYNに含まれていなければSYN-ACKにも付けない
+        let syn_ack = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+        assert_eq!(window_scale_option(&syn_ack), None);
+    }
+}
+
+// =============================================================================
+// Timestamps（RFC 7323）のテスト
+// =============================================================================
+
+#[cfg(test)]
+mod timestamps_tests {
+    use super::*;
+
+    #[test]
+    fn test_active_open_echoes_syn_ack_tsval() {
+        let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, 40000);
+        let server = std::thread::spawn(move || {
+            let syn = parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap());
+            let (syn_tsval, _) = timestamps_option(&syn).unwrap();
+            let mut syn_ack = TcpHeader::new(
+                40000,
+                syn.get_source_port(),
+                5000,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::SYN | tcp_flags::ACK,
+                65535,
+            );
+            syn_ack
+                .set_options(&[TcpOption::Timestamps {
+                    tsval: 123456,
+                    tsecr: syn_tsval,
+                }])
+                .unwrap();
+            peer.send(&wrap_tcp(remote_ip, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+            let ack = parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap());
+            (syn_tsval, ack)
+        });
+        conn.connect(5).unwrap();
+        let (syn_tsval, ack) = server.join().unwrap();
+
+        assert_eq!(conn.ts_recent, Some(123456));
+        let (tsval, tsecr) = timestamps_option(&ack).unwrap();
+        assert_eq!(tsecr, 123456);
+        // 同じ時計なので、SYNより前の値にはならない
+        assert!(tsval.wrapping_sub(syn_tsval) < 5000);
+    }
+
+    #[test]
+    fn test_active_open_without_timestamps() {
+        let remote_ip = Ipv4Addr::new(10, 0, 1, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, 40000);
+        let server = std::thread::spawn(move || {
+            let syn = parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap());
+            let syn_ack = TcpHeader::new(
+                40000,
+                syn.get_source_port(),
+                5000,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::SYN | tcp_flags::ACK,
+                65535,
+            );
+            peer.send(&wrap_tcp(remote_ip, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+            parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap())
+        });
+        conn.connect(5).unwrap();
+
+        assert_eq!(conn.ts_recent, None);
+        assert_eq!(timestamps_option(&server.join().unwrap()), None);
+    }
+
+    #[test]
+    fn test_passive_open_echoes_syn_tsval() {
+        let (local, peer) = LoopbackDevice::pair();
+        let mut listener = TcpListener::with_device(local, TEST_LOCAL_IP, 8080).unwrap();
+        let client_ip = Ipv4Addr::new(10, 0, 0, 9);
+
+        let mut syn = TcpHeader::new(40000, 8080, 7000, 0, tcp_flags::SYN, 65535);
+        syn.set_options(&[TcpOption::Timestamps {
+            tsval: 1000,
+            tsecr: 0,
+        }])
+        .unwrap();
+        peer.send(&wrap_tcp(client_ip, TEST_LOCAL_IP, syn)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        let syn_ack = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+        let (server_tsval, tsecr) = timestamps_option(&syn_ack).unwrap();
+        assert_eq!(tsecr, 1000);
+
+        let mut ack = TcpHeader::new(
+            40000,
+            8080,
+            7001,
+            syn_ack.get_sequence_number().wrapping_add(1),
+            tcp_flags::ACK,
+            1000,
+        );
+        ack.set_options(&[TcpOption::Timestamps {
+            tsval: 1010,
+            tsecr: server_tsval,
+        }])
+        .unwrap();
+        peer.send(&wrap_tcp(client_ip, TEST_LOCAL_IP, ack)).unwrap();
+        let conn = listener.accept(Duration::from_secs(1)).unwrap();
+        assert_eq!(conn.ts_recent, Some(1010));
+        assert_eq!(
+            conn.ts_offset,
+            timestamp_offset_for(TEST_LOCAL_IP.into(), 8080, client_ip.into(), 40000)
+        );
+    }
+
+    #[test]
+    fn test_timestamp_offset_is_independent_of_isn() {
+        let local = IpAddr::from(TEST_LOCAL_IP);
+        let remote = IpAddr::from(Ipv4Addr::new(10, 0, 1, 1));
+
+        // 4タプルごとに決まった値
+        let offset = timestamp_offset_for(local, 40000, remote, 80);
+        assert_eq!(offset, timestamp_offset_for(local, 40000, remote, 80));
+        // ISNのハッシュ（F）とは別の値なので、TSvalからISNは分からない
+        assert_ne!(offset, keyed_hash("isn", local, 40000, remote, 80));
+    }
+}
+
+// =============================================================================
+// FINによる接続の終了のテスト
+// =============================================================================
+
+#[cfg(test)]
+mod close_tests {
+    use super::*;
+
+    const SERVER_PORT: u16 = 40000;
+    const SERVER_ISN: u32 = 5000;
+    const REMOTE_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+
+    /// 3-way handshakeを済ませた接続と、相手ホスト側のデバイス
+    fn established() -> (TcpConnection<LoopbackDevice>, LoopbackDevice) {
+        let (mut conn, peer) = loopback_connection(REMOTE_IP, SERVER_PORT);
+        let server = std::thread::spawn(move || {
+            let syn = parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap());
+            let syn_ack = TcpHeader::new(
+                SERVER_PORT,
+                syn.get_source_port(),
+                SERVER_ISN,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::SYN | tcp_flags::ACK,
+                65535,
+            );
+            peer.send(&wrap_tcp(REMOTE_IP, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+            peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+            peer
+        });
+        conn.connect(5).unwrap();
+        (conn, server.join().unwrap())
+    }
+
+    /// 相手ホスト役としてセグメントを送る
+    fn reply(peer: &LoopbackDevice, dest_port: u16, seq: u32, ack: u32, flags: u8) {
+        let header = TcpHeader::new(SERVER_PORT, dest_port, seq, ack, flags, 65535);
+        peer.send(&wrap_tcp(REMOTE_IP, TEST_LOCAL_IP, header))
+            .unwrap();
+    }
+
+    fn receive(peer: &LoopbackDevice) -> TcpHeader {
+        parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap())
+    }
+
+    fn visited(conn: &TcpConnection<LoopbackDevice>) -> Vec<TcpState> {
+        conn.state_history().iter().map(|&(_, to, _)| to).collect()
+    }
+
+    #[test]
+    fn test_active_close() {
+        let (mut conn, peer) = established();
+        let local_seq = conn.local_seq;
+        let server = std::thread::spawn(move || {
+            let fin = receive(&peer);
+            assert_eq!(fin.get_flags(), tcp_flags::FIN | tcp_flags::ACK);
+            let port = fin.get_source_port();
+            // FINもシーケンス番号を1つ消費する
+            reply(
+                &peer,
+                port,
+                SERVER_ISN + 1,
+                fin.get_sequence_number() + 1,
+                tcp_flags::ACK,
+            );
+            reply(
+                &peer,
+                port,
+                SERVER_ISN + 1,
+                fin.get_sequence_number() + 1,
+                tcp_flags::FIN | tcp_flags::ACK,
+            );
+            (fin, receive(&peer))
+        });
+
+        conn.close(5).unwrap();
+        let (fin, ack) = server.join().unwrap();
+        assert_eq!(fin.get_sequence_number(), local_seq);
+        assert_eq!(fin.get_ack_number(), SERVER_ISN + 1);
+        assert_eq!(ack.get_flags(), tcp_flags::ACK);
+        assert_eq!(ack.get_sequence_number(), local_seq + 1);
+        assert_eq!(ack.get_ack_number(), SERVER_ISN + 2);
+
+        assert_eq!(conn.state(), TcpState::TimeWait);
+        assert!(conn.recv_buffer.is_eof());
+        // 2·MSLの間は同じ4タプルを使えない
+        let entry = *time_wait_table()
+            .get(&conn.quadruple(), Instant::now())
+            .unwrap();
+        assert_eq!(entry.snd_nxt, SequenceNumber::new(local_seq + 1));
+        assert_eq!(entry.rcv_nxt, SequenceNumber::new(SERVER_ISN + 2));
+        assert!(visited(&conn).ends_with(&[
+            TcpState::FinWait1,
+            TcpState::FinWait2,
+            TcpState::TimeWait
+        ]));
+    }
+
+    #[test]
+    fn test_simultaneous_close() {
+        let (mut conn, peer) = established();
+        let server = std::thread::spawn(move || {
+            let fin = receive(&peer);
+            let port = fin.get_source_port();
+            // 相手もFINを送っていて、まだこちらのFINを確認していない
+            reply(
+                &peer,
+                port,
+                SERVER_ISN + 1,
+                fin.get_sequence_number(),
+                tcp_flags::FIN | tcp_flags::ACK,
+            );
+            let ack = receive(&peer);
+            reply(
+                &peer,
+                port,
+                SERVER_ISN + 2,
+                fin.get_sequence_number() + 1,
+                tcp_flags::ACK,
+            );
+            ack
+        });
+
+        conn.close(5).unwrap();
+        assert_eq!(server.join().unwrap().get_ack_number(), SERVER_ISN + 2);
+        assert!(visited(&conn).ends_with(&[
+            TcpState::FinWait1,
+            TcpState::Closing,
+            TcpState::TimeWait
+        ]));
+    }
+
+    #[test]
+    fn test_lost_fin_is_retransmitted() {
+        let (mut conn, peer) = established();
+        let server = std::thread::spawn(move || {
+            let first = receive(&peer);
+            let again = receive(&peer);
+            let port = first.get_source_port();
+            let ack = first.get_sequence_number() + 1;
+            reply(
+                &peer,
+                port,
+                SERVER_ISN + 1,
+                ack,
+                tcp_flags::FIN | tcp_flags::ACK,
+            );
+            receive(&peer);
+            (first, again)
+        });
+
+        conn.close(10).unwrap();
+        let (first, again) = server.join().unwrap();
+        assert_eq!(again.get_flags(), tcp_flags::FIN | tcp_flags::ACK);
+        assert_eq!(again.get_sequence_number(), first.get_sequence_number());
+        assert_eq!(conn.state(), TcpState::TimeWait);
+    }
+
+    #[test]
+    fn test_rst_while_closing() {
+        let (mut conn, peer) = established();
+        let server = std::thread::spawn(move || {
+            let fin = receive(&peer);
+            reply(
+                &peer,
+                fin.get_source_port(),
+                SERVER_ISN + 1,
+                0,
+                tcp_flags::RST,
+            );
+        });
+
+        assert!(conn.close(5).is_err());
+        server.join().unwrap();
+        assert_eq!(conn.state(), TcpState::Closed);
+    }
+
+    #[test]
+    fn test_close_before_connect_is_rejected() {
+        let (mut conn, _peer) = loopback_connection(REMOTE_IP, SERVER_PORT);
+        assert!(conn.close(1).is_err());
+        assert_eq!(conn.state(), TcpState::Closed);
+    }
+}
+
+// =============================================================================
+// TIME-WAIT（4タプルの再利用）のテスト
+// =============================================================================
+
+#[cfg(test)]
+mod time_wait_tests {
+    use super::*;
+
+    /// 4タプルをTIME-WAITの表に登録する（テストごとに別のアドレスを使う）
+    fn register(local: SocketAddr, remote: SocketAddr, ts_recent: Option<u32>) {
+        let entry = TimeWaitEntry {
+            snd_nxt: SequenceNumber::new(1),
+            rcv_nxt: SequenceNumber::new(7001),
+            ts_recent,
+            expires_at: Instant::now() + Duration::from_secs(60),
+        };
+        time_wait_table().insert((local, remote), entry);
+    }
+
+    fn syn(port: u16, seq: u32, tsval: Option<u32>) -> TcpHeader {
+        let mut syn = TcpHeader::new(40000, port, seq, 0, tcp_flags::SYN, 65535);
+        if let Some(tsval) = tsval {
+            syn.set_options(&[TcpOption::Timestamps { tsval, tsecr: 0 }])
+                .unwrap();
+        }
+        syn
+    }
+
+    #[test]
+    fn test_connect_rejects_quadruple_in_time_wait() {
+        let remote_ip = Ipv4Addr::new(10, 0, 3, 1);
+        let (mut conn, peer) = loopback_connection(remote_ip, 80);
+        let (local, remote) = conn.quadruple();
+        register(local, remote, None);
+
+        assert!(conn.connect(1).is_err());
+        assert_eq!(conn.state(), TcpState::Closed);
+        // SYNは送っていない
+        assert!(peer
+            .recv_timeout(Duration::from_millis(50))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_listener_ignores_old_syn_in_time_wait() {
+        let (local, peer) = LoopbackDevice::pair();
+        let mut listener = TcpListener::with_device(local, TEST_LOCAL_IP, 8081).unwrap();
+        let client_ip = Ipv4Addr::new(10, 0, 3, 2);
+        register(
+            SocketAddr::new(TEST_LOCAL_IP.into(), 8081),
+            SocketAddr::new(client_ip.into(), 40000),
+            None,
+        );
+
+        // 前の接続のRCV.NXTより前のSYNは、古い重複かもしれないので無視する
+        peer.send(&wrap_tcp(client_ip, TEST_LOCAL_IP, syn(8081, 7000, None)))
+            .unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        assert_eq!(listener.pending(), 0);
+
+        // RCV.NXTより先から始まるSYNなら、期限前でも再利用できる（RFC 6191）
+        peer.send(&wrap_tcp(client_ip, TEST_LOCAL_IP, syn(8081, 9000, None)))
+            .unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        assert_eq!(listener.pending(), 1);
+        let syn_ack = parse_tcp(&peer.recv_timeout(Duration::from_secs(1)).unwrap().unwrap());
+        assert_eq!(syn_ack.get_ack_number(), 9001);
+    }
+
+    #[test]
+    fn test_listener_reuses_quadruple_with_newer_timestamp() {
+        let (local, peer) = LoopbackDevice::pair();
+        let mut listener = TcpListener::with_device(local, TEST_LOCAL_IP, 8082).unwrap();
+        let client_ip = Ipv4Addr::new(10, 0, 3, 3);
+        register(
+            SocketAddr::new(TEST_LOCAL_IP.into(), 8082),
+            SocketAddr::new(client_ip.into(), 40000),
+            Some(500),
+        );
+
+        // Timestampsを使っていたなら、シーケンス番号ではなくTSvalで判断する
+        peer.send(&wrap_tcp(
+            client_ip,
+            TEST_LOCAL_IP,
+            syn(8082, 9000, Some(400)),
+        ))
+        .unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        assert_eq!(listener.pending(), 0);
+
+        peer.send(&wrap_tcp(
+            client_ip,
+            TEST_LOCAL_IP,
+            syn(8082, 10, Some(600)),
+        ))
+        .unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        assert_eq!(listener.pending(), 1);
+    }
+}
+
+// =============================================================================
+// RSTとチャレンジACK（RFC 9293 Section 3.5.2, RFC 5961）のテスト
+// =============================================================================
+
+#[cfg(test)]
+mod rst_tests {
+    use super::*;
+
+    const SERVER_PORT: u16 = 40000;
+    const SERVER_ISN: u32 = 5000;
+    const REMOTE_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 4, 1);
+
+    fn receive(peer: &LoopbackDevice) -> TcpHeader {
+        parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap())
+    }
+
+    fn reply(peer: &LoopbackDevice, dest_port: u16, seq: u32, ack: u32, flags: u8) {
+        let header = TcpHeader::new(SERVER_PORT, dest_port, seq, ack, flags, 65535);
+        peer.send(&wrap_tcp(REMOTE_IP, TEST_LOCAL_IP, header))
+            .unwrap();
+    }
+
+    /// 3-way handshakeを済ませてからclose()し、相手が`attack`を送ってから正しく閉じる。
+    /// close()の結果と、相手が`attack`の後に受け取ったセグメントを返す
+    fn close_after(attack: (u32, u8)) -> (Result<(), String>, TcpHeader) {
+        let (mut conn, peer) = loopback_connection(REMOTE_IP, SERVER_PORT);
+        let server = std::thread::spawn(move || {
+            let syn = receive(&peer);
+            let port = syn.get_source_port();
+            let fin_seq = syn.get_sequence_number().wrapping_add(1);
+            reply(
+                &peer,
+                port,
+                SERVER_ISN,
+                fin_seq,
+                tcp_flags::SYN | tcp_flags::ACK,
+            );
+            receive(&peer); // ACK
+            receive(&peer); // FIN
+
+            let (seq, flags) = attack;
+            reply(&peer, port, seq, 0, flags);
+            let response = receive(&peer);
+
+            reply(
+                &peer,
+                port,
+                SERVER_ISN + 1,
+                fin_seq + 1,
+                tcp_flags::FIN | tcp_flags::ACK,
+            );
+            receive(&peer);
+            response
+        });
+        conn.connect(5).unwrap();
+        let result = conn.close(5).map_err(|e| e.to_string());
+        (result, server.join().unwrap())
+    }
+
+    #[test]
+    fn test_unacceptable_syn_ack_is_reset() {
+        let (mut conn, peer) = loopback_connection(REMOTE_IP, SERVER_PORT);
+        let server = std::thread::spawn(move || {
+            let syn = receive(&peer);
+            let bad_ack = syn.get_sequence_number().wrapping_add(100);
+            reply(
+                &peer,
+                syn.get_source_port(),
+                SERVER_ISN,
+                bad_ack,
+                tcp_flags::SYN | tcp_flags::ACK,
+            );
+            (bad_ack, receive(&peer))
+        });
+
+        assert!(conn.connect(5).is_err());
+        let (bad_ack, rst) = server.join().unwrap();
+        // ACKのあるセグメントへのRSTは <SEQ=SEG.ACK><CTL=RST>
+        assert_eq!(rst.get_flags(), tcp_flags::RST);
+        assert_eq!(rst.get_sequence_number(), bad_ack);
+        assert_eq!(conn.state(), TcpState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_unacceptable_ack_is_reset() {
+        let (local, peer) = LoopbackDevice::pair();
+        let mut listener = TcpListener::with_device(local, TEST_LOCAL_IP, 8083).unwrap();
+        let syn = TcpHeader::new(40000, 8083, 7000, 0, tcp_flags::SYN, 8192);
+        peer.send(&wrap_tcp(REMOTE_IP, TEST_LOCAL_IP, syn)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+        let server_isn = receive(&peer).get_sequence_number();
+
+        let ack = TcpHeader::new(
+            40000,
+            8083,
+            7001,
+            server_isn.wrapping_add(5),
+            tcp_flags::ACK,
+            8192,
+        );
+        peer.send(&wrap_tcp(REMOTE_IP, TEST_LOCAL_IP, ack)).unwrap();
+        assert!(listener.accept(Duration::from_millis(50)).is_err());
+
+        let rst = receive(&peer);
+        assert_eq!(rst.get_flags(), tcp_flags::RST);
+        assert_eq!(rst.get_sequence_number(), server_isn.wrapping_add(5));
+        // 半開き接続は残る
+        assert_eq!(listener.pending(), 1);
+    }
+
+    #[test]
+    fn test_in_window_rst_gets_challenge_ack() {
+        // RCV.NXTと一致しないウィンドウ内のRSTでは切断せず、チャレンジACKを返す
+        let (result, response) = close_after((SERVER_ISN + 100, tcp_flags::RST));
+        assert_eq!(result, Ok(()));
+        assert_eq!(response.get_flags(), tcp_flags::ACK);
+        assert_eq!(response.get_ack_number(), SERVER_ISN + 1);
+    }
+
+    #[test]
+    fn test_syn_on_established_connection_gets_challenge_ack() {
+        let (result, response) = close_after((SERVER_ISN + 1, tcp_flags::SYN));
+        assert_eq!(result, Ok(()));
+        assert_eq!(response.get_flags(), tcp_flags::ACK);
+        assert_eq!(response.get_ack_number(), SERVER_ISN + 1);
+    }
+}
+
+// =============================================================================
+// キープアライブ（RFC 1122）のテスト
+// =============================================================================
+
+#[cfg(test)]
+mod keepalive_tests {
+    use super::*;
+
+    const SERVER_PORT: u16 = 40000;
+    const SERVER_ISN: u32 = 5000;
+    const REMOTE_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 5, 1);
+
+    fn config() -> KeepaliveConfig {
+        KeepaliveConfig {
+            idle: Duration::from_millis(50),
+            interval: Duration::from_millis(50),
+            probes: 2,
+        }
+    }
+
+    fn receive(peer: &LoopbackDevice) -> TcpHeader {
+        parse_tcp(&peer.recv_timeout(Duration::from_secs(5)).unwrap().unwrap())
+    }
+
+    /// 3-way handshakeを済ませた接続と、相手ホスト側のデバイス
+    fn established() -> (TcpConnection<LoopbackDevice>, LoopbackDevice) {
+        let (mut conn, peer) = loopback_connection(REMOTE_IP, SERVER_PORT);
+        let server = std::thread::spawn(move || {
+            let syn = receive(&peer);
+            let syn_ack = TcpHeader::new(
+                SERVER_PORT,
+                syn.get_source_port(),
+                SERVER_ISN,
+                syn.get_sequence_number().wrapping_add(1),
+                tcp_flags::SYN | tcp_flags::ACK,
+                65535,
+            );
+            peer.send(&wrap_tcp(REMOTE_IP, TEST_LOCAL_IP, syn_ack))
+                .unwrap();
+            receive(&peer);
+            peer
+        });
+        conn.connect(5).unwrap();
+        (conn, server.join().unwrap())
+    }
+
+    #[test]
+    fn test_probe_is_answered() {
+        let (mut conn, peer) = established();
+        let local_seq = conn.local_seq;
+        let server = std::thread::spawn(move || {
+            let probe = receive(&peer);
+            // 生きている相手は、確認応答済みの位置へのプローブにACKを返す
+            let ack = TcpHeader::new(
+                SERVER_PORT,
+                probe.get_source_port(),
+                SERVER_ISN + 1,
+                probe.get_sequence_number().wrapping_add(1),
+                tcp_flags::ACK,
+                65535,
+            );
+            peer.send(&wrap_tcp(REMOTE_IP, TEST_LOCAL_IP, ack)).unwrap();
+            probe
+        });
+
+        conn.keepalive(&config()).unwrap();
+        let probe = server.join().unwrap();
+        assert_eq!(probe.get_flags(), tcp_flags::ACK);
+        assert_eq!(probe.get_sequence_number(), local_seq.wrapping_sub(1));
+        assert_eq!(probe.get_ack_number(), SERVER_ISN + 1);
+        assert_eq!(conn.state(), TcpState::Established);
+    }
+
+    #[test]
+    fn test_unanswered_probes_abort_connection() {
+        let (mut conn, peer) = established();
+        let error = conn.keepalive(&config()).unwrap_err();
+        assert!(
+            error.to_string().starts_with("Keepalive timeout"),
+            "{}",
+            error
+        );
+        assert_eq!(conn.state(), TcpState::Closed);
+        assert_eq!(conn.state_history().last().unwrap().2, TcpEvent::Timeout);
 
-        if result.is_err() {
-            let error = result.unwrap_err().to_string();
-            // 到達不可能または接続拒否の場合、タイムアウトエラーになる
-            assert!(error.starts_with("Timeout after"));
+        // 応答がなくても、決められた数だけプローブを送る
+        for _ in 0..config().probes {
+            assert_eq!(receive(&peer).get_flags(), tcp_flags::ACK);
         }
+        assert!(peer
+            .recv_timeout(Duration::from_millis(10))
+            .unwrap()
+            .is_none());
     }
 }
 
@@ -531,7 +1974,7 @@ mod performance_tests {
         let iterations = 5;
 
         for i in 0..iterations {
-            let mut conn = TcpConnection::new(remote_ip, 8080).unwrap();
+            let (mut conn, _peer) = loopback_connection(remote_ip, 8080);
 
             let start = Instant::now();
             let _result = conn.connect(5);
@@ -44,6 +44,7 @@ pub enum TcpEvent {
 }
 
 // Task A3: StateMachineの基本構造
+#[derive(Debug)]
 pub struct TcpStateMachine {
     current_state: TcpState,
     // 状態遷移履歴（デバッグ用）
@@ -63,6 +64,12 @@ impl TcpStateMachine {
     }
 }
 
+impl Default for TcpStateMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // =============================================================================
 // Phase B: 状態遷移ロジック
 // =============================================================================
@@ -71,7 +78,7 @@ impl TcpStateMachine {
     // Task B1: 状態遷移メソッドの実装
     pub fn transition(&mut self, event: TcpEvent) -> Result<TcpState, String> {
         let old_state = self.current_state;
-        let new_state = self.next_state(old_state, event.clone())?;
+        let new_state = self.next_state(old_state, event)?;
 
         self.current_state = new_state;
         self.state_history.push((old_state, new_state, event));
@@ -141,6 +148,15 @@ impl TcpStateMachine {
             (SynSent, Timeout) => Ok(Closed),
             (SynReceived, Timeout) => Ok(Closed),
 
+            // 相手が応答しなくなった接続の中止（RFC 9293 Section 3.10.8 USER TIMEOUT、
+            // キープアライブ）。handle_timeout()からは遷移しない
+            (Established, Timeout) => Ok(Closed),
+            (FinWait1, Timeout) => Ok(Closed),
+            (FinWait2, Timeout) => Ok(Closed),
+            (CloseWait, Timeout) => Ok(Closed),
+            (Closing, Timeout) => Ok(Closed),
+            (LastAck, Timeout) => Ok(Closed),
+
             // 不正な遷移
             _ => Err(format!("Invalid transition: {:?} + {:?}", current, event)),
         }
@@ -296,6 +312,8 @@ impl fmt::Display for TcpEvent {
 // デモ用main関数
 // =============================================================================
 
+// lib.rsにinclude!されたときは呼ばれない
+#[allow(dead_code)]
 fn main() {
     println!("Step 4: TCP State Machine Implementation");
     println!("=========================================");
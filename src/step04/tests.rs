@@ -753,6 +753,17 @@ mod phase_e_tests {
         );
     }
 
+    // 応答のない相手との接続は、Timeoutイベントで中止できる（キープアライブ、USER TIMEOUT）
+    #[test]
+    fn test_abort_established_on_timeout() {
+        let mut sm = TcpStateMachine::new();
+        sm.transition(TcpEvent::Connect).ok();
+        sm.transition(TcpEvent::ReceiveSynAck).ok();
+
+        assert_eq!(sm.transition(TcpEvent::Timeout), Ok(TcpState::Closed));
+        assert!(!sm.can_send_data());
+    }
+
     // Task E3: 不正遷移の検出とログ
     #[test]
     fn test_state_history_tracking() {
@@ -0,0 +1,220 @@
+// Congestion control (RFC 5681)
+//
+// 受信側のウィンドウとは別に、ネットワークが詰まらないよう送信側が自分で
+// 送信量を制限する。送信済み未確認のデータ量（FlightSize）が
+// 輻輳ウィンドウ（cwnd）を超えないように新しいデータを送る。
+//
+//   slow start          (cwnd <  ssthresh): ACKごとに cwnd += min(確認されたバイト数, SMSS)
+//                                           → 1往復でほぼ2倍
+//   congestion avoidance (cwnd >= ssthresh): cwnd分のデータが確認されるごとに cwnd += SMSS
+//                                           → 1往復で1セグメント
+//   再送タイムアウト:    ssthresh = max(FlightSize / 2, 2·SMSS), cwnd = 1·SMSS
+//
+// 重複ACKが3つ続いたら、タイムアウトを待たずに失われたセグメントを再送し
+// （fast retransmit）、slow startに戻らずにfast recoveryに入る（RFC 6582 NewReno）。
+//
+//   3つ目の重複ACK: ssthresh = max(FlightSize / 2, 2·SMSS), cwnd = ssthresh + 3·SMSS
+//   さらに重複ACK:  cwnd += SMSS（受信側に届いて抜けたセグメントの分）
+//   partial ACK:    cwnd -= 確認されたバイト数（1·SMSS以上なら+SMSS）、次の穴を再送
+//   full ACK:       cwnd = min(ssthresh, max(FlightSize, SMSS) + SMSS) で終了
+//
+// SACKが使えるときは（RFC 6675）cwndを膨らませる代わりに、回線上にあると
+// 見積もったデータ量（pipe）で送信量を決めるので、回復開始時は cwnd = ssthresh とする。
+//
+// アルゴリズムを差し替えて比べられるよう、TcpConnectionはtraitを通して使う。
+
+use std::fmt;
+
+/// 輻輳制御アルゴリズム
+///
+/// `TcpConnection`は`window()`からFlightSizeを引いた分だけ新しいデータを送る
+pub trait CongestionControl: fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    /// 輻輳ウィンドウ（cwnd、バイト）
+    fn window(&self) -> usize;
+
+    /// スロースタート閾値（ssthresh、バイト）
+    fn ssthresh(&self) -> usize;
+
+    fn in_slow_start(&self) -> bool {
+        self.window() < self.ssthresh()
+    }
+
+    /// 送信者の最大セグメントサイズ（SMSS）が変わった
+    fn set_mss(&mut self, mss: usize);
+
+    /// 新しいデータが`acked`バイト確認された
+    fn on_ack(&mut self, acked: usize);
+
+    /// 再送タイマーが切れた。`repeated`は同じセグメントの2回目以降のタイムアウト
+    fn on_timeout(&mut self, flight_size: usize, repeated: bool);
+
+    /// 3つ目の重複ACKでfast retransmitし、fast recoveryに入る
+    fn on_enter_recovery(&mut self, flight_size: usize);
+
+    /// SACKによるloss recovery（RFC 6675）に入る。回復中の送信量は接続側がpipeで管理する
+    fn on_enter_sack_recovery(&mut self, flight_size: usize);
+
+    /// fast recovery中にさらに重複ACKが届いた
+    fn on_recovery_dup_ack(&mut self);
+
+    /// fast recovery中に、回復開始時点の送信済みデータの一部だけを確認するACKが届いた
+    fn on_partial_ack(&mut self, acked: usize);
+
+    /// 回復開始時点の送信済みデータがすべて確認され、fast recoveryを終える
+    fn on_exit_recovery(&mut self, flight_size: usize);
+}
+
+/// 初期ウィンドウ（RFC 5681 Section 3.1, 式(1)）
+pub fn initial_window(smss: usize) -> usize {
+    (4 * smss).min((2 * smss).max(4380))
+}
+
+/// RFC 5681のslow start / congestion avoidance
+#[derive(Debug, Clone)]
+pub struct Reno {
+    smss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    // congestion avoidance中に確認されたバイト数（cwndに達するごとに1セグメント増やす）
+    bytes_acked: usize,
+    // ACKやタイムアウトでcwndが一度でも変わったか
+    started: bool,
+}
+
+impl Reno {
+    pub fn new(smss: usize) -> Self {
+        Self {
+            smss,
+            cwnd: initial_window(smss),
+            // 初期値は任意に大きくてよい（最初の損失まではslow start）
+            ssthresh: usize::MAX,
+            bytes_acked: 0,
+            started: false,
+        }
+    }
+}
+
+impl CongestionControl for Reno {
+    fn name(&self) -> &'static str {
+        "reno"
+    }
+
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn set_mss(&mut self, mss: usize) {
+        self.smss = mss;
+        if !self.started {
+            self.cwnd = initial_window(mss);
+        }
+    }
+
+    fn on_ack(&mut self, acked: usize) {
+        self.started = true;
+        if self.cwnd < self.ssthresh {
+            // 式(2): 1回のACKで増やすのは最大1セグメント
+            self.cwnd += acked.min(self.smss);
+        } else {
+            // 1往復（cwnd分のデータが確認される）ごとに1セグメント
+            self.bytes_acked += acked;
+            if self.bytes_acked >= self.cwnd {
+                self.bytes_acked -= self.cwnd;
+                self.cwnd += self.smss;
+            }
+        }
+    }
+
+    fn on_timeout(&mut self, flight_size: usize, repeated: bool) {
+        self.started = true;
+        // 式(4): 再送したセグメントがまたタイムアウトしてもssthreshは下げ直さない
+        if !repeated {
+            self.ssthresh = (flight_size / 2).max(2 * self.smss);
+        }
+        // 損失ウィンドウ（LW）= 1セグメントからslow startをやり直す
+        self.cwnd = self.smss;
+        self.bytes_acked = 0;
+    }
+
+    fn on_enter_recovery(&mut self, flight_size: usize) {
+        self.started = true;
+        // RFC 5681 式(4)と同じssthreshに、受信側に届いた3セグメント分を足す
+        self.ssthresh = (flight_size / 2).max(2 * self.smss);
+        self.cwnd = self.ssthresh + 3 * self.smss;
+        self.bytes_acked = 0;
+    }
+
+    fn on_enter_sack_recovery(&mut self, flight_size: usize) {
+        self.started = true;
+        // RFC 6675 Section 5 (4.2)
+        self.ssthresh = (flight_size / 2).max(2 * self.smss);
+        self.cwnd = self.ssthresh;
+        self.bytes_acked = 0;
+    }
+
+    fn on_recovery_dup_ack(&mut self) {
+        self.cwnd += self.smss;
+    }
+
+    fn on_partial_ack(&mut self, acked: usize) {
+        // RFC 6582 Section 3.2 (5): 確認された分だけ縮め、再送する1セグメント分は残す
+        self.cwnd = self.cwnd.saturating_sub(acked);
+        if acked >= self.smss {
+            self.cwnd += self.smss;
+        }
+    }
+
+    fn on_exit_recovery(&mut self, flight_size: usize) {
+        // RFC 6582 Section 3.2 (3) の1つ目の方法: 直後にバーストを送らないよう抑える
+        self.cwnd = self.ssthresh.min(flight_size.max(self.smss) + self.smss);
+        self.bytes_acked = 0;
+    }
+}
+
+/// 輻輳制御なし: 常に同じウィンドウで送る（比較用）
+#[derive(Debug, Clone)]
+pub struct FixedWindow {
+    window: usize,
+}
+
+impl FixedWindow {
+    pub fn new(window: usize) -> Self {
+        Self { window }
+    }
+}
+
+impl CongestionControl for FixedWindow {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn window(&self) -> usize {
+        self.window
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.window
+    }
+
+    fn set_mss(&mut self, _mss: usize) {}
+
+    fn on_ack(&mut self, _acked: usize) {}
+
+    fn on_timeout(&mut self, _flight_size: usize, _repeated: bool) {}
+
+    fn on_enter_recovery(&mut self, _flight_size: usize) {}
+
+    fn on_enter_sack_recovery(&mut self, _flight_size: usize) {}
+
+    fn on_recovery_dup_ack(&mut self) {}
+
+    fn on_partial_ack(&mut self, _acked: usize) {}
+
+    fn on_exit_recovery(&mut self, _flight_size: usize) {}
+}
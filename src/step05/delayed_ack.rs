@@ -0,0 +1,122 @@
+// Delayed ACK (RFC 1122 Section 4.2.3.2, RFC 5681 Section 4.2)
+//
+// データを受け取るたびにACKを返すとACKだけでセグメント数が倍になるので、
+// 少し待って次のデータや送信データに相乗りさせる。ただし待ちすぎると
+// 送信側のRTT測定や輻輳ウィンドウの成長が遅れるので、次のときはすぐ返す:
+//
+//   - 最後のACKからフルサイズのセグメントを2つ受け取った
+//   - 最初の未確認のセグメントから遅延時間（200ms、上限は500ms）が過ぎた
+//   - 順序外のセグメント（重複ACKでfast retransmitさせる）や重複したセグメント
+//   - 順序外データの穴を埋めるセグメント
+//   - 接続の開始直後（quick-ACKモード）: slow startを速く進めるため、
+//     最初の数セグメントには遅延させずにACKを返す
+
+use std::time::{Duration, Instant};
+
+/// ACKを遅らせる時間（RFC 1122では500ms未満）
+pub const DEFAULT_ACK_DELAY: Duration = Duration::from_millis(200);
+
+/// ACKを返さずに受け取るフルサイズのセグメントの数（RFC 5681: 少なくとも2つに1回はACK）
+pub const ACK_EVERY_SEGMENTS: u32 = 2;
+
+/// 接続の開始直後に、遅延させずにACKを返すセグメントの数（Linuxの最大値と同じ）
+pub const DEFAULT_QUICK_ACKS: u32 = 16;
+
+/// 受け取ったデータにいつACKを返すかを決める
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckScheduler {
+    delay: Duration,
+    enabled: bool,
+    // quick-ACKモードで残っている、すぐにACKを返すセグメントの数
+    quick_acks: u32,
+    // 最後のACKから受け取ったフルサイズのセグメントの数
+    full_segments: u32,
+    // 遅延させているACKを送る時刻
+    deadline: Option<Instant>,
+}
+
+impl Default for AckScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AckScheduler {
+    pub fn new() -> Self {
+        Self {
+            delay: DEFAULT_ACK_DELAY,
+            enabled: true,
+            quick_acks: DEFAULT_QUICK_ACKS,
+            full_segments: 0,
+            deadline: None,
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// falseにすると、データを受け取るたびにすぐACKを返す
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// quick-ACKモードで残っているセグメントの数
+    pub fn quick_acks(&self) -> u32 {
+        self.quick_acks
+    }
+
+    /// quick-ACKモードに入り、次の`count`セグメントにはすぐACKを返す
+    pub fn enter_quick_ack(&mut self, count: u32) {
+        self.quick_acks = self.quick_acks.max(count);
+    }
+
+    /// 遅延させているACKを送る時刻。遅延させていなければNone
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// データを含むセグメントを受け取った。すぐにACKを返すべきならtrue
+    ///
+    /// `full_sized`はMSSいっぱいのセグメント、`immediate`は順序外・重複・穴埋めのセグメント
+    pub fn on_data(&mut self, full_sized: bool, immediate: bool, now: Instant) -> bool {
+        if full_sized {
+            self.full_segments += 1;
+        }
+        if !self.enabled || immediate || self.full_segments >= ACK_EVERY_SEGMENTS {
+            return true;
+        }
+        if self.quick_acks > 0 {
+            self.quick_acks -= 1;
+            return true;
+        }
+        // タイマーは最初の未確認のセグメントから数える
+        self.deadline.get_or_insert(now + self.delay);
+        false
+    }
+
+    /// 遅延させていたACKの期限が来たらtrue
+    pub fn on_timeout(&mut self, now: Instant) -> bool {
+        match self.deadline {
+            Some(at) if at <= now => {
+                self.deadline = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// ACK（データへの相乗りを含む）を送った
+    pub fn on_ack_sent(&mut self) {
+        self.full_segments = 0;
+        self.deadline = None;
+    }
+}
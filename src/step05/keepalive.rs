@@ -0,0 +1,52 @@
+// TCP keepalive (RFC 1122 Section 4.2.3.6)
+//
+// 確立した接続では、どちらも何も送らなければ相手が落ちていても気づけない。
+// そこで、相手から何も届かないまま一定時間（idle）が過ぎたら、データのない
+// キープアライブプローブを送って応答を確かめる:
+//
+//   SEG.SEQ = SND.NXT - 1、データなし
+//
+// 相手にとっては確認応答済みの古いセグメントなので、生きていれば必ずACKを返す
+// （RFC 9293 Section 3.10.7.4）。応答がなければintervalごとに送り直し、
+// probes回送っても応答がなければ接続を中止する。
+//
+// 送信中のデータがある間は再送タイマーが相手の応答を確かめるので、プローブは送らない。
+
+use std::time::Duration;
+
+/// プローブを送り始めるまでのアイドル時間（RFC 1122: デフォルトは2時間以上）
+pub const DEFAULT_KEEPALIVE_IDLE: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// 応答のないプローブを送り直す間隔（Linuxのtcp_keepalive_intvlと同じ）
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(75);
+
+/// 応答がないまま送るプローブの数（Linuxのtcp_keepalive_probesと同じ）
+pub const DEFAULT_KEEPALIVE_PROBES: u32 = 9;
+
+/// キープアライブの設定（SO_KEEPALIVE, TCP_KEEPIDLE, TCP_KEEPINTVL, TCP_KEEPCNT）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// 相手から最後にセグメントが届いてから、最初のプローブを送るまで
+    pub idle: Duration,
+    /// 応答のないプローブを送り直す間隔
+    pub interval: Duration,
+    /// 応答のないまま送るプローブの数。すべて応答がなければ接続を中止する
+    pub probes: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            idle: DEFAULT_KEEPALIVE_IDLE,
+            interval: DEFAULT_KEEPALIVE_INTERVAL,
+            probes: DEFAULT_KEEPALIVE_PROBES,
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    /// 相手から最後にセグメントが届いてから、接続を中止するまでの時間
+    pub fn timeout(&self) -> Duration {
+        self.idle + self.interval * self.probes
+    }
+}
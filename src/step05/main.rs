@@ -2,7 +2,46 @@
 // RFC 9293 Section 3.4 (Sequence Numbers), Section 3.7 (Segmentation), Section 3.8 (Data Communication)
 
 use std::cmp::Ordering;
-use std::collections::BTreeMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::net::Shutdown;
+use std::sync::atomic::{self, AtomicU32};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rust_tcp_handson_with_claude_code::step02::{tcp_flags, TcpOption};
+use rust_tcp_handson_with_claude_code::step04::{TcpEvent, TcpState, TcpStateMachine};
+
+mod segment;
+pub use segment::TcpSegment;
+
+pub mod congestion;
+use congestion::{CongestionControl, Reno};
+
+pub mod delayed_ack;
+use delayed_ack::AckScheduler;
+
+pub mod keepalive;
+use keepalive::KeepaliveConfig;
+
+pub mod nagle;
+use nagle::TransmitPolicy;
+
+#[cfg(target_os = "linux")]
+pub mod reactor;
+
+pub mod rto;
+use rto::{RtoEstimator, MAX_RTO};
+
+pub mod simnet;
+
+pub mod stream;
+
+pub mod time_wait;
+use time_wait::{TimeWaitEntry, DEFAULT_MSL};
 
 // =============================================================================
 // Phase A: シーケンス番号の定義
@@ -24,119 +63,203 @@ impl SequenceNumber {
 
     /// Task A2: シーケンス番号の加算
     pub fn wrapping_add(&self, n: u32) -> Self {
-        // TODO: Task A2 - ラップアラウンドを考慮した加算
-        // ヒント: u32::wrapping_add を使用
-        todo!("Task A2: Implement wrapping_add")
+        Self(self.0.wrapping_add(n))
     }
 
     /// Task A2: シーケンス番号の減算
     pub fn wrapping_sub(&self, other: Self) -> u32 {
-        // TODO: Task A2 - ラップアラウンドを考慮した減算
-        // ヒント: u32::wrapping_sub を使用
-        todo!("Task A2: Implement wrapping_sub")
+        self.0.wrapping_sub(other.0)
     }
 
     /// Task A3: シーケンス番号の比較
     /// RFC 793のシーケンス番号比較アルゴリズムを実装
     pub fn compare(&self, other: Self) -> Ordering {
-        // TODO: Task A3 - RFC 793準拠の比較
-        // ヒント: 2^31 (約21億) を超える差は逆方向と判定
         // (i < j) = ((i < j) && (j - i < 2^31)) || ((i > j) && (i - j > 2^31))
-        todo!("Task A3: Implement RFC 793 sequence number comparison")
+        // つまり j - i（ラップアラウンド込み）が 1..2^31 なら i < j
+        let diff = other.0.wrapping_sub(self.0);
+        if diff == 0 {
+            Ordering::Equal
+        } else if diff < (1 << 31) {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }
     }
 }
 
 impl PartialOrd for SequenceNumber {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // TODO: Task A3 - compare メソッドを使用
-        todo!("Task A3: Implement PartialOrd")
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for SequenceNumber {
     fn cmp(&self, other: &Self) -> Ordering {
-        // TODO: Task A3 - compare メソッドを使用
-        todo!("Task A3: Implement Ord")
+        self.compare(*other)
     }
 }
 
 /// Task A4: ISN生成
 /// Initial Sequence Number（ISN）を生成
+///
+/// RFC 6528: ISN = M + F(4タプル, 秘密鍵)
+/// - M: 4マイクロ秒ごとに1増えるタイマー
+/// - F: 外部から推測できない値。ここでは4タプルがないので、プロセスごとの乱数に
+///   呼び出しごとのカウンターを足す（同じ4マイクロ秒内の呼び出しでも値が重ならない）
 pub fn generate_isn() -> SequenceNumber {
-    // TODO: Task A4 - ISN生成
-    // ヒント: SystemTime::now() と UNIX_EPOCH を使用
-    // RFC 6528推奨: 時刻ベース + ランダム性
-    todo!("Task A4: Implement ISN generation")
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros())
+        .unwrap_or(0);
+    let timer = (micros / 4) as u32;
+    let secret = isn_secret().wrapping_add(COUNTER.fetch_add(1, atomic::Ordering::Relaxed));
+    SequenceNumber::new(timer.wrapping_add(secret))
+}
+
+/// プロセス起動ごとに変わる秘密値（std のハッシュ用乱数キーを流用）
+fn isn_secret() -> u32 {
+    static SECRET: OnceLock<u32> = OnceLock::new();
+    *SECRET.get_or_init(|| RandomState::new().build_hasher().finish() as u32)
 }
 
 // =============================================================================
 // Phase B: 送信バッファの実装
 // =============================================================================
 
+/// 送信バッファの標準サイズ（64KB）
+pub const DEFAULT_SEND_BUFFER_SIZE: usize = 65536;
+
 /// Task B1: SendBuffer構造体の定義
+///
+/// `buffer`の先頭は`unacked_seq`に対応し、`unacked_seq..next_seq`が送信済み未確認、
+/// `next_seq`以降が未送信のデータ
+#[derive(Debug)]
 pub struct SendBuffer {
     buffer: Vec<u8>,             // 送信待ちデータ
     next_seq: SequenceNumber,    // 次に送信するシーケンス番号
     unacked_seq: SequenceNumber, // 未確認の最小シーケンス番号
     max_buffer_size: usize,      // バッファの最大サイズ
+    // SACKスコアボード: 受信側に届いたと報告された範囲（左端 → 右端）。重なりなし
+    sacked: BTreeMap<SequenceNumber, SequenceNumber>,
 }
 
 impl SendBuffer {
     /// Task B2: 新しい送信バッファを作成
     pub fn new(initial_seq: SequenceNumber) -> Self {
-        // TODO: Task B2 - SendBufferを初期化
-        // ヒント: max_buffer_size は 64KB (65536) が標準的
-        todo!("Task B2: Implement SendBuffer::new")
+        Self {
+            buffer: Vec::new(),
+            next_seq: initial_seq,
+            unacked_seq: initial_seq,
+            max_buffer_size: DEFAULT_SEND_BUFFER_SIZE,
+            sacked: BTreeMap::new(),
+        }
     }
 
     /// Task B2: データをバッファに追加
+    ///
+    /// 空きが足りない場合は入る分だけ書き込む（write(2)と同じ）。空きがなければエラー
     pub fn write(&mut self, data: &[u8]) -> Result<usize, String> {
-        // TODO: Task B2 - データを送信バッファに追加
-        // ヒント:
-        // 1. 利用可能な容量をチェック
-        // 2. データをbufferの末尾に追加
-        // 3. 追加したバイト数を返す
-        todo!("Task B2: Implement write")
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let space = self.available_space();
+        if space == 0 {
+            return Err("Send buffer is full".to_string());
+        }
+        let written = data.len().min(space);
+        self.buffer.extend_from_slice(&data[..written]);
+        Ok(written)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.max_buffer_size
+    }
+
+    /// バッファの最大サイズを変える（まだ確認されていないデータより小さくはできない）
+    pub fn set_capacity(&mut self, size: usize) {
+        self.max_buffer_size = size.max(self.buffer.len());
     }
 
     /// Task B2: 送信可能な残り容量
     pub fn available_space(&self) -> usize {
-        // TODO: Task B2 - 空き容量を計算
-        // ヒント: max_buffer_size - buffer.len()
-        todo!("Task B2: Implement available_space")
+        self.max_buffer_size - self.buffer.len()
     }
 
     /// Task B3: 次に送信するデータを取得（削除しない）
+    ///
+    /// 送信済みの部分は飛ばし、`next_seq`から最大`size`バイトを返す
     pub fn peek(&self, size: usize) -> &[u8] {
-        // TODO: Task B3 - 送信すべきデータを取得
-        // ヒント: buffer[..min(size, buffer.len())]
-        todo!("Task B3: Implement peek")
+        let sent = self.unacked_data();
+        let end = (sent + size).min(self.buffer.len());
+        &self.buffer[sent..end]
     }
 
     /// Task B3: 送信済みデータをマーク
     pub fn consume(&mut self, size: usize) {
-        // TODO: Task B3 - 送信完了後の処理
-        // ヒント: next_seq を size 分進める
-        todo!("Task B3: Implement consume")
+        let size = size.min(self.unsent_data());
+        self.next_seq = self.next_seq.wrapping_add(size as u32);
     }
 
     /// Task B4: ACK受信時の処理
+    ///
+    /// 確認されたバイト数を返す。古いACK（重複ACK）は0。
+    /// まだ送っていないデータまで確認するACK（SEG.ACK > SND.NXT）はエラー
     pub fn acknowledge(&mut self, ack_seq: SequenceNumber) -> Result<usize, String> {
-        // TODO: Task B4 - 確認済みデータをバッファから削除
-        // ヒント:
-        // 1. ack_seq と unacked_seq を比較
-        // 2. ack_seq > unacked_seq なら処理
-        // 3. 確認されたバイト数を計算
-        // 4. bufferから該当データを削除
-        // 5. unacked_seq を更新
-        todo!("Task B4: Implement acknowledge")
+        if ack_seq <= self.unacked_seq {
+            return Ok(0);
+        }
+        if ack_seq > self.next_seq {
+            return Err(format!(
+                "ACK {} is beyond sent data (una={}, nxt={})",
+                ack_seq.value(),
+                self.unacked_seq.value(),
+                self.next_seq.value()
+            ));
+        }
+        let acked = ack_seq.wrapping_sub(self.unacked_seq) as usize;
+
+        self.buffer.drain(..acked);
+        self.unacked_seq = ack_seq;
+        // 累積ACKに追い越されたSACK済み範囲を捨てる
+        while let Some((&left, &right)) = self.sacked.first_key_value() {
+            if left >= ack_seq {
+                break;
+            }
+            self.sacked.remove(&left);
+            if right > ack_seq {
+                self.sacked.insert(ack_seq, right);
+            }
+        }
+        Ok(acked)
     }
 
     /// Task B4: 未確認データ量
     pub fn unacked_data(&self) -> usize {
-        // TODO: Task B4 - 送信済み未確認データ量を計算
-        // ヒント: next_seq - unacked_seq
-        todo!("Task B4: Implement unacked_data")
+        self.next_seq.wrapping_sub(self.unacked_seq) as usize
+    }
+
+    /// まだ一度も送信していないデータ量
+    pub fn unsent_data(&self) -> usize {
+        self.buffer.len() - self.unacked_data()
+    }
+
+    /// 再送用: 送信済み未確認データの先頭から最大`size`バイトを返す
+    ///
+    /// `unacked_seq..next_seq`がそのまま再送キューになる（ACKされるまで消さない）
+    pub fn peek_unacked(&self, size: usize) -> &[u8] {
+        &self.buffer[..size.min(self.unacked_data())]
+    }
+
+    /// 再送用: 送信済み未確認データのうち`seq`から最大`size`バイトを返す
+    pub fn peek_at(&self, seq: SequenceNumber, size: usize) -> &[u8] {
+        if seq < self.unacked_seq || seq >= self.next_seq {
+            return &[];
+        }
+        let start = seq.wrapping_sub(self.unacked_seq) as usize;
+        let end = (start + size).min(self.unacked_data());
+        &self.buffer[start..end]
     }
 
     pub fn next_seq(&self) -> SequenceNumber {
@@ -146,74 +269,252 @@ impl SendBuffer {
     pub fn unacked_seq(&self) -> SequenceNumber {
         self.unacked_seq
     }
+
+    /// SACKブロック（左端, 右端）をスコアボードに記録し、新しくSACKされたバイト数を返す
+    ///
+    /// 送信済み未確認の範囲に収まらないブロックは無視する（RFC 2018 Section 4）
+    pub fn on_sack(&mut self, left: SequenceNumber, right: SequenceNumber) -> usize {
+        if right <= left || right <= self.unacked_seq || right > self.next_seq {
+            return 0;
+        }
+        let mut left = left.max(self.unacked_seq);
+        let mut right = right;
+        let before = self.sacked_bytes();
+
+        // 重なる・隣接する範囲をまとめて1つにする
+        let touching: Vec<SequenceNumber> = self
+            .sacked
+            .iter()
+            .filter(|&(&l, &r)| l <= right && r >= left)
+            .map(|(&l, _)| l)
+            .collect();
+        for l in touching {
+            let r = self.sacked.remove(&l).expect("listed above");
+            left = left.min(l);
+            right = right.max(r);
+        }
+        self.sacked.insert(left, right);
+        self.sacked_bytes() - before
+    }
+
+    /// SACK済み範囲（左端, 右端）を昇順に返す
+    pub fn sacked_ranges(&self) -> impl Iterator<Item = (SequenceNumber, SequenceNumber)> + '_ {
+        self.sacked.iter().map(|(&l, &r)| (l, r))
+    }
+
+    pub fn sacked_bytes(&self) -> usize {
+        self.sacked
+            .iter()
+            .map(|(l, r)| r.wrapping_sub(*l) as usize)
+            .sum()
+    }
+
+    /// SACKされた最も大きいシーケンス番号（の次）。SACKがなければNone
+    pub fn highest_sacked(&self) -> Option<SequenceNumber> {
+        self.sacked.last_key_value().map(|(_, &r)| r)
+    }
+
+    /// 送信済み未確認でSACKされていない範囲（左端, 右端）を昇順に返す
+    pub fn unsacked_ranges(&self) -> Vec<(SequenceNumber, SequenceNumber)> {
+        let mut ranges = Vec::new();
+        let mut from = self.unacked_seq;
+        for (l, r) in self.sacked_ranges() {
+            if l > from {
+                ranges.push((from, l));
+            }
+            from = r;
+        }
+        if self.next_seq > from {
+            ranges.push((from, self.next_seq));
+        }
+        ranges
+    }
 }
 
 // =============================================================================
 // Phase C: 受信バッファの実装
 // =============================================================================
 
+/// 受信バッファの標準サイズ（ウィンドウスケールなしで広告できる最大値）
+pub const DEFAULT_RECV_BUFFER_SIZE: usize = 65535;
+
 /// Task C1: ReceiveBuffer構造体の定義
+///
+/// 受信ウィンドウは、アプリケーションがまだ読んでいないデータを除いた空き容量。
+/// 順序外データは`next_expected`からウィンドウの範囲内に置かれる
+#[derive(Debug)]
 pub struct ReceiveBuffer {
     buffer: Vec<u8>,                                 // 順序通りに並んだデータ
     next_expected: SequenceNumber,                   // 次に期待するシーケンス番号
     out_of_order: BTreeMap<SequenceNumber, Vec<u8>>, // 順序外データ
+    recent: Vec<SequenceNumber>,                     // 順序外データの開始位置（新しい順）
+    max_buffer_size: usize,                          // バッファの最大サイズ
+    fin: Option<SequenceNumber>,                     // 相手のFINのシーケンス番号
+    fin_received: bool,                              // FINまでのデータがすべて揃った
 }
 
 impl ReceiveBuffer {
     /// Task C2: 新しい受信バッファを作成
     pub fn new(initial_seq: SequenceNumber) -> Self {
-        // TODO: Task C2 - ReceiveBufferを初期化
-        todo!("Task C2: Implement ReceiveBuffer::new")
+        Self {
+            buffer: Vec::new(),
+            next_expected: initial_seq,
+            out_of_order: BTreeMap::new(),
+            recent: Vec::new(),
+            max_buffer_size: DEFAULT_RECV_BUFFER_SIZE,
+            fin: None,
+            fin_received: false,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.max_buffer_size
+    }
+
+    /// バッファの最大サイズを変える（読まれていないデータより小さくはできない）
+    pub fn set_capacity(&mut self, size: usize) {
+        self.max_buffer_size = size.max(self.buffer.len());
+    }
+
+    /// 受信ウィンドウ: `next_expected`から受け入れられるバイト数
+    pub fn window(&self) -> usize {
+        self.max_buffer_size - self.buffer.len()
     }
 
     /// Task C2: データを受信
+    ///
+    /// 一部だけ新しいデータ（再送と新規が重なったセグメント）は新しい部分だけを使う。
+    /// ウィンドウからはみ出した部分は捨てる
     pub fn receive(&mut self, seq: SequenceNumber, data: &[u8]) -> Result<(), String> {
-        // TODO: Task C2 - 受信データを適切に格納
-        // ヒント:
-        // 1. seq と next_expected を比較
-        // 2. seq == next_expected: 順序通り → bufferに追加
-        // 3. seq > next_expected: 順序外 → out_of_orderに保管
-        // 4. seq < next_expected: 重複 → 無視
-        // 5. process_out_of_order() を呼び出し
-        todo!("Task C2: Implement receive")
+        let mut right_edge = self.next_expected.wrapping_add(self.window() as u32);
+        // FINより後ろにデータはない
+        if let Some(fin) = self.fin {
+            right_edge = right_edge.min(fin);
+        }
+        if seq >= right_edge {
+            return Ok(());
+        }
+        let data = &data[..data.len().min(right_edge.wrapping_sub(seq) as usize)];
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = seq.wrapping_add(data.len() as u32);
+        if end <= self.next_expected {
+            // 全部受信済み（重複）
+            return Ok(());
+        }
+
+        if seq <= self.next_expected {
+            let skip = self.next_expected.wrapping_sub(seq) as usize;
+            self.buffer.extend_from_slice(&data[skip..]);
+            self.next_expected = end;
+        } else {
+            // 同じ位置から始まる順序外データは長い方を残す
+            let entry = self.out_of_order.entry(seq).or_default();
+            if data.len() > entry.len() {
+                *entry = data.to_vec();
+            }
+            self.recent.retain(|&s| s != seq);
+            self.recent.insert(0, seq);
+        }
+
+        self.process_out_of_order();
+        Ok(())
     }
 
     /// Task C3: データを読み取る
     pub fn read(&mut self, size: usize) -> Vec<u8> {
-        // TODO: Task C3 - bufferから最大sizeバイトを取り出す
-        // ヒント:
-        // 1. buffer.drain(..min(size, buffer.len()))
-        // 2. 取り出したデータを返す
-        todo!("Task C3: Implement read")
+        let size = size.min(self.buffer.len());
+        self.buffer.drain(..size).collect()
     }
 
     /// Task C3: 読み取り可能なデータ量
     pub fn available(&self) -> usize {
-        // TODO: Task C3 - 利用可能なデータ量を返す
-        todo!("Task C3: Implement available")
+        self.buffer.len()
     }
 
     /// Task C4: 順序外データの処理
     fn process_out_of_order(&mut self) {
-        // TODO: Task C4 - out_of_orderから連続データをbufferに移動
-        // ヒント:
-        // 1. out_of_order を走査
-        // 2. next_expected と一致するデータを探す
-        // 3. 見つかったらbufferに追加
-        // 4. next_expected を更新
-        // 5. 再帰的に処理（連続データがなくなるまで）
-        todo!("Task C4: Implement process_out_of_order")
+        // 先頭（最小のシーケンス番号）が next_expected 以下である限り取り込む
+        while let Some((&seq, _)) = self.out_of_order.first_key_value() {
+            if seq > self.next_expected {
+                break;
+            }
+            let data = self.out_of_order.remove(&seq).expect("entry exists");
+            let end = seq.wrapping_add(data.len() as u32);
+            if end > self.next_expected {
+                let skip = self.next_expected.wrapping_sub(seq) as usize;
+                self.buffer.extend_from_slice(&data[skip..]);
+                self.next_expected = end;
+            }
+        }
+        let out_of_order = &self.out_of_order;
+        self.recent.retain(|seq| out_of_order.contains_key(seq));
+        self.consume_fin();
+    }
+
+    /// 相手のFINを受信した。`seq`はFINのシーケンス番号（データの直後）
+    ///
+    /// 手前のデータが揃っていれば、FINもシーケンス番号を1つ消費する。
+    /// 揃っていなければ、穴が埋まるまで覚えておく
+    pub fn receive_fin(&mut self, seq: SequenceNumber) {
+        if self.fin.is_none() && seq >= self.next_expected {
+            self.fin = Some(seq);
+        }
+        self.consume_fin();
+    }
+
+    fn consume_fin(&mut self) {
+        if !self.fin_received && self.fin == Some(self.next_expected) {
+            self.next_expected = self.next_expected.wrapping_add(1);
+            self.fin_received = true;
+        }
+    }
+
+    /// FINまでのデータがすべて届いた（これ以上データは来ない）
+    pub fn fin_received(&self) -> bool {
+        self.fin_received
+    }
+
+    /// FINまでのデータをすべて読み終えた
+    pub fn is_eof(&self) -> bool {
+        self.fin_received && self.buffer.is_empty()
     }
 
     /// Task C4: データにギャップがあるか確認
     pub fn has_gap(&self) -> bool {
-        // TODO: Task C4 - 順序外データの有無を返す
-        todo!("Task C4: Implement has_gap")
+        !self.out_of_order.is_empty()
     }
 
     pub fn next_expected(&self) -> SequenceNumber {
         self.next_expected
     }
+
+    /// 順序外データからSACKブロック（左端, 右端）を最大`MAX_SACK_BLOCKS`個作る
+    ///
+    /// 最後に受信したセグメントを含むブロックを先頭に、新しく受信した順に並べる
+    /// （RFC 2018 Section 4）
+    pub fn sack_blocks(&self) -> Vec<(SequenceNumber, SequenceNumber)> {
+        // 隣接・重複する順序外データをまとめる
+        let mut blocks: Vec<(SequenceNumber, SequenceNumber)> = Vec::new();
+        for (&seq, data) in &self.out_of_order {
+            let end = seq.wrapping_add(data.len() as u32);
+            match blocks.last_mut() {
+                Some((_, right)) if seq <= *right => *right = (*right).max(end),
+                _ => blocks.push((seq, end)),
+            }
+        }
+
+        let rank = |&(left, right): &(SequenceNumber, SequenceNumber)| {
+            self.recent
+                .iter()
+                .position(|&seq| seq >= left && seq < right)
+                .unwrap_or(usize::MAX)
+        };
+        blocks.sort_by_key(rank);
+        blocks.truncate(MAX_SACK_BLOCKS);
+        blocks
+    }
 }
 
 // =============================================================================
@@ -229,13 +530,11 @@ pub struct Segment {
 
 impl Segment {
     pub fn new(seq: SequenceNumber, data: Vec<u8>) -> Self {
-        // TODO: Task D1 - Segmentを生成
-        todo!("Task D1: Implement Segment::new")
+        Self { seq, data }
     }
 
     pub fn len(&self) -> usize {
-        // TODO: Task D1 - データ長を返す
-        todo!("Task D1: Implement Segment::len")
+        self.data.len()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -248,82 +547,379 @@ impl Segment {
 pub const DEFAULT_MSS: usize = 1460;
 
 pub fn segment_data(data: &[u8], mss: usize, start_seq: SequenceNumber) -> Vec<Segment> {
-    // TODO: Task D2 - データをmssサイズのセグメントに分割
-    // ヒント:
-    // 1. data.chunks(mss) で分割
-    // 2. 各チャンクをSegmentに変換
-    // 3. 連続したシーケンス番号を付与
-    todo!("Task D2: Implement segment_data")
+    data.chunks(mss)
+        .enumerate()
+        .map(|(i, chunk)| Segment::new(start_seq.wrapping_add((i * mss) as u32), chunk.to_vec()))
+        .collect()
 }
 
 /// Task D3: セグメント検証
 pub fn validate_segment(seg: &Segment, expected_seq: SequenceNumber, window_size: u32) -> bool {
-    // TODO: Task D3 - セグメントが受信ウィンドウ内にあるか確認
-    // ヒント:
-    // 1. expected_seq <= seg.seq < expected_seq + window_size
-    // 2. ラップアラウンドを考慮した比較
-    todo!("Task D3: Implement validate_segment")
+    // expected_seq からの距離で比べればラップアラウンドを気にしなくてよい
+    seg.seq.wrapping_sub(expected_seq) < window_size
 }
 
 // =============================================================================
 // Phase E: データ送受信統合
 // =============================================================================
 
-// Step04の状態マシンを再利用
-// use crate::step04::{TcpStateMachine, TcpState, TcpEvent};
-// 注意: この段階では状態マシンの統合は行わず、バッファ管理に集中
+// 接続の状態はStep04の状態マシンで管理する（ハンドシェイク済みのESTABLISHEDから始める）
+
+/// 相手の受信ウィンドウの初期値（ハンドシェイクで相手の広告を受け取った想定）
+pub const DEFAULT_WINDOW: u16 = 65535;
+
+/// ACKが進まないまま再送を繰り返す上限（Linuxのtcp_retries2と同じ）
+pub const DEFAULT_MAX_RETRANSMISSIONS: u32 = 15;
+
+/// 応答のないウィンドウプローブを送り続ける上限
+pub const DEFAULT_MAX_WINDOW_PROBES: u32 = 15;
+
+/// fast retransmitを起こす重複ACKの数（RFC 5681 Section 3.2）
+pub const DUP_ACK_THRESHOLD: u32 = 3;
+
+/// 1つのACKに載せるSACKブロックの最大数（オプション領域40バイトに収まる数）
+pub const MAX_SACK_BLOCKS: usize = 4;
+
+/// ウィンドウスケールのシフト数の上限（RFC 7323 Section 2.3: ウィンドウは最大1GiB）
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+/// TS.Recentを信用する期間。これより長く更新がなければPAWSで比べない（RFC 7323 Section 5.5）
+pub const PAWS_IDLE_LIMIT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+/// 1秒間に送るチャレンジACKの上限（RFC 5961 Section 7）
+pub const DEFAULT_CHALLENGE_ACK_LIMIT: u32 = 100;
+
+/// `buffer_size`バイトのウィンドウを16ビットのフィールドで広告するのに必要なシフト数
+pub fn window_scale_for(buffer_size: usize) -> u8 {
+    let mut shift = 0;
+    while shift < MAX_WINDOW_SCALE && buffer_size >> shift > u16::MAX as usize {
+        shift += 1;
+    }
+    shift
+}
+
+/// `on_timeout()`が接続を諦めた理由
+///
+/// 呼び出し側が再送・プローブ・キープアライブのどれで諦めたのかを区別できるようにする
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeoutError {
+    /// 再送の上限まで確認応答が進まない（タイマーは止まり、状態はそのまま）
+    Retransmission { timeouts: u32, una: SequenceNumber },
+    /// ゼロウィンドウへのプローブの上限まで応答がない（状態はそのまま）
+    WindowProbe { probes: u32, window: u32 },
+    /// キープアライブのプローブに応答がない（接続はCLOSEDになる）
+    Keepalive { probes: u32 },
+    /// タイマーによる状態遷移に失敗した
+    State(String),
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutError::Retransmission { timeouts, una } => write!(
+                f,
+                "Retransmission limit exceeded: {} timeouts without progress (una={})",
+                timeouts,
+                una.value()
+            ),
+            TimeoutError::WindowProbe { probes, window } => write!(
+                f,
+                "Window probe limit exceeded: {} probes without response (window={})",
+                probes, window
+            ),
+            TimeoutError::Keepalive { probes } => {
+                write!(f, "Keepalive timeout: no response to {} probes", probes)
+            }
+            TimeoutError::State(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for TimeoutError {}
 
 /// Task E1: TcpConnection構造体の定義
 pub struct TcpConnection {
-    // state: TcpStateMachine,  // 将来の統合用（Step04）
+    state: TcpStateMachine,
     send_buffer: SendBuffer,
     recv_buffer: ReceiveBuffer,
     local_seq: SequenceNumber,
     remote_seq: SequenceNumber,
+    // 1セグメントで送る最大データ長
+    mss: usize,
+    // データを受信したがまだACKを返していない
+    ack_pending: bool,
+    // 再送タイマー（RFC 6298）
+    rto: RtoEstimator,
+    retransmit_at: Option<Instant>,
+    // RTTを測定中のセグメント（終端のシーケンス番号, 送信時刻）
+    rtt_probe: Option<(SequenceNumber, Instant)>,
+    // タイムアウトしたので、次の送信で未確認の先頭セグメントを再送する
+    retransmit_pending: bool,
+    // ACKが進まないまま連続でタイムアウトした回数
+    consecutive_timeouts: u32,
+    max_retransmissions: u32,
+    retransmissions: u64,
+    // 輻輳制御（RFC 5681）。新しいデータはcwnd - FlightSizeの分だけ送る
+    congestion: Box<dyn CongestionControl>,
+    // 送信ウィンドウ（RFC 9293のSND.WND）と、それを更新したセグメントのSEQ/ACK（SND.WL1/WL2）
+    snd_wnd: u32,
+    snd_wl1: SequenceNumber,
+    snd_wl2: SequenceNumber,
+    // 相手がこれまでに広告した最大のウィンドウ（送信側のSWS回避に使う）
+    max_snd_wnd: u32,
+    // 最後に広告したウィンドウの右端（RCV.NXT + RCV.WND）。ここより左には縮めない
+    rcv_adv: SequenceNumber,
+    // ウィンドウスケール（RFC 7323）: 相手のウィンドウを左シフトする数と、自分のウィンドウを右シフトする数
+    snd_wscale: u8,
+    rcv_wscale: u8,
+    // persistタイマー（RFC 9293 Section 3.8.6.1）: ウィンドウが閉じて送れない間に相手へ問い合わせる
+    persist_at: Option<Instant>,
+    persist_backoffs: u32,
+    // 期限が来たので、次の送信でウィンドウプローブを送る
+    probe_pending: bool,
+    // ゼロウィンドウに送った1バイトのプローブの終わり（送信済みとして数える）
+    probe_end: Option<SequenceNumber>,
+    // ACKが返ってこないまま送ったプローブの数
+    unanswered_probes: u32,
+    max_window_probes: u32,
+    window_probes: u64,
+    // 連続して届いた重複ACKの数
+    dup_acks: u32,
+    // fast recovery中か
+    in_recovery: bool,
+    // 回復を始めた時点のnext_seq（RFC 6582のrecover）。ここまで確認されたら回復終了
+    recover: SequenceNumber,
+    fast_retransmissions: u64,
+    recoveries: u64,
+    // SACKを使うか（本来はSYNのSACK-Permittedオプションで合意する）
+    sack_enabled: bool,
+    // SACKによる回復中に再送した最も大きいシーケンス番号（の次）。RFC 6675のHighRxt
+    high_rxt: SequenceNumber,
+    // Timestampsオプション（RFC 7323）を使うか（本来はSYNで合意する）
+    ts_enabled: bool,
+    // TSvalの時計: 最初に使った時刻からのミリ秒に、接続ごとのオフセットを足す
+    ts_base: Option<Instant>,
+    ts_offset: u32,
+    // 確認されていないセグメントの終わりと、最初に送ったときのTSval（送った順）
+    ts_sent: VecDeque<(SequenceNumber, u32)>,
+    // 相手から受け取った最新のTSval（TS.Recent）と、それを記録した時刻
+    ts_recent: u32,
+    ts_recent_at: Option<Instant>,
+    // 最後に送ったACK番号（Last.ACK.sent）
+    last_ack_sent: SequenceNumber,
+    paws_rejected: u64,
+    // shutdown(Write)された: 送信バッファのデータを送り終えたらFINを送る
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    // shutdown(Read)された: 届いたデータは読まずに捨てる
+    read_closed: bool,
+    msl: Duration,
+    // TIME-WAITを抜けてCLOSEDになる時刻
+    time_wait_at: Option<Instant>,
+    // 次に送るRST（閉じた接続に届いたセグメントへの応答）
+    rst_pending: Option<TcpSegment>,
+    challenge_ack_limit: u32,
+    // 今の1秒間の始まりと、その間に送ったチャレンジACKの数
+    challenge_window: Option<(Instant, u32)>,
+    challenge_acks: u64,
+    // 受け取ったデータにいつACKを返すか（遅延ACK）
+    ack_scheduler: AckScheduler,
+    // 小さなセグメントをいつ送るか（Nagle、NODELAY、CORK）
+    transmit_policy: TransmitPolicy,
+    // キープアライブ。Noneなら使わない
+    keepalive: Option<KeepaliveConfig>,
+    keepalive_at: Option<Instant>,
+    // 次の送信でキープアライブプローブを送る
+    keepalive_pending: bool,
+    // 応答のないまま送ったプローブの数
+    unanswered_keepalives: u32,
+    keepalive_probes: u64,
 }
 
 impl TcpConnection {
     pub fn new(local_isn: SequenceNumber, remote_isn: SequenceNumber) -> Self {
-        // TODO: Task E1 - TcpConnectionを初期化
-        todo!("Task E1: Implement TcpConnection::new")
+        let mut state = TcpStateMachine::new();
+        state
+            .active_open()
+            .and_then(|_| state.complete_active_open())
+            .expect("CLOSED -> SYN-SENT -> ESTABLISHED is a valid path");
+        Self {
+            state,
+            send_buffer: SendBuffer::new(local_isn),
+            recv_buffer: ReceiveBuffer::new(remote_isn),
+            local_seq: local_isn,
+            remote_seq: remote_isn,
+            mss: DEFAULT_MSS,
+            ack_pending: false,
+            rto: RtoEstimator::new(),
+            retransmit_at: None,
+            rtt_probe: None,
+            retransmit_pending: false,
+            consecutive_timeouts: 0,
+            max_retransmissions: DEFAULT_MAX_RETRANSMISSIONS,
+            retransmissions: 0,
+            congestion: Box::new(Reno::new(DEFAULT_MSS)),
+            snd_wnd: DEFAULT_WINDOW as u32,
+            snd_wl1: remote_isn,
+            snd_wl2: local_isn,
+            max_snd_wnd: DEFAULT_WINDOW as u32,
+            persist_at: None,
+            persist_backoffs: 0,
+            probe_pending: false,
+            probe_end: None,
+            unanswered_probes: 0,
+            max_window_probes: DEFAULT_MAX_WINDOW_PROBES,
+            window_probes: 0,
+            rcv_adv: remote_isn
+                .wrapping_add(DEFAULT_RECV_BUFFER_SIZE.min(u16::MAX as usize) as u32),
+            snd_wscale: 0,
+            rcv_wscale: 0,
+            dup_acks: 0,
+            in_recovery: false,
+            recover: local_isn,
+            fast_retransmissions: 0,
+            recoveries: 0,
+            sack_enabled: false,
+            high_rxt: local_isn,
+            ts_enabled: false,
+            ts_base: None,
+            ts_sent: VecDeque::new(),
+            // ISNと同じく、外から推測しにくい値から始める（RFC 7323 Section 5.4）
+            ts_offset: generate_isn().value(),
+            ts_recent: 0,
+            ts_recent_at: None,
+            last_ack_sent: remote_isn,
+            paws_rejected: 0,
+            fin_queued: false,
+            fin_sent: false,
+            fin_acked: false,
+            read_closed: false,
+            msl: DEFAULT_MSL,
+            time_wait_at: None,
+            rst_pending: None,
+            challenge_ack_limit: DEFAULT_CHALLENGE_ACK_LIMIT,
+            challenge_window: None,
+            challenge_acks: 0,
+            ack_scheduler: AckScheduler::new(),
+            transmit_policy: TransmitPolicy::new(),
+            keepalive: None,
+            keepalive_at: None,
+            keepalive_pending: false,
+            unanswered_keepalives: 0,
+            keepalive_probes: 0,
+        }
     }
 
     /// Task E2: データを送信バッファに書き込み
+    ///
+    /// shutdown(Write)の後や、閉じた接続には書き込めない
     pub fn send(&mut self, data: &[u8]) -> Result<usize, String> {
-        // TODO: Task E2 - データを送信バッファに追加
-        // ヒント:
-        // 1. 状態チェック（現段階では省略可）
-        // 2. send_buffer.write(data)
-        todo!("Task E2: Implement send")
+        if self.fin_queued || !self.state.can_send_data() {
+            return Err(format!(
+                "Cannot send in state {}",
+                self.state.current_state()
+            ));
+        }
+        self.send_buffer.write(data)
+    }
+
+    /// 接続の片方向または両方向を閉じる
+    ///
+    /// `Write`: 送信バッファのデータを送り終えたらFINを送る（ESTABLISHED → FIN-WAIT-1,
+    /// CLOSE-WAIT → LAST-ACK）。`Read`: 以降に届くデータは読まずに捨てる
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<(), String> {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.read_closed = true;
+            self.recv_buffer.read(usize::MAX);
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) && !self.fin_queued {
+            self.state.transition(TcpEvent::Close)?;
+            self.fin_queued = true;
+        }
+        Ok(())
+    }
+
+    /// 接続を閉じる（`shutdown(Shutdown::Both)`）
+    ///
+    /// 送信済みのデータは相手に届けてからFINを送る。相手のFINを受け取るまで接続は残る
+    pub fn close(&mut self) -> Result<(), String> {
+        self.shutdown(Shutdown::Both)
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.state.current_state()
+    }
+
+    pub fn msl(&self) -> Duration {
+        self.msl
+    }
+
+    /// MSLを変更する。TIME-WAITには2·MSLの間留まる
+    pub fn set_msl(&mut self, msl: Duration) {
+        self.msl = msl;
+    }
+
+    /// TIME-WAIT中なら、4タプルを`TimeWaitTable`に残すためのエントリ
+    pub fn time_wait_entry(&self) -> Option<TimeWaitEntry> {
+        Some(TimeWaitEntry {
+            snd_nxt: self.snd_nxt(),
+            rcv_nxt: self.recv_buffer.next_expected(),
+            ts_recent: self.ts_enabled.then_some(self.ts_recent),
+            expires_at: self.time_wait_at?,
+        })
+    }
+
+    /// 相手がFINを送り、そこまでのデータをすべて読み終えた（read()はもう何も返さない）
+    pub fn is_eof(&self) -> bool {
+        self.recv_buffer.is_eof()
     }
 
     /// Task E3: セグメントを受信
     pub fn receive(&mut self, seq: SequenceNumber, data: &[u8]) -> Result<(), String> {
-        // TODO: Task E3 - 受信セグメントを処理
-        // ヒント:
-        // 1. 状態チェック（現段階では省略可）
-        // 2. validate_segment() でセグメント検証
-        // 3. recv_buffer.receive()
-        todo!("Task E3: Implement receive")
+        let expected = self.recv_buffer.next_expected();
+        // 古い（重複）セグメントはReceiveBufferが捨てるので、先の方だけを検証する
+        if seq > expected
+            && !validate_segment(
+                &Segment::new(seq, data.to_vec()),
+                expected,
+                self.recv_buffer.window() as u32,
+            )
+        {
+            return Err(format!(
+                "Segment {} is outside the receive window (expected {})",
+                seq.value(),
+                expected.value()
+            ));
+        }
+        self.recv_buffer.receive(seq, data)
     }
 
     /// Task E3: データを読み取る
+    ///
+    /// 読んだことでウィンドウが十分に開いたら、ウィンドウ更新のACKを送る
     pub fn read(&mut self, size: usize) -> Vec<u8> {
-        // TODO: Task E3 - アプリケーションがデータを読み取る
-        todo!("Task E3: Implement read")
+        if self.read_closed {
+            return Vec::new();
+        }
+        let data = self.recv_buffer.read(size);
+        if !data.is_empty() && self.window_opened() {
+            self.ack_pending = true;
+        }
+        data
     }
 
     /// Task E4: ACKを生成
     pub fn generate_ack(&self) -> SequenceNumber {
-        // TODO: Task E4 - 次に期待するシーケンス番号を返す
-        todo!("Task E4: Implement generate_ack")
+        self.recv_buffer.next_expected()
     }
 
     /// Task E4: ACKを処理
+    ///
+    /// ACK番号だけでは重複ACKを判定できないので、fast retransmitはon_segment()で扱う
     pub fn process_ack(&mut self, ack_seq: SequenceNumber) -> Result<(), String> {
-        // TODO: Task E4 - ACK受信時の処理
-        // ヒント: send_buffer.acknowledge(ack_seq)
-        todo!("Task E4: Implement process_ack")
+        self.send_buffer.acknowledge(ack_seq)?;
+        Ok(())
     }
 
     pub fn send_buffer(&self) -> &SendBuffer {
@@ -333,12 +929,1144 @@ impl TcpConnection {
     pub fn recv_buffer(&self) -> &ReceiveBuffer {
         &self.recv_buffer
     }
+
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
+
+    pub fn set_mss(&mut self, mss: usize) {
+        self.mss = mss.max(1);
+        self.congestion.set_mss(self.mss);
+    }
+
+    pub fn rto(&self) -> &RtoEstimator {
+        &self.rto
+    }
+
+    /// これまでに再送したセグメント数（タイムアウトとfast retransmitの合計）
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions
+    }
+
+    /// 重複ACKやpartial ACKをきっかけに再送したセグメント数
+    pub fn fast_retransmissions(&self) -> u64 {
+        self.fast_retransmissions
+    }
+
+    /// fast recoveryに入った回数
+    pub fn recoveries(&self) -> u64 {
+        self.recoveries
+    }
+
+    pub fn in_recovery(&self) -> bool {
+        self.in_recovery
+    }
+
+    /// 連続して届いた重複ACKの数
+    pub fn dup_acks(&self) -> u32 {
+        self.dup_acks
+    }
+
+    pub fn sack_enabled(&self) -> bool {
+        self.sack_enabled
+    }
+
+    /// SACK（RFC 2018）を使うかを設定する。両端で有効にしたときだけ意味がある
+    pub fn set_sack_enabled(&mut self, enabled: bool) {
+        self.sack_enabled = enabled;
+    }
+
+    pub fn timestamps_enabled(&self) -> bool {
+        self.ts_enabled
+    }
+
+    /// Timestampsオプション（RFC 7323）を使うかを設定する。両端で有効にしたときだけ意味がある
+    pub fn set_timestamps_enabled(&mut self, enabled: bool) {
+        self.ts_enabled = enabled;
+    }
+
+    /// PAWSで古い重複として捨てたセグメント数
+    pub fn paws_rejected(&self) -> u64 {
+        self.paws_rejected
+    }
+
+    /// 送ったチャレンジACKの数（RFC 5961）
+    pub fn challenge_acks(&self) -> u64 {
+        self.challenge_acks
+    }
+
+    /// 1秒間に送るチャレンジACKの上限を変更する
+    pub fn set_challenge_ack_limit(&mut self, limit: u32) {
+        self.challenge_ack_limit = limit;
+    }
+
+    pub fn ack_scheduler(&self) -> &AckScheduler {
+        &self.ack_scheduler
+    }
+
+    /// 遅延ACKを使うかを設定する。使わなければデータを受け取るたびにすぐACKを返す
+    pub fn set_delayed_ack_enabled(&mut self, enabled: bool) {
+        self.ack_scheduler.set_enabled(enabled);
+    }
+
+    /// ACKを遅らせる時間を変更する
+    pub fn set_ack_delay(&mut self, delay: Duration) {
+        self.ack_scheduler.set_delay(delay);
+    }
+
+    pub fn transmit_policy(&self) -> &TransmitPolicy {
+        &self.transmit_policy
+    }
+
+    /// trueにするとNagleのアルゴリズムを使わず、小さな書き込みもすぐ送る（TCP_NODELAY）
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.transmit_policy.set_nodelay(nodelay);
+    }
+
+    /// uncork()するまで、MSSに満たないデータを送らずに貯める（TCP_CORK）
+    pub fn cork(&mut self) {
+        self.transmit_policy.set_corked(true);
+    }
+
+    /// cork()で貯めていたデータを送れるようにする（次のpoll_transmit()で送る）
+    pub fn uncork(&mut self) {
+        self.transmit_policy.set_corked(false);
+    }
+
+    pub fn keepalive(&self) -> Option<KeepaliveConfig> {
+        self.keepalive
+    }
+
+    /// キープアライブを設定する（Noneで無効）。アイドル時間は次の送信の機会から数える
+    pub fn set_keepalive(&mut self, config: Option<KeepaliveConfig>) {
+        self.keepalive = config;
+        self.keepalive_at = None;
+        self.unanswered_keepalives = 0;
+    }
+
+    /// 送ったキープアライブプローブの数
+    pub fn keepalive_probes(&self) -> u64 {
+        self.keepalive_probes
+    }
+
+    pub fn set_max_retransmissions(&mut self, max: u32) {
+        self.max_retransmissions = max;
+    }
+
+    /// これまでに送ったウィンドウプローブの数
+    pub fn window_probes(&self) -> u64 {
+        self.window_probes
+    }
+
+    pub fn set_max_window_probes(&mut self, max: u32) {
+        self.max_window_probes = max;
+    }
+
+    /// ハンドシェイクで合意したウィンドウスケールを設定する
+    ///
+    /// `snd_shift`は相手がWindow Scaleオプションで送ってきた値、`rcv_shift`は自分が送った値。
+    /// どちらかがオプションを送らなければ、両方とも0（スケールなし）
+    pub fn set_window_scale(&mut self, snd_shift: u8, rcv_shift: u8) {
+        self.snd_wscale = snd_shift.min(MAX_WINDOW_SCALE);
+        self.rcv_wscale = rcv_shift.min(MAX_WINDOW_SCALE);
+    }
+
+    /// 合意したウィンドウスケール（相手のシフト数, 自分のシフト数）
+    pub fn window_scale(&self) -> (u8, u8) {
+        (self.snd_wscale, self.rcv_wscale)
+    }
+
+    pub fn congestion(&self) -> &dyn CongestionControl {
+        self.congestion.as_ref()
+    }
+
+    /// 輻輳制御アルゴリズムを差し替える
+    pub fn set_congestion_control(&mut self, mut congestion: Box<dyn CongestionControl>) {
+        congestion.set_mss(self.mss);
+        self.congestion = congestion;
+    }
+
+    /// 今送れる新しいデータ量
+    ///
+    /// 輻輳ウィンドウの残り（cwnd - FlightSize）と、相手の受信ウィンドウの残り
+    /// （SND.UNA + SND.WND - SND.NXT）の小さい方。
+    /// SACKによる回復中はFlightSizeの代わりにpipeを使う
+    pub fn send_allowance(&self) -> usize {
+        let in_flight = if self.in_sack_recovery() {
+            self.pipe()
+        } else {
+            self.send_buffer.unacked_data()
+        };
+        self.congestion
+            .window()
+            .saturating_sub(in_flight)
+            .min(self.usable_window())
+    }
+
+    /// 相手の受信ウィンドウの残り（SND.UNA + SND.WND - SND.NXT）
+    pub fn usable_window(&self) -> usize {
+        (self.snd_wnd as usize).saturating_sub(self.send_buffer.unacked_data())
+    }
+
+    /// 相手が最後に広告した受信ウィンドウ（SND.WND）
+    pub fn send_window(&self) -> u32 {
+        self.snd_wnd
+    }
+
+    /// 送信バッファの大きさを設定する（send()で書き込めるデータ量の上限になる）
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.send_buffer.set_capacity(size);
+    }
+
+    /// 受信バッファの大きさを設定する（広告するウィンドウの上限になる）
+    ///
+    /// 新しいウィンドウは次のACKで相手に伝える。64KiBを超える分は、
+    /// ウィンドウスケールで合意したシフト数で表せる範囲でしか広告できない
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.recv_buffer.set_capacity(size);
+        self.rcv_adv = self
+            .recv_buffer
+            .next_expected()
+            .wrapping_add(self.recv_buffer.window().min(self.max_receive_window()) as u32);
+        self.ack_pending = true;
+    }
+
+    /// 広告できるウィンドウの最大値（65535をrcv_wscaleだけ左シフトした値）
+    fn max_receive_window(&self) -> usize {
+        (u16::MAX as usize) << self.rcv_wscale
+    }
+
+    /// 次に広告する受信ウィンドウ（バイト、受信側のSWS回避込み）
+    ///
+    /// 右端を広げるのは、空きが min(バッファの半分, MSS) 以上増えたときだけ
+    /// （RFC 9293 Section 3.8.6.2.2）。それまでは前回広告した右端を保つ
+    pub fn receive_window(&self) -> u32 {
+        let next = self.recv_buffer.next_expected();
+        let available = self.recv_buffer.window().min(self.max_receive_window());
+        let advertised = if self.rcv_adv > next {
+            self.rcv_adv.wrapping_sub(next) as usize
+        } else {
+            0
+        };
+        if self.window_opened() {
+            available as u32
+        } else {
+            advertised.min(available) as u32
+        }
+    }
+
+    /// 前回広告した右端から、広告してよいだけウィンドウが開いたか
+    fn window_opened(&self) -> bool {
+        let available = self.recv_buffer.window().min(self.max_receive_window());
+        let edge = self
+            .recv_buffer
+            .next_expected()
+            .wrapping_add(available as u32);
+        let threshold = (self.recv_buffer.capacity() / 2).min(self.mss);
+        edge > self.rcv_adv && edge.wrapping_sub(self.rcv_adv) as usize >= threshold
+    }
+
+    /// 送るセグメントに載せるウィンドウ（rcv_wscaleだけ右シフトした値）を決め、広告した右端を覚える
+    ///
+    /// シフトで切り捨てた端数は広告しない（RFC 7323 Section 2.4）
+    fn advertise_window(&mut self) -> u16 {
+        let window = (self.receive_window() >> self.rcv_wscale) as u16;
+        self.rcv_adv = self
+            .recv_buffer
+            .next_expected()
+            .wrapping_add((window as u32) << self.rcv_wscale);
+        window
+    }
+
+    /// 届いたセグメントのウィンドウ（バイト）。SYNのウィンドウはスケールしない（RFC 7323 Section 2.2）
+    fn peer_window(&self, segment: &TcpSegment) -> u32 {
+        if segment.has_flag(tcp_flags::SYN) {
+            segment.window as u32
+        } else {
+            (segment.window as u32) << self.snd_wscale
+        }
+    }
+
+    /// 回線上にあると見積もったデータ量（RFC 6675のpipe）
+    ///
+    /// SACKされておらず失われたとも判断していないバイトと、再送したバイトの合計
+    pub fn pipe(&self) -> usize {
+        let mut pipe = 0;
+        for (left, right) in self.send_buffer.unsacked_ranges() {
+            if !self.is_lost(left) {
+                pipe += right.wrapping_sub(left) as usize;
+            }
+            if left < self.high_rxt {
+                pipe += self.high_rxt.min(right).wrapping_sub(left) as usize;
+            }
+        }
+        pipe
+    }
+
+    fn in_sack_recovery(&self) -> bool {
+        self.in_recovery && self.sack_enabled
+    }
+
+    /// `seq`を失われたとみなすか（RFC 6675のIsLost）
+    ///
+    /// それより後ろでDupThresh個の範囲、または(DupThresh - 1)·SMSSより多くのバイトがSACKされていれば失われた
+    fn is_lost(&self, seq: SequenceNumber) -> bool {
+        let mut ranges = 0;
+        let mut bytes = 0;
+        for (left, right) in self.send_buffer.sacked_ranges() {
+            if left > seq {
+                ranges += 1;
+                bytes += right.wrapping_sub(left) as usize;
+            }
+        }
+        ranges >= DUP_ACK_THRESHOLD as usize || bytes > (DUP_ACK_THRESHOLD as usize - 1) * self.mss
+    }
+}
+
+// =============================================================================
+// セグメント単位の入出力
+// =============================================================================
+//
+// ここまでのAPIは「データを渡す」「ACK番号を渡す」単位だった。
+// 実際の回線（やシミュレーション）とつなぐため、ヘッダー情報付きの
+// TcpSegmentを受け取り・取り出すAPIを用意する。I/Oは行わない（sans-IO）。
+// 時刻も呼び出し側から渡すので、仮想時計でも実時間でも同じように動く。
+
+impl TcpConnection {
+    /// 相手から届いたセグメントを処理する
+    ///
+    /// データを含むセグメントには（重複や範囲外でも）ACKを返す（RFC 9293 Section 3.10.7.4）。
+    /// 順序通りのデータへのACKは、遅延ACKの規則に従って遅らせることがある
+    pub fn on_segment(&mut self, segment: &TcpSegment, now: Instant) -> Result<(), String> {
+        if self.state.current_state() == TcpState::Closed {
+            // 閉じた接続に届いたセグメントにはRSTで応える（RFC 9293 Section 3.10.7.1）
+            self.rst_pending = segment.reset_reply();
+            return Ok(());
+        }
+        if self.ts_enabled && !self.check_timestamp(segment, now) {
+            return Ok(());
+        }
+        if segment.has_flag(tcp_flags::RST) {
+            return self.on_reset(segment, now);
+        }
+        if segment.has_flag(tcp_flags::SYN) {
+            // 確立した接続へのSYNは、シーケンス番号によらずチャレンジACKを返して捨てる
+            // （RFC 5961 Section 4）。本物の相手が再起動していれば、これにRSTを返してくる
+            self.challenge_ack(now);
+            return Ok(());
+        }
+        if !self.segment_acceptable(segment) {
+            // 受信ウィンドウの外のセグメントはACKを返して捨て、ACKやウィンドウの欄も使わない
+            // （RFC 9293 Section 3.10.7.4）。使うと、古いセグメントや偽のセグメントで
+            // SND.WL1が先に進み、本物のウィンドウ更新を受け付けなくなる
+            self.ack_pending = true;
+            if self.state.current_state() == TcpState::TimeWait && segment.has_flag(tcp_flags::FIN)
+            {
+                // TIME-WAITで届く相手のFINの再送: 2·MSLのタイマーを再起動する
+                self.time_wait_at = Some(now + 2 * self.msl);
+            }
+            let expected = self.recv_buffer.next_expected();
+            if segment.seq > expected {
+                return Err(format!(
+                    "Segment {} is outside the receive window (expected {})",
+                    segment.seq.value(),
+                    expected.value()
+                ));
+            }
+            // 確認応答済みの位置より前（再送やキープアライブプローブ）はACKを返すだけ
+            return Ok(());
+        }
+        if segment.has_flag(tcp_flags::ACK) && segment.ack > self.snd_nxt() {
+            // まだ送っていないデータへのACKは、ACKを返して捨てる
+            // （RFC 9293 Section 3.10.7.4, RFC 5961 Section 5）
+            self.challenge_ack(now);
+            return Ok(());
+        }
+        // 相手は生きている: キープアライブのアイドル時間を数え直す
+        self.keepalive_at = None;
+        self.unanswered_keepalives = 0;
+        if segment.has_flag(tcp_flags::FIN) {
+            // 再送されたFINにもACKを返す
+            self.ack_pending = true;
+        }
+        if !segment.payload.is_empty() {
+            // 順序外・重複のセグメントと、穴を埋めるセグメントにはすぐACKを返す（RFC 5681 Section 4.2）
+            let immediate =
+                segment.seq != self.recv_buffer.next_expected() || self.recv_buffer.has_gap();
+            let full_sized = segment.payload.len() >= self.mss;
+            if self.ack_scheduler.on_data(full_sized, immediate, now) {
+                self.ack_pending = true;
+            }
+        }
+        if segment.has_flag(tcp_flags::ACK) {
+            self.on_ack(segment, now)?;
+        }
+        if !segment.payload.is_empty() {
+            self.receive(segment.seq, &segment.payload)?;
+            if self.read_closed {
+                self.recv_buffer.read(usize::MAX);
+            }
+        }
+        if segment.has_flag(tcp_flags::FIN) {
+            // FINはデータの直後のシーケンス番号を1つ消費する
+            self.recv_buffer
+                .receive_fin(segment.seq.wrapping_add(segment.payload.len() as u32));
+        }
+        if self.recv_buffer.fin_received()
+            && matches!(
+                self.state.current_state(),
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+            )
+        {
+            // 同時クローズならFIN-WAIT-1 → CLOSING
+            self.state.transition(TcpEvent::ReceiveFin)?;
+        }
+        if self.state.current_state() == TcpState::TimeWait
+            && (self.time_wait_at.is_none() || segment.has_flag(tcp_flags::FIN))
+        {
+            // TIME-WAITで届くのは相手のFINの再送だけ: ACKを返し、2·MSLのタイマーを再起動する
+            self.time_wait_at = Some(now + 2 * self.msl);
+        }
+        Ok(())
+    }
+
+    /// FINのシーケンス番号（送信バッファのデータの直後）
+    fn fin_seq(&self) -> SequenceNumber {
+        self.send_buffer
+            .next_seq()
+            .wrapping_add(self.send_buffer.unsent_data() as u32)
+    }
+
+    /// 次に送るシーケンス番号（FINを送った後はFINの次）
+    fn snd_nxt(&self) -> SequenceNumber {
+        if self.fin_sent {
+            self.fin_seq().wrapping_add(1)
+        } else {
+            self.send_buffer.next_seq()
+        }
+    }
+
+    /// FINを送ったがまだACKされていない
+    fn fin_outstanding(&self) -> bool {
+        self.fin_sent && !self.fin_acked
+    }
+
+    /// 送信バッファを送り切ったので、次のセグメントでFINを送れる
+    fn fin_ready(&self) -> bool {
+        self.fin_queued && !self.fin_sent && self.send_buffer.unsent_data() == 0
+    }
+
+    /// RSTを受け取った（RFC 5961 Section 3）
+    ///
+    /// シーケンス番号がRCV.NXTにちょうど一致するときだけ接続をリセットする。
+    /// ウィンドウ内でも一致しなければチャレンジACKを返し、本物の相手にRCV.NXTで
+    /// RSTを送り直させる。ウィンドウ外なら黙って捨てる
+    fn on_reset(&mut self, segment: &TcpSegment, now: Instant) -> Result<(), String> {
+        let rcv_nxt = self.recv_buffer.next_expected();
+        if segment.seq == rcv_nxt {
+            self.abort(TcpEvent::ReceiveRst)?;
+            return Err("Connection reset by peer".to_string());
+        }
+        let window = (self.recv_buffer.window() as u32).max(1);
+        if segment.seq > rcv_nxt && segment.seq < rcv_nxt.wrapping_add(window) {
+            self.challenge_ack(now);
+        }
+        Ok(())
+    }
+
+    /// 接続をCLOSEDにして、すべてのタイマーを止める
+    fn abort(&mut self, event: TcpEvent) -> Result<(), String> {
+        self.state.transition(event)?;
+        self.retransmit_at = None;
+        self.persist_at = None;
+        self.time_wait_at = None;
+        self.keepalive_at = None;
+        self.ack_pending = false;
+        Ok(())
+    }
+
+    /// チャレンジACK（RCV.NXTを伝える空のACK）を送る。1秒あたりの数を制限する（RFC 5961 Section 7）
+    fn challenge_ack(&mut self, now: Instant) {
+        let (start, count) = match self.challenge_window {
+            Some((start, count)) if now < start + Duration::from_secs(1) => (start, count),
+            _ => (now, 0),
+        };
+        if count >= self.challenge_ack_limit {
+            return;
+        }
+        self.challenge_window = Some((start, count + 1));
+        self.challenge_acks += 1;
+        self.ack_pending = true;
+    }
+
+    /// Timestampsオプションを確かめ、TS.Recentを更新する。捨てるセグメントならfalse
+    ///
+    /// TS.Recentより古いTSvalのセグメントは、シーケンス番号が一周する前の古い重複なので
+    /// ウィンドウ内でも受け取らず、ACKだけ返す（PAWS、RFC 7323 Section 5.3）
+    fn check_timestamp(&mut self, segment: &TcpSegment, now: Instant) -> bool {
+        let rst = segment.has_flag(tcp_flags::RST);
+        let Some((tsval, _)) = segment.timestamps() else {
+            // 合意した後にオプションのないセグメントは捨てる（RFC 7323 Section 3.2）
+            return rst;
+        };
+        if let Some(at) = self.ts_recent_at {
+            if now.saturating_duration_since(at) > PAWS_IDLE_LIMIT {
+                // 長く更新されなかったTS.Recentは、相手の時計が一周しているかもしれない
+                self.ts_recent_at = None;
+            } else if (tsval.wrapping_sub(self.ts_recent) as i32) < 0 && !rst {
+                self.paws_rejected += 1;
+                self.ack_pending = true;
+                return false;
+            }
+        }
+        // 確認応答済みの位置から始まるセグメントのTSvalだけを覚え、次のACKで返す（RFC 7323 Section 4.3）
+        if segment.seq <= self.last_ack_sent {
+            self.ts_recent = tsval;
+            self.ts_recent_at = Some(now);
+        }
+        true
+    }
+
+    /// 受信ウィンドウに照らしてセグメントを受け入れられるか（RFC 9293 Section 3.10.7.4）
+    ///
+    /// ウィンドウが0でも、RCV.NXTから始まるセグメントはACKを処理するために受け入れる
+    /// （収まらないデータはReceiveBufferが捨てる）
+    fn segment_acceptable(&self, segment: &TcpSegment) -> bool {
+        let rcv_nxt = self.recv_buffer.next_expected();
+        let rcv_wnd = self.recv_buffer.window() as u32;
+        let in_window = |seq: SequenceNumber| seq >= rcv_nxt && seq.wrapping_sub(rcv_nxt) < rcv_wnd;
+        match (segment.seq_len(), rcv_wnd) {
+            (0, 0) => segment.seq == rcv_nxt,
+            (0, _) => in_window(segment.seq),
+            (_, 0) => segment.seq == rcv_nxt,
+            (len, _) => in_window(segment.seq) || in_window(segment.seq.wrapping_add(len - 1)),
+        }
+    }
+
+    /// 自分の時計の現在値（TSval、1ミリ秒刻み）
+    fn ts_now(&mut self, now: Instant) -> u32 {
+        let base = *self.ts_base.get_or_insert(now);
+        let millis = now.saturating_duration_since(base).as_millis() as u32;
+        self.ts_offset.wrapping_add(millis)
+    }
+
+    /// 次に送信すべきセグメントを取り出す。送るものがなければNone
+    ///
+    /// 再送が必要なら未確認の先頭セグメントを、なければ未送信データをMSS単位で
+    /// （輻輳ウィンドウの残りの範囲で）送り、ACKはデータに相乗りさせる。
+    /// データがなくACKだけ必要なら、データなしのACKセグメントを返す。
+    /// Timestampsを使うときは、どのセグメントにもTSvalとTS.Recentを載せる
+    pub fn poll_transmit(&mut self, now: Instant) -> Option<TcpSegment> {
+        if let Some(rst) = self.rst_pending.take() {
+            return Some(rst);
+        }
+        if self.state.current_state() == TcpState::Closed {
+            return None;
+        }
+        let mut segment = self.next_segment(now)?;
+        // どのセグメントにもACKが載るので、遅延させていたACKもこれで送ったことになる
+        self.ack_scheduler.on_ack_sent();
+        self.last_ack_sent = segment.ack;
+        if self.ts_enabled {
+            let tsval = self.ts_now(now);
+            // 新しいデータやFINを送ったら、RTTの測定に使えるTSecrの下限として記録する
+            let end = segment.seq.wrapping_add(segment.seq_len());
+            let last = self.ts_sent.back().map(|&(end, _)| end);
+            if segment.seq_len() > 0 && last.is_none_or(|last| end > last) {
+                self.ts_sent.push_back((end, tsval));
+            }
+            segment.options.insert(
+                0,
+                TcpOption::Timestamps {
+                    tsval,
+                    tsecr: self.ts_recent,
+                },
+            );
+        }
+        Some(segment)
+    }
+
+    fn next_segment(&mut self, now: Instant) -> Option<TcpSegment> {
+        self.update_persist_timer(now);
+        self.update_keepalive_timer(now);
+        if self.probe_pending {
+            self.probe_pending = false;
+            if let Some(segment) = self.window_probe(now) {
+                return Some(segment);
+            }
+        }
+
+        if self.keepalive_pending {
+            self.keepalive_pending = false;
+            self.ack_pending = false;
+            // SND.NXT - 1: 相手が確認応答済みの位置なので、データなしでもACKが返ってくる
+            let seq = SequenceNumber::new(self.snd_nxt().value().wrapping_sub(1));
+            let window = self.advertise_window();
+            return Some(
+                TcpSegment::new(seq, self.generate_ack(), tcp_flags::ACK, window)
+                    .with_options(self.ack_options()),
+            );
+        }
+
+        if self.retransmit_pending {
+            self.retransmit_pending = false;
+            if let Some(segment) = self.retransmit(self.send_buffer.unacked_seq()) {
+                return Some(segment);
+            }
+        }
+
+        // SACKによる回復中は、失われた穴 → 新しいデータ → 残りの穴の順に送る（RFC 6675 NextSeg）
+        let sack_slot = self.in_sack_recovery() && self.send_allowance() >= self.mss;
+        if sack_slot {
+            if let Some(seq) = self.next_hole(true) {
+                self.fast_retransmissions += 1;
+                return self.retransmit(seq);
+            }
+        }
+
+        let ack = self.generate_ack();
+        let size = self.mss.min(self.send_allowance());
+        let data = self.send_buffer.peek(size).to_vec();
+        if !data.is_empty() && self.sws_permits(data.len()) && self.nagle_permits() {
+            let seq = self.send_buffer.next_seq();
+            self.send_buffer.consume(data.len());
+            self.transmit_policy
+                .on_send(seq.wrapping_add(data.len() as u32), data.len(), self.mss);
+            self.ack_pending = false;
+            // 最後のデータにはFINを相乗りさせる
+            let mut flags = tcp_flags::ACK | tcp_flags::PSH;
+            if self.fin_ready() {
+                flags |= tcp_flags::FIN;
+                self.fin_sent = true;
+            }
+            // 1往復に1つのセグメントでRTTを測る
+            if self.rtt_probe.is_none() {
+                self.rtt_probe = Some((seq.wrapping_add(data.len() as u32), now));
+            }
+            // RFC 6298 (5.1): タイマーが止まっていれば起動する
+            if self.retransmit_at.is_none() {
+                self.retransmit_at = Some(now + self.rto.rto());
+            }
+            let window = self.advertise_window();
+            return Some(
+                TcpSegment::new(seq, ack, flags, window)
+                    .with_options(self.ack_options())
+                    .with_payload(data),
+            );
+        }
+
+        if self.fin_ready() {
+            self.fin_sent = true;
+            self.ack_pending = false;
+            if self.retransmit_at.is_none() {
+                self.retransmit_at = Some(now + self.rto.rto());
+            }
+            let window = self.advertise_window();
+            return Some(
+                TcpSegment::new(self.fin_seq(), ack, tcp_flags::FIN | tcp_flags::ACK, window)
+                    .with_options(self.ack_options()),
+            );
+        }
+
+        if sack_slot {
+            if let Some(seq) = self.next_hole(false) {
+                self.fast_retransmissions += 1;
+                return self.retransmit(seq);
+            }
+        }
+
+        if self.ack_pending {
+            self.ack_pending = false;
+            let window = self.advertise_window();
+            return Some(
+                TcpSegment::new(self.snd_nxt(), ack, tcp_flags::ACK, window)
+                    .with_options(self.ack_options()),
+            );
+        }
+        None
+    }
+
+    /// 送信側のSWS回避（RFC 9293 Section 3.8.6.2.1）: `size`バイトのセグメントを今送ってよいか
+    ///
+    /// 1セグメント分送れる、残りのデータを全部送れる、相手の最大ウィンドウの半分以上送れる、
+    /// のどれかを満たすまで、小さなセグメントを送らずに待つ
+    fn sws_permits(&self, size: usize) -> bool {
+        size >= self.mss
+            || size >= self.send_buffer.unsent_data()
+            || size >= self.max_snd_wnd as usize / 2
+    }
+
+    /// Nagleのアルゴリズム（RFC 896）: 未送信のデータがMSSに満たなければ、
+    /// 前に送った小さなセグメントが確認されるまで（CORK中はuncorkされるまで）待つ
+    fn nagle_permits(&self) -> bool {
+        self.transmit_policy.permits(
+            self.send_buffer.unsent_data(),
+            self.mss,
+            self.send_buffer.unacked_seq(),
+            self.fin_queued,
+        )
+    }
+
+    /// `seq`から次のSACK済み範囲の手前まで（最大MSS）を再送するセグメントを作る
+    ///
+    /// 未確認のFINに届くところまで再送するなら、FINも付け直す
+    fn retransmit(&mut self, seq: SequenceNumber) -> Option<TcpSegment> {
+        let limit = self
+            .send_buffer
+            .sacked_ranges()
+            .map(|(left, _)| left)
+            .find(|&left| left > seq)
+            .map_or(self.mss, |left| {
+                self.mss.min(left.wrapping_sub(seq) as usize)
+            });
+        let data = self.send_buffer.peek_at(seq, limit).to_vec();
+        let end = seq.wrapping_add(data.len() as u32);
+        let fin = self.fin_outstanding() && end == self.fin_seq();
+        if data.is_empty() && !fin {
+            return None;
+        }
+        let mut flags = tcp_flags::ACK;
+        if !data.is_empty() {
+            flags |= tcp_flags::PSH;
+        }
+        if fin {
+            flags |= tcp_flags::FIN;
+        }
+        self.retransmissions += 1;
+        self.ack_pending = false;
+        if end > self.high_rxt {
+            self.high_rxt = end;
+        }
+        let window = self.advertise_window();
+        Some(
+            TcpSegment::new(seq, self.generate_ack(), flags, window)
+                .with_options(self.ack_options())
+                .with_payload(data),
+        )
+    }
+
+    /// まだ再送していない、最後のSACK済み範囲より前の穴の先頭（RFC 6675 NextSeg (1)(3)）
+    ///
+    /// `lost_only`ならIsLostを満たす穴だけを対象にする
+    fn next_hole(&self, lost_only: bool) -> Option<SequenceNumber> {
+        let highest = self.send_buffer.highest_sacked()?;
+        let from = self.high_rxt.max(self.send_buffer.unacked_seq());
+        self.send_buffer
+            .unsacked_ranges()
+            .into_iter()
+            .filter(|&(left, right)| right > from && left < highest)
+            .map(|(left, _)| left.max(from))
+            .find(|&seq| !lost_only || self.is_lost(seq))
+    }
+
+    /// ACKに載せるオプション: 順序外データがあればSACKブロック
+    fn ack_options(&self) -> Vec<TcpOption> {
+        if !self.sack_enabled || !self.recv_buffer.has_gap() {
+            return Vec::new();
+        }
+        // Timestampsと一緒なら、オプション領域に入るのは3ブロックまで
+        let max_blocks = if self.ts_enabled {
+            MAX_SACK_BLOCKS - 1
+        } else {
+            MAX_SACK_BLOCKS
+        };
+        let blocks = self
+            .recv_buffer
+            .sack_blocks()
+            .into_iter()
+            .take(max_blocks)
+            .map(|(left, right)| (left.value(), right.value()))
+            .collect();
+        vec![TcpOption::Sack(blocks)]
+    }
+
+    /// 次にon_timeout()を呼ぶべき時刻。タイマーが止まっていればNone
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        [
+            self.retransmit_at,
+            self.persist_at,
+            self.time_wait_at,
+            self.keepalive_at,
+            self.ack_scheduler.deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// 期限の来たタイマー（再送・persist・TIME-WAIT・遅延ACK・キープアライブ）を処理する
+    ///
+    /// 再送やプローブの上限を超えたらタイマーを止めてエラーを返す。
+    /// キープアライブに応答がなければ、接続を中止（CLOSED）してエラーを返す
+    pub fn on_timeout(&mut self, now: Instant) -> Result<(), TimeoutError> {
+        if self.ack_scheduler.on_timeout(now) {
+            self.ack_pending = true;
+        }
+        self.on_time_wait_timeout(now)
+            .map_err(TimeoutError::State)?;
+        self.on_keepalive_timeout(now)?;
+        self.on_persist_timeout(now)?;
+        self.on_retransmit_timeout(now)
+    }
+
+    /// 2·MSLが過ぎたらTIME-WAIT → CLOSED
+    fn on_time_wait_timeout(&mut self, now: Instant) -> Result<(), String> {
+        match self.time_wait_at {
+            Some(at) if at <= now => {}
+            _ => return Ok(()),
+        }
+        self.time_wait_at = None;
+        self.state.handle_timeout()
+    }
+
+    /// 確立した接続がアイドル（送信中・未送信のデータもFINもない）ならキープアライブのタイマーを起動する
+    fn update_keepalive_timer(&mut self, now: Instant) {
+        let idle = matches!(
+            self.state.current_state(),
+            TcpState::Established | TcpState::CloseWait
+        ) && self.send_buffer.unacked_data() == 0
+            && self.send_buffer.unsent_data() == 0
+            && !self.fin_queued;
+        match self.keepalive {
+            Some(config) if idle => {
+                self.keepalive_at.get_or_insert(now + config.idle);
+            }
+            _ => {
+                self.keepalive_at = None;
+                self.unanswered_keepalives = 0;
+            }
+        }
+    }
+
+    /// キープアライブのタイマーの処理: プローブを予約し、上限まで応答がなければ接続を中止する
+    fn on_keepalive_timeout(&mut self, now: Instant) -> Result<(), TimeoutError> {
+        let Some(config) = self.keepalive else {
+            return Ok(());
+        };
+        match self.keepalive_at {
+            Some(at) if at <= now => {}
+            _ => return Ok(()),
+        }
+        if self.unanswered_keepalives >= config.probes {
+            self.abort(TcpEvent::Timeout).map_err(TimeoutError::State)?;
+            return Err(TimeoutError::Keepalive {
+                probes: self.unanswered_keepalives,
+            });
+        }
+        self.keepalive_pending = true;
+        self.unanswered_keepalives += 1;
+        self.keepalive_probes += 1;
+        self.keepalive_at = Some(now + config.interval);
+        Ok(())
+    }
+
+    /// 送れるデータがあるのにウィンドウが閉じていて何も送っていなければ、persistタイマーを起動する
+    ///
+    /// 送信中のデータがあれば再送タイマーとACKがウィンドウの変化を運んでくるので不要。
+    /// ただし送信中なのがゼロウィンドウへのプローブの1バイトだけなら、プローブを続ける
+    fn update_persist_timer(&mut self, now: Instant) {
+        let probing = self.probe_in_flight();
+        let unsent = self.send_buffer.unsent_data();
+        let size = self.mss.min(self.usable_window()).min(unsent);
+        let idle = probing || (self.send_buffer.unacked_data() == 0 && unsent > 0);
+        let blocked = idle && (size == 0 || !self.sws_permits(size));
+        if !blocked {
+            self.persist_at = None;
+            self.persist_backoffs = 0;
+            if probing && self.retransmit_at.is_none() {
+                // ウィンドウが開いた: 捨てられたかもしれないプローブの1バイトは普通に再送する
+                self.retransmit_at = Some(now + self.rto.rto());
+            }
+        } else if self.persist_at.is_none() && self.unanswered_probes < self.max_window_probes {
+            // 上限までプローブに応答がなければ、ACKが届くまで諦める
+            self.persist_at = Some(now + self.persist_interval());
+        }
+    }
+
+    /// 送信中なのがゼロウィンドウへのプローブの1バイトだけか
+    fn probe_in_flight(&self) -> bool {
+        self.probe_end == Some(self.send_buffer.next_seq()) && self.send_buffer.unacked_data() == 1
+    }
+
+    /// プローブの間隔: RTOから始めて毎回2倍（MAX_RTOで頭打ち）
+    fn persist_interval(&self) -> Duration {
+        self.rto
+            .rto()
+            .saturating_mul(1u32.checked_shl(self.persist_backoffs).unwrap_or(u32::MAX))
+            .min(MAX_RTO)
+    }
+
+    /// persistタイマーの処理: 次の送信でプローブを送るよう予約し、間隔を2倍にして再起動する
+    fn on_persist_timeout(&mut self, now: Instant) -> Result<(), TimeoutError> {
+        match self.persist_at {
+            Some(at) if at <= now => {}
+            _ => return Ok(()),
+        }
+        if self.unanswered_probes >= self.max_window_probes {
+            self.persist_at = None;
+            return Err(TimeoutError::WindowProbe {
+                probes: self.unanswered_probes,
+                window: self.snd_wnd,
+            });
+        }
+        self.probe_pending = true;
+        self.unanswered_probes += 1;
+        self.window_probes += 1;
+        self.persist_backoffs += 1;
+        self.persist_at = Some(now + self.persist_interval());
+        Ok(())
+    }
+
+    /// ウィンドウプローブを作る
+    ///
+    /// ウィンドウが0なら、ウィンドウの外の1バイトを送って相手にACKを返させる。
+    /// この1バイトは送信済み（SND.NXTを進める）として扱い、確認されるまで同じバイトを
+    /// プローブに使う（Linuxと同じ）。小さなウィンドウが開いているのに
+    /// SWS回避で止まっていたなら、入る分だけ送る
+    fn window_probe(&mut self, now: Instant) -> Option<TcpSegment> {
+        let usable = self.usable_window();
+        let (seq, data) = if usable == 0 && self.probe_in_flight() {
+            let una = self.send_buffer.unacked_seq();
+            (una, self.send_buffer.peek_unacked(1).to_vec())
+        } else {
+            let size = if usable == 0 { 1 } else { self.mss.min(usable) };
+            (
+                self.send_buffer.next_seq(),
+                self.send_buffer.peek(size).to_vec(),
+            )
+        };
+        if data.is_empty() {
+            return None;
+        }
+        if seq == self.send_buffer.next_seq() {
+            self.send_buffer.consume(data.len());
+            if usable == 0 {
+                self.probe_end = Some(self.send_buffer.next_seq());
+            } else if self.retransmit_at.is_none() {
+                self.retransmit_at = Some(now + self.rto.rto());
+            }
+        }
+        self.ack_pending = false;
+        let window = self.advertise_window();
+        Some(
+            TcpSegment::new(
+                seq,
+                self.generate_ack(),
+                tcp_flags::ACK | tcp_flags::PSH,
+                window,
+            )
+            .with_options(self.ack_options())
+            .with_payload(data),
+        )
+    }
+
+    /// 再送タイマーの処理（RFC 6298 Section 5.4 - 5.6）
+    ///
+    /// 期限が来ていれば未確認の先頭セグメントを再送するよう予約し、RTOを2倍にして
+    /// タイマーを再起動する
+    fn on_retransmit_timeout(&mut self, now: Instant) -> Result<(), TimeoutError> {
+        match self.retransmit_at {
+            Some(at) if at <= now => {}
+            _ => return Ok(()),
+        }
+        if self.send_buffer.unacked_data() == 0 && !self.fin_outstanding() {
+            self.retransmit_at = None;
+            return Ok(());
+        }
+        if self.consecutive_timeouts >= self.max_retransmissions {
+            self.retransmit_at = None;
+            return Err(TimeoutError::Retransmission {
+                timeouts: self.consecutive_timeouts,
+                una: self.send_buffer.unacked_seq(),
+            });
+        }
+
+        self.congestion.on_timeout(
+            self.send_buffer.unacked_data(),
+            self.consecutive_timeouts > 0,
+        );
+        // タイムアウトしたらfast recoveryは打ち切り、ここまでに送った分への
+        // 重複ACKでは再びfast retransmitしない（RFC 6582 Section 3.2 (4)）
+        self.in_recovery = false;
+        self.dup_acks = 0;
+        self.recover = self.send_buffer.next_seq();
+        self.consecutive_timeouts += 1;
+        self.retransmit_pending = true;
+        // Karn: 再送した範囲のACKではRTTを測らない
+        self.rtt_probe = None;
+        self.rto.backoff();
+        self.retransmit_at = Some(now + self.rto.rto());
+        Ok(())
+    }
+
+    /// ACKで送信バッファを進め、RTTの測定と再送タイマーを更新する
+    ///
+    /// FINもシーケンス番号を1つ消費するので、FINのACKはデータの末尾+1を指す
+    fn on_ack(&mut self, segment: &TcpSegment, now: Instant) -> Result<(), String> {
+        let covers_fin = self.fin_sent && segment.ack == self.fin_seq().wrapping_add(1);
+        let ack_seq = if covers_fin {
+            self.fin_seq()
+        } else {
+            segment.ack
+        };
+        let una = self.send_buffer.unacked_seq();
+        let acked = self.send_buffer.acknowledge(ack_seq)?;
+        // 最も古い未確認の送信のTSval（TSecrはこれより前にはならない）
+        let ts_oldest = self.ts_sent.front().map(|&(_, tsval)| tsval);
+        while self
+            .ts_sent
+            .front()
+            .is_some_and(|&(end, _)| end <= segment.ack)
+        {
+            self.ts_sent.pop_front();
+        }
+        let fin_ack = covers_fin && !self.fin_acked;
+        if fin_ack {
+            // FIN-WAIT-1 → FIN-WAIT-2, CLOSING → TIME-WAIT, LAST-ACK → CLOSED
+            self.fin_acked = true;
+            self.state.transition(TcpEvent::ReceiveAck)?;
+        }
+        let window_changed = self.peer_window(segment) != self.snd_wnd;
+        if ack_seq >= una {
+            self.update_send_window(segment);
+            // 相手は生きている（プローブへの応答でもウィンドウが0のままのことはある）
+            self.unanswered_probes = 0;
+        }
+        if self.sack_enabled {
+            for option in &segment.options {
+                if let TcpOption::Sack(blocks) = option {
+                    for &(left, right) in blocks {
+                        self.send_buffer
+                            .on_sack(SequenceNumber::new(left), SequenceNumber::new(right));
+                    }
+                }
+            }
+        }
+        if acked == 0 && !fin_ack {
+            // RFC 5681 Section 2の重複ACKの条件
+            let duplicate = ack_seq == una
+                && self.send_buffer.unacked_data() > 0
+                && segment.payload.is_empty()
+                && !segment.has_flag(tcp_flags::SYN | tcp_flags::FIN)
+                && !window_changed
+                && !self.probe_in_flight();
+            if duplicate {
+                self.on_duplicate_ack();
+            }
+            return Ok(());
+        }
+
+        self.dup_acks = 0;
+        if !self.in_recovery {
+            self.congestion.on_ack(acked);
+        } else if ack_seq >= self.recover {
+            // full ACK: 回復を始めた時点の送信済みデータがすべて届いた
+            self.in_recovery = false;
+            self.congestion
+                .on_exit_recovery(self.send_buffer.unacked_data());
+        } else if !self.sack_enabled {
+            // partial ACK: 同じウィンドウの中の次の穴もすぐに再送する（NewReno）
+            self.congestion.on_partial_ack(acked);
+            self.schedule_fast_retransmit();
+        }
+        match segment.timestamps().filter(|_| self.ts_enabled) {
+            // RFC 7323 Section 4: 確認応答が進んだACKのTSecrからRTTを測る。
+            // 自分の送信時刻が返ってくるので、再送したセグメントのACKでも測れる。
+            // TSecrが最も古い未確認の送信のTSvalから今までの間になければ、0や古い値・
+            // 偽の値なので測らない（RFC 7323 Section 4.1, Appendix G）
+            Some((_, tsecr)) => {
+                let ts_now = self.ts_now(now);
+                let valid = ts_oldest.is_some_and(|oldest| {
+                    tsecr.wrapping_sub(oldest) <= ts_now.wrapping_sub(oldest)
+                });
+                if valid {
+                    let rtt = ts_now.wrapping_sub(tsecr);
+                    self.rto.on_rtt_sample(Duration::from_millis(rtt as u64));
+                }
+                self.rtt_probe = None;
+            }
+            None => {
+                if let Some((end, sent_at)) = self.rtt_probe {
+                    if ack_seq >= end {
+                        self.rto
+                            .on_rtt_sample(now.saturating_duration_since(sent_at));
+                        self.rtt_probe = None;
+                    }
+                }
+            }
+        }
+        self.consecutive_timeouts = 0;
+
+        // RFC 6298 (5.2)(5.3): 全部確認されたら止め、そうでなければ再起動
+        self.retransmit_at = if self.send_buffer.unacked_data() == 0 && !self.fin_outstanding() {
+            self.retransmit_pending = false;
+            None
+        } else {
+            Some(now + self.rto.rto())
+        };
+        Ok(())
+    }
+
+    /// 送信ウィンドウを更新する（RFC 9293 Section 3.10.7.4）
+    ///
+    /// 古いセグメントが運んだウィンドウで上書きしないよう、SND.WL1/WL2より新しいものだけを使う
+    fn update_send_window(&mut self, segment: &TcpSegment) {
+        if self.snd_wl1 < segment.seq
+            || (self.snd_wl1 == segment.seq && self.snd_wl2 <= segment.ack)
+        {
+            self.snd_wnd = self.peer_window(segment);
+            self.snd_wl1 = segment.seq;
+            self.snd_wl2 = segment.ack;
+            self.max_snd_wnd = self.max_snd_wnd.max(self.snd_wnd);
+        }
+    }
+
+    /// 重複ACKを数え、3つ目でfast retransmitしてfast recoveryに入る
+    fn on_duplicate_ack(&mut self) {
+        self.dup_acks += 1;
+        if self.in_recovery {
+            // 受信側に届いたセグメントが1つ回線から抜けたので、その分新しく送れる
+            // （SACKならpipeが減るのでcwndは膨らませない）
+            if !self.sack_enabled {
+                self.congestion.on_recovery_dup_ack();
+            }
+            return;
+        }
+        let una = self.send_buffer.unacked_seq();
+        // SACKがあれば、重複ACKが3つ揃う前でも先頭が失われたと分かることがある
+        let lost = self.sack_enabled && self.is_lost(una);
+        // recoverより前のデータへの重複ACKは、タイムアウト後の再送で生じたものかもしれない
+        if (self.dup_acks < DUP_ACK_THRESHOLD && !lost) || una < self.recover {
+            return;
+        }
+        self.in_recovery = true;
+        self.recoveries += 1;
+        self.recover = self.send_buffer.next_seq();
+        self.high_rxt = una;
+        let flight_size = self.send_buffer.unacked_data();
+        if self.sack_enabled {
+            self.congestion.on_enter_sack_recovery(flight_size);
+        } else {
+            self.congestion.on_enter_recovery(flight_size);
+        }
+        self.schedule_fast_retransmit();
+    }
+
+    /// 未確認の先頭セグメントを次のpoll_transmit()で再送する
+    fn schedule_fast_retransmit(&mut self) {
+        self.retransmit_pending = true;
+        self.fast_retransmissions += 1;
+        // Karn: 再送した範囲のACKではRTTを測らない
+        self.rtt_probe = None;
+    }
 }
 
 // =============================================================================
 // デモ用main関数
 // =============================================================================
 
+// lib.rsにinclude!されたときは呼ばれない
+#[allow(dead_code)]
 fn main() {
     println!("Step 5: データ送受信とシーケンス番号管理");
     println!("===========================================");
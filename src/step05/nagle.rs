@@ -0,0 +1,72 @@
+// Nagle's algorithm (RFC 896, RFC 1122 Section 4.2.3.4)
+//
+// アプリケーションが1バイトずつ書き込むと、そのままでは40バイトのヘッダーに
+// 1バイトのデータを載せたセグメントが並んでしまう。そこで、送信済みで未確認の
+// 小さなセグメントがある間は、次の小さなセグメントを送らずに貯めておく:
+//
+//   MSS以上送れる                           → すぐ送る
+//   未確認の小さなセグメントがない          → 小さくてもすぐ送る
+//   未確認の小さなセグメントがある          → ACKが届くか、MSS分貯まるまで待つ
+//
+// 1往復に小さなセグメントは1つだけになる。RFC 896の元の規則は「未確認のデータが
+// あれば待つ」だが、それだと大きな転送の最後の端数まで1往復待たされるので、
+// Linuxと同じく小さなセグメントだけを数える（Minshallの変形）。
+// 対話的な操作のように遅延が問題になるときはNODELAYで無効にする（TCP_NODELAY）。
+//
+// CORKはその逆で、アイドルでもMSSに満たない分は送らずに貯め、uncorkしたときに
+// まとめて送る（TCP_CORK）。ヘッダーとボディを別々に書き込む場合などに使う。
+// どちらのモードでも、FINを送るとき（close）は残りのデータを送り切る。
+
+use super::SequenceNumber;
+
+/// 小さなセグメントを今送ってよいかを決める
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransmitPolicy {
+    nodelay: bool,
+    corked: bool,
+    // 最後に送った小さなセグメントの終わり（SND.SML）
+    snd_sml: Option<SequenceNumber>,
+}
+
+impl TransmitPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    /// trueにするとNagleのアルゴリズムを使わない
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    pub fn corked(&self) -> bool {
+        self.corked
+    }
+
+    pub fn set_corked(&mut self, corked: bool) {
+        self.corked = corked;
+    }
+
+    /// 未送信のデータ`unsent`バイトのうち、MSSに満たない最後の部分を今送ってよいか
+    ///
+    /// `snd_una`は未確認の最小のシーケンス番号、`push`はデータの後にFINを送るとき
+    pub fn permits(&self, unsent: usize, mss: usize, snd_una: SequenceNumber, push: bool) -> bool {
+        if unsent >= mss || push {
+            return true;
+        }
+        if self.corked {
+            return false;
+        }
+        self.nodelay || self.snd_sml.is_none_or(|sml| sml <= snd_una)
+    }
+
+    /// `end`で終わる`len`バイトのセグメントを送った
+    pub fn on_send(&mut self, end: SequenceNumber, len: usize, mss: usize) {
+        if len < mss {
+            self.snd_sml = Some(end);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+// Event-driven I/O loop (epoll + timerfd)
+//
+// 1つのスレッドで多数の接続を動かすには、「パケットが届く」と「どれかの接続の
+// タイマーが期限を迎える」の両方を同時に待たなければならない。短い間隔で起きて
+// 確かめる（スリープでのポーリング）と、その分CPUを使い、届いてから気づくまでも遅れる。
+//
+// Reactorはデバイスのfdと、最も近いタイマーに合わせたtimerfdをepollに登録して、
+// どちらかが起きるまで眠る:
+//
+//   デバイスが読める    → データグラムを4タプルで接続に振り分け、on_segment()
+//   timerfdが期限切れ   → 期限の来た接続のon_timeout()
+//   最後に              → すべての接続のpoll_transmit()を送り、timerfdを次の期限に合わせる
+//
+// 接続（TcpConnection）は送受信を行わないので、Reactorは振り分けと送信だけを受け持つ。
+// どの接続にも当てはまらないセグメント（新しい接続のSYNなど）は呼び出し側に返す。
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use rust_tcp_handson_with_claude_code::step01::{parse_ip_header, IpHeader, PacketDevice};
+use rust_tcp_handson_with_claude_code::step04::TcpState;
+
+use super::{TcpConnection, TcpSegment, TimeoutError};
+
+/// 接続を区別するキー: (ローカル, リモート)
+pub type ConnectionKey = (SocketAddrV4, SocketAddrV4);
+
+/// epollに登録したfdの区別
+const TOKEN_DEVICE: u64 = 0;
+const TOKEN_TIMER: u64 = 1;
+
+/// `Reactor::poll()`で起きたこと
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReactorEvent {
+    /// 接続にセグメントが届いた（読めるデータや状態の変化を確かめる）
+    Segment(ConnectionKey),
+    /// 接続の期限の来たタイマーを処理した
+    Timer(ConnectionKey),
+    /// 接続がRSTで閉じた
+    Closed(ConnectionKey, String),
+    /// 再送・プローブ・キープアライブの上限に達し、接続を諦めた
+    TimedOut(ConnectionKey, TimeoutError),
+    /// どの接続にも当てはまらないセグメント
+    Unmatched {
+        key: ConnectionKey,
+        segment: TcpSegment,
+    },
+}
+
+/// 1つのパケットデバイスの上で多数の接続を動かすイベントループ
+pub struct Reactor<D: PacketDevice> {
+    device: D,
+    epoll: i32,
+    timer: i32,
+    connections: HashMap<ConnectionKey, TcpConnection>,
+}
+
+impl<D: PacketDevice> Reactor<D> {
+    /// `device`のfdとタイマーをepollに登録する。fdを持たないデバイスはエラー
+    pub fn new(device: D) -> Result<Self, Box<dyn Error>> {
+        let fd = device
+            .raw_fd()
+            .ok_or("Device has no file descriptor to wait on")?;
+
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(format!("epoll_create1 failed: {}", io::Error::last_os_error()).into());
+        }
+        let timer = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if timer < 0 {
+            let error = io::Error::last_os_error();
+            unsafe {
+                libc::close(epoll);
+            }
+            return Err(format!("timerfd_create failed: {}", error).into());
+        }
+
+        // ここから先の失敗ではDropがfdを閉じる
+        let reactor = Self {
+            device,
+            epoll,
+            timer,
+            connections: HashMap::new(),
+        };
+        reactor.register(fd, TOKEN_DEVICE)?;
+        reactor.register(timer, TOKEN_TIMER)?;
+        Ok(reactor)
+    }
+
+    fn register(&self, fd: i32, token: u64) -> Result<(), Box<dyn Error>> {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token,
+        };
+        let result = unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_ADD, fd, &mut event) };
+        if result < 0 {
+            return Err(format!("epoll_ctl failed: {}", io::Error::last_os_error()).into());
+        }
+        Ok(())
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// `local`と`remote`の間の接続を登録する
+    pub fn insert(
+        &mut self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        conn: TcpConnection,
+    ) -> Result<(), String> {
+        let key = (local, remote);
+        if self.connections.contains_key(&key) {
+            return Err(format!("Connection {} -> {} already exists", local, remote));
+        }
+        self.connections.insert(key, conn);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &ConnectionKey) -> Option<TcpConnection> {
+        self.connections.remove(key)
+    }
+
+    pub fn connection(&self, key: &ConnectionKey) -> Option<&TcpConnection> {
+        self.connections.get(key)
+    }
+
+    pub fn connection_mut(&mut self, key: &ConnectionKey) -> Option<&mut TcpConnection> {
+        self.connections.get_mut(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// すべての接続の中で最も近いタイマーの期限
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .filter_map(|conn| conn.poll_timeout())
+            .min()
+    }
+
+    /// パケットが届くか、タイマーの期限が来るか、`timeout`が過ぎるまで眠り、起きたことを処理する
+    ///
+    /// `timeout`がNoneなら、接続のタイマーかパケットで起きるまで待つ
+    pub fn poll(&mut self, timeout: Option<Duration>) -> Result<Vec<ReactorEvent>, Box<dyn Error>> {
+        // 書き込まれたデータなど、待つ前に送れるものは送っておく
+        self.flush()?;
+
+        let now = Instant::now();
+        let wake = self
+            .next_timeout()
+            .into_iter()
+            .chain(timeout.map(|timeout| now + timeout))
+            .min();
+        self.arm_timer(wake, now)?;
+
+        let mut ready = [libc::epoll_event { events: 0, u64: 0 }; 2];
+        let count = unsafe { libc::epoll_wait(self.epoll, ready.as_mut_ptr(), 2, -1) };
+        if count < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(format!("epoll_wait failed: {}", error).into());
+            }
+        }
+
+        let mut events = Vec::new();
+        for event in &ready[..count.max(0) as usize] {
+            match event.u64 {
+                TOKEN_DEVICE => self.receive(&mut events)?,
+                TOKEN_TIMER => self.clear_timer(),
+                _ => {}
+            }
+        }
+        self.expire_timers(Instant::now(), &mut events);
+        self.flush()?;
+        Ok(events)
+    }
+
+    /// すべての接続の送るべきセグメントを送る
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        let now = Instant::now();
+        for (&(local, remote), conn) in self.connections.iter_mut() {
+            while let Some(segment) = conn.poll_transmit(now) {
+                let tcp = segment.encode(local, remote)?;
+                let mut datagram =
+                    IpHeader::new(*local.ip(), *remote.ip(), tcp.len() as u16).to_wire_bytes(0);
+                datagram.extend_from_slice(&tcp);
+                self.device.send(&datagram)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 届いているデータグラムをすべて接続に振り分ける
+    fn receive(&mut self, events: &mut Vec<ReactorEvent>) -> Result<(), Box<dyn Error>> {
+        while let Some(datagram) = self.device.recv_timeout(Duration::ZERO)? {
+            if let Some(event) = self.dispatch(&datagram) {
+                events.push(event);
+            }
+        }
+        Ok(())
+    }
+
+    /// 1つのデータグラムを4タプルの一致する接続に渡す。壊れたものは捨てる
+    fn dispatch(&mut self, datagram: &[u8]) -> Option<ReactorEvent> {
+        let ip = parse_ip_header(datagram).ok()?;
+        let start = ip.header_length() as usize;
+        let end = (ip.total_length() as usize).min(datagram.len());
+        let bytes = datagram.get(start..end)?;
+        let ports = bytes.get(..4)?;
+        let remote = SocketAddrV4::new(ip.source_ip(), u16::from_be_bytes([ports[0], ports[1]]));
+        let local = SocketAddrV4::new(ip.dest_ip(), u16::from_be_bytes([ports[2], ports[3]]));
+        let segment = TcpSegment::decode(bytes, remote, local).ok()?;
+
+        let key = (local, remote);
+        let Some(conn) = self.connections.get_mut(&key) else {
+            return Some(ReactorEvent::Unmatched { key, segment });
+        };
+        match conn.on_segment(&segment, Instant::now()) {
+            Ok(()) => Some(ReactorEvent::Segment(key)),
+            // RSTで閉じたときだけ知らせ、範囲外などで受け付けなかったものは捨てる
+            Err(e) if conn.state() == TcpState::Closed => Some(ReactorEvent::Closed(key, e)),
+            Err(_) => None,
+        }
+    }
+
+    /// 期限の来たタイマーを持つ接続のon_timeout()を呼ぶ
+    fn expire_timers(&mut self, now: Instant, events: &mut Vec<ReactorEvent>) {
+        for (&key, conn) in self.connections.iter_mut() {
+            if conn.poll_timeout().is_none_or(|at| at > now) {
+                continue;
+            }
+            match conn.on_timeout(now) {
+                Ok(()) => events.push(ReactorEvent::Timer(key)),
+                Err(e) => events.push(ReactorEvent::TimedOut(key, e)),
+            }
+        }
+    }
+
+    /// timerfdを`at`に合わせる。Noneなら止める
+    fn arm_timer(&self, at: Option<Instant>, now: Instant) -> Result<(), Box<dyn Error>> {
+        // it_valueが0だとタイマーが止まるので、過ぎた期限は1nsにしてすぐ起こす
+        let value = at.map_or(Duration::ZERO, |at| {
+            at.saturating_duration_since(now)
+                .max(Duration::from_nanos(1))
+        });
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: value.as_secs() as libc::time_t,
+                tv_nsec: value.subsec_nanos() as libc::c_long,
+            },
+        };
+        let result = unsafe { libc::timerfd_settime(self.timer, 0, &spec, std::ptr::null_mut()) };
+        if result < 0 {
+            return Err(format!("timerfd_settime failed: {}", io::Error::last_os_error()).into());
+        }
+        Ok(())
+    }
+
+    /// 期限切れの回数を読み捨てて、timerfdを読み込み可能でなくする
+    fn clear_timer(&self) {
+        let mut expirations = 0u64;
+        unsafe {
+            libc::read(
+                self.timer,
+                &mut expirations as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            );
+        }
+    }
+}
+
+impl<D: PacketDevice> Drop for Reactor<D> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.timer);
+            libc::close(self.epoll);
+        }
+    }
+}
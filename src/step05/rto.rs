@@ -0,0 +1,103 @@
+// Retransmission timeout (RFC 6298)
+//
+// 往復時間（RTT）の測定値から再送タイムアウト（RTO）を計算する。
+//
+//   最初の測定値R:  SRTT = R,  RTTVAR = R/2
+//   以降の測定値R': RTTVAR = (1 - β)·RTTVAR + β·|SRTT - R'|
+//                   SRTT   = (1 - α)·SRTT   + α·R'
+//   RTO = SRTT + max(G, K·RTTVAR)    （α = 1/8, β = 1/4, K = 4）
+//
+// 再送したセグメントのACKからはRTTを測らない（Karnのアルゴリズム）。
+// どちらの送信へのACKか区別できないため。これは呼び出し側の責任で、
+// タイムアウトのたびにbackoff()でRTOを2倍にする。
+
+use std::time::Duration;
+
+/// RTTを測る前のRTO（RFC 6298 Section 2.1）
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// RTOの下限（RFC 6298 Section 2.4）
+pub const MIN_RTO: Duration = Duration::from_secs(1);
+
+/// RTOの上限（RFC 6298 Section 2.5: 少なくとも60秒）
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// クロックの粒度G
+pub const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+const K: u32 = 4;
+
+/// SRTT/RTTVARからRTOを求める
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RtoEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    // 測定値から計算したRTO（バックオフ前）
+    base_rto: Duration,
+    // 測定値が得られるまでにRTOを2倍にした回数
+    backoffs: u32,
+}
+
+impl Default for RtoEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RtoEstimator {
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            base_rto: INITIAL_RTO,
+            backoffs: 0,
+        }
+    }
+
+    /// 現在のRTO（バックオフ込み、MAX_RTOで頭打ち）
+    pub fn rto(&self) -> Duration {
+        self.base_rto
+            .saturating_mul(1u32.checked_shl(self.backoffs).unwrap_or(u32::MAX))
+            .min(MAX_RTO)
+    }
+
+    /// 平滑化したRTT。まだ測定していなければNone
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// RTOを2倍にした回数（MAX_RTOに達した後は増えない）
+    pub fn backoffs(&self) -> u32 {
+        self.backoffs
+    }
+
+    /// 再送していないセグメントのRTT測定値を反映する（RFC 6298 Section 2.2, 2.3）
+    ///
+    /// 新しい測定値が得られたらバックオフも解除する（Karnのアルゴリズム）
+    pub fn on_rtt_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let srtt = self.srtt.expect("set above");
+        self.base_rto = (srtt + CLOCK_GRANULARITY.max(self.rttvar * K)).clamp(MIN_RTO, MAX_RTO);
+        self.backoffs = 0;
+    }
+
+    /// 再送タイマーが切れたのでRTOを2倍にする（RFC 6298 Section 5.5）
+    pub fn backoff(&mut self) {
+        if self.rto() < MAX_RTO {
+            self.backoffs += 1;
+        }
+    }
+}
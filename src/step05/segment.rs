@@ -0,0 +1,135 @@
+// TCP segment with header fields
+//
+// Phase DのSegmentはシーケンス番号とデータだけを持つ。回線に流すには
+// ACK番号・フラグ・ウィンドウ・オプションが必要なので、それらをまとめて
+// Step02のTcpHeaderとの相互変換（チェックサム付き）を提供する。
+
+use super::SequenceNumber;
+use rust_tcp_handson_with_claude_code::step02::{tcp_flags, TcpHeader, TcpOption};
+use std::net::SocketAddrV4;
+
+/// ヘッダー情報付きのTCPセグメント
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpSegment {
+    pub seq: SequenceNumber,
+    pub ack: SequenceNumber,
+    pub flags: u8,
+    pub window: u16,
+    pub options: Vec<TcpOption>,
+    pub payload: Vec<u8>,
+}
+
+impl TcpSegment {
+    /// データ・オプションなしのセグメントを作成
+    pub fn new(seq: SequenceNumber, ack: SequenceNumber, flags: u8, window: u16) -> Self {
+        Self {
+            seq,
+            ack,
+            flags,
+            window,
+            options: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn with_payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    pub fn with_options(mut self, options: Vec<TcpOption>) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Timestampsオプションの(TSval, TSecr)
+    pub fn timestamps(&self) -> Option<(u32, u32)> {
+        self.options.iter().find_map(|option| match *option {
+            TcpOption::Timestamps { tsval, tsecr } => Some((tsval, tsecr)),
+            _ => None,
+        })
+    }
+
+    /// シーケンス番号空間で占める長さ（SYNとFINはそれぞれ1つ消費する）
+    pub fn seq_len(&self) -> u32 {
+        let mut len = self.payload.len() as u32;
+        if self.has_flag(tcp_flags::SYN) {
+            len += 1;
+        }
+        if self.has_flag(tcp_flags::FIN) {
+            len += 1;
+        }
+        len
+    }
+
+    /// このセグメントへの応答として送るRST（RFC 9293 Section 3.10.7.1）。RSTにはRSTを返さない
+    ///
+    /// ACKが付いていればそのACK番号をシーケンス番号にし、なければ受け取った範囲の次をACKする
+    pub fn reset_reply(&self) -> Option<TcpSegment> {
+        if self.has_flag(tcp_flags::RST) {
+            return None;
+        }
+        Some(if self.has_flag(tcp_flags::ACK) {
+            TcpSegment::new(self.ack, SequenceNumber::new(0), tcp_flags::RST, 0)
+        } else {
+            TcpSegment::new(
+                SequenceNumber::new(0),
+                self.seq.wrapping_add(self.seq_len()),
+                tcp_flags::RST | tcp_flags::ACK,
+                0,
+            )
+        })
+    }
+
+    /// チェックサム付きのTCPセグメント（ヘッダー + データ）に変換
+    pub fn encode(&self, source: SocketAddrV4, dest: SocketAddrV4) -> Result<Vec<u8>, String> {
+        let mut header = TcpHeader::new(
+            source.port(),
+            dest.port(),
+            self.seq.value(),
+            self.ack.value(),
+            self.flags,
+            self.window,
+        );
+        header.set_options(&self.options)?;
+        header.calculate_checksum(
+            u32::from(*source.ip()),
+            u32::from(*dest.ip()),
+            &self.payload,
+        );
+
+        let mut bytes = header.to_bytes();
+        bytes.extend_from_slice(&self.payload);
+        Ok(bytes)
+    }
+
+    /// 受信したTCPセグメントを解析する
+    ///
+    /// チェックサムが合わない、宛先ポートが違う場合はエラー（セグメントは破棄される）
+    pub fn decode(bytes: &[u8], source: SocketAddrV4, dest: SocketAddrV4) -> Result<Self, String> {
+        let header = TcpHeader::from_bytes(bytes)?;
+        let payload = &bytes[header.header_len()..];
+        if !header.verify_checksum(u32::from(*source.ip()), u32::from(*dest.ip()), payload) {
+            return Err("TCP checksum mismatch".to_string());
+        }
+        if header.get_destination_port() != dest.port() {
+            return Err(format!(
+                "Unexpected destination port {}",
+                header.get_destination_port()
+            ));
+        }
+
+        Ok(Self {
+            seq: SequenceNumber::new(header.get_sequence_number()),
+            ack: SequenceNumber::new(header.get_ack_number()),
+            flags: header.get_flags(),
+            window: header.get_window_size(),
+            options: header.options()?,
+            payload: payload.to_vec(),
+        })
+    }
+}
@@ -0,0 +1,493 @@
+// Simulated network with a virtual clock
+//
+// 「パケットロスのシミュレーション」用の仮想回線。
+// 2つのTcpConnectionを片方向ずつのリンクでつなぎ、シード付きの乱数で
+// 損失・遅延・重複・破損・順序入れ替えを起こす。
+// 時刻は仮想時計なので、何秒分の通信でも実時間を待たずに終わり、
+// 同じシード・同じ設定なら毎回まったく同じ結果（トレース）になる。
+//
+//   A (10.0.0.1:49152)  --- link A→B --->  B (10.0.0.2:80)
+//                       <--- link B→A ---
+
+use super::{SequenceNumber, TcpConnection, TcpSegment};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+/// シード付きの疑似乱数生成器（SplitMix64）
+///
+/// 外部クレートに頼らず、プラットフォームに依存しない同じ系列を生成する
+#[derive(Debug, Clone)]
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// [0, 1) の一様乱数
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// 確率`probability`でtrue（0以下なら乱数を消費しない）
+    pub fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    /// [0, bound) の一様乱数（bound == 0 なら 0）
+    pub fn below(&mut self, bound: u64) -> u64 {
+        if bound == 0 {
+            0
+        } else {
+            self.next_u64() % bound
+        }
+    }
+}
+
+/// 回線の両端
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    A,
+    B,
+}
+
+impl Side {
+    pub fn peer(self) -> Side {
+        match self {
+            Side::A => Side::B,
+            Side::B => Side::A,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Side::A => 0,
+            Side::B => 1,
+        }
+    }
+}
+
+/// 片方向リンクの特性
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConfig {
+    /// 片道の伝搬遅延
+    pub delay: Duration,
+    /// 遅延に加わる揺らぎの最大値（0..=jitter の一様分布）
+    pub jitter: Duration,
+    /// セグメントが失われる確率
+    pub loss_rate: f64,
+    /// セグメントが2つに複製される確率
+    pub duplicate_rate: f64,
+    /// 1ビット反転する確率（受信側のチェックサム検証で破棄される）
+    pub corrupt_rate: f64,
+    /// `reorder_delay`だけ余計に遅れ、後続に追い越される確率
+    pub reorder_rate: f64,
+    pub reorder_delay: Duration,
+}
+
+impl Default for LinkConfig {
+    /// 遅延10msの理想的なリンク
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+            loss_rate: 0.0,
+            duplicate_rate: 0.0,
+            corrupt_rate: 0.0,
+            reorder_rate: 0.0,
+            reorder_delay: Duration::ZERO,
+        }
+    }
+}
+
+/// 片方向リンクの統計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// 送信側が送り出したセグメント数
+    pub sent: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub corrupted: u64,
+    pub reordered: u64,
+    /// 受信側に届き、チェックサム検証を通ったセグメント数
+    pub delivered: u64,
+    /// 受信側で破棄されたセグメント数（チェックサム不一致など）
+    pub discarded: u64,
+}
+
+/// トレースに記録するイベント
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkEvent {
+    Sent,
+    Dropped,
+    Duplicated,
+    Corrupted,
+    Delivered,
+    Discarded,
+}
+
+/// トレース1行分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// シミュレーション開始からの仮想時刻
+    pub at: Duration,
+    /// セグメントを送った側
+    pub from: Side,
+    pub event: LinkEvent,
+    pub seq: SequenceNumber,
+    pub ack: SequenceNumber,
+    pub flags: u8,
+    pub payload_len: usize,
+}
+
+/// 回線上を移動中のセグメント
+#[derive(Debug)]
+struct InFlight {
+    deliver_at: Duration,
+    // 同時刻に届くものは送った順に渡す
+    order: u64,
+    from: Side,
+    bytes: Vec<u8>,
+    original: TcpSegment,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.order).cmp(&(other.deliver_at, other.order))
+    }
+}
+
+#[derive(Debug, Default)]
+struct SimLink {
+    config: LinkConfig,
+    stats: LinkStats,
+    // 確実に落とすセグメントの番号（このリンクで何番目に送られたか、0始まり）
+    scripted_drops: BTreeSet<u64>,
+}
+
+struct SimEndpoint {
+    address: SocketAddrV4,
+    connection: TcpConnection,
+}
+
+/// 2つのTcpConnectionをつなぐ決定的なシミュレーションネットワーク
+///
+/// 3-way handshakeは済んだものとして、確立済みの2つの接続から始める。
+/// `step()`のたびに両端から送信セグメントを取り出して回線に載せ、
+/// 次のイベント時刻まで仮想時計を進めてセグメントを届ける。
+/// 接続の再送タイマーも仮想時計で動く
+pub struct SimNetwork {
+    origin: Instant,
+    elapsed: Duration,
+    rng: SimRng,
+    endpoints: [SimEndpoint; 2],
+    links: [SimLink; 2],
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    next_order: u64,
+    trace: Vec<TraceEntry>,
+}
+
+impl SimNetwork {
+    /// 両方向とも`config`のリンクでつながったネットワークを作成
+    pub fn new(seed: u64, config: LinkConfig) -> Self {
+        let mut rng = SimRng::new(seed);
+        let isn_a = SequenceNumber::new(rng.next_u64() as u32);
+        let isn_b = SequenceNumber::new(rng.next_u64() as u32);
+        Self::with_rng(rng, config, isn_a, isn_b)
+    }
+
+    /// 両端のISNを指定して作成（シーケンス番号の一周を試すときに使う）
+    pub fn with_isns(
+        seed: u64,
+        config: LinkConfig,
+        isn_a: SequenceNumber,
+        isn_b: SequenceNumber,
+    ) -> Self {
+        Self::with_rng(SimRng::new(seed), config, isn_a, isn_b)
+    }
+
+    fn with_rng(
+        rng: SimRng,
+        config: LinkConfig,
+        isn_a: SequenceNumber,
+        isn_b: SequenceNumber,
+    ) -> Self {
+        let endpoint = |ip: [u8; 4], port: u16, local, remote| SimEndpoint {
+            address: SocketAddrV4::new(Ipv4Addr::from(ip), port),
+            connection: TcpConnection::new(local, remote),
+        };
+        let link = || SimLink {
+            config,
+            ..SimLink::default()
+        };
+
+        Self {
+            origin: Instant::now(),
+            elapsed: Duration::ZERO,
+            rng,
+            endpoints: [
+                endpoint([10, 0, 0, 1], 49152, isn_a, isn_b),
+                endpoint([10, 0, 0, 2], 80, isn_b, isn_a),
+            ],
+            links: [link(), link()],
+            in_flight: BinaryHeap::new(),
+            next_order: 0,
+            trace: Vec::new(),
+        }
+    }
+
+    /// `from`から出ていくリンクの特性を変更する
+    pub fn set_link_config(&mut self, from: Side, config: LinkConfig) {
+        self.links[from.index()].config = config;
+    }
+
+    pub fn link_config(&self, from: Side) -> LinkConfig {
+        self.links[from.index()].config
+    }
+
+    /// `from`から出ていくリンクの統計
+    pub fn stats(&self, from: Side) -> LinkStats {
+        self.links[from.index()].stats
+    }
+
+    /// `from`が`n`番目（0始まり）に送るセグメントを必ず落とす
+    pub fn drop_nth(&mut self, from: Side, n: u64) {
+        self.links[from.index()].scripted_drops.insert(n);
+    }
+
+    pub fn connection(&self, side: Side) -> &TcpConnection {
+        &self.endpoints[side.index()].connection
+    }
+
+    pub fn connection_mut(&mut self, side: Side) -> &mut TcpConnection {
+        &mut self.endpoints[side.index()].connection
+    }
+
+    pub fn address(&self, side: Side) -> SocketAddrV4 {
+        self.endpoints[side.index()].address
+    }
+
+    /// 仮想時計の現在時刻
+    pub fn now(&self) -> Instant {
+        self.origin + self.elapsed
+    }
+
+    /// シミュレーション開始からの仮想経過時間
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn trace(&self) -> &[TraceEntry] {
+        &self.trace
+    }
+
+    /// 回線上を移動中のセグメント数
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// 送信を回線に載せ、次のイベント時刻まで進めてそこで起きることを処理する
+    ///
+    /// 何も起きることがなければ（アイドルなら）false
+    pub fn step(&mut self) -> bool {
+        self.flush_transmits();
+        match self.next_event() {
+            Some(at) => {
+                self.advance_to(at);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// アイドルになるまで進める。`limit`の仮想時間内にアイドルにならなければfalse
+    pub fn run_until_idle(&mut self, limit: Duration) -> bool {
+        let deadline = self.elapsed + limit;
+        while self.elapsed <= deadline {
+            if !self.step() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// `condition`が成り立つまで進める。`limit`の仮想時間内に成り立たなければfalse
+    pub fn run_until<F>(&mut self, mut condition: F, limit: Duration) -> bool
+    where
+        F: FnMut(&SimNetwork) -> bool,
+    {
+        let deadline = self.elapsed + limit;
+        loop {
+            if condition(self) {
+                return true;
+            }
+            if self.elapsed >= deadline || !self.step() {
+                return condition(self);
+            }
+        }
+    }
+
+    /// 仮想時間を`duration`だけ進め、その間に起きることをすべて処理する
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.elapsed + duration;
+        loop {
+            self.flush_transmits();
+            match self.next_event() {
+                Some(at) if at <= end => self.advance_to(at),
+                _ => break,
+            }
+        }
+        self.elapsed = end;
+    }
+
+    /// 次にセグメントが届くか、どちらかのタイマーが切れる時刻
+    fn next_event(&self) -> Option<Duration> {
+        let arrival = self.in_flight.peek().map(|Reverse(p)| p.deliver_at);
+        let timers = self.endpoints.iter().filter_map(|endpoint| {
+            let at = endpoint.connection.poll_timeout()?;
+            Some(at.saturating_duration_since(self.origin))
+        });
+        arrival.into_iter().chain(timers).min()
+    }
+
+    /// 時刻`at`までに届くセグメントを渡し、期限の来たタイマーを処理する
+    ///
+    /// 受信側はセグメントを1つ受け取るたびに応答する（同時刻に届いても1つずつACKを返す）。
+    /// 同時刻ならセグメントを先に渡す（届いたACKで止まるタイマーは発火させない）
+    fn advance_to(&mut self, at: Duration) {
+        self.elapsed = self.elapsed.max(at);
+        while let Some(Reverse(p)) = self.in_flight.peek() {
+            if p.deliver_at > self.elapsed {
+                break;
+            }
+            let Reverse(p) = self.in_flight.pop().expect("peeked");
+            self.deliver(p);
+            self.flush_transmits();
+        }
+
+        let now = self.now();
+        for side in [Side::A, Side::B] {
+            // 再送の上限を超えた接続はタイマーを止めるだけ（このネットワークでは切断まで扱わない）
+            let _ = self.connection_mut(side).on_timeout(now);
+        }
+    }
+
+    fn flush_transmits(&mut self) {
+        let now = self.now();
+        for side in [Side::A, Side::B] {
+            while let Some(segment) = self.connection_mut(side).poll_transmit(now) {
+                self.transmit(side, segment);
+            }
+        }
+    }
+
+    /// セグメントを回線に載せる（ここで損失・重複・破損・遅延を決める）
+    fn transmit(&mut self, from: Side, segment: TcpSegment) {
+        let config = self.links[from.index()].config;
+        let index = self.links[from.index()].stats.sent;
+        self.links[from.index()].stats.sent += 1;
+        self.record(from, LinkEvent::Sent, &segment);
+
+        let scripted = self.links[from.index()].scripted_drops.remove(&index);
+        if scripted || self.rng.chance(config.loss_rate) {
+            self.links[from.index()].stats.dropped += 1;
+            self.record(from, LinkEvent::Dropped, &segment);
+            return;
+        }
+
+        let bytes = segment
+            .encode(self.address(from), self.address(from.peer()))
+            .expect("simulated segment must be encodable");
+
+        let copies = if self.rng.chance(config.duplicate_rate) {
+            self.links[from.index()].stats.duplicated += 1;
+            self.record(from, LinkEvent::Duplicated, &segment);
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut bytes = bytes.clone();
+            if self.rng.chance(config.corrupt_rate) {
+                let bit = self.rng.below(bytes.len() as u64 * 8) as usize;
+                bytes[bit / 8] ^= 1 << (bit % 8);
+                self.links[from.index()].stats.corrupted += 1;
+                self.record(from, LinkEvent::Corrupted, &segment);
+            }
+
+            let jitter = self.rng.below(config.jitter.as_nanos() as u64 + 1);
+            let mut delay = config.delay + Duration::from_nanos(jitter);
+            if self.rng.chance(config.reorder_rate) {
+                delay += config.reorder_delay;
+                self.links[from.index()].stats.reordered += 1;
+            }
+
+            self.in_flight.push(Reverse(InFlight {
+                deliver_at: self.elapsed + delay,
+                order: self.next_order,
+                from,
+                bytes,
+                original: segment.clone(),
+            }));
+            self.next_order += 1;
+        }
+    }
+
+    /// 届いたセグメントを受信側で解析し、接続に渡す
+    fn deliver(&mut self, packet: InFlight) {
+        let from = packet.from;
+        let to = from.peer();
+        match TcpSegment::decode(&packet.bytes, self.address(from), self.address(to)) {
+            Ok(segment) => {
+                self.links[from.index()].stats.delivered += 1;
+                self.record(from, LinkEvent::Delivered, &segment);
+                // 範囲外などで接続が受け付けなかったセグメントは、実際のTCPと同様に捨てる
+                let now = self.now();
+                let _ = self.connection_mut(to).on_segment(&segment, now);
+            }
+            Err(_) => {
+                self.links[from.index()].stats.discarded += 1;
+                self.record(from, LinkEvent::Discarded, &packet.original);
+            }
+        }
+    }
+
+    fn record(&mut self, from: Side, event: LinkEvent, segment: &TcpSegment) {
+        self.trace.push(TraceEntry {
+            at: self.elapsed,
+            from,
+            event,
+            seq: segment.seq,
+            ack: segment.ack,
+            flags: segment.flags,
+            payload_len: segment.payload.len(),
+        });
+    }
+}
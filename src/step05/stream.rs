@@ -0,0 +1,373 @@
+// std::io adapter
+//
+// TcpConnectionはI/Oを行わない（sans-IO）ので、そのままではstd::io::Read / Writeを
+// 受け取るコード（BufReader、io::copy、read_to_end …）に渡せない。TcpStreamは接続と
+// IPv4のパケットデバイスを1つにまとめ、読み書きできるようになるまで次を繰り返す:
+//
+//   poll_transmit()のセグメントを送る → 届いたデータグラムをon_segment()に渡す
+//   → 期限の来たタイマーをon_timeout()で処理する
+//
+// ノンブロッキングモードでは1回だけ回し、進めなければWouldBlockを返す。
+// 接続の失敗はio::ErrorKindに対応させ、一度失敗したら以降の読み書きも同じエラーにする:
+//
+//   RSTを受け取った                         → ConnectionReset
+//   再送・プローブ・キープアライブの上限    → TimedOut
+//   set_read_timeout / set_write_timeout    → TimedOut
+//   shutdown(Write)や閉じた接続への書き込み → BrokenPipe
+//
+// 上限に達したときのエラーはTimeoutErrorを包んでいるので、get_ref()から
+// 再送・プローブ・キープアライブのどれで諦めたのかを取り出せる。
+
+use std::io::{self, BufRead, Read, Write};
+use std::net::{Shutdown, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use rust_tcp_handson_with_claude_code::step01::{parse_ip_header, IpHeader, PacketDevice};
+use rust_tcp_handson_with_claude_code::step04::TcpState;
+
+use super::{TcpConnection, TcpSegment, TimeoutError};
+
+/// 期限のない待ちでも、この間隔でタイマーを確かめる
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// BufReadの内部バッファの大きさ
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// 接続が失敗した理由
+#[derive(Debug, Clone)]
+enum Failure {
+    /// RSTを受け取った
+    Reset(String),
+    /// 再送・プローブ・キープアライブの上限に達した
+    Timeout(TimeoutError),
+}
+
+impl Failure {
+    fn to_io_error(&self) -> io::Error {
+        match self {
+            Failure::Reset(message) => {
+                io::Error::new(io::ErrorKind::ConnectionReset, message.clone())
+            }
+            Failure::Timeout(reason) => io::Error::new(io::ErrorKind::TimedOut, reason.clone()),
+        }
+    }
+}
+
+/// 確立した接続を`std::io::Read` / `Write` / `BufRead`として使うハンドル
+pub struct TcpStream<D: PacketDevice> {
+    conn: TcpConnection,
+    device: D,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    nonblocking: bool,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    // 接続が失敗した理由（以降の読み書きはすべてこのエラーになる）
+    failure: Option<Failure>,
+    // shutdown(Read)の後はread()が0を返す
+    read_closed: bool,
+    // fill_buf()で接続から取り出し、まだconsume()されていないデータ
+    read_buffer: Vec<u8>,
+    read_pos: usize,
+}
+
+impl<D: PacketDevice> TcpStream<D> {
+    /// 確立した`conn`を、`device`を通して`local`と`remote`の間で使う
+    pub fn new(conn: TcpConnection, device: D, local: SocketAddrV4, remote: SocketAddrV4) -> Self {
+        Self {
+            conn,
+            device,
+            local,
+            remote,
+            nonblocking: false,
+            read_timeout: None,
+            write_timeout: None,
+            failure: None,
+            read_closed: false,
+            read_buffer: Vec::new(),
+            read_pos: 0,
+        }
+    }
+
+    pub fn connection(&self) -> &TcpConnection {
+        &self.conn
+    }
+
+    pub fn connection_mut(&mut self) -> &mut TcpConnection {
+        &mut self.conn
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.local
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.remote
+    }
+
+    /// trueにすると、読み書きできなければ待たずにWouldBlockを返す
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// read()が待つ時間の上限（Noneなら無制限）。0は指定できない（std::net::TcpStreamと同じ）
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    /// write()とflush()が待つ時間の上限（Noneなら無制限）
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.write_timeout = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// 接続の片方向または両方向を閉じる。FINはすぐに送る
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        self.check_failure()?;
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.read_closed = true;
+            self.read_buffer.clear();
+            self.read_pos = 0;
+        }
+        self.conn
+            .shutdown(how)
+            .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))?;
+        self.transmit()
+    }
+
+    fn check_failure(&self) -> io::Result<()> {
+        match &self.failure {
+            Some(failure) => Err(failure.to_io_error()),
+            None => Ok(()),
+        }
+    }
+
+    fn fail(&mut self, failure: Failure) -> io::Error {
+        let error = failure.to_io_error();
+        self.failure = Some(failure);
+        error
+    }
+
+    /// 送るべきセグメントをすべて送る
+    fn transmit(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        while let Some(segment) = self.conn.poll_transmit(now) {
+            let datagram = self.encode(&segment)?;
+            self.device.send(&datagram).map_err(device_error)?;
+        }
+        Ok(())
+    }
+
+    fn encode(&self, segment: &TcpSegment) -> io::Result<Vec<u8>> {
+        let tcp = segment
+            .encode(self.local, self.remote)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut datagram =
+            IpHeader::new(*self.local.ip(), *self.remote.ip(), tcp.len() as u16).to_wire_bytes(0);
+        datagram.extend_from_slice(&tcp);
+        Ok(datagram)
+    }
+
+    /// 接続を1回動かす: 送り、`block`なら`deadline`（かタイマー）まで届くのを待って処理し、
+    /// 期限の来たタイマーを処理する
+    fn drive(&mut self, block: bool, deadline: Option<Instant>) -> io::Result<()> {
+        self.transmit()?;
+        let now = Instant::now();
+        let wait = if block {
+            [
+                deadline,
+                self.conn.poll_timeout(),
+                Some(now + POLL_INTERVAL),
+            ]
+            .into_iter()
+            .flatten()
+            .min()
+            .map_or(Duration::ZERO, |at| at.saturating_duration_since(now))
+        } else {
+            Duration::ZERO
+        };
+
+        let mut next = self.device.recv_timeout(wait).map_err(device_error)?;
+        while let Some(datagram) = next {
+            self.on_datagram(&datagram)?;
+            next = self
+                .device
+                .recv_timeout(Duration::ZERO)
+                .map_err(device_error)?;
+        }
+
+        if let Err(e) = self.conn.on_timeout(Instant::now()) {
+            return Err(self.fail(Failure::Timeout(e)));
+        }
+        self.transmit()
+    }
+
+    /// 自分宛てのTCPセグメントを接続に渡す。壊れたものや他の接続宛てのものは捨てる
+    fn on_datagram(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let Ok(ip) = parse_ip_header(datagram) else {
+            return Ok(());
+        };
+        if ip.source_ip() != *self.remote.ip() || ip.dest_ip() != *self.local.ip() {
+            return Ok(());
+        }
+        let start = ip.header_length() as usize;
+        let end = (ip.total_length() as usize).min(datagram.len());
+        let Some(bytes) = datagram.get(start..end) else {
+            return Ok(());
+        };
+        let Ok(segment) = TcpSegment::decode(bytes, self.remote, self.local) else {
+            return Ok(());
+        };
+        // 範囲外などで受け付けなかったセグメントは捨てる。RSTで閉じたときだけ失敗にする
+        if let Err(e) = self.conn.on_segment(&segment, Instant::now()) {
+            if self.conn.state() == TcpState::Closed {
+                return Err(self.fail(Failure::Reset(e)));
+            }
+        }
+        Ok(())
+    }
+
+    /// 接続から最大`buf.len()`バイト読み取る。読めるまで（またはEOFまで）接続を動かす
+    fn read_connection(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.read_closed {
+            return Ok(0);
+        }
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        let mut polled = false;
+        loop {
+            if self.conn.recv_buffer().available() > 0 {
+                let data = self.conn.read(buf.len());
+                buf[..data.len()].copy_from_slice(&data);
+                // 読んでウィンドウが開いたら、ウィンドウ更新を送る
+                self.transmit()?;
+                return Ok(data.len());
+            }
+            if self.conn.is_eof() {
+                return Ok(0);
+            }
+            self.check_failure()?;
+            self.wait(deadline, &mut polled, "Read")?;
+        }
+    }
+
+    /// 読み書きできるようになるのを待つ（1回接続を動かす）
+    ///
+    /// ノンブロッキングモードでは待たずに1回だけ動かし、それでも進めなければ
+    /// （`polled`が立っていれば）WouldBlockを返す
+    fn wait(
+        &mut self,
+        deadline: Option<Instant>,
+        polled: &mut bool,
+        operation: &str,
+    ) -> io::Result<()> {
+        if self.nonblocking {
+            if *polled {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            *polled = true;
+            return self.drive(false, None);
+        }
+        if deadline.is_some_and(|at| Instant::now() >= at) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} timed out", operation),
+            ));
+        }
+        self.drive(true, deadline)
+    }
+}
+
+impl<D: PacketDevice> Read for TcpStream<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_pos < self.read_buffer.len() {
+            let n = buf.len().min(self.read_buffer.len() - self.read_pos);
+            buf[..n].copy_from_slice(&self.read_buffer[self.read_pos..self.read_pos + n]);
+            self.consume(n);
+            return Ok(n);
+        }
+        self.read_connection(buf)
+    }
+}
+
+impl<D: PacketDevice> BufRead for TcpStream<D> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.read_pos >= self.read_buffer.len() {
+            let mut buffer = std::mem::take(&mut self.read_buffer);
+            buffer.resize(READ_BUFFER_SIZE, 0);
+            let result = self.read_connection(&mut buffer);
+            buffer.truncate(*result.as_ref().unwrap_or(&0));
+            self.read_buffer = buffer;
+            self.read_pos = 0;
+            result?;
+        }
+        Ok(&self.read_buffer[self.read_pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.read_pos = (self.read_pos + amt).min(self.read_buffer.len());
+    }
+}
+
+impl<D: PacketDevice> Write for TcpStream<D> {
+    /// 送信バッファに入るだけ書き込む。空きができるまで接続を動かす
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.write_timeout.map(|timeout| Instant::now() + timeout);
+        let mut polled = false;
+        loop {
+            self.check_failure()?;
+            // shutdown(Write)の後や閉じた接続には、空きを待っても書き込めない
+            self.conn.send(&[]).map_err(broken_pipe)?;
+            if self.conn.send_buffer().available_space() > 0 {
+                let written = self.conn.send(buf).map_err(broken_pipe)?;
+                self.transmit()?;
+                return Ok(written);
+            }
+            self.wait(deadline, &mut polled, "Write")?;
+        }
+    }
+
+    /// 書き込んだデータがすべて相手に確認されるまで待つ
+    fn flush(&mut self) -> io::Result<()> {
+        let deadline = self.write_timeout.map(|timeout| Instant::now() + timeout);
+        let mut polled = false;
+        loop {
+            self.check_failure()?;
+            self.transmit()?;
+            let buffer = self.conn.send_buffer();
+            if buffer.unsent_data() == 0 && buffer.unacked_data() == 0 {
+                return Ok(());
+            }
+            self.wait(deadline, &mut polled, "Flush")?;
+        }
+    }
+}
+
+fn check_timeout(timeout: Option<Duration>) -> io::Result<Option<Duration>> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(timeout)
+}
+
+fn broken_pipe(e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, e)
+}
+
+fn device_error(e: Box<dyn std::error::Error>) -> io::Error {
+    io::Error::other(e.to_string())
+}
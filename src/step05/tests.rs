@@ -1,5 +1,10 @@
 use super::*;
 
+/// テスト用: シーケンス番号を短く書く
+fn seq(n: u32) -> SequenceNumber {
+    SequenceNumber::new(n)
+}
+
 // =============================================================================
 // Phase A: シーケンス番号の定義 - TDD Tests
 // =============================================================================
@@ -210,6 +215,19 @@ mod phase_b_tests {
         buffer.acknowledge(SequenceNumber::new(1005)).unwrap();
         assert_eq!(buffer.unacked_data(), 5); // 5バイト確認済み
     }
+
+    #[test]
+    fn test_send_buffer_rejects_ack_for_unsent_data() {
+        let mut buffer = SendBuffer::new(SequenceNumber::new(1000));
+        buffer.write(b"0123456789").unwrap();
+        buffer.consume(4);
+
+        // SND.NXT（1004）より先は、バッファにあってもまだ送っていない
+        assert!(buffer.acknowledge(SequenceNumber::new(1010)).is_err());
+        assert_eq!(buffer.unacked_seq(), SequenceNumber::new(1000));
+        assert_eq!(buffer.next_seq(), SequenceNumber::new(1004));
+        assert_eq!(buffer.unsent_data(), 6);
+    }
 }
 
 // =============================================================================
@@ -501,12 +519,38 @@ mod phase_e_tests {
         let data = b"Sending data";
         conn.send(data).unwrap();
 
-        // データを送信したことにする（実際のネットワーク送信は省略）
-        // ここではバッファ内部のconsume操作を想定
-        // テストでは直接バッファ操作するか、別途メソッドを用意
+        // データを送信する（セグメントは回線に流さない）。送っていないデータはACKできない
+        let segment = conn.poll_transmit(std::time::Instant::now()).unwrap();
+        assert_eq!(segment.payload, data);
 
         let ack_seq = local_isn.wrapping_add(data.len() as u32);
         conn.process_ack(ack_seq).unwrap();
+        assert_eq!(conn.send_buffer().unacked_data(), 0);
+    }
+
+    #[test]
+    fn test_ack_for_unsent_data_is_dropped() {
+        let now = std::time::Instant::now();
+        let mut conn = TcpConnection::new(SequenceNumber::new(0), SequenceNumber::new(500));
+        conn.send(&[7; 100]).unwrap();
+
+        // 送る前に届いた、未送信のデータまで確認するACK（データ付き）
+        let ack = TcpSegment::new(
+            SequenceNumber::new(500),
+            SequenceNumber::new(100),
+            tcp_flags::ACK,
+            65535,
+        )
+        .with_payload(b"dropped".to_vec());
+        conn.on_segment(&ack, now).unwrap();
+        assert_eq!(conn.send_buffer().unacked_seq(), SequenceNumber::new(0));
+        assert_eq!(conn.send_buffer().unsent_data(), 100);
+        assert_eq!(conn.recv_buffer().available(), 0);
+
+        // 最初に返すのはRCV.NXTを伝えるACK（データも捨てたので進まない）
+        let reply = conn.poll_transmit(now).unwrap();
+        assert_eq!(reply.ack, SequenceNumber::new(500));
+        assert_eq!(reply.seq, SequenceNumber::new(0));
     }
 }
 
@@ -583,7 +627,7 @@ mod phase_f_tests {
         assert_eq!(conn.recv_buffer().available(), 9); // 全部読める
 
         let all_data = conn.read(100);
-        assert_eq!(all_data, b"AAABBBBCCC");
+        assert_eq!(all_data, b"AAABBBCCC");
     }
 
     // Task F4: バッファ境界テスト
@@ -630,6 +674,3190 @@ mod phase_f_tests {
     }
 }
 
+// =============================================================================
+// シミュレーションネットワーク - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod simnet_tests {
+    use super::*;
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork, SimRng};
+    use std::time::{Duration, Instant};
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// AからBへdataを送り、アイドルになるまで進めてBが読めたデータを返す
+    ///
+    /// 届かないデータは再送の上限まで送り直すので、仮想時間は長めに与える
+    fn transfer(net: &mut SimNetwork, data: &[u8]) -> Vec<u8> {
+        net.connection_mut(Side::A).send(data).unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(3600)));
+        net.connection_mut(Side::B).read(usize::MAX)
+    }
+
+    fn impaired_link() -> LinkConfig {
+        LinkConfig {
+            jitter: Duration::from_millis(5),
+            loss_rate: 0.1,
+            duplicate_rate: 0.1,
+            corrupt_rate: 0.1,
+            reorder_rate: 0.1,
+            reorder_delay: Duration::from_millis(20),
+            ..LinkConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_rng_is_deterministic() {
+        let mut rng1 = SimRng::new(7);
+        let mut rng2 = SimRng::new(7);
+        let mut rng3 = SimRng::new(8);
+
+        let seq1: Vec<u64> = (0..16).map(|_| rng1.next_u64()).collect();
+        let seq2: Vec<u64> = (0..16).map(|_| rng2.next_u64()).collect();
+        let seq3: Vec<u64> = (0..16).map(|_| rng3.next_u64()).collect();
+        assert_eq!(seq1, seq2);
+        assert_ne!(seq1, seq3);
+
+        for _ in 0..1000 {
+            let x = rng1.next_f64();
+            assert!((0.0..1.0).contains(&x));
+            assert!(rng1.below(10) < 10);
+        }
+        assert!(!rng1.chance(0.0));
+        assert!(rng1.chance(1.0));
+    }
+
+    #[test]
+    fn test_ideal_link_transfer() {
+        let mut net = SimNetwork::new(1, LinkConfig::default());
+        let data = pattern(20_000);
+
+        let received = transfer(&mut net, &data);
+
+        assert_eq!(received, data);
+        // すべてのデータがACKされている
+        let sender = net.connection(Side::A).send_buffer();
+        assert_eq!(sender.unacked_data(), 0);
+        assert_eq!(sender.unsent_data(), 0);
+        // データ（A→B）とACK（B→A）で少なくとも1往復分の時間が経過
+        assert!(net.elapsed() >= Duration::from_millis(20));
+        assert_eq!(net.stats(Side::A).sent, 14); // ceil(20000 / 1460)
+        assert_eq!(net.stats(Side::A).delivered, 14);
+    }
+
+    #[test]
+    fn test_same_seed_same_trace() {
+        let run = |seed| {
+            let mut net = SimNetwork::new(seed, impaired_link());
+            net.connection_mut(Side::A).send(&pattern(30_000)).unwrap();
+            net.connection_mut(Side::B).send(&pattern(10_000)).unwrap();
+            net.run_until_idle(Duration::from_secs(60));
+            net.trace().to_vec()
+        };
+
+        let trace1 = run(42);
+        let trace2 = run(42);
+        let trace3 = run(43);
+
+        assert!(!trace1.is_empty());
+        assert_eq!(trace1, trace2);
+        assert_ne!(trace1, trace3);
+    }
+
+    #[test]
+    fn test_total_loss() {
+        let config = LinkConfig {
+            loss_rate: 1.0,
+            ..LinkConfig::default()
+        };
+        let mut net = SimNetwork::new(2, config);
+
+        let received = transfer(&mut net, &pattern(5000));
+
+        assert!(received.is_empty());
+        let stats = net.stats(Side::A);
+        assert_eq!(stats.dropped, stats.sent);
+        assert_eq!(stats.delivered, 0);
+        assert_eq!(net.stats(Side::B).sent, 0); // 何も届かないのでACKも出ない
+
+        // 初期ウィンドウ（3セグメント）だけ送り、先頭セグメントの再送を上限まで繰り返して諦める
+        let sender = net.connection(Side::A);
+        assert_eq!(sender.retransmissions(), DEFAULT_MAX_RETRANSMISSIONS as u64);
+        assert_eq!(stats.sent, 3 + DEFAULT_MAX_RETRANSMISSIONS as u64);
+        assert_eq!(sender.poll_timeout(), None);
+    }
+
+    #[test]
+    fn test_corruption_is_detected_by_checksum() {
+        let config = LinkConfig {
+            corrupt_rate: 1.0,
+            ..LinkConfig::default()
+        };
+        let mut net = SimNetwork::new(3, config);
+
+        let received = transfer(&mut net, &pattern(5000));
+
+        // 破損したセグメントはチェックサム検証で破棄され、受信側に渡らない
+        assert!(received.is_empty());
+        let stats = net.stats(Side::A);
+        assert_eq!(stats.corrupted, stats.sent);
+        assert_eq!(stats.discarded, stats.sent);
+        assert_eq!(stats.delivered, 0);
+    }
+
+    #[test]
+    fn test_duplicates_are_ignored() {
+        let config = LinkConfig {
+            duplicate_rate: 1.0,
+            ..LinkConfig::default()
+        };
+        let mut net = SimNetwork::new(4, config);
+        let data = pattern(10_000);
+
+        let received = transfer(&mut net, &data);
+
+        assert_eq!(received, data);
+        let stats = net.stats(Side::A);
+        assert_eq!(stats.duplicated, stats.sent);
+        assert_eq!(stats.delivered, stats.sent * 2);
+    }
+
+    #[test]
+    fn test_reordering_is_reassembled() {
+        let config = LinkConfig {
+            reorder_rate: 0.3,
+            reorder_delay: Duration::from_millis(30),
+            ..LinkConfig::default()
+        };
+        let mut net = SimNetwork::new(5, config);
+        let data = pattern(30_000);
+
+        let received = transfer(&mut net, &data);
+
+        assert_eq!(received, data);
+        assert!(net.stats(Side::A).reordered > 0);
+
+        // 受信順にシーケンス番号が後戻りしている箇所がある
+        let delivered: Vec<SequenceNumber> = net
+            .trace()
+            .iter()
+            .filter(|e| e.from == Side::A && e.event == LinkEvent::Delivered)
+            .map(|e| e.seq)
+            .collect();
+        assert!(delivered.windows(2).any(|w| w[1] < w[0]));
+    }
+
+    #[test]
+    fn test_scripted_drop_is_retransmitted() {
+        let mut net = SimNetwork::new(6, LinkConfig::default());
+        net.connection_mut(Side::A).set_mss(1000);
+        net.drop_nth(Side::A, 0);
+        let una = net.connection(Side::A).send_buffer().unacked_seq();
+        let data = pattern(3000);
+        net.connection_mut(Side::A).send(&data).unwrap();
+
+        // 先頭セグメントが失われ、RTO（1秒）までは後続が順序外のまま
+        net.run_for(Duration::from_millis(500));
+        assert!(net.connection(Side::B).recv_buffer().has_gap());
+        let first = &net.trace()[1];
+        assert_eq!(first.event, LinkEvent::Dropped);
+        assert_eq!(first.seq, una);
+
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+        assert_eq!(net.connection_mut(Side::B).read(usize::MAX), data);
+        assert_eq!(net.stats(Side::A).dropped, 1);
+        assert_eq!(net.connection(Side::A).retransmissions(), 1);
+        let resent = net
+            .trace()
+            .iter()
+            .filter(|e| e.from == Side::A && e.event == LinkEvent::Sent)
+            .nth(3)
+            .unwrap();
+        assert_eq!(resent.seq, una);
+        assert_eq!(resent.at, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_virtual_clock_does_not_wait() {
+        let config = LinkConfig {
+            delay: Duration::from_secs(5),
+            ..LinkConfig::default()
+        };
+        let mut net = SimNetwork::new(7, config);
+        let started = Instant::now();
+
+        let una = net.connection(Side::A).send_buffer().unacked_seq();
+        net.connection_mut(Side::A).send(b"hello").unwrap();
+        let acked = |net: &SimNetwork| net.connection(Side::A).send_buffer().unacked_seq() != una;
+        assert!(net.run_until(acked, Duration::from_secs(60)));
+
+        assert_eq!(net.connection_mut(Side::B).read(100), b"hello");
+        // データとACKで仮想時間は10秒進むが、実時間はほとんど経過しない
+        assert_eq!(net.elapsed(), Duration::from_secs(10));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // RTT(10秒)が初期RTO(1秒)より長いので、ACKが届く前に不要な再送が起きている
+        assert!(net.connection(Side::A).retransmissions() > 0);
+
+        net.run_for(Duration::from_secs(3));
+        assert_eq!(net.elapsed(), Duration::from_secs(13));
+    }
+
+    #[test]
+    fn test_segment_encode_decode_roundtrip() {
+        let net = SimNetwork::new(8, LinkConfig::default());
+        let (a, b) = (net.address(Side::A), net.address(Side::B));
+        let segment = TcpSegment::new(
+            SequenceNumber::new(100),
+            SequenceNumber::new(200),
+            tcp_flags::ACK | tcp_flags::PSH,
+            4096,
+        )
+        .with_payload(b"payload".to_vec());
+
+        let bytes = segment.encode(a, b).unwrap();
+        assert_eq!(TcpSegment::decode(&bytes, a, b).unwrap(), segment);
+
+        // 送信元アドレスが違えば疑似ヘッダーが変わりチェックサムが合わない
+        assert!(TcpSegment::decode(&bytes, b, b).is_err());
+    }
+}
+
+// =============================================================================
+// 再送タイマー（RFC 6298） - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod rto_tests {
+    use super::*;
+    use rto::{RtoEstimator, INITIAL_RTO, MAX_RTO, MIN_RTO};
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork};
+    use std::time::Duration;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_initial_rto() {
+        let rto = RtoEstimator::new();
+        assert_eq!(rto.rto(), INITIAL_RTO);
+        assert_eq!(rto.srtt(), None);
+        assert_eq!(rto.backoffs(), 0);
+    }
+
+    #[test]
+    fn test_first_and_subsequent_samples() {
+        let mut rto = RtoEstimator::new();
+
+        // 最初: SRTT = R, RTTVAR = R/2, RTO = SRTT + 4·RTTVAR
+        rto.on_rtt_sample(ms(2000));
+        assert_eq!(rto.srtt(), Some(ms(2000)));
+        assert_eq!(rto.rttvar(), ms(1000));
+        assert_eq!(rto.rto(), ms(6000));
+
+        // 以降: RTTVAR = 3/4·1000 + 1/4·|2000 - 1000|, SRTT = 7/8·2000 + 1/8·1000
+        rto.on_rtt_sample(ms(1000));
+        assert_eq!(rto.rttvar(), ms(1000));
+        assert_eq!(rto.srtt(), Some(ms(1875)));
+        assert_eq!(rto.rto(), ms(5875));
+    }
+
+    #[test]
+    fn test_rto_is_clamped() {
+        let mut rto = RtoEstimator::new();
+        rto.on_rtt_sample(ms(1));
+        assert_eq!(rto.rto(), MIN_RTO);
+
+        let mut rto = RtoEstimator::new();
+        rto.on_rtt_sample(Duration::from_secs(30));
+        assert_eq!(rto.rto(), MAX_RTO);
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let mut rto = RtoEstimator::new();
+        rto.on_rtt_sample(ms(2000)); // RTO = 6秒
+
+        let mut seen = Vec::new();
+        for _ in 0..5 {
+            rto.backoff();
+            seen.push(rto.rto());
+        }
+        assert_eq!(seen, [ms(12_000), ms(24_000), ms(48_000), MAX_RTO, MAX_RTO]);
+        assert_eq!(rto.backoffs(), 4);
+
+        // 新しい測定値でバックオフは解除される
+        rto.on_rtt_sample(ms(2000));
+        assert_eq!(rto.backoffs(), 0);
+        assert!(rto.rto() < ms(12_000));
+    }
+
+    #[test]
+    fn test_rtt_is_measured_on_ideal_link() {
+        let mut net = SimNetwork::new(11, LinkConfig::default());
+        net.connection_mut(Side::A).send(&[0u8; 500]).unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+
+        // 片道10msなので1往復20ms。RTOは下限の1秒
+        let sender = net.connection(Side::A);
+        assert_eq!(sender.rto().srtt(), Some(ms(20)));
+        assert_eq!(sender.rto().rto(), MIN_RTO);
+        assert_eq!(sender.retransmissions(), 0);
+        assert_eq!(sender.poll_timeout(), None);
+    }
+
+    #[test]
+    fn test_karn_skips_retransmitted_samples() {
+        let mut net = SimNetwork::new(12, LinkConfig::default());
+        net.drop_nth(Side::A, 0);
+        net.connection_mut(Side::A).send(&[0u8; 500]).unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+
+        // 再送したセグメントへのACKからはRTTを測らず、バックオフも残る
+        let sender = net.connection(Side::A);
+        assert_eq!(sender.retransmissions(), 1);
+        assert_eq!(sender.send_buffer().unacked_data(), 0);
+        assert_eq!(sender.rto().srtt(), None);
+        assert_eq!(sender.rto().rto(), ms(2000));
+    }
+
+    #[test]
+    fn test_retransmission_backoff_timing() {
+        let config = LinkConfig {
+            loss_rate: 1.0,
+            ..LinkConfig::default()
+        };
+        let mut net = SimNetwork::new(13, config);
+        net.connection_mut(Side::A).set_max_retransmissions(5);
+        net.connection_mut(Side::A).send(b"ping").unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(600)));
+
+        // 送信時刻: 0, 1, 3, 7, 15, 31秒（間隔が1, 2, 4, 8, 16秒と倍になる）
+        let sent: Vec<u64> = net
+            .trace()
+            .iter()
+            .filter(|e| e.event == LinkEvent::Sent)
+            .map(|e| e.at.as_secs())
+            .collect();
+        assert_eq!(sent, [0, 1, 3, 7, 15, 31]);
+        // 最後の再送のタイマー（32秒）が切れた時点で諦める
+        assert_eq!(net.elapsed(), Duration::from_secs(63));
+    }
+
+    #[test]
+    fn test_retransmission_limit_reports_reason() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_max_retransmissions(2);
+        conn.send(b"ping").unwrap();
+        conn.poll_transmit(now).unwrap();
+
+        let error = loop {
+            let at = conn
+                .poll_timeout()
+                .expect("retransmit timer must be running");
+            match conn.on_timeout(at) {
+                Ok(()) => while conn.poll_transmit(at).is_some() {},
+                Err(e) => break e,
+            }
+        };
+        // キープアライブと違い、接続は閉じずにタイマーだけが止まる
+        assert_eq!(
+            error,
+            TimeoutError::Retransmission {
+                timeouts: 2,
+                una: seq(0)
+            }
+        );
+        assert_eq!(conn.state(), TcpState::Established);
+        assert_eq!(conn.poll_timeout(), None);
+    }
+
+    #[test]
+    fn test_lossy_link_delivers_everything() {
+        let config = LinkConfig {
+            loss_rate: 0.2,
+            jitter: ms(5),
+            ..LinkConfig::default()
+        };
+        for seed in 0..5 {
+            let mut net = SimNetwork::new(seed, config);
+            let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+            net.connection_mut(Side::A).send(&data).unwrap();
+            assert!(net.run_until_idle(Duration::from_secs(3600)));
+
+            assert_eq!(
+                net.connection_mut(Side::B).read(usize::MAX),
+                data,
+                "seed {}",
+                seed
+            );
+            assert!(net.connection(Side::A).retransmissions() > 0);
+        }
+    }
+
+    #[test]
+    fn test_peek_unacked_is_retransmission_queue() {
+        let mut buffer = SendBuffer::new(SequenceNumber::new(100));
+        buffer.write(b"HelloWorld").unwrap();
+        assert!(buffer.peek_unacked(5).is_empty()); // まだ何も送っていない
+
+        buffer.consume(8);
+        assert_eq!(buffer.peek_unacked(5), b"Hello");
+        assert_eq!(buffer.peek_unacked(100), b"HelloWor");
+
+        buffer.acknowledge(SequenceNumber::new(105)).unwrap();
+        assert_eq!(buffer.peek_unacked(100), b"Wor");
+    }
+}
+
+// =============================================================================
+// 輻輳制御（RFC 5681） - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod congestion_tests {
+    use super::*;
+    use congestion::{initial_window, CongestionControl, FixedWindow, Reno};
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork};
+    use std::time::Duration;
+
+    const SMSS: usize = 1460;
+
+    /// Aが送ったセグメント数を送信時刻ごとにまとめる（理想リンクでは1往復ごとの送信量）
+    fn bursts(net: &SimNetwork) -> Vec<usize> {
+        let mut bursts: Vec<(Duration, usize)> = Vec::new();
+        for entry in net.trace() {
+            if entry.from != Side::A || entry.event != LinkEvent::Sent {
+                continue;
+            }
+            match bursts.last_mut() {
+                Some((at, count)) if *at == entry.at => *count += 1,
+                _ => bursts.push((entry.at, 1)),
+            }
+        }
+        bursts.into_iter().map(|(_, count)| count).collect()
+    }
+
+    fn transfer_with(congestion: Box<dyn CongestionControl>, len: usize) -> SimNetwork {
+        let mut net = SimNetwork::new(21, LinkConfig::default());
+        net.connection_mut(Side::A)
+            .set_congestion_control(congestion);
+        // 1往復ごとの増え方を見るため、受信側はセグメントごとにACKを返す
+        net.connection_mut(Side::B).set_delayed_ack_enabled(false);
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        net.connection_mut(Side::A).send(&data).unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+        assert_eq!(net.connection_mut(Side::B).read(usize::MAX), data);
+        net
+    }
+
+    #[test]
+    fn test_initial_window() {
+        // IW = min(4·SMSS, max(2·SMSS, 4380))
+        assert_eq!(initial_window(1460), 4380);
+        assert_eq!(initial_window(536), 2144);
+        assert_eq!(initial_window(4000), 8000);
+
+        let reno = Reno::new(SMSS);
+        assert_eq!(reno.window(), 4380);
+        assert!(reno.in_slow_start());
+    }
+
+    #[test]
+    fn test_slow_start_grows_per_ack() {
+        let mut reno = Reno::new(SMSS);
+        for _ in 0..3 {
+            reno.on_ack(SMSS);
+        }
+        assert_eq!(reno.window(), 4380 + 3 * SMSS);
+
+        // 1回のACKで増えるのは最大1セグメント
+        reno.on_ack(4 * SMSS);
+        assert_eq!(reno.window(), 4380 + 4 * SMSS);
+    }
+
+    #[test]
+    fn test_timeout_collapses_window() {
+        let mut reno = Reno::new(SMSS);
+        reno.on_timeout(20_000, false);
+        assert_eq!(reno.ssthresh(), 10_000);
+        assert_eq!(reno.window(), SMSS);
+
+        // 同じセグメントの再タイムアウトではssthreshを下げ直さない
+        reno.on_timeout(SMSS, true);
+        assert_eq!(reno.ssthresh(), 10_000);
+
+        // FlightSizeが小さくてもssthreshは2·SMSS以上
+        let mut reno = Reno::new(SMSS);
+        reno.on_timeout(SMSS, false);
+        assert_eq!(reno.ssthresh(), 2 * SMSS);
+    }
+
+    #[test]
+    fn test_congestion_avoidance_grows_per_rtt() {
+        let mut reno = Reno::new(SMSS);
+        reno.on_timeout(8 * SMSS, false); // ssthresh = 4·SMSS
+        while reno.in_slow_start() {
+            reno.on_ack(SMSS);
+        }
+        let cwnd = reno.window();
+        assert_eq!(cwnd, 4 * SMSS);
+
+        // cwnd分（4セグメント）確認されるまでは増えない
+        for _ in 0..3 {
+            reno.on_ack(SMSS);
+        }
+        assert_eq!(reno.window(), cwnd);
+        reno.on_ack(SMSS);
+        assert_eq!(reno.window(), cwnd + SMSS);
+    }
+
+    #[test]
+    fn test_window_gates_transmission() {
+        let mut conn = TcpConnection::new(SequenceNumber::new(0), SequenceNumber::new(0));
+        conn.send(&[0u8; 20_000]).unwrap();
+        let now = std::time::Instant::now();
+
+        let mut sent = 0;
+        while let Some(segment) = conn.poll_transmit(now) {
+            sent += segment.payload.len();
+        }
+        assert_eq!(sent, 4380);
+        assert_eq!(conn.send_allowance(), 0);
+        assert_eq!(conn.send_buffer().unsent_data(), 20_000 - 4380);
+
+        // 2セグメント分のACK: FlightSizeは2セグメント減り、cwndは1セグメント増える
+        let ack = TcpSegment::new(
+            SequenceNumber::new(0),
+            SequenceNumber::new(2 * SMSS as u32),
+            tcp_flags::ACK,
+            DEFAULT_WINDOW,
+        );
+        conn.on_segment(&ack, now).unwrap();
+        assert_eq!(conn.congestion().window(), 4380 + SMSS);
+        assert_eq!(conn.send_allowance(), 3 * SMSS);
+    }
+
+    #[test]
+    fn test_slow_start_doubles_each_round_trip() {
+        let net = transfer_with(Box::new(Reno::new(SMSS)), 60_000);
+        assert_eq!(bursts(&net), [3, 6, 12, 21]); // 42セグメント
+        assert_eq!(net.connection(Side::A).congestion().name(), "reno");
+    }
+
+    #[test]
+    fn test_compare_with_fixed_window() {
+        let reno = transfer_with(Box::new(Reno::new(SMSS)), 60_000);
+        let fixed = transfer_with(Box::new(FixedWindow::new(usize::MAX)), 60_000);
+
+        // ウィンドウ制限がなければ最初の1回ですべて送ってしまう
+        assert_eq!(bursts(&fixed), [42]);
+        assert!(fixed.elapsed() < reno.elapsed());
+        assert_eq!(fixed.stats(Side::A).sent, reno.stats(Side::A).sent);
+    }
+
+    #[test]
+    fn test_rto_restarts_slow_start() {
+        let mut net = SimNetwork::new(22, LinkConfig::default());
+        net.drop_nth(Side::A, 0);
+        net.connection_mut(Side::A).send(&[0u8; 50_000]).unwrap();
+
+        // 先頭が失われたまま初期ウィンドウを使い切り、RTOを待つ
+        net.run_for(Duration::from_millis(500));
+        assert_eq!(net.stats(Side::A).sent, 3);
+
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+        let congestion = net.connection(Side::A).congestion();
+        assert_eq!(congestion.ssthresh(), 2 * SMSS); // FlightSize 4380 / 2 < 2·SMSS
+        assert!(!congestion.in_slow_start());
+        assert_eq!(net.connection(Side::A).send_buffer().unacked_data(), 0);
+    }
+}
+
+// =============================================================================
+// Fast retransmit / fast recovery（RFC 5681, RFC 6582） - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod fast_recovery_tests {
+    use super::*;
+    use congestion::{CongestionControl, Reno};
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork};
+    use std::time::{Duration, Instant};
+
+    const SMSS: usize = 1460;
+
+    fn ack_segment(acked_segments: usize) -> TcpSegment {
+        TcpSegment::new(
+            SequenceNumber::new(0),
+            SequenceNumber::new((acked_segments * SMSS) as u32),
+            tcp_flags::ACK,
+            DEFAULT_WINDOW,
+        )
+    }
+
+    /// 先頭の1セグメントが確認され、2〜5番目の4セグメントが送信済み未確認の接続
+    fn four_in_flight(now: Instant) -> TcpConnection {
+        let mut conn = TcpConnection::new(SequenceNumber::new(0), SequenceNumber::new(0));
+        conn.send(&[0u8; 20 * SMSS]).unwrap();
+        while conn.poll_transmit(now).is_some() {}
+        conn.on_segment(&ack_segment(1), now).unwrap();
+        while conn.poll_transmit(now).is_some() {}
+        assert_eq!(conn.send_buffer().unacked_data(), 4 * SMSS);
+        conn
+    }
+
+    fn transfer(net: &mut SimNetwork, len: usize) {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        net.connection_mut(Side::A).send(&data).unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+        assert_eq!(net.connection_mut(Side::B).read(usize::MAX), data);
+    }
+
+    #[test]
+    fn test_third_dup_ack_triggers_retransmit() {
+        let now = Instant::now();
+        let mut conn = four_in_flight(now);
+
+        for expected in 1..=2 {
+            conn.on_segment(&ack_segment(1), now).unwrap();
+            assert_eq!(conn.dup_acks(), expected);
+            assert!(conn.poll_transmit(now).is_none());
+        }
+        conn.on_segment(&ack_segment(1), now).unwrap();
+        assert!(conn.in_recovery());
+
+        let resent = conn.poll_transmit(now).unwrap();
+        assert_eq!(resent.seq, SequenceNumber::new(SMSS as u32));
+        assert_eq!(resent.payload.len(), SMSS);
+        assert_eq!(conn.fast_retransmissions(), 1);
+        assert_eq!(conn.retransmissions(), 1);
+
+        // ssthresh = FlightSize / 2、cwnd = ssthresh + 3·SMSS（2セグメント送れる）
+        assert_eq!(conn.congestion().ssthresh(), 2 * SMSS);
+        assert_eq!(conn.congestion().window(), 5 * SMSS);
+        assert_eq!(conn.send_allowance(), SMSS);
+    }
+
+    #[test]
+    fn test_duplicate_ack_conditions() {
+        let now = Instant::now();
+        let mut conn = four_in_flight(now);
+        conn.on_segment(&ack_segment(1), now).unwrap();
+        conn.on_segment(&ack_segment(1), now).unwrap();
+
+        // ウィンドウの更新は重複ACKではない
+        let mut window_update = ack_segment(1);
+        window_update.window = DEFAULT_WINDOW - 1;
+        conn.on_segment(&window_update, now).unwrap();
+        // データ付きのACKも数えない
+        let with_data = ack_segment(1).with_payload(b"x".to_vec());
+        conn.on_segment(&with_data, now).unwrap();
+        // 以降の相手のセグメントは受け取った1バイトの次から始まる
+        let after_data = |acked_segments| {
+            let mut segment = ack_segment(acked_segments);
+            segment.seq = SequenceNumber::new(1);
+            segment
+        };
+        // 確認済みより古いACKも数えない
+        conn.on_segment(&after_data(0), now).unwrap();
+        assert_eq!(conn.dup_acks(), 2);
+        assert!(!conn.in_recovery());
+
+        conn.on_segment(&after_data(1), now).unwrap();
+        assert_eq!(conn.dup_acks(), 3);
+        assert!(conn.in_recovery());
+    }
+
+    #[test]
+    fn test_reno_recovery_window() {
+        let mut reno = Reno::new(SMSS);
+        reno.on_enter_recovery(8 * SMSS);
+        assert_eq!(reno.ssthresh(), 4 * SMSS);
+        assert_eq!(reno.window(), 7 * SMSS);
+
+        // 重複ACKのたびに1セグメント分膨らむ
+        reno.on_recovery_dup_ack();
+        assert_eq!(reno.window(), 8 * SMSS);
+
+        // partial ACK: 確認された分縮め、1セグメント戻す
+        reno.on_partial_ack(2 * SMSS);
+        assert_eq!(reno.window(), 7 * SMSS);
+
+        // full ACK: min(ssthresh, FlightSize + SMSS)
+        reno.on_exit_recovery(2 * SMSS);
+        assert_eq!(reno.window(), 3 * SMSS);
+        reno.on_exit_recovery(6 * SMSS);
+        assert_eq!(reno.window(), 4 * SMSS);
+        assert!(!reno.in_slow_start());
+    }
+
+    #[test]
+    fn test_single_loss_recovers_without_timeout() {
+        let mut net = SimNetwork::new(31, LinkConfig::default());
+        // 2往復目（6セグメント）の先頭を落とす。後続5つが重複ACKを生む
+        net.drop_nth(Side::A, 3);
+        let data: Vec<u8> = (0..60_000).map(|i| (i % 251) as u8).collect();
+        net.connection_mut(Side::A).send(&data).unwrap();
+
+        let recovering = |net: &SimNetwork| net.connection(Side::A).in_recovery();
+        assert!(net.run_until(recovering, Duration::from_secs(1)));
+        // 同時刻に届いた重複ACKはまとめて処理されるので、3つ以上になる
+        assert!(net.connection(Side::A).dup_acks() >= DUP_ACK_THRESHOLD);
+
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+        assert_eq!(net.connection_mut(Side::B).read(usize::MAX), data);
+        let a = net.connection(Side::A);
+        assert!(!a.in_recovery());
+        assert_eq!(a.recoveries(), 1);
+        assert_eq!(a.fast_retransmissions(), 1);
+        assert_eq!(a.retransmissions(), 1);
+        assert_eq!(a.rto().backoffs(), 0);
+        // RTO（1秒）を待たずに終わる
+        assert!(net.elapsed() < Duration::from_secs(1));
+
+        let dropped = net
+            .trace()
+            .iter()
+            .find(|e| e.event == LinkEvent::Dropped)
+            .unwrap();
+        let resent = net
+            .trace()
+            .iter()
+            .filter(|e| e.from == Side::A && e.event == LinkEvent::Sent)
+            .find(|e| e.seq == dropped.seq && e.at > dropped.at)
+            .unwrap();
+        // 落ちたセグメントの1往復後（重複ACKが3つ届いた時点）に再送している
+        assert_eq!(resent.at - dropped.at, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_partial_ack_retransmits_next_hole() {
+        let mut net = SimNetwork::new(32, LinkConfig::default());
+        // 同じウィンドウの中で2つ落とす
+        net.drop_nth(Side::A, 3);
+        net.drop_nth(Side::A, 5);
+        transfer(&mut net, 60_000);
+
+        // NewReno: 1回の回復で両方を再送し、タイムアウトしない
+        let a = net.connection(Side::A);
+        assert_eq!(a.recoveries(), 1);
+        assert_eq!(a.fast_retransmissions(), 2);
+        assert_eq!(a.retransmissions(), 2);
+        assert_eq!(a.rto().backoffs(), 0);
+        assert!(net.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_no_fast_retransmit_for_data_sent_before_timeout() {
+        let now = Instant::now();
+        let mut conn = four_in_flight(now);
+        let later = now + Duration::from_secs(2);
+        conn.on_timeout(later).unwrap();
+        assert!(conn.poll_transmit(later).is_some());
+        assert_eq!(conn.retransmissions(), 1);
+
+        // タイムアウト前に送った分への重複ACKでは回復に入らない
+        for _ in 0..DUP_ACK_THRESHOLD {
+            conn.on_segment(&ack_segment(1), later).unwrap();
+        }
+        assert!(!conn.in_recovery());
+        assert_eq!(conn.recoveries(), 0);
+        assert_eq!(conn.fast_retransmissions(), 0);
+    }
+
+    #[test]
+    fn test_too_few_dup_acks_wait_for_timeout() {
+        let mut net = SimNetwork::new(33, LinkConfig::default());
+        // 初期ウィンドウ（3セグメント）の先頭: 重複ACKは2つしか来ない
+        net.drop_nth(Side::A, 0);
+        transfer(&mut net, 60_000);
+
+        let a = net.connection(Side::A);
+        assert_eq!(a.recoveries(), 0);
+        assert_eq!(a.fast_retransmissions(), 0);
+        assert_eq!(a.retransmissions(), 1);
+        assert!(net.elapsed() > Duration::from_secs(1));
+    }
+}
+
+// =============================================================================
+// SACK（RFC 2018, RFC 6675） - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod sack_tests {
+    use super::*;
+    use rust_tcp_handson_with_claude_code::step02::TcpOption;
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork};
+    use std::time::{Duration, Instant};
+
+    const SMSS: usize = 1460;
+
+    fn blocks(pairs: &[(usize, usize)]) -> Vec<(SequenceNumber, SequenceNumber)> {
+        pairs
+            .iter()
+            .map(|&(l, r)| (seq(l as u32), seq(r as u32)))
+            .collect()
+    }
+
+    /// Aが`drops`番目（0始まりの送信順）に送るセグメントを落として60KB送る
+    fn transfer_with_drops(sack: bool, drops: &[u64]) -> SimNetwork {
+        let mut net = SimNetwork::new(41, LinkConfig::default());
+        net.connection_mut(Side::A).set_sack_enabled(sack);
+        net.connection_mut(Side::B).set_sack_enabled(sack);
+        for &n in drops {
+            net.drop_nth(Side::A, n);
+        }
+        let data: Vec<u8> = (0..60_000).map(|i| (i % 251) as u8).collect();
+        net.connection_mut(Side::A).send(&data).unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+        assert_eq!(net.connection_mut(Side::B).read(usize::MAX), data);
+        net
+    }
+
+    #[test]
+    fn test_sack_blocks_most_recent_first() {
+        let mut buffer = ReceiveBuffer::new(seq(0));
+        buffer.receive(seq(100), &[0; 100]).unwrap();
+        buffer.receive(seq(300), &[0; 100]).unwrap();
+        buffer.receive(seq(500), &[0; 100]).unwrap();
+        assert_eq!(
+            buffer.sack_blocks(),
+            blocks(&[(500, 600), (300, 400), (100, 200)])
+        );
+
+        // 隣接するデータは1つのブロックにまとめ、最後に受信したものを先頭にする
+        buffer.receive(seq(200), &[0; 50]).unwrap();
+        assert_eq!(
+            buffer.sack_blocks(),
+            blocks(&[(100, 250), (500, 600), (300, 400)])
+        );
+
+        // 順序通りに届いた分は累積ACKで伝わるのでブロックから消える
+        buffer.receive(seq(0), &[0; 100]).unwrap();
+        assert_eq!(buffer.next_expected(), seq(250));
+        assert_eq!(buffer.sack_blocks(), blocks(&[(500, 600), (300, 400)]));
+    }
+
+    #[test]
+    fn test_sack_blocks_are_limited() {
+        let mut buffer = ReceiveBuffer::new(seq(0));
+        for i in 1..=6 {
+            buffer.receive(seq(i * 100), &[0; 10]).unwrap();
+        }
+        let sack = buffer.sack_blocks();
+        assert_eq!(sack.len(), MAX_SACK_BLOCKS);
+        assert_eq!(
+            sack,
+            blocks(&[(600, 610), (500, 510), (400, 410), (300, 310)])
+        );
+    }
+
+    #[test]
+    fn test_scoreboard() {
+        let mut buffer = SendBuffer::new(seq(0));
+        buffer.write(&[0; 10_000]).unwrap();
+        buffer.consume(10_000);
+
+        assert_eq!(buffer.on_sack(seq(2000), seq(3000)), 1000);
+        assert_eq!(buffer.on_sack(seq(3000), seq(4000)), 1000);
+        assert_eq!(buffer.on_sack(seq(2500), seq(3500)), 0);
+        // 送信していない範囲や空のブロックは無視する
+        assert_eq!(buffer.on_sack(seq(9000), seq(12_000)), 0);
+        assert_eq!(buffer.on_sack(seq(500), seq(500)), 0);
+
+        assert_eq!(
+            buffer.sacked_ranges().collect::<Vec<_>>(),
+            blocks(&[(2000, 4000)])
+        );
+        assert_eq!(buffer.highest_sacked(), Some(seq(4000)));
+        assert_eq!(
+            buffer.unsacked_ranges(),
+            blocks(&[(0, 2000), (4000, 10_000)])
+        );
+        assert_eq!(buffer.peek_at(seq(9500), 1000).len(), 500);
+
+        // 累積ACKに追い越された部分は捨てる
+        buffer.acknowledge(seq(3000)).unwrap();
+        assert_eq!(buffer.sacked_bytes(), 1000);
+        buffer.acknowledge(seq(5000)).unwrap();
+        assert_eq!(buffer.sacked_bytes(), 0);
+        assert_eq!(buffer.highest_sacked(), None);
+    }
+
+    #[test]
+    fn test_acks_carry_sack_blocks() {
+        let now = Instant::now();
+        for enabled in [true, false] {
+            let mut conn = TcpConnection::new(seq(0), seq(0));
+            conn.set_sack_enabled(enabled);
+            let segment = TcpSegment::new(seq(1000), seq(0), tcp_flags::ACK, DEFAULT_WINDOW)
+                .with_payload(vec![0; 100]);
+            conn.on_segment(&segment, now).unwrap();
+
+            let ack = conn.poll_transmit(now).unwrap();
+            assert_eq!(ack.ack, seq(0));
+            let expected = if enabled {
+                vec![TcpOption::Sack(vec![(1000, 1100)])]
+            } else {
+                Vec::new()
+            };
+            assert_eq!(ack.options, expected);
+        }
+    }
+
+    #[test]
+    fn test_sack_detects_loss_before_three_dup_acks() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_sack_enabled(true);
+        conn.send(&[0u8; 20 * SMSS]).unwrap();
+        while conn.poll_transmit(now).is_some() {}
+        let ack = |n: usize| {
+            TcpSegment::new(
+                seq(0),
+                seq((n * SMSS) as u32),
+                tcp_flags::ACK,
+                DEFAULT_WINDOW,
+            )
+        };
+        conn.on_segment(&ack(1), now).unwrap();
+        while conn.poll_transmit(now).is_some() {}
+        assert_eq!(conn.send_buffer().next_seq(), seq((5 * SMSS) as u32));
+
+        // 1つの重複ACKで、先頭の後ろ3セグメント分がSACKされた
+        let sack = TcpOption::Sack(vec![(2 * SMSS as u32, 5 * SMSS as u32)]);
+        conn.on_segment(&ack(1).with_options(vec![sack]), now)
+            .unwrap();
+        assert_eq!(conn.dup_acks(), 1);
+        assert!(conn.in_recovery());
+        assert_eq!(conn.congestion().window(), 2 * SMSS);
+        assert_eq!(conn.pipe(), 0);
+
+        let resent = conn.poll_transmit(now).unwrap();
+        assert_eq!(resent.seq, seq(SMSS as u32));
+        assert_eq!(resent.payload.len(), SMSS);
+        // 再送した分がpipeに入り、残りのcwndで新しいデータを送る
+        assert_eq!(conn.pipe(), SMSS);
+        let next = conn.poll_transmit(now).unwrap();
+        assert_eq!(next.seq, seq((5 * SMSS) as u32));
+        assert!(conn.poll_transmit(now).is_none());
+    }
+
+    #[test]
+    fn test_sack_recovery_retransmits_only_holes() {
+        // 3往復目（12セグメント）の中で3つ落とす
+        let drops = [9, 12, 15];
+        let net = transfer_with_drops(true, &drops);
+        let dropped: Vec<SequenceNumber> = net
+            .trace()
+            .iter()
+            .filter(|e| e.event == LinkEvent::Dropped)
+            .map(|e| e.seq)
+            .collect();
+        assert_eq!(dropped.len(), drops.len());
+
+        // 2回目以降に送られたセグメント = 再送は、落ちたセグメントだけ
+        let mut seen = std::collections::BTreeSet::new();
+        let mut resent: Vec<SequenceNumber> = net
+            .trace()
+            .iter()
+            .filter(|e| e.from == Side::A && e.event == LinkEvent::Sent)
+            .filter(|e| !seen.insert(e.seq))
+            .map(|e| e.seq)
+            .collect();
+        resent.sort();
+        assert_eq!(resent, dropped);
+
+        let a = net.connection(Side::A);
+        assert_eq!(a.recoveries(), 1);
+        assert_eq!(a.retransmissions(), 3);
+        assert_eq!(a.rto().backoffs(), 0);
+        assert!(!a.in_recovery());
+        assert_eq!(a.send_buffer().sacked_bytes(), 0);
+    }
+
+    #[test]
+    fn test_sack_recovers_faster_than_newreno() {
+        let drops = [9, 12, 15];
+        let sack = transfer_with_drops(true, &drops);
+        let newreno = transfer_with_drops(false, &drops);
+
+        // NewRenoは1往復に1つしか穴を埋められない
+        assert_eq!(newreno.connection(Side::A).recoveries(), 1);
+        assert_eq!(newreno.connection(Side::A).retransmissions(), 3);
+        assert!(sack.elapsed() < newreno.elapsed());
+    }
+}
+
+// =============================================================================
+// フロー制御（RFC 9293 Section 3.8.6） - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod flow_control_tests {
+    use super::*;
+    use simnet::{LinkConfig, Side, SimNetwork};
+    use std::time::{Duration, Instant};
+
+    const SMSS: usize = 1460;
+
+    fn ack_with_window(seg_seq: usize, ack: usize, window: u16) -> TcpSegment {
+        TcpSegment::new(seq(seg_seq as u32), seq(ack as u32), tcp_flags::ACK, window)
+    }
+
+    fn data_segment(start: usize, len: usize) -> TcpSegment {
+        TcpSegment::new(seq(start as u32), seq(0), tcp_flags::ACK, DEFAULT_WINDOW)
+            .with_payload(vec![7; len])
+    }
+
+    #[test]
+    fn test_out_of_window_ack_does_not_update_send_window() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.send(&[1; 100]).unwrap();
+        conn.poll_transmit(now).unwrap();
+
+        // ACK番号がSND.UNA..SND.NXTの中でも、受信ウィンドウのはるか先のセグメントは使わない
+        let forged = ack_with_window(1 << 30, 50, 0);
+        assert!(conn.on_segment(&forged, now).is_err());
+        assert_eq!(conn.send_window(), DEFAULT_WINDOW as u32);
+        assert_eq!(conn.send_buffer().unacked_data(), 100);
+        // 捨てたセグメントにはRCV.NXTを伝えるACKを返す
+        let ack = conn.poll_transmit(now).unwrap();
+        assert_eq!(ack.ack, seq(0));
+        assert!(ack.payload.is_empty());
+
+        // 後から届く本物のウィンドウ更新は反映される
+        conn.on_segment(&ack_with_window(0, 100, 0), now).unwrap();
+        assert_eq!(conn.send_window(), 0);
+        conn.on_segment(&ack_with_window(0, 100, 4000), now)
+            .unwrap();
+        assert_eq!(conn.send_window(), 4000);
+    }
+
+    #[test]
+    fn test_receive_window_is_free_space() {
+        let mut buffer = ReceiveBuffer::new(seq(0));
+        assert_eq!(buffer.window(), DEFAULT_RECV_BUFFER_SIZE);
+        buffer.set_capacity(1000);
+
+        buffer.receive(seq(0), &[1; 600]).unwrap();
+        assert_eq!(buffer.window(), 400);
+        // 順序外データはウィンドウの内側に置かれるので、ウィンドウを減らさない
+        buffer.receive(seq(700), &[1; 100]).unwrap();
+        assert_eq!(buffer.window(), 400);
+
+        buffer.read(300);
+        assert_eq!(buffer.window(), 700);
+    }
+
+    #[test]
+    fn test_data_beyond_window_is_dropped() {
+        let mut buffer = ReceiveBuffer::new(seq(0));
+        buffer.set_capacity(1000);
+        buffer.receive(seq(0), &[1; 1200]).unwrap();
+        assert_eq!(buffer.available(), 1000);
+        assert_eq!(buffer.next_expected(), seq(1000));
+
+        // ウィンドウが0なら何も受け取らない
+        assert_eq!(buffer.window(), 0);
+        buffer.receive(seq(1000), &[1; 10]).unwrap();
+        assert_eq!(buffer.available(), 1000);
+        assert!(!buffer.has_gap());
+    }
+
+    #[test]
+    fn test_advertised_window_follows_buffer() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_recv_buffer_size(4000);
+        assert_eq!(conn.poll_transmit(now).unwrap().window, 4000);
+
+        conn.on_segment(&data_segment(0, 3000), now).unwrap();
+        let ack = conn.poll_transmit(now).unwrap();
+        assert_eq!(ack.ack, seq(3000));
+        assert_eq!(ack.window, 1000);
+
+        // 右端を守るので、ウィンドウの外に送られたデータは受け取らない
+        assert!(conn.on_segment(&data_segment(5000, 100), now).is_err());
+        let ack = conn.poll_transmit(now).unwrap();
+        assert_eq!(ack.window, 1000);
+        assert!(!conn.recv_buffer().has_gap());
+    }
+
+    #[test]
+    fn test_receiver_avoids_silly_window() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_recv_buffer_size(4000);
+        conn.on_segment(&data_segment(0, 4000), now).unwrap();
+        assert_eq!(conn.poll_transmit(now).unwrap().window, 0);
+
+        // min(バッファの半分, MSS) = 1460バイト空くまではウィンドウを開かない
+        conn.read(1000);
+        assert_eq!(conn.receive_window(), 0);
+        assert!(conn.poll_transmit(now).is_none());
+
+        // 開いたらウィンドウ更新のACKを送る
+        conn.read(500);
+        assert_eq!(conn.receive_window(), 1500);
+        let update = conn.poll_transmit(now).unwrap();
+        assert_eq!(update.ack, seq(4000));
+        assert_eq!(update.window, 1500);
+    }
+
+    #[test]
+    fn test_send_window_update_rules() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(100));
+        conn.send(&[0; 100]).unwrap();
+        assert_eq!(conn.send_window(), DEFAULT_WINDOW as u32);
+
+        conn.on_segment(&ack_with_window(105, 0, 1000), now)
+            .unwrap();
+        assert_eq!(conn.send_window(), 1000);
+
+        // SND.WL1より古いセグメントのウィンドウは使わない
+        conn.on_segment(&ack_with_window(103, 0, 5000), now)
+            .unwrap();
+        assert_eq!(conn.send_window(), 1000);
+
+        // 同じSEQならACKが進んでいるものを使う
+        conn.on_segment(&ack_with_window(105, 0, 3000), now)
+            .unwrap();
+        assert_eq!(conn.send_window(), 3000);
+    }
+
+    #[test]
+    fn test_sender_stays_within_peer_window() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_congestion_control(Box::new(congestion::FixedWindow::new(usize::MAX)));
+        conn.send(&[0; 10_000]).unwrap();
+        conn.on_segment(&ack_with_window(0, 0, 3000), now).unwrap();
+
+        let mut sent = 0;
+        while let Some(segment) = conn.poll_transmit(now) {
+            sent += segment.payload.len();
+        }
+        // 3000バイトのウィンドウにMSS 2つ。残り80バイトの小さなセグメントは送らない
+        assert_eq!(sent, 2 * SMSS);
+        assert_eq!(conn.usable_window(), 3000 - 2 * SMSS);
+
+        // ACKでウィンドウが右に進めば続きを送る（SND.UNA + SND.WND）
+        conn.on_segment(&ack_with_window(0, 2 * SMSS, 3000), now)
+            .unwrap();
+        let next = conn.poll_transmit(now).unwrap();
+        assert_eq!(next.seq, seq((2 * SMSS) as u32));
+    }
+
+    #[test]
+    fn test_sender_sends_last_small_segment() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.send(&[0; 100]).unwrap();
+        // 残りのデータを全部送れるなら、MSS未満でも送る
+        assert_eq!(conn.poll_transmit(now).unwrap().payload.len(), 100);
+    }
+
+    #[test]
+    fn test_slow_reader_throttles_sender() {
+        let mut net = SimNetwork::new(51, LinkConfig::default());
+        net.connection_mut(Side::B).set_recv_buffer_size(4000);
+        // ハンドシェイクの代わりに、Bのウィンドウ更新をAに届けておく
+        net.run_for(Duration::from_millis(50));
+        assert_eq!(net.connection(Side::A).send_window(), 4000);
+        let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        net.connection_mut(Side::A).send(&data).unwrap();
+
+        // Bが読まない間は、バッファに入る分しか届かない
+        net.run_for(Duration::from_secs(1));
+        assert!(net.connection(Side::B).recv_buffer().available() <= 4000);
+        assert!(net.connection(Side::A).send_buffer().unsent_data() > 0);
+
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            received.extend(net.connection_mut(Side::B).read(usize::MAX));
+            net.run_for(Duration::from_millis(100));
+            assert!(net.elapsed() < Duration::from_secs(60));
+        }
+        assert_eq!(received, data);
+        // 受信側が捨てたデータはない（ウィンドウを超えて送っていない）
+        assert_eq!(net.connection(Side::A).retransmissions(), 0);
+    }
+}
+
+// =============================================================================
+// Persistタイマー（ゼロウィンドウプローブ） - Tests
+// =============================================================================
+
+#[cfg(test)]
+mod persist_tests {
+    use super::*;
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork};
+    use std::time::{Duration, Instant};
+
+    fn data() -> Vec<u8> {
+        (0..10_000).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Bの受信バッファ（4000バイト）が埋まり、Aへのウィンドウが0になったネットワーク
+    fn zero_window_network(seed: u64) -> SimNetwork {
+        let mut net = SimNetwork::new(seed, LinkConfig::default());
+        net.connection_mut(Side::B).set_recv_buffer_size(4000);
+        net.run_for(Duration::from_millis(50));
+        net.connection_mut(Side::A).send(&data()).unwrap();
+        let closed = |net: &SimNetwork| net.connection(Side::A).send_window() == 0;
+        assert!(net.run_until(closed, Duration::from_secs(10)));
+        assert_eq!(net.connection(Side::B).recv_buffer().available(), 4000);
+        net
+    }
+
+    /// Bが読み続けて全部受け取るまで進める
+    fn drain(net: &mut SimNetwork, mut received: Vec<u8>) -> Vec<u8> {
+        let deadline = net.elapsed() + Duration::from_secs(600);
+        while received.len() < data().len() {
+            received.extend(net.connection_mut(Side::B).read(usize::MAX));
+            net.run_for(Duration::from_millis(100));
+            assert!(net.elapsed() < deadline);
+        }
+        received
+    }
+
+    /// Aが送った1バイトのプローブの送信時刻
+    fn probe_times(net: &SimNetwork) -> Vec<Duration> {
+        net.trace()
+            .iter()
+            .filter(|e| e.from == Side::A && e.event == LinkEvent::Sent && e.payload_len == 1)
+            .map(|e| e.at)
+            .collect()
+    }
+
+    #[test]
+    fn test_zero_window_starts_persist_timer() {
+        let net = zero_window_network(61);
+        let a = net.connection(Side::A);
+        assert_eq!(a.send_buffer().unacked_data(), 0);
+        assert_eq!(a.send_buffer().unsent_data(), 6000);
+        assert!(a.poll_timeout().is_some());
+    }
+
+    #[test]
+    fn test_probes_back_off_exponentially() {
+        let mut net = zero_window_network(62);
+        net.run_for(Duration::from_secs(20));
+
+        let times = probe_times(&net);
+        let gaps: Vec<Duration> = times.windows(2).map(|w| w[1] - w[0]).collect();
+        assert_eq!(
+            gaps,
+            [2, 4, 8].map(Duration::from_secs),
+            "probes at {:?}",
+            times
+        );
+        // プローブは受け取られず（ウィンドウの外）、相手は0のウィンドウを返し続ける
+        assert_eq!(net.connection(Side::B).recv_buffer().available(), 4000);
+        assert_eq!(net.connection(Side::A).send_window(), 0);
+        assert!(net.connection(Side::A).poll_timeout().is_some());
+    }
+
+    #[test]
+    fn test_window_reopens() {
+        let mut net = zero_window_network(63);
+        net.run_for(Duration::from_secs(5));
+        assert!(!probe_times(&net).is_empty());
+
+        let received = drain(&mut net, Vec::new());
+        assert_eq!(received, data());
+        assert_eq!(net.connection(Side::A).send_buffer().unacked_data(), 0);
+        assert!(net.connection(Side::A).poll_timeout().is_none());
+    }
+
+    #[test]
+    fn test_lost_window_update_is_recovered_by_probe() {
+        let mut net = zero_window_network(64);
+        let probes = net.connection(Side::A).window_probes();
+
+        // ウィンドウ更新のACKが失われる
+        let link = net.link_config(Side::B);
+        net.set_link_config(
+            Side::B,
+            LinkConfig {
+                loss_rate: 1.0,
+                ..link
+            },
+        );
+        let received = net.connection_mut(Side::B).read(usize::MAX);
+        net.run_for(Duration::from_millis(100));
+        net.set_link_config(Side::B, link);
+        assert_eq!(net.connection(Side::A).send_window(), 0);
+
+        // プローブへの応答で開いたウィンドウを知る
+        let received = drain(&mut net, received);
+        assert_eq!(received, data());
+        assert!(net.connection(Side::A).window_probes() > probes);
+    }
+
+    #[test]
+    fn test_unanswered_probes_abort() {
+        let start = Instant::now();
+        let mut conn = TcpConnection::new(SequenceNumber::new(0), SequenceNumber::new(0));
+        conn.set_max_window_probes(3);
+        conn.send(&[0; 1000]).unwrap();
+        let zero_window = TcpSegment::new(
+            SequenceNumber::new(0),
+            SequenceNumber::new(0),
+            tcp_flags::ACK,
+            0,
+        );
+        conn.on_segment(&zero_window, start).unwrap();
+        assert!(conn.poll_transmit(start).is_none());
+
+        let mut probes = Vec::new();
+        let aborted_at = loop {
+            let at = conn.poll_timeout().expect("persist timer must be running");
+            if let Err(e) = conn.on_timeout(at) {
+                assert_eq!(
+                    e,
+                    TimeoutError::WindowProbe {
+                        probes: 3,
+                        window: 0
+                    }
+                );
+                break at;
+            }
+            let probe = conn.poll_transmit(at).unwrap();
+            assert_eq!(probe.seq, SequenceNumber::new(0));
+            assert_eq!(probe.payload.len(), 1);
+            probes.push(at - start);
+        };
+
+        assert_eq!(probes, [1, 3, 7].map(Duration::from_secs));
+        assert_eq!(aborted_at - start, Duration::from_secs(15));
+        assert_eq!(conn.window_probes(), 3);
+        // プローブの1バイトは送信済み（SND.NXTを進め、同じバイトを送り直す）
+        assert_eq!(conn.send_buffer().unacked_data(), 1);
+        assert_eq!(conn.send_buffer().unsent_data(), 999);
+        // 諦めた後はタイマーを起動し直さない
+        assert!(conn.poll_transmit(aborted_at).is_none());
+        assert!(conn.poll_timeout().is_none());
+    }
+
+    #[test]
+    fn test_probe_byte_is_tracked_in_flight() {
+        let start = Instant::now();
+        let mut conn = TcpConnection::new(SequenceNumber::new(0), SequenceNumber::new(0));
+        conn.send(&[7; 100]).unwrap();
+        let window = |ack: u32, window: u16| {
+            TcpSegment::new(
+                SequenceNumber::new(0),
+                SequenceNumber::new(ack),
+                tcp_flags::ACK,
+                window,
+            )
+        };
+        conn.on_segment(&window(0, 0), start).unwrap();
+        assert!(conn.poll_transmit(start).is_none());
+
+        let at = conn.poll_timeout().unwrap();
+        conn.on_timeout(at).unwrap();
+        let probe = conn.poll_transmit(at).unwrap();
+        assert_eq!(probe.seq, SequenceNumber::new(0));
+        assert_eq!(conn.send_buffer().next_seq(), SequenceNumber::new(1));
+
+        // 相手がプローブを受け取った: 1バイトだけ確認され、ウィンドウは0のまま
+        conn.on_segment(&window(1, 0), at).unwrap();
+        assert_eq!(conn.send_buffer().unacked_seq(), SequenceNumber::new(1));
+        assert_eq!(conn.send_buffer().unsent_data(), 99);
+        assert!(conn.poll_transmit(at).is_none());
+
+        // 次のプローブは2バイト目。捨てられたままウィンドウが開いたら、そこから送り直す
+        let at = conn.poll_timeout().unwrap();
+        conn.on_timeout(at).unwrap();
+        let probe = conn.poll_transmit(at).unwrap();
+        assert_eq!(probe.seq, SequenceNumber::new(1));
+        conn.on_segment(&window(1, 1000), at).unwrap();
+        let resumed = conn.poll_transmit(at).unwrap();
+        assert_eq!(resumed.seq, SequenceNumber::new(2));
+        assert_eq!(resumed.payload.len(), 98);
+        assert!(conn.poll_timeout().is_some());
+
+        // 再送タイマーで、捨てられたプローブのバイトから送り直す
+        let at = conn.poll_timeout().unwrap();
+        conn.on_timeout(at).unwrap();
+        let retransmitted = conn.poll_transmit(at).unwrap();
+        assert_eq!(retransmitted.seq, SequenceNumber::new(1));
+    }
+}
+
+// =============================================================================
+// ウィンドウスケール（RFC 7323）のテスト
+// =============================================================================
+
+#[cfg(test)]
+mod window_scale_tests {
+    use super::*;
+    use simnet::{LinkConfig, Side, SimNetwork};
+    use std::time::{Duration, Instant};
+
+    const LARGE_BUFFER: usize = 2 * 1024 * 1024;
+
+    /// 遅延50ms（RTT 100ms）のリンクで`len`バイト送り、Bが読み切るまでの時間を返す
+    fn timed_transfer(scaled: bool, len: usize) -> (SimNetwork, Duration) {
+        let link = LinkConfig {
+            delay: Duration::from_millis(50),
+            ..LinkConfig::default()
+        };
+        let mut net = SimNetwork::new(16, link);
+        if scaled {
+            let shift = window_scale_for(LARGE_BUFFER);
+            for side in [Side::A, Side::B] {
+                let conn = net.connection_mut(side);
+                conn.set_window_scale(shift, shift);
+                conn.set_recv_buffer_size(LARGE_BUFFER);
+            }
+        }
+        net.connection_mut(Side::A)
+            .set_send_buffer_size(LARGE_BUFFER);
+        // 新しいウィンドウを相手に伝えてから送り始める
+        net.run_for(Duration::from_millis(100));
+        let start = net.elapsed();
+
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        assert_eq!(net.connection_mut(Side::A).send(&data).unwrap(), len);
+        let mut received = Vec::new();
+        while received.len() < len {
+            net.run_for(Duration::from_millis(10));
+            received.extend(net.connection_mut(Side::B).read(usize::MAX));
+            assert!(net.elapsed() < Duration::from_secs(60), "transfer stalled");
+        }
+        assert_eq!(received, data);
+        let elapsed = net.elapsed() - start;
+        (net, elapsed)
+    }
+
+    #[test]
+    fn test_window_scale_for_buffer_size() {
+        assert_eq!(window_scale_for(0), 0);
+        assert_eq!(window_scale_for(65535), 0);
+        assert_eq!(window_scale_for(65536), 1);
+        assert_eq!(window_scale_for(256 * 1024), 3);
+        assert_eq!(window_scale_for(LARGE_BUFFER), 6);
+        // 1GiBを超えるバッファでも14まで
+        assert_eq!(window_scale_for(usize::MAX), MAX_WINDOW_SCALE);
+    }
+
+    #[test]
+    fn test_unscaled_window_is_capped() {
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_recv_buffer_size(LARGE_BUFFER);
+        assert_eq!(conn.receive_window(), u16::MAX as u32);
+        assert_eq!(conn.poll_transmit(Instant::now()).unwrap().window, u16::MAX);
+    }
+
+    #[test]
+    fn test_advertised_window_is_shifted() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_window_scale(0, 3);
+        conn.set_recv_buffer_size(256 * 1024);
+        assert_eq!(conn.receive_window(), 256 * 1024);
+        assert_eq!(conn.poll_transmit(now).unwrap().window, 32768);
+
+        // 8で割り切れない端数は切り捨てて広告する
+        let data =
+            TcpSegment::new(seq(0), seq(0), tcp_flags::ACK, 1000).with_payload(vec![1; 1003]);
+        conn.on_segment(&data, now).unwrap();
+        let ack = conn.poll_transmit(now).unwrap();
+        assert_eq!(ack.window as usize, (256 * 1024 - 1003) / 8);
+    }
+
+    #[test]
+    fn test_peer_window_is_shifted() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_window_scale(2, 0);
+        assert_eq!(conn.window_scale(), (2, 0));
+
+        conn.on_segment(&TcpSegment::new(seq(0), seq(0), tcp_flags::ACK, 40000), now)
+            .unwrap();
+        assert_eq!(conn.send_window(), 160000);
+
+        // 確立後のSYN-ACKはウィンドウ更新に使わない（チャレンジACKを返して捨てる）
+        let syn_ack = TcpSegment::new(seq(0), seq(0), tcp_flags::SYN | tcp_flags::ACK, 40000);
+        conn.on_segment(&syn_ack, now).unwrap();
+        assert_eq!(conn.send_window(), 160000);
+        assert_eq!(conn.challenge_acks(), 1);
+    }
+
+    #[test]
+    fn test_window_scale_is_clamped() {
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_window_scale(20, 15);
+        assert_eq!(conn.window_scale(), (MAX_WINDOW_SCALE, MAX_WINDOW_SCALE));
+    }
+
+    #[test]
+    fn test_send_buffer_beyond_64k() {
+        let mut buffer = SendBuffer::new(seq(0));
+        assert_eq!(buffer.capacity(), DEFAULT_SEND_BUFFER_SIZE);
+        buffer.set_capacity(LARGE_BUFFER);
+        assert_eq!(buffer.write(&vec![0; 1_000_000]).unwrap(), 1_000_000);
+        assert_eq!(buffer.available_space(), LARGE_BUFFER - 1_000_000);
+        // 書き込み済みのデータより小さくはできない
+        buffer.set_capacity(1000);
+        assert_eq!(buffer.capacity(), 1_000_000);
+    }
+
+    #[test]
+    fn test_scaled_window_raises_throughput() {
+        let len = 1_000_000;
+        let (unscaled_net, unscaled) = timed_transfer(false, len);
+        let (scaled_net, scaled) = timed_transfer(true, len);
+
+        // スケールなしでは1往復に64KiBまでしか送れない
+        let rtt = Duration::from_millis(100);
+        assert!(unscaled >= rtt * (len / u16::MAX as usize) as u32);
+        assert!(unscaled_net.connection(Side::A).send_window() <= u16::MAX as u32);
+
+        // スケールすれば64KiBを超えるウィンドウを使える
+        assert!(scaled_net.connection(Side::A).send_window() > u16::MAX as u32);
+        assert!(
+            scaled < unscaled,
+            "scaled {scaled:?} vs unscaled {unscaled:?}"
+        );
+    }
+}
+
+// =============================================================================
+// Timestamps（RFC 7323: RTTM, PAWS）のテスト
+// =============================================================================
+
+#[cfg(test)]
+mod timestamps_tests {
+    use super::*;
+    use simnet::{LinkConfig, Side, SimNetwork};
+    use std::time::{Duration, Instant};
+
+    // 1000バイト受け取ると0に戻る位置から始める
+    const WRAP_ISN: u32 = u32::MAX - 999;
+
+    fn stamped(seg_seq: u32, tsval: u32, payload: &[u8]) -> TcpSegment {
+        TcpSegment::new(seq(seg_seq), seq(0), tcp_flags::ACK, DEFAULT_WINDOW)
+            .with_options(vec![TcpOption::Timestamps { tsval, tsecr: 0 }])
+            .with_payload(payload.to_vec())
+    }
+
+    fn receiver(timestamps: bool) -> TcpConnection {
+        let mut conn = TcpConnection::new(seq(0), seq(WRAP_ISN));
+        conn.set_timestamps_enabled(timestamps);
+        conn
+    }
+
+    #[test]
+    fn test_every_segment_carries_timestamps() {
+        let start = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_timestamps_enabled(true);
+        conn.send(&[1; 100]).unwrap();
+
+        let data = conn.poll_transmit(start).unwrap();
+        let (tsval, tsecr) = data.timestamps().unwrap();
+        assert_eq!(tsecr, 0); // まだ相手のTSvalを受け取っていない
+
+        conn.on_segment(&stamped(0, 500, b"x"), start).unwrap();
+        let ack = conn
+            .poll_transmit(start + Duration::from_millis(5))
+            .unwrap();
+        assert!(ack.payload.is_empty());
+        // 1ミリ秒刻みの時計と、受け取ったTSvalのエコー
+        assert_eq!(ack.timestamps(), Some((tsval.wrapping_add(5), 500)));
+    }
+
+    #[test]
+    fn test_ts_recent_follows_last_ack_sent() {
+        let now = Instant::now();
+        let mut conn = receiver(true);
+        conn.on_segment(&stamped(WRAP_ISN, 100, &[1; 10]), now)
+            .unwrap();
+        assert_eq!(
+            conn.poll_transmit(now).unwrap().timestamps().unwrap().1,
+            100
+        );
+
+        // 順序外のセグメントのTSvalは覚えない（遅延ACKの相手にRTTを短く見せない）
+        conn.on_segment(&stamped(WRAP_ISN + 20, 200, &[1; 10]), now)
+            .unwrap();
+        assert_eq!(
+            conn.poll_transmit(now).unwrap().timestamps().unwrap().1,
+            100
+        );
+
+        // 穴を埋めたセグメントのTSvalを返す
+        conn.on_segment(&stamped(WRAP_ISN + 10, 150, &[1; 10]), now)
+            .unwrap();
+        assert_eq!(
+            conn.poll_transmit(now).unwrap().timestamps().unwrap().1,
+            150
+        );
+    }
+
+    #[test]
+    fn test_rtt_is_measured_from_retransmission() {
+        let start = Instant::now();
+        let rto = rto::INITIAL_RTO;
+        let mut with_ts = TcpConnection::new(seq(0), seq(0));
+        with_ts.set_timestamps_enabled(true);
+        let mut without_ts = TcpConnection::new(seq(0), seq(0));
+
+        for conn in [&mut with_ts, &mut without_ts] {
+            conn.send(&[1; 100]).unwrap();
+            conn.poll_transmit(start).unwrap();
+            conn.on_timeout(start + rto).unwrap();
+            let resent = conn.poll_transmit(start + rto).unwrap();
+            assert_eq!(resent.seq, seq(0));
+
+            // 再送から100ms後に、再送したセグメントのTSvalを返すACKが届く
+            let tsecr = resent.timestamps().map_or(0, |(tsval, _)| tsval);
+            let ack = TcpSegment::new(seq(0), seq(100), tcp_flags::ACK, DEFAULT_WINDOW)
+                .with_options(vec![TcpOption::Timestamps { tsval: 1, tsecr }]);
+            conn.on_segment(&ack, start + rto + Duration::from_millis(100))
+                .unwrap();
+            assert_eq!(conn.send_buffer().unacked_data(), 0);
+        }
+
+        assert_eq!(with_ts.rto().srtt(), Some(Duration::from_millis(100)));
+        // Timestampsがなければ、どちらへの応答か区別できないので測らない（Karn）
+        assert_eq!(without_ts.rto().srtt(), None);
+    }
+
+    #[test]
+    fn test_rtt_ignores_tsecr_outside_unacked_sends() {
+        let start = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_timestamps_enabled(true);
+        conn.send(&[1; 300]).unwrap();
+        let first = conn.poll_transmit(start).unwrap();
+        let (tsval, _) = first.timestamps().unwrap();
+        let later = start + Duration::from_millis(50);
+        let ack = |ack: u32, tsecr: u32| {
+            TcpSegment::new(seq(0), seq(ack), tcp_flags::ACK, DEFAULT_WINDOW)
+                .with_options(vec![TcpOption::Timestamps { tsval: 1, tsecr }])
+        };
+
+        // 送っていないTSval（未来の時刻）と、最も古い未確認の送信より前のTSecrは測らない
+        conn.on_segment(&ack(100, tsval.wrapping_add(1000)), later)
+            .unwrap();
+        conn.on_segment(&ack(200, tsval.wrapping_sub(1)), later)
+            .unwrap();
+        assert_eq!(conn.send_buffer().unacked_data(), 100);
+        assert_eq!(conn.rto().srtt(), None);
+
+        // 未確認の送信のTSvalを返すACKからは測る
+        conn.on_segment(&ack(300, tsval), later).unwrap();
+        assert_eq!(conn.rto().srtt(), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_paws_rejects_old_duplicate_after_wrap() {
+        let now = Instant::now();
+        let mut with_ts = receiver(true);
+        let mut without_ts = receiver(false);
+
+        for conn in [&mut with_ts, &mut without_ts] {
+            conn.on_segment(&stamped(WRAP_ISN, 100, &[1; 1000]), now)
+                .unwrap();
+            // シーケンス番号が一周して0に戻る
+            conn.on_segment(&stamped(0, 200, &[2; 1000]), now).unwrap();
+            assert_eq!(conn.generate_ack(), seq(1000));
+            conn.read(usize::MAX);
+            conn.poll_transmit(now);
+
+            // 前の周回で遅れていたセグメントが、ちょうどウィンドウの中に現れる
+            conn.on_segment(&stamped(1000, 50, b"OLD"), now).unwrap();
+            conn.on_segment(&stamped(1000, 300, b"NEW"), now).unwrap();
+        }
+
+        assert_eq!(with_ts.paws_rejected(), 1);
+        assert_eq!(with_ts.read(usize::MAX), b"NEW");
+        // シーケンス番号だけでは区別できず、古いデータを受け取ってしまう
+        assert_eq!(without_ts.read(usize::MAX), b"OLD");
+    }
+
+    #[test]
+    fn test_rejected_segment_is_acked() {
+        let now = Instant::now();
+        let mut conn = receiver(true);
+        conn.on_segment(&stamped(WRAP_ISN, 100, &[1; 10]), now)
+            .unwrap();
+        conn.poll_transmit(now).unwrap();
+
+        conn.on_segment(&stamped(WRAP_ISN + 10, 99, &[1; 10]), now)
+            .unwrap();
+        let ack = conn.poll_transmit(now).unwrap();
+        assert_eq!(ack.ack, seq(WRAP_ISN + 10));
+        assert_eq!(ack.timestamps().unwrap().1, 100);
+        assert_eq!(conn.recv_buffer().available(), 10);
+    }
+
+    #[test]
+    fn test_paws_compares_timestamps_with_wraparound() {
+        let now = Instant::now();
+        let mut conn = receiver(true);
+        conn.on_segment(&stamped(WRAP_ISN, u32::MAX - 10, &[1; 10]), now)
+            .unwrap();
+
+        // TSvalも一周する: 5はu32::MAX - 10より新しい
+        conn.on_segment(&stamped(WRAP_ISN + 10, 5, &[1; 10]), now)
+            .unwrap();
+        conn.on_segment(&stamped(WRAP_ISN + 20, u32::MAX - 100, &[1; 10]), now)
+            .unwrap();
+        assert_eq!(conn.paws_rejected(), 1);
+        assert_eq!(conn.recv_buffer().available(), 20);
+    }
+
+    #[test]
+    fn test_stale_ts_recent_is_not_used() {
+        let now = Instant::now();
+        let mut conn = receiver(true);
+        conn.on_segment(&stamped(WRAP_ISN, 1_000_000, &[1; 10]), now)
+            .unwrap();
+
+        // 24日以上何も届かなければ、相手の時計が一周していてもおかしくない
+        let later = now + PAWS_IDLE_LIMIT + Duration::from_secs(1);
+        conn.on_segment(&stamped(WRAP_ISN + 10, 5, &[1; 10]), later)
+            .unwrap();
+        assert_eq!(conn.paws_rejected(), 0);
+        assert_eq!(conn.recv_buffer().available(), 20);
+    }
+
+    #[test]
+    fn test_segment_without_timestamps_is_dropped() {
+        let now = Instant::now();
+        let mut conn = receiver(true);
+        let plain = TcpSegment::new(seq(WRAP_ISN), seq(0), tcp_flags::ACK, DEFAULT_WINDOW)
+            .with_payload(vec![1; 10]);
+        conn.on_segment(&plain, now).unwrap();
+        assert_eq!(conn.recv_buffer().available(), 0);
+        assert!(conn.poll_transmit(now).is_none());
+    }
+
+    #[test]
+    fn test_sack_blocks_leave_room_for_timestamps() {
+        let now = Instant::now();
+        let mut conn = receiver(true);
+        conn.set_sack_enabled(true);
+        for i in 0..5 {
+            conn.on_segment(&stamped(WRAP_ISN + 20 * (i + 1), 100, &[1; 10]), now)
+                .unwrap();
+        }
+
+        let ack = conn.poll_transmit(now).unwrap();
+        let blocks = ack
+            .options
+            .iter()
+            .find_map(|option| match option {
+                TcpOption::Sack(blocks) => Some(blocks.len()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(blocks, MAX_SACK_BLOCKS - 1);
+        assert!(ack.timestamps().is_some());
+        // 40バイトのオプション領域に収まる
+        let address = std::net::SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 80);
+        assert!(ack.encode(address, address).is_ok());
+    }
+
+    #[test]
+    fn test_transfer_across_sequence_wrap() {
+        let link = LinkConfig {
+            jitter: Duration::from_millis(5),
+            loss_rate: 0.05,
+            duplicate_rate: 0.1,
+            reorder_rate: 0.1,
+            reorder_delay: Duration::from_millis(20),
+            ..LinkConfig::default()
+        };
+        let mut net = SimNetwork::with_isns(17, link, seq(u32::MAX - 100_000), seq(u32::MAX - 10));
+        for side in [Side::A, Side::B] {
+            let conn = net.connection_mut(side);
+            conn.set_timestamps_enabled(true);
+            conn.set_sack_enabled(true);
+            conn.set_send_buffer_size(1 << 20);
+            conn.set_recv_buffer_size(1 << 20);
+            conn.set_window_scale(5, 5);
+        }
+
+        let data: Vec<u8> = (0..500_000).map(|i| (i % 251) as u8).collect();
+        net.connection_mut(Side::A).send(&data).unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(600)));
+        assert_eq!(net.connection_mut(Side::B).read(usize::MAX), data);
+
+        // シーケンス番号は一周し、RTTは毎ACKのTSecrから測れている
+        let a = net.connection(Side::A);
+        assert!(a.send_buffer().next_seq().value() < 500_000);
+        assert!(a.rto().srtt().unwrap() >= Duration::from_millis(20));
+    }
+}
+
+// =============================================================================
+// 発展: FINによる接続の終了
+// =============================================================================
+
+#[cfg(test)]
+mod fin_tests {
+    use super::*;
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork};
+    use std::net::Shutdown;
+    use std::time::{Duration, Instant};
+
+    fn data() -> Vec<u8> {
+        (0..10_000).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn fin(seg_seq: u32, ack: u32, payload: &[u8]) -> TcpSegment {
+        TcpSegment::new(
+            seq(seg_seq),
+            seq(ack),
+            tcp_flags::FIN | tcp_flags::ACK,
+            DEFAULT_WINDOW,
+        )
+        .with_payload(payload.to_vec())
+    }
+
+    /// `side`が送ったFINセグメントの数
+    fn fins_sent(net: &SimNetwork, side: Side) -> usize {
+        net.trace()
+            .iter()
+            .filter(|e| {
+                e.from == side && e.event == LinkEvent::Sent && e.flags & tcp_flags::FIN != 0
+            })
+            .count()
+    }
+
+    fn closed(side: Side) -> impl Fn(&SimNetwork) -> bool {
+        move |net| net.connection(side).state() == TcpState::Closed
+    }
+
+    #[test]
+    fn test_fin_consumes_one_sequence_number() {
+        let mut buffer = ReceiveBuffer::new(seq(100));
+        buffer.receive(seq(100), b"hello").unwrap();
+        buffer.receive_fin(seq(105));
+
+        assert_eq!(buffer.next_expected(), seq(106));
+        assert!(buffer.fin_received());
+        // 読み終えるまではEOFではない
+        assert!(!buffer.is_eof());
+        assert_eq!(buffer.read(100), b"hello");
+        assert!(buffer.is_eof());
+    }
+
+    #[test]
+    fn test_out_of_order_fin_waits_for_missing_data() {
+        let mut buffer = ReceiveBuffer::new(seq(100));
+        buffer.receive(seq(105), b"world").unwrap();
+        buffer.receive_fin(seq(110));
+        assert_eq!(buffer.next_expected(), seq(100));
+        assert!(!buffer.fin_received());
+
+        // FINより後ろのデータは受け取らない
+        buffer.receive(seq(110), b"extra").unwrap();
+        buffer.receive(seq(100), b"hello").unwrap();
+        assert_eq!(buffer.next_expected(), seq(111));
+        assert!(buffer.fin_received());
+        assert_eq!(buffer.read(100), b"helloworld");
+        assert!(buffer.is_eof());
+    }
+
+    #[test]
+    fn test_fin_piggybacks_on_last_data_segment() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.send(&[1; 2000]).unwrap();
+        conn.close().unwrap();
+        assert_eq!(conn.state(), TcpState::FinWait1);
+        assert!(conn.send(b"late").is_err());
+
+        let first = conn.poll_transmit(now).unwrap();
+        assert!(!first.has_flag(tcp_flags::FIN));
+        let last = conn.poll_transmit(now).unwrap();
+        assert!(last.has_flag(tcp_flags::FIN));
+        assert_eq!(last.seq, seq(DEFAULT_MSS as u32));
+        assert_eq!(last.payload.len(), 2000 - DEFAULT_MSS);
+        assert!(conn.poll_transmit(now).is_none());
+
+        // FINのACKはデータの末尾+1
+        let ack = TcpSegment::new(seq(0), seq(2001), tcp_flags::ACK, DEFAULT_WINDOW);
+        conn.on_segment(&ack, now).unwrap();
+        assert_eq!(conn.state(), TcpState::FinWait2);
+        assert_eq!(conn.poll_timeout(), None);
+    }
+
+    #[test]
+    fn test_fin_without_data_and_retransmission() {
+        let start = Instant::now();
+        let mut conn = TcpConnection::new(seq(500), seq(0));
+        conn.shutdown(Shutdown::Write).unwrap();
+
+        let fin = conn.poll_transmit(start).unwrap();
+        assert!(fin.has_flag(tcp_flags::FIN));
+        assert!(fin.payload.is_empty());
+        assert_eq!(fin.seq, seq(500));
+
+        // FINが失われたら再送タイマーで送り直す
+        let deadline = conn.poll_timeout().unwrap();
+        conn.on_timeout(deadline).unwrap();
+        let again = conn.poll_transmit(deadline).unwrap();
+        assert!(again.has_flag(tcp_flags::FIN));
+        assert_eq!(again.seq, seq(500));
+        assert_eq!(conn.retransmissions(), 1);
+    }
+
+    #[test]
+    fn test_passive_close_signals_eof() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(1000));
+        conn.on_segment(&fin(1000, 0, b"bye"), now).unwrap();
+        assert_eq!(conn.state(), TcpState::CloseWait);
+        // データとFINの両方にACKを返す
+        assert_eq!(conn.poll_transmit(now).unwrap().ack, seq(1004));
+
+        assert!(!conn.is_eof());
+        assert_eq!(conn.read(100), b"bye");
+        assert!(conn.is_eof());
+
+        // CLOSE-WAITでも送信はできる
+        conn.send(b"ok").unwrap();
+        conn.close().unwrap();
+        assert_eq!(conn.state(), TcpState::LastAck);
+        let last = conn.poll_transmit(now).unwrap();
+        assert!(last.has_flag(tcp_flags::FIN));
+        let ack = TcpSegment::new(seq(1004), seq(3), tcp_flags::ACK, DEFAULT_WINDOW);
+        conn.on_segment(&ack, now).unwrap();
+        assert_eq!(conn.state(), TcpState::Closed);
+    }
+
+    #[test]
+    fn test_retransmitted_fin_is_acked_again() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(1000));
+        conn.on_segment(&fin(1000, 0, b""), now).unwrap();
+        assert_eq!(conn.poll_transmit(now).unwrap().ack, seq(1001));
+
+        conn.on_segment(&fin(1000, 0, b""), now).unwrap();
+        assert_eq!(conn.state(), TcpState::CloseWait);
+        assert_eq!(conn.poll_transmit(now).unwrap().ack, seq(1001));
+    }
+
+    #[test]
+    fn test_shutdown_read_discards_incoming_data() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(1000));
+        conn.shutdown(Shutdown::Read).unwrap();
+        let segment = TcpSegment::new(seq(1000), seq(0), tcp_flags::ACK, DEFAULT_WINDOW)
+            .with_payload(vec![1; 100]);
+        conn.on_segment(&segment, now).unwrap();
+
+        // ACKは返すが、データは読めずウィンドウも減らない
+        assert_eq!(conn.poll_transmit(now).unwrap().ack, seq(1100));
+        assert!(conn.read(100).is_empty());
+        assert_eq!(conn.recv_buffer().available(), 0);
+        // 送信側はまだ開いている
+        assert_eq!(conn.state(), TcpState::Established);
+        conn.send(b"still writable").unwrap();
+    }
+
+    #[test]
+    fn test_graceful_close_over_simnet() {
+        let mut net = SimNetwork::new(3, LinkConfig::default());
+        net.connection_mut(Side::A).send(&data()).unwrap();
+        net.connection_mut(Side::A).close().unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+
+        // Bは全データの後にEOFを見る
+        let b = net.connection_mut(Side::B);
+        assert_eq!(b.state(), TcpState::CloseWait);
+        assert_eq!(b.read(usize::MAX), data());
+        assert!(b.is_eof());
+        assert_eq!(net.connection(Side::A).state(), TcpState::FinWait2);
+
+        net.connection_mut(Side::B).close().unwrap();
+        assert!(net.run_until(closed(Side::B), Duration::from_secs(60)));
+        assert_eq!(net.connection(Side::A).state(), TcpState::TimeWait);
+        assert!(net.connection(Side::A).is_eof());
+        assert_eq!(fins_sent(&net, Side::A), 1);
+        assert_eq!(fins_sent(&net, Side::B), 1);
+    }
+
+    #[test]
+    fn test_simultaneous_close() {
+        let mut net = SimNetwork::new(5, LinkConfig::default());
+        for side in [Side::A, Side::B] {
+            net.connection_mut(side).send(&data()).unwrap();
+            net.connection_mut(side).shutdown(Shutdown::Write).unwrap();
+        }
+        let both = |net: &SimNetwork| {
+            [Side::A, Side::B]
+                .iter()
+                .all(|&side| net.connection(side).state() == TcpState::TimeWait)
+        };
+        assert!(net.run_until(both, Duration::from_secs(60)));
+
+        // 両方がFIN-WAIT-1 → CLOSING → TIME-WAITを通る
+        for side in [Side::A, Side::B] {
+            let conn = net.connection_mut(side);
+            assert_eq!(conn.state(), TcpState::TimeWait);
+            assert_eq!(conn.read(usize::MAX), data());
+            assert!(conn.is_eof());
+        }
+    }
+
+    #[test]
+    fn test_close_over_lossy_link() {
+        let link = LinkConfig {
+            jitter: Duration::from_millis(5),
+            loss_rate: 0.1,
+            duplicate_rate: 0.1,
+            reorder_rate: 0.1,
+            reorder_delay: Duration::from_millis(20),
+            ..LinkConfig::default()
+        };
+        let mut net = SimNetwork::new(11, link);
+        net.connection_mut(Side::A).send(&data()).unwrap();
+        net.connection_mut(Side::A).close().unwrap();
+        let closing = |net: &SimNetwork| net.connection(Side::B).state() == TcpState::CloseWait;
+        assert!(net.run_until(closing, Duration::from_secs(600)));
+        // close()は読んでいないデータを捨てるので、書き込み側だけ閉じる
+        net.connection_mut(Side::B)
+            .shutdown(Shutdown::Write)
+            .unwrap();
+        assert!(net.run_until(closed(Side::B), Duration::from_secs(600)));
+
+        assert_eq!(net.connection_mut(Side::B).read(usize::MAX), data());
+        assert_eq!(net.connection(Side::A).state(), TcpState::TimeWait);
+        assert_eq!(net.connection(Side::B).state(), TcpState::Closed);
+    }
+}
+
+// =============================================================================
+// 発展: TIME-WAIT（2·MSL、4タプルの再利用）
+// =============================================================================
+
+#[cfg(test)]
+mod time_wait_tests {
+    use super::*;
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+    use time_wait::{TimeWaitEntry, TimeWaitTable};
+
+    const MSL: Duration = Duration::from_secs(1);
+
+    fn quadruple() -> (SocketAddr, SocketAddr) {
+        (
+            "10.0.0.2:40000".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap(),
+        )
+    }
+
+    fn peer_fin(ack: u32) -> TcpSegment {
+        TcpSegment::new(
+            seq(1000),
+            seq(ack),
+            tcp_flags::FIN | tcp_flags::ACK,
+            DEFAULT_WINDOW,
+        )
+    }
+
+    /// FINを送り、相手のFINとACKを受け取ってTIME-WAITに入った接続
+    fn time_wait_connection(now: Instant) -> TcpConnection {
+        let mut conn = TcpConnection::new(seq(0), seq(1000));
+        conn.set_msl(MSL);
+        conn.close().unwrap();
+        assert!(conn.poll_transmit(now).unwrap().has_flag(tcp_flags::FIN));
+        conn.on_segment(&peer_fin(1), now).unwrap();
+        assert_eq!(conn.state(), TcpState::TimeWait);
+        assert_eq!(conn.poll_transmit(now).unwrap().ack, seq(1001));
+        conn
+    }
+
+    fn entry(ts_recent: Option<u32>, expires_at: Instant) -> TimeWaitEntry {
+        TimeWaitEntry {
+            snd_nxt: seq(1),
+            rcv_nxt: seq(1001),
+            ts_recent,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_time_wait_lasts_two_msl() {
+        let start = Instant::now();
+        let mut conn = time_wait_connection(start);
+        assert_eq!(conn.poll_timeout(), Some(start + 2 * MSL));
+
+        conn.on_timeout(start + 2 * MSL - Duration::from_millis(1))
+            .unwrap();
+        assert_eq!(conn.state(), TcpState::TimeWait);
+        conn.on_timeout(start + 2 * MSL).unwrap();
+        assert_eq!(conn.state(), TcpState::Closed);
+        assert_eq!(conn.poll_timeout(), None);
+    }
+
+    #[test]
+    fn test_retransmitted_fin_restarts_timer() {
+        let start = Instant::now();
+        let mut conn = time_wait_connection(start);
+
+        // 最後のACKが失われ、相手がFINを再送してきた
+        let later = start + MSL;
+        conn.on_segment(&peer_fin(1), later).unwrap();
+        let ack = conn.poll_transmit(later).unwrap();
+        assert_eq!(ack.flags, tcp_flags::ACK);
+        assert_eq!((ack.seq, ack.ack), (seq(1), seq(1001)));
+        assert_eq!(conn.poll_timeout(), Some(later + 2 * MSL));
+    }
+
+    #[test]
+    fn test_time_wait_entry() {
+        let start = Instant::now();
+        let established = TcpConnection::new(seq(0), seq(1000));
+        assert_eq!(established.time_wait_entry(), None);
+
+        let conn = time_wait_connection(start);
+        assert_eq!(conn.time_wait_entry(), Some(entry(None, start + 2 * MSL)));
+    }
+
+    #[test]
+    fn test_quadruple_is_rejected_until_expiry() {
+        let now = Instant::now();
+        let mut table = TimeWaitTable::with_msl(MSL);
+        table.insert(quadruple(), entry(None, now + 2 * MSL));
+        assert!(table.contains(&quadruple(), now));
+
+        // 前の接続のシーケンス番号の範囲にあるSYNは、古い重複かもしれない
+        assert!(!table.accept_syn(&quadruple(), seq(500), None, now));
+        assert!(table.contains(&quadruple(), now));
+
+        // 別の4タプルには関係ない
+        let other = (quadruple().0, "10.0.0.1:81".parse().unwrap());
+        assert!(table.accept_syn(&other, seq(500), None, now));
+
+        let expired = now + 2 * MSL;
+        assert!(!table.contains(&quadruple(), expired));
+        assert_eq!(table.expire(expired), 1);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_syn_beyond_rcv_nxt_reuses_quadruple() {
+        let now = Instant::now();
+        let mut table = TimeWaitTable::new();
+        table.insert(quadruple(), entry(None, now + 2 * MSL));
+
+        assert!(!table.accept_syn(&quadruple(), seq(1001), None, now));
+        assert!(table.accept_syn(&quadruple(), seq(1002), None, now));
+        assert!(!table.contains(&quadruple(), now));
+    }
+
+    #[test]
+    fn test_timestamps_allow_early_reuse() {
+        let now = Instant::now();
+        let mut table = TimeWaitTable::new();
+        table.insert(quadruple(), entry(Some(u32::MAX - 5), now + 2 * MSL));
+
+        // Timestampsを使っていたなら、シーケンス番号ではなくTSvalで新しさを判断する（RFC 6191）
+        assert!(!table.accept_syn(&quadruple(), seq(5000), Some(u32::MAX - 10), now));
+        assert!(!table.accept_syn(&quadruple(), seq(5000), Some(u32::MAX - 5), now));
+        // TSvalは一周しても新しいと分かる
+        assert!(table.accept_syn(&quadruple(), seq(0), Some(3), now));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_restart_extends_expiry() {
+        let now = Instant::now();
+        let mut table = TimeWaitTable::with_msl(MSL);
+        table.insert(quadruple(), entry(None, now + 2 * MSL));
+
+        let later = now + MSL;
+        let restarted = table.restart(&quadruple(), later).unwrap();
+        assert_eq!(restarted.snd_nxt, seq(1));
+        assert_eq!(restarted.expires_at, later + 2 * MSL);
+        assert!(table.contains(&quadruple(), now + 2 * MSL));
+        assert!(table.restart(&quadruple(), later + 2 * MSL).is_none());
+    }
+
+    #[test]
+    fn test_lost_last_ack_over_simnet() {
+        let mut net = SimNetwork::new(7, LinkConfig::default());
+        for side in [Side::A, Side::B] {
+            net.connection_mut(side).set_msl(MSL);
+        }
+        net.connection_mut(Side::A).close().unwrap();
+        let fin_wait_2 = |net: &SimNetwork| net.connection(Side::A).state() == TcpState::FinWait2;
+        assert!(net.run_until(fin_wait_2, Duration::from_secs(10)));
+
+        // Bのclose()の後にAが最初に送るのが最後のACK
+        let last_ack = net.stats(Side::A).sent;
+        net.drop_nth(Side::A, last_ack);
+        net.connection_mut(Side::B).close().unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+
+        // BはFINを再送し、TIME-WAITのAが返し直したACKで閉じる
+        let b_fins = net
+            .trace()
+            .iter()
+            .filter(|e| {
+                e.from == Side::B && e.event == LinkEvent::Sent && e.flags & tcp_flags::FIN != 0
+            })
+            .count();
+        assert_eq!(b_fins, 2);
+        for side in [Side::A, Side::B] {
+            assert_eq!(net.connection(side).state(), TcpState::Closed);
+        }
+    }
+}
+
+// =============================================================================
+// 発展: RSTとRFC 5961（ブラインド攻撃への対策）
+// =============================================================================
+
+#[cfg(test)]
+mod rst_tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn rst(seg_seq: u32) -> TcpSegment {
+        TcpSegment::new(seq(seg_seq), seq(0), tcp_flags::RST, 0)
+    }
+
+    /// 相手のRCV.NXTは1000
+    fn connection() -> TcpConnection {
+        TcpConnection::new(seq(0), seq(1000))
+    }
+
+    #[test]
+    fn test_reset_reply() {
+        // ACK付きのセグメントには、そのACK番号をシーケンス番号にしたRST
+        let ack =
+            TcpSegment::new(seq(100), seq(5000), tcp_flags::ACK, 1000).with_payload(vec![1; 10]);
+        let reply = ack.reset_reply().unwrap();
+        assert_eq!((reply.seq, reply.flags), (seq(5000), tcp_flags::RST));
+
+        // ACKがなければ、SYNとデータの次をACKする
+        let syn = TcpSegment::new(seq(100), seq(0), tcp_flags::SYN, 1000).with_payload(vec![1; 10]);
+        let reply = syn.reset_reply().unwrap();
+        assert_eq!(reply.seq, seq(0));
+        assert_eq!(reply.ack, seq(111));
+        assert_eq!(reply.flags, tcp_flags::RST | tcp_flags::ACK);
+
+        // RSTにはRSTを返さない
+        assert!(rst(100).reset_reply().is_none());
+    }
+
+    #[test]
+    fn test_exact_rst_resets_connection() {
+        let now = Instant::now();
+        let mut conn = connection();
+        conn.send(&[1; 100]).unwrap();
+        conn.poll_transmit(now).unwrap();
+        assert!(conn.poll_timeout().is_some());
+
+        assert!(conn.on_segment(&rst(1000), now).is_err());
+        assert_eq!(conn.state(), TcpState::Closed);
+        assert_eq!(conn.poll_timeout(), None);
+        assert!(conn.poll_transmit(now).is_none());
+    }
+
+    #[test]
+    fn test_in_window_rst_gets_challenge_ack() {
+        let now = Instant::now();
+        let mut conn = connection();
+
+        // ウィンドウ内を推測しただけのRSTでは切れない
+        for guess in (1001..1000 + DEFAULT_RECV_BUFFER_SIZE as u32).step_by(997) {
+            conn.on_segment(&rst(guess), now).unwrap();
+        }
+        assert_eq!(conn.state(), TcpState::Established);
+        let challenge = conn.poll_transmit(now).unwrap();
+        assert_eq!(challenge.flags, tcp_flags::ACK);
+        assert_eq!(challenge.ack, seq(1000));
+        assert!(conn.poll_transmit(now).is_none());
+
+        // 本物の相手はチャレンジACKのACK番号でRSTを送り直せる
+        assert!(conn.on_segment(&rst(challenge.ack.value()), now).is_err());
+        assert_eq!(conn.state(), TcpState::Closed);
+    }
+
+    #[test]
+    fn test_out_of_window_rst_is_ignored() {
+        let now = Instant::now();
+        let mut conn = connection();
+        for guess in [999, 1000 + DEFAULT_RECV_BUFFER_SIZE as u32, 1_000_000] {
+            conn.on_segment(&rst(guess), now).unwrap();
+        }
+        assert_eq!(conn.state(), TcpState::Established);
+        assert_eq!(conn.challenge_acks(), 0);
+        assert!(conn.poll_transmit(now).is_none());
+    }
+
+    #[test]
+    fn test_syn_gets_challenge_ack() {
+        let now = Instant::now();
+        let mut conn = connection();
+        // ウィンドウ内でもウィンドウ外でも、SYNで接続を捨てない
+        for syn_seq in [1000, 1500, 7] {
+            let syn = TcpSegment::new(seq(syn_seq), seq(0), tcp_flags::SYN, 1000);
+            conn.on_segment(&syn, now).unwrap();
+            let challenge = conn.poll_transmit(now).unwrap();
+            assert_eq!((challenge.seq, challenge.ack), (seq(0), seq(1000)));
+        }
+        assert_eq!(conn.state(), TcpState::Established);
+        assert_eq!(conn.challenge_acks(), 3);
+    }
+
+    #[test]
+    fn test_challenge_acks_are_rate_limited() {
+        let start = Instant::now();
+        let mut conn = connection();
+        conn.set_challenge_ack_limit(3);
+        for _ in 0..10 {
+            conn.on_segment(&rst(1500), start).unwrap();
+        }
+        assert_eq!(conn.challenge_acks(), 3);
+
+        // 1秒経てばまた送れる
+        conn.on_segment(&rst(1500), start + Duration::from_millis(999))
+            .unwrap();
+        assert_eq!(conn.challenge_acks(), 3);
+        conn.on_segment(&rst(1500), start + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(conn.challenge_acks(), 4);
+    }
+
+    #[test]
+    fn test_closed_connection_answers_with_rst() {
+        let now = Instant::now();
+        let mut conn = connection();
+        conn.on_segment(&rst(1000), now).unwrap_err();
+
+        let data =
+            TcpSegment::new(seq(1000), seq(0), tcp_flags::ACK, 1000).with_payload(vec![1; 10]);
+        conn.on_segment(&data, now).unwrap();
+        let reply = conn.poll_transmit(now).unwrap();
+        assert_eq!((reply.seq, reply.flags), (seq(0), tcp_flags::RST));
+        assert!(conn.poll_transmit(now).is_none());
+
+        // RSTにはRSTを返さない
+        conn.on_segment(&rst(1000), now).unwrap();
+        assert!(conn.poll_transmit(now).is_none());
+    }
+}
+
+// =============================================================================
+// 発展: 遅延ACK（RFC 1122, RFC 5681）
+// =============================================================================
+
+#[cfg(test)]
+mod delayed_ack_tests {
+    use super::*;
+    use delayed_ack::{DEFAULT_ACK_DELAY, DEFAULT_QUICK_ACKS};
+    use simnet::{LinkConfig, Side, SimNetwork};
+    use std::time::{Duration, Instant};
+
+    fn data(seg_seq: u32, len: usize) -> TcpSegment {
+        TcpSegment::new(seq(seg_seq), seq(0), tcp_flags::ACK, DEFAULT_WINDOW)
+            .with_payload(vec![0; len])
+    }
+
+    /// 相手のRCV.NXTは0。quick-ACKモードを使い切ってから返す（次に期待するのは`DEFAULT_QUICK_ACKS`）
+    fn past_quick_ack(now: Instant) -> TcpConnection {
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        for n in 0..DEFAULT_QUICK_ACKS {
+            conn.on_segment(&data(n, 1), now).unwrap();
+            assert!(conn.poll_transmit(now).is_some());
+        }
+        assert_eq!(conn.ack_scheduler().quick_acks(), 0);
+        conn
+    }
+
+    #[test]
+    fn test_quick_ack_then_delay() {
+        let now = Instant::now();
+        let mut conn = past_quick_ack(now);
+
+        // quick-ACKモードを抜けると、小さなセグメントへのACKは遅延タイマーまで待つ
+        let start = DEFAULT_QUICK_ACKS;
+        conn.on_segment(&data(start, 1), now).unwrap();
+        assert!(conn.poll_transmit(now).is_none());
+        let deadline = now + DEFAULT_ACK_DELAY;
+        assert_eq!(conn.poll_timeout(), Some(deadline));
+
+        // タイマーは最初の未確認のセグメントから数える
+        let later = now + Duration::from_millis(150);
+        conn.on_segment(&data(start + 1, 1), later).unwrap();
+        assert!(conn.poll_transmit(later).is_none());
+        assert_eq!(conn.poll_timeout(), Some(deadline));
+
+        conn.on_timeout(deadline).unwrap();
+        let ack = conn.poll_transmit(deadline).unwrap();
+        assert_eq!(ack.ack, seq(start + 2));
+        assert_eq!(conn.poll_timeout(), None);
+    }
+
+    #[test]
+    fn test_every_second_full_sized_segment() {
+        let now = Instant::now();
+        let mut conn = past_quick_ack(now);
+        let start = DEFAULT_QUICK_ACKS;
+        let mss = conn.mss();
+
+        conn.on_segment(&data(start, mss), now).unwrap();
+        assert!(conn.poll_transmit(now).is_none());
+        conn.on_segment(&data(start + mss as u32, mss), now)
+            .unwrap();
+        let ack = conn.poll_transmit(now).unwrap();
+        assert_eq!(ack.ack, seq(start + 2 * mss as u32));
+        assert_eq!(conn.ack_scheduler().deadline(), None);
+    }
+
+    #[test]
+    fn test_out_of_order_and_gap_fill_are_acked_immediately() {
+        let now = Instant::now();
+        let mut conn = past_quick_ack(now);
+        let start = DEFAULT_QUICK_ACKS;
+
+        // 順序外: 重複ACKで送信側に穴を知らせる
+        conn.on_segment(&data(start + 10, 10), now).unwrap();
+        assert_eq!(conn.poll_transmit(now).unwrap().ack, seq(start));
+
+        // 穴を埋めたら、進んだACK番号をすぐ返す
+        conn.on_segment(&data(start, 10), now).unwrap();
+        assert_eq!(conn.poll_transmit(now).unwrap().ack, seq(start + 20));
+
+        // 重複したセグメントにもすぐ返す
+        conn.on_segment(&data(start, 10), now).unwrap();
+        assert_eq!(conn.poll_transmit(now).unwrap().ack, seq(start + 20));
+    }
+
+    #[test]
+    fn test_delayed_ack_piggybacks_on_data() {
+        let now = Instant::now();
+        let mut conn = past_quick_ack(now);
+        let start = DEFAULT_QUICK_ACKS;
+
+        conn.on_segment(&data(start, 1), now).unwrap();
+        assert!(conn.ack_scheduler().deadline().is_some());
+
+        // 送るデータがあれば、遅延させていたACKはそれに相乗りする
+        conn.send(b"reply").unwrap();
+        let segment = conn.poll_transmit(now).unwrap();
+        assert_eq!(segment.payload, b"reply");
+        assert_eq!(segment.ack, seq(start + 1));
+        assert_eq!(conn.ack_scheduler().deadline(), None);
+        assert!(conn.poll_transmit(now).is_none());
+    }
+
+    #[test]
+    fn test_disabled_acks_every_segment() {
+        let now = Instant::now();
+        let mut conn = past_quick_ack(now);
+        conn.set_delayed_ack_enabled(false);
+        let start = DEFAULT_QUICK_ACKS;
+
+        for n in 0..3 {
+            conn.on_segment(&data(start + n, 1), now).unwrap();
+            assert_eq!(conn.poll_transmit(now).unwrap().ack, seq(start + n + 1));
+        }
+    }
+
+    #[test]
+    fn test_bulk_transfer_acks_every_other_segment() {
+        let mut net = SimNetwork::new(61, LinkConfig::default());
+        let data: Vec<u8> = (0..60_000).map(|i| (i % 251) as u8).collect();
+        net.connection_mut(Side::A).send(&data).unwrap();
+        assert!(net.run_until_idle(Duration::from_secs(60)));
+        assert_eq!(net.connection_mut(Side::B).read(usize::MAX), data);
+
+        // quick-ACKの後は2セグメントに1つのACK
+        let segments = net.stats(Side::A).sent;
+        let acks = net.stats(Side::B).sent;
+        let quick = DEFAULT_QUICK_ACKS as u64;
+        assert_eq!(segments, 42);
+        assert_eq!(acks, quick + (segments - quick).div_ceil(2));
+    }
+}
+
+// =============================================================================
+// 発展: Nagleのアルゴリズム（RFC 896）とNODELAY / CORK
+// =============================================================================
+
+#[cfg(test)]
+mod nagle_tests {
+    use super::*;
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork};
+    use std::time::{Duration, Instant};
+
+    fn ack(n: u32) -> TcpSegment {
+        TcpSegment::new(seq(0), seq(n), tcp_flags::ACK, DEFAULT_WINDOW)
+    }
+
+    /// 送れるだけ送り、各セグメントのデータ長を返す
+    fn drain(conn: &mut TcpConnection, now: Instant) -> Vec<usize> {
+        std::iter::from_fn(|| conn.poll_transmit(now))
+            .map(|segment| segment.payload.len())
+            .collect()
+    }
+
+    #[test]
+    fn test_tiny_writes_coalesce() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+
+        // アイドルなら1バイトでもすぐ送る
+        conn.send(b"a").unwrap();
+        assert_eq!(drain(&mut conn, now), [1]);
+
+        // 小さなセグメントが未確認の間は貯める
+        for _ in 0..9 {
+            conn.send(b"b").unwrap();
+            assert!(drain(&mut conn, now).is_empty());
+        }
+
+        // ACKが届いたら、貯めた分を1つのセグメントで送る
+        conn.on_segment(&ack(1), now).unwrap();
+        assert_eq!(drain(&mut conn, now), [9]);
+    }
+
+    #[test]
+    fn test_full_sized_segments_are_not_delayed() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.send(b"a").unwrap();
+        assert_eq!(drain(&mut conn, now), [1]);
+
+        // MSS分はすぐ送り、端数だけ待つ
+        conn.send(&[0; 2000]).unwrap();
+        assert_eq!(drain(&mut conn, now), [DEFAULT_MSS]);
+        conn.on_segment(&ack(1), now).unwrap();
+        assert_eq!(drain(&mut conn, now), [2000 - DEFAULT_MSS]);
+    }
+
+    #[test]
+    fn test_nodelay_sends_every_write() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.set_nodelay(true);
+        assert!(conn.transmit_policy().nodelay());
+
+        for _ in 0..5 {
+            conn.send(b"x").unwrap();
+            assert_eq!(drain(&mut conn, now), [1]);
+        }
+    }
+
+    #[test]
+    fn test_cork_holds_partial_segments() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.cork();
+
+        // アイドルでもMSSに満たない分は送らない
+        conn.send(b"header").unwrap();
+        assert!(drain(&mut conn, now).is_empty());
+        conn.send(&[0; 3000]).unwrap();
+        assert_eq!(drain(&mut conn, now), [DEFAULT_MSS, DEFAULT_MSS]);
+
+        conn.uncork();
+        assert_eq!(drain(&mut conn, now), [3006 - 2 * DEFAULT_MSS]);
+    }
+
+    #[test]
+    fn test_close_flushes_corked_data() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(0), seq(0));
+        conn.cork();
+        conn.send(b"bye").unwrap();
+        assert!(drain(&mut conn, now).is_empty());
+
+        conn.shutdown(Shutdown::Write).unwrap();
+        let segment = conn.poll_transmit(now).unwrap();
+        assert_eq!(segment.payload, b"bye");
+        assert!(segment.has_flag(tcp_flags::FIN));
+    }
+
+    /// 1msごとに1バイト書き込み、Aが送ったデータセグメントの数を返す
+    fn keystrokes(nodelay: bool) -> usize {
+        let mut net = SimNetwork::new(71, LinkConfig::default());
+        net.connection_mut(Side::A).set_nodelay(nodelay);
+        for _ in 0..100 {
+            net.connection_mut(Side::A).send(b"k").unwrap();
+            net.run_for(Duration::from_millis(1));
+        }
+        assert!(net.run_until_idle(Duration::from_secs(5)));
+        assert_eq!(net.connection_mut(Side::B).read(usize::MAX), [b'k'; 100]);
+        net.trace()
+            .iter()
+            .filter(|e| e.from == Side::A && e.event == LinkEvent::Sent && e.payload_len > 0)
+            .count()
+    }
+
+    #[test]
+    fn test_keystrokes_over_simnet() {
+        // Nagleでは1往復（20ms）に1セグメント程度、NODELAYでは書き込みごとに1セグメント
+        let coalesced = keystrokes(false);
+        assert!(coalesced <= 10, "{}", coalesced);
+        assert_eq!(keystrokes(true), 100);
+    }
+}
+
+// =============================================================================
+// 発展: キープアライブ（RFC 1122）
+// =============================================================================
+
+#[cfg(test)]
+mod keepalive_tests {
+    use super::*;
+    use keepalive::KeepaliveConfig;
+    use simnet::{LinkConfig, LinkEvent, Side, SimNetwork};
+    use std::time::{Duration, Instant};
+
+    fn config() -> KeepaliveConfig {
+        KeepaliveConfig {
+            idle: Duration::from_secs(10),
+            interval: Duration::from_secs(1),
+            probes: 3,
+        }
+    }
+
+    /// SND.NXT = 100, RCV.NXT = 500 でキープアライブを有効にした接続
+    fn connection() -> TcpConnection {
+        let mut conn = TcpConnection::new(seq(100), seq(500));
+        conn.set_keepalive(Some(config()));
+        conn
+    }
+
+    #[test]
+    fn test_probe_after_idle() {
+        let now = Instant::now();
+        let mut conn = connection();
+        assert!(conn.poll_transmit(now).is_none());
+        let at = now + config().idle;
+        assert_eq!(conn.poll_timeout(), Some(at));
+
+        conn.on_timeout(at).unwrap();
+        let probe = conn.poll_transmit(at).unwrap();
+        assert_eq!(probe.seq, seq(99)); // SND.NXT - 1
+        assert_eq!(probe.ack, seq(500));
+        assert!(probe.payload.is_empty());
+        assert_eq!(conn.keepalive_probes(), 1);
+        assert_eq!(conn.poll_timeout(), Some(at + config().interval));
+    }
+
+    #[test]
+    fn test_abort_when_probes_are_unanswered() {
+        let now = Instant::now();
+        let mut conn = connection();
+        conn.poll_transmit(now);
+        while let Some(at) = conn.poll_timeout() {
+            match conn.on_timeout(at) {
+                Ok(()) => while conn.poll_transmit(at).is_some() {},
+                Err(e) => {
+                    assert_eq!(e, TimeoutError::Keepalive { probes: 3 });
+                    assert_eq!(at, now + config().timeout());
+                    break;
+                }
+            }
+        }
+        assert_eq!(conn.keepalive_probes(), 3);
+        assert_eq!(conn.state(), TcpState::Closed);
+        assert_eq!(conn.poll_timeout(), None);
+    }
+
+    #[test]
+    fn test_response_restarts_idle_timer() {
+        let now = Instant::now();
+        let mut conn = connection();
+        conn.poll_transmit(now);
+        let at = now + config().idle;
+        conn.on_timeout(at).unwrap();
+        conn.poll_transmit(at).unwrap();
+
+        // プローブへのACKが届いたら、またアイドル時間から数える
+        let later = at + Duration::from_millis(20);
+        let ack = TcpSegment::new(seq(500), seq(100), tcp_flags::ACK, DEFAULT_WINDOW);
+        conn.on_segment(&ack, later).unwrap();
+        assert!(conn.poll_transmit(later).is_none());
+        assert_eq!(conn.poll_timeout(), Some(later + config().idle));
+    }
+
+    #[test]
+    fn test_probe_is_acknowledged() {
+        let now = Instant::now();
+        let mut conn = TcpConnection::new(seq(100), seq(500));
+        let probe = TcpSegment::new(seq(499), seq(100), tcp_flags::ACK, DEFAULT_WINDOW);
+        conn.on_segment(&probe, now).unwrap();
+        let ack = conn.poll_transmit(now).unwrap();
+        assert_eq!((ack.seq, ack.ack), (seq(100), seq(500)));
+    }
+
+    #[test]
+    fn test_no_probes_while_data_is_in_flight() {
+        let now = Instant::now();
+        let mut conn = connection();
+        conn.send(b"data").unwrap();
+        conn.poll_transmit(now).unwrap();
+        // 再送タイマーだけが動く
+        assert_eq!(conn.poll_timeout(), Some(now + conn.rto().rto()));
+    }
+
+    #[test]
+    fn test_dead_peer_over_simnet() {
+        let mut net = SimNetwork::new(81, LinkConfig::default());
+        net.connection_mut(Side::A).set_keepalive(Some(config()));
+        net.connection_mut(Side::A).send(b"hello").unwrap();
+
+        // 相手が生きていれば、プローブに応答があって接続は続く
+        net.run_for(Duration::from_secs(60));
+        assert!(net.connection(Side::A).keepalive_probes() >= 5);
+        assert_eq!(net.connection(Side::A).state(), TcpState::Established);
+
+        // 相手が落ちたら、最後に応答があってからidle + interval·probesで中止する
+        let dead = LinkConfig {
+            loss_rate: 1.0,
+            ..LinkConfig::default()
+        };
+        net.set_link_config(Side::B, dead);
+        let died = net.elapsed();
+        assert!(net.run_until(
+            |net| net.connection(Side::A).state() == TcpState::Closed,
+            Duration::from_secs(60),
+        ));
+        assert!(net.elapsed() - died <= config().timeout());
+        let unanswered = net
+            .trace()
+            .iter()
+            .filter(|e| e.at > died && e.from == Side::A && e.event == LinkEvent::Sent)
+            .count();
+        assert_eq!(unanswered, config().probes as usize);
+    }
+}
+
+// =============================================================================
+// std::ioのストリーム（stream.rs）
+// =============================================================================
+
+#[cfg(test)]
+mod stream_tests {
+    use super::*;
+    use keepalive::KeepaliveConfig;
+    use rust_tcp_handson_with_claude_code::step01::{IpHeader, LoopbackDevice, PacketDevice};
+    use std::io::{self, BufRead, Read, Write};
+    use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use stream::TcpStream;
+
+    const A_ISN: u32 = 100;
+    const B_ISN: u32 = 500;
+
+    fn addr_a() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 6, 1), 40000)
+    }
+
+    fn addr_b() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 6, 2), 8080)
+    }
+
+    fn stream_a(device: LoopbackDevice) -> TcpStream<LoopbackDevice> {
+        let conn = TcpConnection::new(SequenceNumber::new(A_ISN), SequenceNumber::new(B_ISN));
+        TcpStream::new(conn, device, addr_a(), addr_b())
+    }
+
+    fn stream_b(device: LoopbackDevice) -> TcpStream<LoopbackDevice> {
+        let conn = TcpConnection::new(SequenceNumber::new(B_ISN), SequenceNumber::new(A_ISN));
+        TcpStream::new(conn, device, addr_b(), addr_a())
+    }
+
+    /// ループバックでつながった、確立済みの2つのストリーム
+    fn pair() -> (TcpStream<LoopbackDevice>, TcpStream<LoopbackDevice>) {
+        let (device_a, device_b) = LoopbackDevice::pair();
+        (stream_a(device_a), stream_b(device_b))
+    }
+
+    /// Aの側から、IPv4に包んだセグメントを直接Bに送る
+    fn inject(device: &LoopbackDevice, segment: &TcpSegment) {
+        let tcp = segment.encode(addr_a(), addr_b()).unwrap();
+        let mut datagram =
+            IpHeader::new(*addr_a().ip(), *addr_b().ip(), tcp.len() as u16).to_wire_bytes(0);
+        datagram.extend_from_slice(&tcp);
+        device.send(&datagram).unwrap();
+    }
+
+    #[test]
+    fn test_read_into_caller_buffer() {
+        let (mut a, mut b) = pair();
+        assert_eq!(a.write(b"hello, world").unwrap(), 12);
+
+        let mut buf = [0u8; 5];
+        assert_eq!(b.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+        let mut rest = [0u8; 64];
+        let n = b.read(&mut rest).unwrap();
+        assert_eq!(&rest[..n], b", world");
+        assert_eq!(b.read(&mut []).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_buf_read_lines() {
+        let (mut a, mut b) = pair();
+        a.write_all(b"GET / HTTP/1.1\r\nHost: example\r\n\r\n")
+            .unwrap();
+        a.shutdown(Shutdown::Write).unwrap();
+
+        let mut line = String::new();
+        b.read_line(&mut line).unwrap();
+        assert_eq!(line, "GET / HTTP/1.1\r\n");
+        let rest: Vec<String> = b.lines().map(|line| line.unwrap()).collect();
+        assert_eq!(rest, vec!["Host: example", ""]);
+    }
+
+    #[test]
+    fn test_eof_after_peer_shutdown() {
+        let (mut a, mut b) = pair();
+        a.write_all(b"bye").unwrap();
+        a.shutdown(Shutdown::Write).unwrap();
+
+        let mut data = Vec::new();
+        assert_eq!(b.read_to_end(&mut data).unwrap(), 3);
+        assert_eq!(data, b"bye");
+        assert_eq!(b.connection().state(), TcpState::CloseWait);
+    }
+
+    #[test]
+    fn test_nonblocking_read_would_block() {
+        let (mut a, mut b) = pair();
+        b.set_nonblocking(true);
+        let mut buf = [0u8; 16];
+        let err = b.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        a.write_all(b"ping").unwrap();
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let (_a, mut b) = pair();
+        let timeout = Duration::from_millis(50);
+        b.set_read_timeout(Some(timeout)).unwrap();
+        assert_eq!(b.read_timeout(), Some(timeout));
+
+        let start = Instant::now();
+        let err = b.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= timeout);
+        // タイムアウトは接続を壊さない
+        assert_eq!(b.connection().state(), TcpState::Established);
+    }
+
+    #[test]
+    fn test_zero_timeout_is_rejected() {
+        let (mut a, _b) = pair();
+        let err = a.set_write_timeout(Some(Duration::ZERO)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(a.write_timeout(), None);
+    }
+
+    #[test]
+    fn test_reset_maps_to_connection_reset() {
+        let (device_a, device_b) = LoopbackDevice::pair();
+        let mut b = stream_b(device_b);
+        let rst = TcpSegment::new(
+            SequenceNumber::new(A_ISN),
+            SequenceNumber::new(0),
+            tcp_flags::RST,
+            0,
+        );
+        inject(&device_a, &rst);
+
+        let err = b.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(b.connection().state(), TcpState::Closed);
+        // 以降の書き込みも同じエラーになる
+        let err = b.write(b"data").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn test_write_after_shutdown_is_broken_pipe() {
+        let (mut a, _b) = pair();
+        a.shutdown(Shutdown::Write).unwrap();
+        let err = a.write(b"late").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_keepalive_failure_maps_to_timed_out() {
+        // Aは何も答えない
+        let (_device_a, device_b) = LoopbackDevice::pair();
+        let mut b = stream_b(device_b);
+        b.connection_mut().set_keepalive(Some(KeepaliveConfig {
+            idle: Duration::from_millis(30),
+            interval: Duration::from_millis(10),
+            probes: 2,
+        }));
+
+        let err = b.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // 再送の上限ではなくキープアライブで諦めたことが分かる
+        let reason = err.get_ref().and_then(|e| e.downcast_ref::<TimeoutError>());
+        assert_eq!(reason, Some(&TimeoutError::Keepalive { probes: 2 }));
+        assert_eq!(b.connection().state(), TcpState::Closed);
+        assert_eq!(b.connection().keepalive_probes(), 2);
+    }
+
+    #[test]
+    fn test_bulk_transfer_between_threads() {
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let (device_a, device_b) = LoopbackDevice::pair();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        // TcpConnectionはSendではないので、ストリームはスレッドの中で作る
+        let expected = data.clone();
+        let writer = thread::spawn(move || {
+            let mut a = stream_a(device_a);
+            a.write_all(&expected)?;
+            a.flush()?;
+            a.shutdown(Shutdown::Write)?;
+            // 読み終わるまでデバイスを残しておく
+            done_rx.recv().ok();
+            io::Result::Ok(())
+        });
+
+        let mut b = stream_b(device_b);
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        done_tx.send(()).unwrap();
+        writer.join().unwrap().unwrap();
+        assert_eq!(received, data);
+    }
+}
+
+// =============================================================================
+// epoll + timerfdのイベントループ（reactor.rs）
+// =============================================================================
+
+#[cfg(all(test, target_os = "linux"))]
+mod reactor_tests {
+    use super::*;
+    use keepalive::KeepaliveConfig;
+    use reactor::{Reactor, ReactorEvent};
+    use rust_tcp_handson_with_claude_code::step01::{
+        IpHeader, LoopbackDevice, PacketDevice, SocketPairDevice,
+    };
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::{Duration, Instant};
+    use stream::TcpStream;
+
+    const CLIENT_ISN: u32 = 100;
+    const SERVER_ISN: u32 = 500;
+
+    fn server() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 7, 2), 8080)
+    }
+
+    fn client(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 7, 1), port)
+    }
+
+    fn server_connection() -> TcpConnection {
+        TcpConnection::new(
+            SequenceNumber::new(SERVER_ISN),
+            SequenceNumber::new(CLIENT_ISN),
+        )
+    }
+
+    /// サーバー側のReactorと、クライアント側のデバイス
+    fn reactor() -> (Reactor<SocketPairDevice>, SocketPairDevice) {
+        let (client_device, server_device) = SocketPairDevice::pair().unwrap();
+        (Reactor::new(server_device).unwrap(), client_device)
+    }
+
+    /// クライアントから、IPv4に包んだセグメントを直接サーバーに送る
+    fn inject(device: &SocketPairDevice, from: SocketAddrV4, segment: &TcpSegment) {
+        let tcp = segment.encode(from, server()).unwrap();
+        let mut datagram =
+            IpHeader::new(*from.ip(), *server().ip(), tcp.len() as u16).to_wire_bytes(0);
+        datagram.extend_from_slice(&tcp);
+        device.send(&datagram).unwrap();
+    }
+
+    fn data(payload: &[u8]) -> TcpSegment {
+        TcpSegment::new(
+            SequenceNumber::new(CLIENT_ISN),
+            SequenceNumber::new(SERVER_ISN),
+            tcp_flags::ACK | tcp_flags::PSH,
+            65535,
+        )
+        .with_payload(payload.to_vec())
+    }
+
+    #[test]
+    fn test_device_without_fd_is_rejected() {
+        let (device, _peer) = LoopbackDevice::pair();
+        assert!(Reactor::new(device).is_err());
+    }
+
+    #[test]
+    fn test_poll_times_out_without_events() {
+        let (mut reactor, _client) = reactor();
+        let timeout = Duration::from_millis(50);
+        let start = Instant::now();
+        assert!(reactor.poll(Some(timeout)).unwrap().is_empty());
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn test_echo_with_stream_client() {
+        let (mut reactor, client_device) = reactor();
+        let key = (server(), client(40000));
+        reactor.insert(key.0, key.1, server_connection()).unwrap();
+        let conn = TcpConnection::new(
+            SequenceNumber::new(CLIENT_ISN),
+            SequenceNumber::new(SERVER_ISN),
+        );
+        let mut stream = TcpStream::new(conn, client_device, client(40000), server());
+
+        stream.write_all(b"hello").unwrap();
+        // パケットが届いたらすぐに起きる（スリープの間隔を待たない）
+        let start = Instant::now();
+        let events = reactor.poll(Some(Duration::from_secs(5))).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(events, vec![ReactorEvent::Segment(key)]);
+
+        let conn = reactor.connection_mut(&key).unwrap();
+        let request = conn.read(64);
+        assert_eq!(request, b"hello");
+        conn.send(&request).unwrap();
+        reactor.flush().unwrap();
+
+        let mut reply = [0u8; 5];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"hello");
+    }
+
+    #[test]
+    fn test_dispatches_by_four_tuple() {
+        let (mut reactor, client_device) = reactor();
+        for port in [40001, 40002, 40003] {
+            reactor
+                .insert(server(), client(port), server_connection())
+                .unwrap();
+        }
+        assert_eq!(reactor.len(), 3);
+        assert!(reactor
+            .insert(server(), client(40001), server_connection())
+            .is_err());
+
+        inject(&client_device, client(40003), &data(b"three"));
+        inject(&client_device, client(40001), &data(b"one"));
+        let events = reactor.poll(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                ReactorEvent::Segment((server(), client(40003))),
+                ReactorEvent::Segment((server(), client(40001))),
+            ]
+        );
+        let read = |reactor: &mut Reactor<SocketPairDevice>, port| {
+            reactor
+                .connection_mut(&(server(), client(port)))
+                .unwrap()
+                .read(64)
+        };
+        assert_eq!(read(&mut reactor, 40001), b"one");
+        assert_eq!(read(&mut reactor, 40002), b"");
+        assert_eq!(read(&mut reactor, 40003), b"three");
+    }
+
+    #[test]
+    fn test_unmatched_segment_is_returned() {
+        let (mut reactor, client_device) = reactor();
+        let syn = TcpSegment::new(
+            SequenceNumber::new(CLIENT_ISN),
+            SequenceNumber::new(0),
+            tcp_flags::SYN,
+            65535,
+        );
+        inject(&client_device, client(40004), &syn);
+        let events = reactor.poll(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            events,
+            vec![ReactorEvent::Unmatched {
+                key: (server(), client(40004)),
+                segment: syn,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_timer_wakes_without_packets() {
+        // クライアントは何も答えないので、キープアライブが尽きて閉じる
+        let (mut reactor, client_device) = reactor();
+        let key = (server(), client(40005));
+        let mut conn = server_connection();
+        let config = KeepaliveConfig {
+            idle: Duration::from_millis(30),
+            interval: Duration::from_millis(10),
+            probes: 2,
+        };
+        conn.set_keepalive(Some(config));
+        reactor.insert(key.0, key.1, conn).unwrap();
+
+        let start = Instant::now();
+        let mut timers = 0;
+        let closed = loop {
+            let events = reactor.poll(None).unwrap();
+            if let Some(ReactorEvent::TimedOut(closed, e)) = events
+                .iter()
+                .find(|event| matches!(event, ReactorEvent::TimedOut(..)))
+            {
+                assert_eq!(*e, TimeoutError::Keepalive { probes: 2 });
+                break *closed;
+            }
+            timers += events.len();
+        };
+        assert_eq!(closed, key);
+        assert!(timers >= 2);
+        assert!(start.elapsed() >= config.timeout());
+        assert_eq!(reactor.connection(&key).unwrap().state(), TcpState::Closed);
+        assert_eq!(reactor.next_timeout(), None);
+
+        // 送ったプローブはクライアントに届いている
+        let mut probes = 0;
+        while client_device
+            .recv_timeout(Duration::ZERO)
+            .unwrap()
+            .is_some()
+        {
+            probes += 1;
+        }
+        assert_eq!(probes, config.probes as usize);
+    }
+}
+
 /*
 TDD実行手順:
 1. cargo test phase_a_tests で Phase A のテストを実行
@@ -644,5 +3872,22 @@ TDD実行手順:
 - cargo test phase_d_tests  -- Phase D のテスト
 - cargo test phase_e_tests  -- Phase E のテスト
 - cargo test phase_f_tests  -- Phase F のテスト
+- cargo test simnet_tests   -- シミュレーションネットワークのテスト
+- cargo test rto_tests      -- 再送タイマー（RFC 6298）のテスト
+- cargo test congestion_tests -- 輻輳制御（RFC 5681）のテスト
+- cargo test fast_recovery_tests -- fast retransmit / fast recovery のテスト
+- cargo test sack_tests     -- SACK（RFC 2018, RFC 6675）のテスト
+- cargo test flow_control_tests -- フロー制御とSWS回避のテスト
+- cargo test persist_tests  -- persistタイマー（ゼロウィンドウプローブ）のテスト
+- cargo test window_scale_tests -- ウィンドウスケール（RFC 7323）のテスト
+- cargo test timestamps_tests -- Timestamps（RTTM, PAWS）のテスト
+- cargo test fin_tests      -- FINによる接続の終了のテスト
+- cargo test time_wait_tests -- TIME-WAIT（2·MSL、4タプルの再利用）のテスト
+- cargo test rst_tests      -- RSTとチャレンジACK（RFC 5961）のテスト
+- cargo test delayed_ack_tests -- 遅延ACKとquick-ACKのテスト
+- cargo test nagle_tests    -- Nagleのアルゴリズム、NODELAY、CORKのテスト
+- cargo test keepalive_tests -- キープアライブのテスト
+- cargo test stream_tests   -- std::ioのストリーム（Read / Write / BufRead）のテスト
+- cargo test reactor_tests  -- epoll + timerfdのイベントループのテスト
 - cargo test --bin step05   -- すべてのテスト
 */
@@ -0,0 +1,140 @@
+// TIME-WAIT (RFC 9293 Section 3.6.1, RFC 6191)
+//
+// 先に閉じた側は、相手のFINにACKを返してから2·MSLの間TIME-WAITに留まる。
+//
+//   - 最後のACKが失われて相手がFINを再送してきたら、ACKを返し直す（タイマーも再起動）
+//   - 同じ4タプルの次の接続に、前の接続の遅れたセグメントが紛れ込まないよう、
+//     期限まで同じ4タプルでの接続を拒否する
+//
+// 接続を捨てた後も4タプルをTimeWaitTableに残しておく。ただし新しいSYNが前の接続の
+// どのセグメントよりも新しいと分かれば、期限を待たずに再利用してよい（RFC 6191）:
+//
+//   前の接続でTimestampsを使っていて、SYNにもある: SYNのTSval > TS.Recent
+//   それ以外:                                         SYNのシーケンス番号 > RCV.NXT
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::SequenceNumber;
+
+/// セグメントがネットワーク上に残りうる最大の時間（MSL、RFC 9293 Section 3.4.1: 2分）
+pub const DEFAULT_MSL: Duration = Duration::from_secs(120);
+
+/// (ローカル, リモート)のアドレスとポート
+pub type Quadruple = (SocketAddr, SocketAddr);
+
+/// TIME-WAITの接続について覚えておくこと
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWaitEntry {
+    /// 自分の次のシーケンス番号（FINの次）。再送されたFINへのACKに使う
+    pub snd_nxt: SequenceNumber,
+    /// 相手の次のシーケンス番号（FINの次）
+    pub rcv_nxt: SequenceNumber,
+    /// 最後に受け取ったTSval。Timestampsを使っていなければNone
+    pub ts_recent: Option<u32>,
+    /// 4タプルを再び使えるようになる時刻
+    pub expires_at: Instant,
+}
+
+impl TimeWaitEntry {
+    /// 同じ4タプルへの新しいSYNを受け入れてよいか（RFC 6191 Section 2）
+    pub fn accepts_syn(&self, seq: SequenceNumber, tsval: Option<u32>) -> bool {
+        match (self.ts_recent, tsval) {
+            // TSvalも32ビットなので一周を考慮して比べる
+            (Some(recent), Some(tsval)) => (tsval.wrapping_sub(recent) as i32) > 0,
+            _ => seq > self.rcv_nxt,
+        }
+    }
+}
+
+/// 最近TIME-WAITに入った4タプルの表
+#[derive(Debug)]
+pub struct TimeWaitTable {
+    msl: Duration,
+    entries: HashMap<Quadruple, TimeWaitEntry>,
+}
+
+impl Default for TimeWaitTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeWaitTable {
+    pub fn new() -> Self {
+        Self::with_msl(DEFAULT_MSL)
+    }
+
+    pub fn with_msl(msl: Duration) -> Self {
+        Self {
+            msl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn msl(&self) -> Duration {
+        self.msl
+    }
+
+    /// TIME-WAITに入った接続を登録する
+    pub fn insert(&mut self, quadruple: Quadruple, entry: TimeWaitEntry) {
+        self.entries.insert(quadruple, entry);
+    }
+
+    /// 期限切れでない`quadruple`のエントリ
+    pub fn get(&self, quadruple: &Quadruple, now: Instant) -> Option<&TimeWaitEntry> {
+        self.entries
+            .get(quadruple)
+            .filter(|entry| now < entry.expires_at)
+    }
+
+    /// `quadruple`がTIME-WAIT中で、新しい接続に使えない
+    pub fn contains(&self, quadruple: &Quadruple, now: Instant) -> bool {
+        self.get(quadruple, now).is_some()
+    }
+
+    /// 相手がFINを再送してきた: 2·MSLのタイマーを再起動し、ACKに使うエントリを返す
+    pub fn restart(&mut self, quadruple: &Quadruple, now: Instant) -> Option<TimeWaitEntry> {
+        let expires_at = now + 2 * self.msl;
+        let entry = self
+            .entries
+            .get_mut(quadruple)
+            .filter(|entry| now < entry.expires_at)?;
+        entry.expires_at = expires_at;
+        Some(*entry)
+    }
+
+    /// 相手から新しいSYNが届いた: 受け入れてよければTIME-WAITのエントリを捨ててtrue
+    pub fn accept_syn(
+        &mut self,
+        quadruple: &Quadruple,
+        seq: SequenceNumber,
+        tsval: Option<u32>,
+        now: Instant,
+    ) -> bool {
+        let accepted = self
+            .get(quadruple, now)
+            .is_none_or(|entry| entry.accepts_syn(seq, tsval));
+        if accepted {
+            self.entries.remove(quadruple);
+        }
+        accepted
+    }
+
+    /// 期限切れのエントリを捨て、捨てた数を返す
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| now < entry.expires_at);
+        before - self.entries.len()
+    }
+
+    /// 登録されているエントリの数（期限切れで未削除のものも含む）
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}